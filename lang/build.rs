@@ -9,21 +9,32 @@ static NAMES: &'static [&'static str] = &[
     "file", "entity", "connections", "logic_hatchet", "script", "seed", "func_instance",
     "targetname", "classname", "logic_relay", "logic_auto", "x", "r", "pitch", "y", "g", "yaw",
     "z", "b", "roll", "w", "a", "Trigger", "OnMapSpawn", "OnTrigger",
+    "origin", "angles", "number", "int", "string", "null",
 ];
 
 static FUNCTIONS: &'static [&'static str] = &[
-    "range", "length",
+    "range", "length", "len", "push", "pop", "map", "filter", "sort", "contains",
+    "insert", "get", "keys", "values", "enumerate",
+    "split", "join", "replace", "substr", "starts_with", "ends_with", "matches", "trim", "upper", "lower",
     "exp", "sqrt", "pow", "sin", "cos", "floor", "ceil", "round", "fmuladd",
-    "rand", "create", "clone", "remove", "find", "find_class",
-    "print", "concat", "to_string", "parse", "get_instance",
-    "get_property", "get_sub_property", "set_property", "set_sub_property",
+    "abs", "log", "min", "max", "tan", "asin", "acos", "atan2", "clamp", "lerp", "sign", "deg", "rad", "mod",
+    "int_abs", "int_min", "int_max", "int_clamp", "int_sign", "int_mod",
+    "rand", "rand_int", "chance", "gaussian", "noise", "noise2", "noise3", "choose", "shuffle", "choose_weighted", "create", "clone", "remove", "has_error", "find", "exists", "entity_exists", "find_class",
+    "array_index", "int_div", "int_rem", "range_step", "print", "concat", "to_string", "int_to_string", "entity_name", "format_number", "parse", "parse_int", "parse_bool", "get_instance",
+    "get_property", "has", "get_sub_property", "set_property", "set_sub_property",
     "create_connection",
+    "str_len", "str_contains", "str_find", "str_starts_with", "str_ends_with", "str_matches",
+    "str_split", "str_join", "str_replace", "str_substr", "str_trim", "str_upper", "str_lower",
+    "vec3", "qangle", "dot", "cross", "normalize", "vector_length", "distance", "vector_lerp", "rotate",
 ];
 static GENERICS: &'static [&'static str] = &[
-    "vec_len", "vec_get", "eq",
+    "vec_len", "vec_get", "vec_slice", "vec_new", "vec_push", "vec_pop",
+    "vec_concat", "vec_contains", "vec_sort", "vec_find", "vec_get_or",
+    "vec_set", "vec_remove", "eq", "vec_choose", "vec_shuffle", "weighted_index",
+    "vector_add", "vector_sub", "vector_scale", "vector_to_string", "parse_vector",
 ];
 static TYPES: &'static [&'static str] = &[
-    "f64", "bool", "i64", "Atom", "Entity", "String",
+    "f64", "bool", "i64", "Atom", "Entity", "String", "vec3", "qangle",
 ];
 
 fn main() {
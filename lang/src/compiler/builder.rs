@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::borrow::Borrow;
 use std::ops::Deref;
 use std::ffi::CString;
+use std::sync::Arc;
 
 use llvm_sys::*;
 use llvm_sys::core::*;
@@ -9,6 +10,10 @@ use llvm_sys::prelude::*;
 
 use atom::Atom;
 use compiler::types::*;
+use error::{HatchetError, HatchetResult};
+use hct::SourceFile;
+use hct::ast::Span;
+use hct::diagnostic::Diagnostic;
 use runtime::stl::Externals;
 use runtime::types::Function;
 use vmf::ir::Entity;
@@ -17,12 +22,28 @@ pub type Type = LLVMTypeRef;
 pub type Value = LLVMValueRef;
 pub type Block = LLVMBasicBlockRef;
 
+/// Instance of a user function: its declaration, the types of
+/// its arguments and whether it is called from an event block
+pub type Instance = (Span, Vec<TypeId>, bool);
+
+/// State of the function being built, saved while building a nested user function
+pub struct FunctionState {
+    func: Value,
+    block: Block,
+    exit: Option<Block>,
+    current: Option<Instance>,
+}
+
 #[derive(Debug)]
 pub struct Builder {
     context: LLVMContextRef,
     module: LLVMModuleRef,
     func: Value,
     builder: LLVMBuilderRef,
+    exit: Option<Block>,
+    current: Option<Instance>,
+    functions: HashMap<Instance, (ValueRef, Option<TypeId>)>,
+    file: Arc<SourceFile>,
     entities: HashMap<Atom, Entity>,
     globals: HashMap<Global, Value>,
     externals: Externals,
@@ -49,7 +70,7 @@ pub type BuilderResult = (
 );
 
 impl Builder {
-    pub fn new(name: &str, entities: HashMap<Atom, Entity>) -> Builder {
+    pub fn new(name: &str, file: Arc<SourceFile>, entities: HashMap<Atom, Entity>) -> Builder {
         let context = unsafe {
             LLVMContextCreate()
         };
@@ -109,7 +130,11 @@ impl Builder {
             module,
             builder,
             func: main,
+            exit: None,
+            current: None,
+            functions: Default::default(),
 
+            file,
             entities,
             globals: Default::default(),
             externals: Externals::new(),
@@ -133,6 +158,15 @@ impl Builder {
         )
     }
 
+    /// Build the error reported at a location of the script,
+    /// returned by the code generation to abort the compilation
+    pub fn error<S: Into<String>>(&self, span: Span, message: S) -> HatchetError {
+        HatchetError::Type {
+            file: self.file.clone(),
+            diagnostics: vec![ Diagnostic::error(span, message) ],
+        }
+    }
+
     fn global_ptr<S, T>(&mut self, ty: TypeId, val: S) -> ValueRef where S: Borrow<T>, T: ToOwned + Deref<Target=str>, Global: From<<T as ToOwned>::Owned> {
         let cmp_ty = self.get_type(&ty);
         let &mut Builder { ref mut globals, module, .. } = self;
@@ -177,6 +211,18 @@ macro_rules! builder_forward {
         }
     };
 }
+macro_rules! builder_forward_same {
+    ($name:ident ( $first:ident $(, $args:ident )* ) = $op:ident ) => {
+        pub fn $name(&mut self, $first: &ValueRef $(, $args: &ValueRef )* ) -> ValueRef {
+            ValueRef {
+                ty: $first.ty.clone(),
+                ptr: unsafe {
+                    $op(self.builder, $first.ptr, $( $args.ptr, )* EMPTY_STRING.as_ptr())
+                },
+            }
+        }
+    };
+}
 macro_rules! builder_cmp {
     ($name:ident<f64, $pred:ident>( $( $args:ident ),* ) ) => {
         pub fn $name(&mut self, $( $args: &ValueRef ),* ) -> ValueRef {
@@ -208,6 +254,13 @@ impl Builder {
             TypeId::Vec { .. } => unsafe {
                 LLVMPointerType(LLVMInt8TypeInContext(self.context), 0)
             },
+            // Vectors and angles point to their three components
+            TypeId::vec3 | TypeId::qangle => unsafe {
+                LLVMPointerType(
+                    LLVMArrayType(LLVMDoubleTypeInContext(self.context), 3),
+                    0,
+                )
+            },
             TypeId::Array { ref len, ref ty } => unsafe {
                 LLVMPointerType(
                     LLVMArrayType(
@@ -236,6 +289,18 @@ impl Builder {
                     )
                 }
             },
+            // Dictionaries are a pair of vectors, holding the keys and the values
+            TypeId::HashMap { .. } => unsafe {
+                let vec = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+                let mut items = [ vec, vec ];
+
+                LLVMStructTypeInContext(
+                    self.context,
+                    items.as_mut_ptr(),
+                    items.len() as _,
+                    0,
+                )
+            },
             TypeId::f64 => unsafe { LLVMDoubleTypeInContext(self.context) },
             TypeId::bool => unsafe { LLVMInt1TypeInContext(self.context) },
             TypeId::i64 => unsafe { LLVMInt64TypeInContext(self.context) },
@@ -296,6 +361,133 @@ impl Builder {
     pub fn get_external(&mut self, name: Atom) -> Function {
         self.externals.get_function(self, name)
     }
+    pub fn get_opaque_pointer_type(&self) -> TypeId {
+        TypeId::Other(unsafe {
+            LLVMPointerType(LLVMInt8TypeInContext(self.context), 0)
+        })
+    }
+    pub fn get_param(&self, index: u32, ty: &TypeId) -> ValueRef {
+        ValueRef {
+            ty: ty.clone(),
+            ptr: unsafe {
+                LLVMGetParam(self.func, index)
+            },
+        }
+    }
+
+    /// Get a built instance of a user function along with its return type,
+    /// which is unknown until the first return statement of its body
+    pub fn get_user_function(&self, instance: &Instance) -> Option<(ValueRef, Option<TypeId>)> {
+        self.functions.get(instance).cloned()
+    }
+    /// Start building the body of a user function, returning the state
+    /// of the current function to restore once it is done
+    pub fn enter_function(&mut self, instance: Instance, func: ValueRef) -> FunctionState {
+        let state = FunctionState {
+            func: self.func,
+            block: self.get_insert_block(),
+            exit: self.exit.take(),
+            current: self.current.take(),
+        };
+
+        self.func = func.ptr;
+        self.current = Some(instance.clone());
+        self.functions.insert(instance, (func, None));
+
+        let entry_block = self.append_basic_block();
+        let start_block = self.append_basic_block();
+        unsafe {
+            LLVMPositionBuilderAtEnd(self.builder, entry_block);
+            LLVMBuildBr(self.builder, start_block);
+            LLVMPositionBuilderAtEnd(self.builder, start_block);
+        }
+
+        state
+    }
+    /// Finish the body of the current user function, returning its return type
+    pub fn leave_function(&mut self, state: FunctionState) -> Option<TypeId> {
+        unsafe {
+            LLVMBuildRetVoid(self.builder);
+        }
+
+        let instance = self.current.take().expect("not in a user function");
+        self.func = state.func;
+        self.exit = state.exit;
+        self.current = state.current;
+        self.position_at_end(state.block);
+
+        self.functions[&instance].1.clone()
+    }
+    /// Whether a user function is being built, rather than the main function
+    pub fn in_function(&self) -> bool {
+        self.current.is_some()
+    }
+    /// Record the type of a value returned by the current user function
+    pub fn set_return_type(&mut self, span: Span, ty: &TypeId) -> HatchetResult<()> {
+        let prev = match self.current {
+            Some(ref instance) => self.functions[instance].1.clone(),
+            None => return Err(self.error(span, "cannot return a value outside of a function")),
+        };
+
+        match prev {
+            Some(ref prev) if prev != ty => {
+                return Err(self.error(span, format!("mismatched return types: expected {:?}, found {:?}", prev, ty)));
+            },
+            Some(_) => {},
+            None => {
+                let instance = self.current.clone().unwrap();
+                self.functions.get_mut(&instance).unwrap().1 = Some(ty.clone());
+            },
+        }
+
+        Ok(())
+    }
+    /// Get the address the current user function writes its return value to
+    pub fn get_return_slot(&mut self, ty: &TypeId) -> ValueRef {
+        // The return value is written to an untyped pointer passed as the last parameter
+        let ptr = unsafe {
+            let slot = LLVMGetParam(self.func, LLVMCountParams(self.func) - 1);
+            LLVMBuildBitCast(
+                self.builder,
+                slot,
+                LLVMPointerType(self.get_type(ty), 0),
+                EMPTY_STRING.as_ptr()
+            )
+        };
+
+        ValueRef { ty: ty.clone(), ptr }
+    }
+    pub fn build_ret_void(&mut self) {
+        unsafe {
+            LLVMBuildRetVoid(self.builder);
+        }
+    }
+    pub fn build_si_to_fp(&mut self, val: &ValueRef) -> ValueRef {
+        ValueRef {
+            ty: TypeId::f64,
+            ptr: unsafe {
+                LLVMBuildSIToFP(self.builder, val.ptr, self.get_type(&TypeId::f64), EMPTY_STRING.as_ptr())
+            },
+        }
+    }
+
+    pub fn build_fp_to_si(&mut self, val: &ValueRef) -> ValueRef {
+        ValueRef {
+            ty: TypeId::i64,
+            ptr: unsafe {
+                LLVMBuildFPToSI(self.builder, val.ptr, self.get_type(&TypeId::i64), EMPTY_STRING.as_ptr())
+            },
+        }
+    }
+
+    pub fn build_bitcast(&mut self, val: &ValueRef, ty: TypeId) -> ValueRef {
+        let ptr = unsafe {
+            LLVMBuildBitCast(self.builder, val.ptr, self.get_type(&ty), EMPTY_STRING.as_ptr())
+        };
+
+        ValueRef { ty, ptr }
+    }
+
     pub fn get_runtime_context(&self) -> ValueRef {
         ValueRef {
             ty: TypeId::Context,
@@ -349,6 +541,14 @@ impl Builder {
             },
         }
     }
+    pub fn build_const_bool(&self, val: bool) -> ValueRef {
+        ValueRef {
+            ty: TypeId::bool,
+            ptr: unsafe {
+                LLVMConstInt(self.get_type(&TypeId::bool), val as u64, 0)
+            },
+        }
+    }
     pub fn build_const_i32(&self, val: i32) -> ValueRef {
         let ty = unsafe { LLVMInt32TypeInContext(self.context) };
         ValueRef {
@@ -397,6 +597,46 @@ impl Builder {
             None
         }
     }
+    pub fn get_const_i64(&self, val: &ValueRef) -> Option<i64> {
+        let is_int = unsafe { !LLVMIsAConstantInt(val.ptr).is_null() };
+        if val.ty == TypeId::i64 && is_int {
+            Some(unsafe { LLVMConstIntGetSExtValue(val.ptr) })
+        } else {
+            None
+        }
+    }
+    /// Get the value of a string constant from the global it was built as
+    pub fn get_const_string(&self, val: &ValueRef) -> Option<String> {
+        if val.ty != TypeId::String {
+            return None;
+        }
+
+        self.globals.iter()
+            .filter_map(|(glob, ptr)| match *glob {
+                Global::String(ref res) if *ptr == val.ptr => Some(res.clone()),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Get the block returning from the main function, creating it if needed
+    pub fn get_exit_block(&mut self) -> Block {
+        if let Some(block) = self.exit {
+            return block;
+        }
+
+        let current_block = self.get_insert_block();
+        let block = self.append_basic_block();
+
+        unsafe {
+            LLVMPositionBuilderAtEnd(self.builder, block);
+            LLVMBuildRetVoid(self.builder);
+            LLVMPositionBuilderAtEnd(self.builder, current_block);
+        }
+
+        self.exit = Some(block);
+        block
+    }
 
     pub fn append_basic_block(&mut self) -> Block {
         unsafe {
@@ -496,6 +736,19 @@ impl Builder {
 
         ValueRef { ty: obj.ty.clone(), ptr }
     }
+    pub fn build_select(&mut self, cond: &ValueRef, then: &ValueRef, otherwise: &ValueRef) -> ValueRef {
+        let ptr = unsafe {
+            LLVMBuildSelect(
+                self.builder,
+                cond.ptr,
+                then.ptr,
+                otherwise.ptr,
+                EMPTY_STRING.as_ptr()
+            )
+        };
+
+        ValueRef { ty: then.ty.clone(), ptr }
+    }
     pub fn build_insert_value(&mut self, container: &ValueRef, elem: &ValueRef, index: u32) -> ValueRef {
         let ptr = unsafe {
             LLVMBuildInsertValue(
@@ -509,6 +762,18 @@ impl Builder {
 
         ValueRef { ty: container.ty.clone(), ptr }
     }
+    pub fn build_extract_value(&mut self, container: &ValueRef, index: u32, ty: TypeId) -> ValueRef {
+        let ptr = unsafe {
+            LLVMBuildExtractValue(
+                self.builder,
+                container.ptr,
+                index,
+                EMPTY_STRING.as_ptr()
+            )
+        };
+
+        ValueRef { ty, ptr }
+    }
 
     builder_forward!{ build_fadd(rhs, lhs) -> f64 = LLVMBuildFAdd }
     builder_forward!{ build_fsub(rhs, lhs) -> f64 = LLVMBuildFSub }
@@ -516,7 +781,12 @@ impl Builder {
     builder_forward!{ build_fdiv(rhs, lhs) -> f64 = LLVMBuildFDiv }
     builder_forward!{ build_frem(rhs, lhs) -> f64 = LLVMBuildFRem }
 
+    builder_forward!{ build_fneg(val) -> f64 = LLVMBuildFNeg }
+
     builder_forward!{ build_nswadd(rhs, lhs) -> i64 = LLVMBuildNSWAdd }
+    builder_forward!{ build_sub(rhs, lhs) -> i64 = LLVMBuildSub }
+    builder_forward!{ build_mul(rhs, lhs) -> i64 = LLVMBuildMul }
+    builder_forward!{ build_neg(val) -> i64 = LLVMBuildNeg }
     builder_forward!{ build_shl(rhs, lhs) -> i64 = LLVMBuildShl }
     builder_forward!{ build_lshr(rhs, lhs) -> i64 = LLVMBuildLShr }
 
@@ -527,12 +797,17 @@ impl Builder {
     builder_cmp!{ build_float_eq<f64, LLVMRealOEQ>(rhs, lhs) }
     builder_cmp!{ build_float_ne<f64, LLVMRealONE>(rhs, lhs) }
 
-    builder_forward!{ build_and(rhs, lhs) -> bool = LLVMBuildAnd }
-    builder_forward!{ build_or(rhs, lhs) -> bool = LLVMBuildOr }
-    builder_forward!{ build_xor(rhs, lhs) -> bool = LLVMBuildXor }
-    builder_forward!{ build_not(val) -> bool = LLVMBuildNot }
+    // Bitwise operations work on both bool and i64
+    builder_forward_same!{ build_and(rhs, lhs) = LLVMBuildAnd }
+    builder_forward_same!{ build_or(rhs, lhs) = LLVMBuildOr }
+    builder_forward_same!{ build_xor(rhs, lhs) = LLVMBuildXor }
+    builder_forward_same!{ build_not(val) = LLVMBuildNot }
 
     builder_cmp!{ build_int_lt<i64, LLVMIntULT>(rhs, lhs) }
+    builder_cmp!{ build_int_slt<i64, LLVMIntSLT>(rhs, lhs) }
+    builder_cmp!{ build_int_sle<i64, LLVMIntSLE>(rhs, lhs) }
+    builder_cmp!{ build_int_sgt<i64, LLVMIntSGT>(rhs, lhs) }
+    builder_cmp!{ build_int_sge<i64, LLVMIntSGE>(rhs, lhs) }
     builder_cmp!{ build_int_eq<i64, LLVMIntEQ>(rhs, lhs) }
     builder_cmp!{ build_int_ne<i64, LLVMIntNE>(rhs, lhs) }
 }
\ No newline at end of file
@@ -0,0 +1,1867 @@
+//! Static type checking of a script, run before the code generation
+//! The rules mirror the ones of the codegen, but all the errors are collected
+//! instead of aborting on the first one
+
+use std::collections::{HashMap, HashSet};
+use std::iter::once;
+use std::rc::Rc;
+
+use atom::*;
+use hct::ast::*;
+use hct::diagnostic::Diagnostic;
+use runtime::stl::{Externals, is_known_entity, math_function, string_function};
+use vmf::ir::Entity;
+
+use super::types::*;
+
+/// Type environment of a block, the static counterpart of Scope
+struct Env<'a> {
+    parent: Option<&'a Env<'a>>,
+    /// Mutability and type of each binding, the type being
+    /// unknown if its value was invalid
+    bindings: HashMap<Atom, (bool, Option<TypeId>)>,
+    functions: HashMap<Atom, Rc<Function>>,
+    event: bool,
+    /// Whether this block is the body of a loop
+    in_loop: bool,
+}
+
+impl<'a> Env<'a> {
+    fn fork(&'a self, event: bool) -> Env<'a> {
+        Env {
+            parent: Some(self),
+            bindings: HashMap::new(),
+            functions: HashMap::new(),
+            event,
+            in_loop: false,
+        }
+    }
+
+    fn fork_loop(&'a self) -> Env<'a> {
+        Env {
+            in_loop: true,
+            .. self.fork(false)
+        }
+    }
+
+    /// Creates the environment of a function body, which only sees the
+    /// constant bindings and the functions visible from its call site
+    fn function_body(&self, event: bool) -> Env<'static> {
+        let mut bindings = HashMap::new();
+        let mut functions = HashMap::new();
+
+        let mut current = Some(self);
+        while let Some(env) = current {
+            for (key, value) in &env.bindings {
+                bindings.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            for (key, value) in &env.functions {
+                functions.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+
+            current = env.parent;
+        }
+
+        Env {
+            parent: None,
+            bindings: {
+                bindings.into_iter()
+                    .filter(|&(_, (mutable, _))| !mutable)
+                    .collect()
+            },
+            functions,
+            event,
+            in_loop: false,
+        }
+    }
+
+    fn binding(&self, key: &Atom) -> Option<(bool, Option<TypeId>)> {
+        self.bindings.get(key).cloned()
+            .or_else(|| {
+                self.parent.and_then(|parent| parent.binding(key))
+            })
+    }
+
+    fn function(&self, key: &Atom) -> Option<Rc<Function>> {
+        self.functions.get(key).cloned()
+            .or_else(|| {
+                self.parent.and_then(|parent| parent.function(key))
+            })
+    }
+
+    /// Calls are translated to connections inside event blocks
+    fn is_event(&self) -> bool {
+        self.event || self.parent.map_or(false, |parent| parent.is_event())
+    }
+
+    /// Loops can be left from any nested block, but not from a function body
+    fn is_loop(&self) -> bool {
+        self.in_loop || self.parent.map_or(false, |parent| parent.is_loop())
+    }
+}
+
+/// Instance of a user function: its declaration, the types of
+/// its arguments and whether it is called from an event block
+type Instance = (Span, Vec<TypeId>, bool);
+
+/// Return type of a function body being checked
+struct Frame {
+    instance: Option<Instance>,
+    ret: Option<TypeId>,
+    invalid: bool,
+}
+
+struct Checker<'e> {
+    diagnostics: Vec<Diagnostic>,
+    /// Entities of the map, for the names looked up at runtime
+    entities: &'e HashMap<Atom, Entity>,
+    /// Return types of the checked function instances
+    instances: HashMap<Instance, Option<TypeId>>,
+    /// Function bodies being checked, innermost last
+    frames: Vec<Frame>,
+    /// Declarations of the functions checked at least once
+    checked: HashSet<Span>,
+}
+
+/// Index of a virtual sub-property of a vector property
+pub fn sub_property(name: &Atom) -> Option<i64> {
+    match *name {
+        hct_atom!("x") | hct_atom!("r") | hct_atom!("pitch") => Some(0),
+        hct_atom!("y") | hct_atom!("g") | hct_atom!("yaw") => Some(1),
+        hct_atom!("z") | hct_atom!("b") | hct_atom!("roll") => Some(2),
+        hct_atom!("w") | hct_atom!("a") => Some(3),
+        _ => None,
+    }
+}
+
+/// Index of a component of a vector or an angle
+pub fn vector_component(name: &Atom) -> Option<i64> {
+    match sub_property(name) {
+        Some(idx) if idx < 3 => Some(idx),
+        _ => None,
+    }
+}
+
+/// Type of an entity property holding a vector or an angle,
+/// converted from and to its string value when accessed
+pub fn vector_property(name: &Atom) -> Option<TypeId> {
+    match *name {
+        hct_atom!("origin") => Some(TypeId::vec3),
+        hct_atom!("angles") => Some(TypeId::qangle),
+        _ => None,
+    }
+}
+
+/// Whether a value can be used where a float is expected
+fn is_number(ty: &TypeId) -> bool {
+    *ty == TypeId::f64 || *ty == TypeId::i64
+}
+
+/// Check if a value can be converted to a type with `as`: strings are parsed
+/// as numbers, booleans or vectors, and any printable value can be a string
+pub fn can_cast(from: &TypeId, to: &TypeId) -> bool {
+    match (from, to) {
+        _ if from == to => true,
+        (&TypeId::String, to) => is_number(to) || to.is_vector() || *to == TypeId::bool,
+        (&TypeId::f64, &TypeId::i64) | (&TypeId::i64, &TypeId::f64) => true,
+        (from, &TypeId::String) => is_printable(from),
+        _ => false,
+    }
+}
+
+/// Type the value of an entity property is parsed to when an operator
+/// combines it with a number, so that it can be used in arithmetic
+pub fn coerced_type(op: Operator, other: &TypeId) -> Option<TypeId> {
+    if !is_number(other) {
+        return None;
+    }
+
+    match op {
+        Operator::And | Operator::Or | Operator::Coalesce => None,
+        Operator::BAnd | Operator::BOr | Operator::BXor | Operator::Shl | Operator::Shr => Some(TypeId::i64),
+        _ => Some(TypeId::f64),
+    }
+}
+
+/// Error for a type that cannot be stored in a growable list or a dictionary
+pub fn element_error(ty: &TypeId, container: &str) -> String {
+    format!("cannot store a {:?} in a {}, which can only hold numbers, booleans, strings and entities", ty, container)
+}
+
+/// Whether a value can be interpolated in a string
+fn is_printable(ty: &TypeId) -> bool {
+    match *ty {
+        TypeId::String | TypeId::f64 | TypeId::i64 | TypeId::bool | TypeId::Entity |
+        TypeId::vec3 | TypeId::qangle => true,
+        TypeId::Array { ref ty, .. } | TypeId::Vec { ref ty } => is_printable(ty),
+        _ => false,
+    }
+}
+
+/// Value of a string literal without interpolations
+fn literal_string(exp: &Expression) -> Option<String> {
+    let parts = match exp.kind {
+        ExpressionKind::Literal(Literal::String(ref parts)) => parts,
+        _ => return None,
+    };
+
+    let mut res = String::new();
+    for part in parts {
+        match *part {
+            StringPart::String(ref value) => res.push_str(value),
+            StringPart::Expression(..) => return None,
+        }
+    }
+
+    Some(res)
+}
+
+/// Whether a call looks an entity up by name with the STL,
+/// finding a null handle if it is not in the map
+fn is_lookup(call: &Call, env: &Env) -> bool {
+    let name = match call.path.kind {
+        PathKind::Binding(ref name) => name,
+        _ => return false,
+    };
+
+    // Searching a string is also called find, with a pattern as second argument
+    let is_lookup = *name == hct_atom!("find") || *name == hct_atom!("exists");
+    is_lookup && call.args.len() == 1 && env.function(name).is_none() && !env.is_event()
+}
+
+/// Whether a block returns on all of its paths
+fn always_returns(list: &[Statement]) -> bool {
+    list.iter().any(|stmt| match stmt.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::Branch { ref consequent, alternate: Some(ref alternate), .. } |
+        StatementKind::BranchLet { ref consequent, alternate: Some(ref alternate), .. } => {
+            always_returns(consequent) && always_returns(alternate)
+        },
+        // Matches without a wildcard may not run any arm
+        StatementKind::Match { ref arms, .. } => {
+            arms.iter().any(|arm| arm.values.is_none()) &&
+            arms.iter().all(|arm| always_returns(&arm.body))
+        },
+        _ => false,
+    })
+}
+
+impl<'e> Checker<'e> {
+    fn error<S: Into<String>>(&mut self, span: Span, message: S) {
+        self.diagnostics.push(Diagnostic::error(span, message));
+    }
+
+    fn warning<S: Into<String>>(&mut self, span: Span, message: S) {
+        self.diagnostics.push(Diagnostic::warning(span, message));
+    }
+
+    /// Check that a value has the expected type, unless it is already invalid
+    /// Integers are implicitly widened where a float is expected
+    fn expect(&mut self, span: Span, expected: &TypeId, found: &Option<TypeId>) {
+        if let Some(ref found) = *found {
+            if found != expected && !(*expected == TypeId::f64 && *found == TypeId::i64) {
+                self.error(span, format!("expected type {:?}, found {:?}", expected, found));
+            }
+        }
+    }
+
+    fn statements(&mut self, list: &[Statement], mut env: Env) {
+        // Relays and functions are hoisted to the top of their block
+        for stmt in list {
+            match stmt.kind {
+                StatementKind::Relay { ref name, .. } => {
+                    env.bindings.insert(name.clone(), (false, Some(TypeId::Entity)));
+                },
+                StatementKind::Function(ref func) => {
+                    if env.functions.insert(func.name.clone(), Rc::new(func.clone())).is_some() {
+                        self.error(func.span, format!("function {} is already defined", func.name));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        for &Statement { ref kind, span } in list {
+            match *kind {
+                StatementKind::Binding { ref pattern, ref ty, ref value } => {
+                    // Invalid values are still bound to avoid cascading "not found" errors
+                    let ty = self.binding_type(ty, value, span, &env);
+                    for (name, ty) in self.pattern(pattern, ty, span) {
+                        env.bindings.insert(name, (true, ty));
+                    }
+                },
+
+                StatementKind::Relay { ref body, .. } |
+                StatementKind::Auto { ref body } => {
+                    self.statements(body, env.fork(true));
+                },
+
+                StatementKind::Subscriber { ref path, ref body } => {
+                    self.event(path, &env);
+                    self.statements(body, env.fork(true));
+                },
+
+                StatementKind::Delay { ref time, ref body } => {
+                    let ty = self.expression(time, &env);
+                    self.expect(time.span, &TypeId::f64, &ty);
+                    self.statements(body, env.fork(false));
+                },
+
+                StatementKind::Loop { ref condition, ref body } => {
+                    let ty = self.expression(condition, &env);
+                    self.expect(condition.span, &TypeId::bool, &ty);
+                    self.statements(body, env.fork_loop());
+                },
+
+                StatementKind::Iterator { ref var, ref value, ref array, ref body } => {
+                    // The type of the values is only known when iterating on a dictionary
+                    let (ty, values) = if let Some(range) = array.range() {
+                        (self.range(range, array.span, &env), None)
+                    } else {
+                        match array.kind {
+                            // Enumerated lists are iterated on their indices and elements
+                            ExpressionKind::Call(Call { path: Path { kind: PathKind::Binding(hct_atom!("enumerate")), .. }, ref args, span: call_span }) => {
+                                if args.len() != 1 {
+                                    self.error(call_span, format!("enumerate expects 1 argument, found {}", args.len()));
+                                }
+
+                                let list = match args.first() {
+                                    Some(arg) => self.expression(arg, &env),
+                                    None => None,
+                                };
+
+                                match list {
+                                    Some(TypeId::Array { ty, .. }) |
+                                    Some(TypeId::Vec { ty }) => (Some(TypeId::i64), Some(Some(*ty))),
+                                    Some(ty) => {
+                                        self.error(args[0].span, format!("cannot enumerate a {:?}", ty));
+                                        (Some(TypeId::i64), Some(None))
+                                    },
+                                    None => (Some(TypeId::i64), Some(None)),
+                                }
+                            },
+
+                            _ => match self.expression(array, &env) {
+                                Some(TypeId::Array { ty, .. }) |
+                                Some(TypeId::Vec { ty }) => (Some(*ty), None),
+                                // Dictionaries are iterated on their keys
+                                Some(TypeId::HashMap { key, value }) => (Some(*key), Some(Some(*value))),
+                                Some(ty) => {
+                                    self.error(array.span, format!("Tried to iterate on a non-array: {:?}", ty));
+                                    (None, Some(None))
+                                },
+                                None => (None, Some(None)),
+                            },
+                        }
+                    };
+
+                    let mut env = env.fork_loop();
+                    for (name, ty) in self.pattern(var, ty, span) {
+                        env.bindings.insert(name, (true, ty));
+                    }
+                    if let Some(ref value) = *value {
+                        let ty = match values {
+                            Some(ty) => ty,
+                            None => {
+                                self.error(span, "only dictionaries and enumerated lists can be iterated with two bindings");
+                                None
+                            },
+                        };
+
+                        for (name, ty) in self.pattern(value, ty, span) {
+                            env.bindings.insert(name, (true, ty));
+                        }
+                    }
+
+                    self.statements(body, env);
+                },
+
+                StatementKind::Branch { ref condition, ref consequent, ref alternate } => {
+                    let ty = self.expression(condition, &env);
+                    self.expect(condition.span, &TypeId::bool, &ty);
+
+                    self.statements(consequent, env.fork(false));
+                    if let Some(ref alternate) = *alternate {
+                        self.statements(alternate, env.fork(false));
+                    }
+                },
+
+                StatementKind::BranchLet { ref name, ref value, ref consequent, ref alternate } => {
+                    // The entity found by name is only bound if it exists
+                    let ty = match value.kind {
+                        ExpressionKind::Call(ref call) => {
+                            if is_lookup(call, &env) {
+                                self.lookup(call, true);
+                            }
+
+                            self.call(call, &env)
+                        },
+                        _ => self.expression(value, &env),
+                    };
+                    self.expect(value.span, &TypeId::Entity, &ty);
+
+                    let mut inner = env.fork(false);
+                    inner.bindings.insert(name.clone(), (true, Some(TypeId::Entity)));
+                    self.statements(consequent, inner);
+                    if let Some(ref alternate) = *alternate {
+                        self.statements(alternate, env.fork(false));
+                    }
+                },
+
+                StatementKind::Match { ref value, ref arms } => {
+                    let ty = match self.expression(value, &env) {
+                        ty @ Some(TypeId::String) |
+                        ty @ Some(TypeId::f64) |
+                        ty @ Some(TypeId::i64) |
+                        ty @ Some(TypeId::Entity) => ty,
+                        Some(ty) => {
+                            self.error(value.span, format!("cannot match a {:?}", ty));
+                            None
+                        },
+                        None => None,
+                    };
+
+                    for (idx, arm) in arms.iter().enumerate() {
+                        match arm.values {
+                            Some(ref values) => {
+                                for value in values {
+                                    let found = self.expression(value, &env);
+                                    if let Some(ref ty) = ty {
+                                        self.expect(value.span, ty, &found);
+                                    }
+                                }
+                            },
+                            None => if idx + 1 < arms.len() {
+                                self.error(arm.span, "the wildcard must be the last arm of a match");
+                            },
+                        }
+
+                        self.statements(&arm.body, env.fork(false));
+                    }
+                },
+
+                StatementKind::Assignment { ref target, op, ref value } => {
+                    let ty = self.expression(value, &env);
+                    self.assignment(target, op, value.span, ty, &env);
+                },
+
+                StatementKind::Call(ref call) => {
+                    self.call(call, &env);
+                },
+
+                // Function bodies are checked for each call signature
+                StatementKind::Function(_) => {},
+
+                StatementKind::Return(ref value) => {
+                    self.return_(value, span, &env);
+                },
+
+                StatementKind::Break => if !env.is_loop() {
+                    self.error(span, "break outside of a loop");
+                },
+                StatementKind::Continue => if !env.is_loop() {
+                    self.error(span, "continue outside of a loop");
+                },
+
+                // Imports are resolved when loading the script
+                StatementKind::Import { .. } => {},
+
+                // Syntax errors are reported by the parser
+                StatementKind::Error(_) => {},
+            }
+        }
+
+        // Functions that are never called are still checked, with unknown argument types
+        for stmt in list {
+            if let StatementKind::Function(ref func) = stmt.kind {
+                if !self.checked.contains(&func.span) {
+                    let args = vec![ None; func.args.len() ];
+                    self.function(func, args, None, &env);
+                }
+            }
+        }
+    }
+
+    fn return_(&mut self, value: &Option<Expression>, span: Span, env: &Env) {
+        let ty = match *value {
+            Some(ref value) => match self.expression(value, env) {
+                Some(ty @ TypeId::Array { .. }) |
+                Some(ty @ TypeId::Object { .. }) => {
+                    self.error(value.span, format!("cannot return a {:?} from a function", ty));
+                    None
+                },
+                ty => ty,
+            },
+            None => Some(TypeId::Void),
+        };
+
+        // Returning from the script itself stops it early
+        if self.frames.is_empty() {
+            if let Some(ref value) = *value {
+                self.error(value.span, "cannot return a value outside of a function");
+            }
+
+            return;
+        }
+
+        let mismatch = {
+            let frame = self.frames.last_mut().unwrap();
+            match (ty, frame.ret.clone()) {
+                (None, _) => {
+                    frame.invalid = true;
+                    None
+                },
+                (Some(ty), None) => {
+                    frame.ret = Some(ty);
+                    None
+                },
+                (Some(ty), Some(prev)) => if ty != prev {
+                    Some((prev, ty))
+                } else {
+                    None
+                },
+            }
+        };
+
+        if let Some((prev, ty)) = mismatch {
+            self.error(span, format!("mismatched return types: expected {:?}, found {:?}", prev, ty));
+        }
+    }
+
+    /// Check the body of a function with the given argument types, returning its return type
+    fn function(&mut self, func: &Function, args: Vec<Option<TypeId>>, instance: Option<Instance>, env: &Env) -> Option<TypeId> {
+        self.checked.insert(func.span);
+
+        let mut body = env.function_body(env.is_event());
+        for (name, ty) in func.args.iter().zip(args) {
+            body.bindings.insert(name.clone(), (true, ty));
+        }
+
+        self.frames.push(Frame {
+            instance,
+            ret: None,
+            invalid: false,
+        });
+        self.statements(&func.body, body);
+        let frame = self.frames.pop().unwrap();
+
+        if frame.invalid {
+            return None;
+        }
+
+        let ret = frame.ret.unwrap_or(TypeId::Void);
+        if ret != TypeId::Void && !always_returns(&func.body) {
+            self.error(func.span, format!("function {} does not return a value on all paths", func.name));
+        }
+
+        Some(ret)
+    }
+
+    /// Check a call to a user function, instantiating it for the types of the arguments
+    fn user_call(&mut self, func: &Function, args: Vec<(Option<TypeId>, Span)>, span: Span, env: &Env) -> Option<TypeId> {
+        if func.args.len() != args.len() {
+            self.error(span, format!("{} expects {} arguments, found {}", func.name, func.args.len(), args.len()));
+            return None;
+        }
+
+        let args = match args.into_iter().map(|(ty, _)| ty).collect::<Option<Vec<_>>>() {
+            Some(args) => args,
+            None => return None,
+        };
+
+        let instance = (func.span, args, env.is_event());
+
+        // Recursive calls can only be typed after a first return statement
+        let pending = {
+            self.frames.iter()
+                .find(|frame| frame.instance.as_ref() == Some(&instance))
+                .map(|frame| frame.ret.clone())
+        };
+
+        if let Some(ret) = pending {
+            if ret.is_none() {
+                self.error(span, format!("cannot infer the return type of the recursive function {}", func.name));
+            }
+
+            return ret;
+        }
+
+        if let Some(ret) = self.instances.get(&instance) {
+            return ret.clone();
+        }
+
+        let args = instance.1.iter().cloned().map(Some).collect();
+        let ret = self.function(func, args, Some(instance.clone()), env);
+        self.instances.insert(instance, ret.clone());
+        ret
+    }
+
+    /// Type of a call to map or filter, which take a function name as their second argument
+    fn higher_order_call(&mut self, name: &Atom, args: &[Expression], span: Span, env: &Env) -> Option<TypeId> {
+        if args.len() != 2 {
+            self.error(span, format!("{} expects 2 arguments, found {}", name, args.len()));
+            return None;
+        }
+
+        let elem = match self.expression(&args[0], env) {
+            Some(TypeId::Array { ty, .. }) |
+            Some(TypeId::Vec { ty }) => Some(*ty),
+            Some(ty) => {
+                self.error(args[0].span, format!("Tried to iterate on a non-array: {:?}", ty));
+                None
+            },
+            None => None,
+        };
+
+        let func = match args[1].kind {
+            ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), .. }) => env.function(name),
+            _ => None,
+        };
+
+        let func = match func {
+            Some(func) => func,
+            None => {
+                self.error(args[1].span, "expected the name of a function");
+                return None;
+            },
+        };
+
+        let ret = self.user_call(&func, vec![ (elem.clone(), args[0].span) ], span, env);
+        let ty = if *name == hct_atom!("map") {
+            ret
+        } else {
+            self.expect(span, &TypeId::bool, &ret);
+            elem
+        };
+
+        match ty {
+            Some(ty) => if ty.is_vec_element() {
+                Some(TypeId::Vec { ty: box ty })
+            } else {
+                self.error(span, element_error(&ty, "list"));
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Type of a call to a list operation, or None if the
+    /// called function is not one for these arguments
+    fn list_call(&mut self, name: &Atom, args: &[(Option<TypeId>, Span)], span: Span) -> Option<Option<TypeId>> {
+        let arity = match *name {
+            hct_atom!("len") | hct_atom!("pop") | hct_atom!("sort") |
+            hct_atom!("choose") | hct_atom!("shuffle") => 1,
+            hct_atom!("push") | hct_atom!("contains") | hct_atom!("choose_weighted") => 2,
+            // Strings are concatenated by the STL
+            hct_atom!("concat") => match args.first() {
+                Some(&(Some(TypeId::Vec { .. }), _)) => 2,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        if args.len() != arity {
+            self.error(span, format!("{} expects {} arguments, found {}", name, arity, args.len()));
+            return Some(None);
+        }
+
+        let (ref list, list_span) = args[0];
+        let elem = match *list {
+            Some(TypeId::Array { .. }) if *name == hct_atom!("len") => return Some(Some(TypeId::i64)),
+            Some(TypeId::Vec { ref ty }) => (**ty).clone(),
+            // Arrays of other values have a fixed size
+            Some(TypeId::Array { ref ty, .. }) => {
+                self.error(list_span, format!("{} expects a growable list, found a fixed-size array: {}", name, element_error(ty, "list")));
+                return Some(None);
+            },
+            Some(ref ty) => {
+                self.error(list_span, format!("expected a list, found {:?}", ty));
+                return Some(None);
+            },
+            None => return Some(None),
+        };
+
+        if let Some(&(ref value, span)) = args.get(1) {
+            if *name == hct_atom!("choose_weighted") {
+                match *value {
+                    Some(TypeId::Vec { ref ty }) if **ty == TypeId::f64 || **ty == TypeId::i64 => {},
+                    Some(ref ty) => self.error(span, format!("expected a list of numbers, found {:?}", ty)),
+                    None => {},
+                }
+            } else {
+                let expected = if *name == hct_atom!("concat") {
+                    TypeId::Vec { ty: box elem.clone() }
+                } else {
+                    elem.clone()
+                };
+
+                self.expect(span, &expected, value);
+            }
+        }
+
+        Some(match *name {
+            hct_atom!("len") => Some(TypeId::i64),
+            hct_atom!("pop") | hct_atom!("choose") | hct_atom!("choose_weighted") => Some(elem),
+            hct_atom!("contains") => Some(TypeId::bool),
+            hct_atom!("concat") => list.clone(),
+            _ => Some(TypeId::Void),
+        })
+    }
+
+    /// Type of a call to a string function, or None if the
+    /// called function is not one for these arguments
+    fn string_call(&mut self, name: &Atom, args: &[(Option<TypeId>, Span)], span: Span) -> Option<Option<TypeId>> {
+        let ext = match args.first() {
+            Some(&(Some(ref first), _)) => match string_function(name, args.len(), first) {
+                Some(ext) => ext,
+                None => return None,
+            },
+            _ => return None,
+        };
+
+        Some(self.external_call(name, &ext, args, span))
+    }
+
+    /// Type of a call to a math function, or None if the
+    /// called function is not one for these arguments
+    fn math_call(&mut self, name: &Atom, args: &[(Option<TypeId>, Span)], span: Span) -> Option<Option<TypeId>> {
+        let ext = match math_function(name, args.iter().filter_map(|&(ref ty, _)| ty.as_ref())) {
+            Some(ext) => ext,
+            None => return None,
+        };
+
+        Some(self.external_call(name, &ext, args, span))
+    }
+
+    /// Check a call to the external implementing a function
+    /// against its signature, reporting errors with the called name
+    fn external_call(&mut self, name: &Atom, ext: &Atom, args: &[(Option<TypeId>, Span)], span: Span) -> Option<TypeId> {
+        let (params, ret) = Externals::signature(ext).expect("missing external function");
+        let params: Vec<_> = {
+            params.into_iter()
+                .filter(|ty| *ty != TypeId::Context)
+                .collect()
+        };
+
+        if params.len() != args.len() {
+            self.error(span, format!("{} expects {} arguments, found {}", name, params.len(), args.len()));
+            return None;
+        }
+
+        for (param, &(ref arg, span)) in params.iter().zip(args) {
+            self.expect(span, param, arg);
+        }
+
+        Some(ret)
+    }
+
+    /// Type of a call to a dictionary operation, or None if the
+    /// called function is not one for these arguments
+    fn dict_call(&mut self, name: &Atom, args: &[(Option<TypeId>, Span)], span: Span) -> Option<Option<TypeId>> {
+        let is_dict_only = match *name {
+            hct_atom!("insert") | hct_atom!("get") | hct_atom!("keys") | hct_atom!("values") => true,
+            _ => false,
+        };
+
+        let (key, value) = match args.first() {
+            Some(&(Some(TypeId::HashMap { ref key, ref value }), _)) => ((**key).clone(), (**value).clone()),
+            // Avoid cascading errors on invalid dictionaries
+            Some(&(None, _)) if is_dict_only => return Some(None),
+            _ => return None,
+        };
+
+        let arity = match *name {
+            hct_atom!("len") | hct_atom!("keys") | hct_atom!("values") => 1,
+            hct_atom!("contains") | hct_atom!("remove") => 2,
+            hct_atom!("insert") | hct_atom!("get") => 3,
+            _ => return None,
+        };
+
+        if args.len() != arity {
+            self.error(span, format!("{} expects {} arguments, found {}", name, arity, args.len()));
+            return Some(None);
+        }
+
+        // The arguments following the dictionary are a key and a value
+        for (&(ref arg, span), ty) in args[1..].iter().zip(&[ key.clone(), value.clone() ]) {
+            self.expect(span, ty, arg);
+        }
+
+        Some(Some(match *name {
+            hct_atom!("len") => TypeId::i64,
+            hct_atom!("keys") => TypeId::Vec { ty: box key },
+            hct_atom!("values") => TypeId::Vec { ty: box value },
+            hct_atom!("contains") => TypeId::bool,
+            hct_atom!("get") => value,
+            _ => TypeId::Void,
+        }))
+    }
+
+    /// Type of the value of a binding, checked against its annotation
+    fn binding_type(&mut self, ty: &Option<TypeName>, value: &Expression, span: Span, env: &Env) -> Option<TypeId> {
+        let expected = match *ty {
+            Some(ref ty) => match TypeId::from_annotation(ty) {
+                Some(TypeId::Vec { ref ty }) if !ty.is_vec_element() => {
+                    self.error(span, element_error(ty, "list"));
+                    None
+                },
+                Some(TypeId::HashMap { ref key, .. }) if !key.is_dict_key() => {
+                    self.error(span, format!("cannot use a {:?} as a dictionary key", key));
+                    None
+                },
+                Some(TypeId::HashMap { ref value, .. }) if !value.is_vec_element() => {
+                    self.error(span, format!("cannot store a {:?} in a dictionary", value));
+                    None
+                },
+                Some(ty) => Some(ty),
+                None => {
+                    self.error(span, format!("unknown type {}", ty));
+                    None
+                },
+            },
+            None => None,
+        };
+
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return self.expression(value, env),
+        };
+
+        // Empty lists take their element type from the annotation
+        let is_empty_list = match (&expected, &value.kind) {
+            (&TypeId::Vec { .. }, &ExpressionKind::Array(ref values)) => values.is_empty(),
+            _ => false,
+        };
+
+        if is_empty_list {
+            return Some(expected);
+        }
+
+        // Map literals are converted to the annotated dictionary
+        if let ExpressionKind::Map(ref entries) = value.kind {
+            let elem = match expected {
+                TypeId::HashMap { ref value, .. } => Some((**value).clone()),
+                _ => None,
+            };
+
+            if let Some(elem) = elem {
+                for exp in entries.values() {
+                    let found = self.expression(exp, env);
+                    self.expect(exp.span, &elem, &found);
+                }
+
+                return Some(expected);
+            }
+        }
+
+        let found = self.expression(value, env);
+        self.expect(value.span, &expected, &found);
+        Some(expected)
+    }
+
+    fn assignment(&mut self, target: &Expression, op: Option<Operator>, value_span: Span, value: Option<TypeId>, env: &Env) {
+        match target.kind {
+            ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), span }) => {
+                match env.binding(name) {
+                    Some((true, Some(ty))) => {
+                        let value = self.compound(op, Some(ty.clone()), value, target.span);
+                        self.expect(value_span, &ty, &value);
+                    },
+                    Some((true, None)) => {},
+                    _ => self.error(span, format!("binding \"{}\" not found", name)),
+                }
+            },
+
+            ExpressionKind::Index { .. } => {
+                let ty = self.expression(target, env);
+                if let Some(ty) = ty {
+                    let value = self.compound(op, Some(ty.clone()), value, target.span);
+                    self.expect(value_span, &ty, &value);
+                }
+            },
+
+            _ => match target.clone().split_field() {
+                Ok((obj, prop)) => {
+                    let is_property = match obj.clone().split_field() {
+                        Ok((base, _)) => self.expression(&base, env) == Some(TypeId::Entity),
+                        Err(_) => false,
+                    };
+
+                    match self.expression(&obj, env) {
+                        Some(TypeId::Object { items }) => {
+                            match items.binary_search_by(|&(ref item, _)| item.cmp(&prop)) {
+                                Ok(idx) => {
+                                    let value = self.compound(op, Some(items[idx].1.clone()), value, target.span);
+                                    self.expect(value_span, &items[idx].1, &value);
+                                },
+                                Err(_) => self.error(target.span, format!("key \"{}\" not found in object", prop)),
+                            }
+                        },
+
+                        Some(TypeId::Entity) => {
+                            let value = self.property_compound(op, &prop, value, target.span);
+                            self.property_value(value_span, value);
+                        },
+
+                        // Sub-properties can only be assigned on the property of an entity
+                        Some(TypeId::String) if is_property => {
+                            if sub_property(&prop).is_none() {
+                                self.error(target.span, format!("unsupported sub-property \"{}\"", prop));
+                            }
+
+                            let value = self.property_compound(op, &prop, value, target.span);
+                            self.property_value(value_span, value);
+                        },
+                        Some(ref ty) if is_property && ty.is_vector() => {
+                            if vector_component(&prop).is_none() {
+                                self.error(target.span, format!("unsupported component \"{}\" of a {:?}", prop, ty));
+                            }
+
+                            let value = self.property_compound(op, &prop, value, target.span);
+                            self.property_value(value_span, value);
+                        },
+
+                        Some(ty) => self.error(target.span, format!("cannot assign to a field of a {:?}", ty)),
+                        None => {},
+                    }
+                },
+                Err(_) => self.error(target.span, "cannot assign to this expression"),
+            },
+        }
+    }
+
+    /// Resolve the type of the value stored by an assignment,
+    /// applying the operator of a compound assignment
+    fn compound(&mut self, op: Option<Operator>, current: Option<TypeId>, value: Option<TypeId>, span: Span) -> Option<TypeId> {
+        match op {
+            Some(op) => self.binary(current, op, value, span),
+            None => value,
+        }
+    }
+
+    /// Resolve the type of the value stored in an entity property by an
+    /// assignment, the current value being parsed as a vector or a number
+    /// unless a string is appended to it
+    fn property_compound(&mut self, op: Option<Operator>, prop: &Atom, value: Option<TypeId>, span: Span) -> Option<TypeId> {
+        let current = if value == Some(TypeId::String) {
+            TypeId::String
+        } else {
+            vector_property(prop).unwrap_or(TypeId::f64)
+        };
+
+        self.compound(op, Some(current), value, span)
+    }
+
+    /// Whether an expression reads a property of an entity, or a sub-property of one
+    fn is_property(&mut self, exp: &Expression, env: &Env) -> bool {
+        match exp.clone().split_field() {
+            Ok((obj, _)) => match self.expression(&obj, env) {
+                Some(TypeId::Entity) => true,
+                Some(TypeId::String) => self.is_property(&obj, env),
+                _ => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Resolve the type of an operand, the value of an entity property
+    /// being parsed as a number when it is combined with one
+    fn operand(&mut self, exp: &Expression, ty: Option<TypeId>, op: Operator, other: &Option<TypeId>, env: &Env) -> Option<TypeId> {
+        match (ty, other) {
+            (Some(TypeId::String), &Some(ref other)) if coerced_type(op, other).is_some() && self.is_property(exp, env) => {
+                coerced_type(op, other)
+            },
+            (ty, _) => ty,
+        }
+    }
+
+    /// Resolve the type of a binary operation
+    fn binary(&mut self, lhs: Option<TypeId>, op: Operator, rhs: Option<TypeId>, span: Span) -> Option<TypeId> {
+        let (lhs, rhs) = match (lhs, rhs) {
+            // Integers are widened when mixed with floats
+            (Some(TypeId::i64), Some(TypeId::f64)) |
+            (Some(TypeId::f64), Some(TypeId::i64)) => (TypeId::f64, TypeId::f64),
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => return None,
+        };
+
+        match (&lhs, op, &rhs) {
+            (&TypeId::String, Operator::Add, &TypeId::String) => Some(TypeId::String),
+
+            (&TypeId::f64, Operator::Add, &TypeId::f64) |
+            (&TypeId::f64, Operator::Sub, &TypeId::f64) |
+            (&TypeId::f64, Operator::Mul, &TypeId::f64) |
+            (&TypeId::f64, Operator::Div, &TypeId::f64) |
+            (&TypeId::f64, Operator::Mod, &TypeId::f64) => Some(TypeId::f64),
+
+            (&TypeId::i64, Operator::Add, &TypeId::i64) |
+            (&TypeId::i64, Operator::Sub, &TypeId::i64) |
+            (&TypeId::i64, Operator::Mul, &TypeId::i64) |
+            (&TypeId::i64, Operator::Div, &TypeId::i64) |
+            (&TypeId::i64, Operator::Mod, &TypeId::i64) |
+            (&TypeId::i64, Operator::Shl, &TypeId::i64) |
+            (&TypeId::i64, Operator::Shr, &TypeId::i64) |
+            (&TypeId::i64, Operator::BAnd, &TypeId::i64) |
+            (&TypeId::i64, Operator::BOr, &TypeId::i64) |
+            (&TypeId::i64, Operator::BXor, &TypeId::i64) => Some(TypeId::i64),
+
+            (&TypeId::f64, Operator::Lt, &TypeId::f64) |
+            (&TypeId::f64, Operator::Lte, &TypeId::f64) |
+            (&TypeId::f64, Operator::Gt, &TypeId::f64) |
+            (&TypeId::f64, Operator::Gte, &TypeId::f64) |
+            (&TypeId::i64, Operator::Lt, &TypeId::i64) |
+            (&TypeId::i64, Operator::Lte, &TypeId::i64) |
+            (&TypeId::i64, Operator::Gt, &TypeId::i64) |
+            (&TypeId::i64, Operator::Gte, &TypeId::i64) |
+            (&TypeId::bool, Operator::And, &TypeId::bool) |
+            (&TypeId::bool, Operator::Or, &TypeId::bool) => Some(TypeId::bool),
+
+            (&TypeId::f64, Operator::Eq, &TypeId::f64) |
+            (&TypeId::f64, Operator::Neq, &TypeId::f64) |
+            (&TypeId::bool, Operator::Eq, &TypeId::bool) |
+            (&TypeId::bool, Operator::Neq, &TypeId::bool) |
+            (&TypeId::i64, Operator::Eq, &TypeId::i64) |
+            (&TypeId::i64, Operator::Neq, &TypeId::i64) => Some(TypeId::bool),
+
+            (lty, Operator::Add, rty) |
+            (lty, Operator::Sub, rty) if lty.is_vector() && lty == rty => Some(lty.clone()),
+            (lty, Operator::Mul, rty) |
+            (lty, Operator::Div, rty) if lty.is_vector() && is_number(rty) => Some(lty.clone()),
+            (lty, Operator::Mul, rty) if is_number(lty) && rty.is_vector() => Some(rty.clone()),
+
+            (lty, Operator::Eq, rty) |
+            (lty, Operator::Neq, rty) if lty == rty && Externals::signature(&Atom::from(format!("eq.{:?}", lty))).is_some() => {
+                Some(TypeId::bool)
+            },
+
+            (lty, op, rty) => {
+                self.error(span, format!("Unsupported expression: {:?} {} {:?}", lty, op, rty));
+                None
+            },
+        }
+    }
+
+    /// Check the bounds of a range, resolving the type of its values
+    fn range(&mut self, range: Result<Range, String>, span: Span, env: &Env) -> Option<TypeId> {
+        let range = match range {
+            Ok(range) => range,
+            Err(msg) => {
+                self.error(span, msg);
+                return Some(TypeId::f64);
+            },
+        };
+
+        // Ranges of integers are iterated with integers
+        let mut is_int = true;
+        for bound in once(&range.start).chain(once(&range.end)).chain(range.step.as_ref()) {
+            let ty = self.expression(bound, env);
+            self.expect(bound.span, &TypeId::f64, &ty);
+            if ty != Some(TypeId::i64) {
+                is_int = false;
+            }
+        }
+
+        // A zero step would never reach the end of the range
+        if let Some(ref step) = range.step {
+            match step.kind {
+                ExpressionKind::Literal(Literal::Int(0)) => self.error(step.span, "range step cannot be zero"),
+                ExpressionKind::Literal(Literal::Number(val)) if val == 0.0 => self.error(step.span, "range step cannot be zero"),
+                _ => {},
+            }
+        }
+
+        Some(if is_int { TypeId::i64 } else { TypeId::f64 })
+    }
+
+    /// Resolve the types of the names bound by a pattern
+    fn pattern(&mut self, pattern: &Pattern, ty: Option<TypeId>, span: Span) -> Vec<(Atom, Option<TypeId>)> {
+        match *pattern {
+            Pattern::Name(ref name) => vec![ (name.clone(), ty) ],
+            Pattern::Object(ref fields) => {
+                fields.iter()
+                    .map(|&(ref field, ref name)| (name.clone(), self.field(ty.clone(), field, span)))
+                    .collect()
+            },
+            Pattern::Array(ref names) => {
+                let elem = match ty {
+                    Some(TypeId::Array { len, ty }) => {
+                        if names.len() > len as usize {
+                            self.error(span, format!("cannot destructure {} elements from an array of length {}", names.len(), len));
+                        }
+
+                        Some(*ty)
+                    },
+                    // The length of vectors is checked at runtime
+                    Some(TypeId::Vec { ty }) => Some(*ty),
+                    Some(ty) => {
+                        self.error(span, format!("cannot destructure a {:?} as a list", ty));
+                        None
+                    },
+                    None => None,
+                };
+
+                names.iter()
+                    .map(|name| (name.clone(), elem.clone()))
+                    .collect()
+            },
+        }
+    }
+
+    /// Check the type of a value assigned to an entity property
+    fn property_value(&mut self, span: Span, value: Option<TypeId>) {
+        match value {
+            Some(TypeId::String) | Some(TypeId::f64) | Some(TypeId::i64) |
+            Some(TypeId::vec3) | Some(TypeId::qangle) | None => {},
+            Some(ty) => self.error(span, format!("cannot assign a {:?} to a property", ty)),
+        }
+    }
+
+    /// Resolve the type of a field of a value
+    fn field(&mut self, obj: Option<TypeId>, prop: &Atom, span: Span) -> Option<TypeId> {
+        match obj {
+            Some(TypeId::Object { items }) => {
+                match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+                    Ok(idx) => Some(items[idx].1.clone()),
+                    Err(_) => {
+                        self.error(span, format!("key \"{}\" not found in object", prop));
+                        None
+                    },
+                }
+            },
+
+            Some(TypeId::Entity) => Some(vector_property(prop).unwrap_or(TypeId::String)),
+
+            Some(TypeId::String) => {
+                if sub_property(prop).is_none() {
+                    self.error(span, format!("unsupported sub-property \"{}\"", prop));
+                }
+
+                Some(TypeId::String)
+            },
+
+            Some(ref ty) if ty.is_vector() => {
+                if vector_component(prop).is_none() {
+                    self.error(span, format!("unsupported component \"{}\" of a {:?}", prop, ty));
+                }
+
+                Some(TypeId::f64)
+            },
+
+            Some(ty) => {
+                self.error(span, format!("cannot dereference a {:?}", ty));
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Check the path of an event, returning whether it has
+    /// an instance, an entity and a method, like compiler::path::event
+    fn trigger(&mut self, path: &Path, env: &Env) -> Option<(bool, bool, bool)> {
+        match path.kind {
+            PathKind::Deref(ref obj, _) => match self.trigger(obj, env) {
+                Some((inst, true, false)) => Some((inst, true, true)),
+                Some((inst, false, false)) => Some((inst, true, false)),
+                Some(_) => {
+                    self.error(path.span, "invalid event path");
+                    None
+                },
+                None => None,
+            },
+            PathKind::Instance(ref pat) => {
+                self.path(pat, env).map(|_| (true, false, false))
+            },
+            PathKind::Binding(_) => {
+                self.path(path, env).map(|_| (false, true, false))
+            },
+        }
+    }
+
+    fn event(&mut self, path: &Path, env: &Env) {
+        if let Some((_, false, _)) = self.trigger(path, env) {
+            self.error(path.span, "invalid event path");
+        }
+    }
+
+    /// Resolve the type of an entity path, like compiler::path::resolve_path
+    fn path(&mut self, path: &Path, env: &Env) -> Option<TypeId> {
+        match path.kind {
+            PathKind::Deref(ref obj, ref prop) => match self.path(obj, env) {
+                Some(TypeId::Entity) => Some(TypeId::Entity),
+                Some(TypeId::Object { items }) => {
+                    match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+                        Ok(idx) => Some(items[idx].1.clone()),
+                        Err(_) => {
+                            self.error(path.span, format!("key \"{}\" not found in object", prop));
+                            None
+                        },
+                    }
+                },
+                Some(_) => {
+                    self.error(path.span, format!("trying to deref a non-map value {}", path));
+                    None
+                },
+                None => None,
+            },
+            PathKind::Instance(ref obj) => self.path(obj, env),
+            PathKind::Binding(ref name) => match env.binding(name) {
+                Some((_, ty)) => ty,
+                None if name.starts_with('@') => Some(TypeId::Entity),
+                None => {
+                    self.error(path.span, format!("entity {} not found", path));
+                    None
+                },
+            },
+        }
+    }
+
+    fn call(&mut self, &Call { ref path, ref args, span }: &Call, env: &Env) -> Option<TypeId> {
+        if let PathKind::Binding(ref name) = path.kind {
+            if env.function(name).is_none() && (*name == hct_atom!("map") || *name == hct_atom!("filter")) {
+                return self.higher_order_call(name, args, span, env);
+            }
+
+        }
+
+        let args: Vec<_> = {
+            args.iter()
+                .map(|arg| (self.expression(arg, env), arg.span))
+                .collect()
+        };
+
+        if let PathKind::Binding(ref name) = path.kind {
+            if let Some(func) = env.function(name) {
+                return self.user_call(&func, args, span, env);
+            }
+        }
+
+        // List, dictionary, string and math operations are not entity methods, even in an event block
+        if let PathKind::Binding(ref name) = path.kind {
+            if let Some(ty) = self.dict_call(name, &args, span) {
+                return ty;
+            }
+            if let Some(ty) = self.string_call(name, &args, span) {
+                return ty;
+            }
+            if let Some(ty) = self.math_call(name, &args, span) {
+                return ty;
+            }
+            if let Some(ty) = self.list_call(name, &args, span) {
+                return ty;
+            }
+        }
+
+        if env.is_event() {
+            self.event(path, env);
+
+            if let Some(&(Some(ref ty), span)) = args.first() {
+                if *ty != TypeId::String && *ty != TypeId::f64 && *ty != TypeId::i64 {
+                    self.error(span, format!("Unsupported event argument {:?}", ty));
+                }
+            }
+
+            return Some(TypeId::Void);
+        }
+
+        let name = match path.kind {
+            PathKind::Binding(ref name) => name,
+            _ => {
+                self.error(path.span, format!("{} is not a function", path));
+                return None;
+            },
+        };
+
+        if *name == hct_atom!("length") {
+            // The length of a vector is the one of its components
+            if args.len() == 1 && args[0].0 == Some(TypeId::vec3) {
+                return Some(TypeId::f64);
+            }
+
+            for &(ref ty, span) in &args {
+                self.expect(span, &TypeId::f64, ty);
+            }
+
+            return Some(TypeId::f64);
+        }
+
+        let (params, ret) = match Externals::signature(name) {
+            Some(sig) => sig,
+            None => {
+                self.error(span, format!("unknown function {}", name));
+                return None;
+            },
+        };
+
+        let params: Vec<_> = {
+            params.into_iter()
+                .filter(|ty| *ty != TypeId::Context)
+                .collect()
+        };
+
+        if params.len() != args.len() {
+            self.error(span, format!("{} expects {} arguments, found {}", name, params.len(), args.len()));
+        }
+
+        for (param, &(ref arg, span)) in params.iter().zip(&args) {
+            self.expect(span, param, arg);
+        }
+
+        Some(ret)
+    }
+
+    /// Warn about an entity looked up by a literal name missing from the map,
+    /// suggesting `if let` for a `find` whose null handle would be used as is
+    fn lookup(&mut self, &Call { ref path, ref args, .. }: &Call, is_bound: bool) {
+        let value = match args.first().and_then(literal_string) {
+            Some(value) => value,
+            None => return,
+        };
+
+        if is_known_entity(&value, self.entities) {
+            return;
+        }
+
+        let message = match path.kind {
+            PathKind::Binding(hct_atom!("find")) if !is_bound => {
+                format!("entity \"{}\" not found in the map, use `if let` to look it up if it may not exist", value)
+            },
+            _ => format!("entity \"{}\" not found in the map", value),
+        };
+
+        self.warning(args[0].span, message);
+    }
+
+    fn expression(&mut self, exp: &Expression, env: &Env) -> Option<TypeId> {
+        let span = exp.span;
+        match exp.kind {
+            ExpressionKind::Call(ref call) => {
+                if is_lookup(call, env) {
+                    self.lookup(call, false);
+                }
+
+                self.call(call, env)
+            },
+
+            ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), span }) => {
+                match env.binding(name) {
+                    Some((_, ty)) => ty,
+                    None if *name == hct_atom!("null") => {
+                        self.error(span, "there is no null value, use `if let` to handle entities that may not exist");
+                        None
+                    },
+                    None => {
+                        self.error(span, format!("value \"{}\" not found", name));
+                        None
+                    },
+                }
+            },
+            ExpressionKind::Reference(Path { kind: PathKind::Deref(ref obj, ref prop), span }) => {
+                let obj = Expression {
+                    span: obj.span,
+                    kind: ExpressionKind::Reference((**obj).clone()),
+                };
+
+                let obj = self.expression(&obj, env);
+                self.field(obj, prop, span)
+            },
+            ExpressionKind::Field { ref expr, ref name } => {
+                let obj = self.expression(expr, env);
+                self.field(obj, name, span)
+            },
+            ExpressionKind::Reference(ref path) => {
+                self.error(path.span, format!("cannot use {} as a value", path));
+                None
+            },
+
+            ExpressionKind::Array(ref values) => {
+                let mut elem: Option<TypeId> = None;
+                let mut valid = true;
+                for value in values {
+                    match (self.expression(value, env), elem.clone()) {
+                        (Some(ty), Some(prev)) => if ty != prev {
+                            self.error(value.span, format!("heterogeneous array literal: found {:?} and {:?}", prev, ty));
+                            valid = false;
+                        },
+                        (Some(ty), None) => elem = Some(ty),
+                        (None, _) => valid = false,
+                    }
+                }
+
+                if valid {
+                    // Literals of scalar values are growable lists
+                    let elem = elem.unwrap_or(TypeId::Void);
+                    if elem.is_vec_element() {
+                        Some(TypeId::Vec { ty: box elem })
+                    } else {
+                        Some(TypeId::Array {
+                            len: values.len() as u32,
+                            ty: box elem,
+                        })
+                    }
+                } else {
+                    None
+                }
+            },
+            ExpressionKind::Map(ref values) => {
+                let mut items = Vec::with_capacity(values.len());
+                let mut valid = true;
+                for (key, value) in values {
+                    match self.expression(value, env) {
+                        Some(ty) => items.push((key.clone(), ty)),
+                        None => valid = false,
+                    }
+                }
+
+                if valid {
+                    items.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+                    Some(TypeId::Object { items })
+                } else {
+                    None
+                }
+            },
+
+            ExpressionKind::Index { ref expr, ref index } => {
+                let list = self.expression(expr, env);
+                let index_ty = self.expression(index, env);
+                self.expect(index.span, &TypeId::i64, &index_ty);
+
+                match list {
+                    Some(TypeId::Array { ty, .. }) |
+                    Some(TypeId::Vec { ty }) => Some(*ty),
+                    Some(ty) => {
+                        self.error(expr.span, format!("cannot index a {:?}", ty));
+                        None
+                    },
+                    None => None,
+                }
+            },
+            ExpressionKind::Slice { ref expr, ref start, ref end } => {
+                let list = self.expression(expr, env);
+                for bound in start.iter().chain(end) {
+                    let bound_ty = self.expression(bound, env);
+                    self.expect(bound.span, &TypeId::i64, &bound_ty);
+                }
+
+                match list {
+                    Some(ty @ TypeId::Vec { .. }) => Some(ty),
+                    Some(ty) => {
+                        self.error(expr.span, format!("cannot slice a {:?}", ty));
+                        None
+                    },
+                    None => None,
+                }
+            },
+            ExpressionKind::Range { .. } => {
+                self.error(span, "ranges can only be iterated by a for loop");
+                None
+            },
+
+            ExpressionKind::Binary { lhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. }, op: Operator::Add, rhs: box ref e3 } |
+            ExpressionKind::Binary { lhs: box ref e3, op: Operator::Add, rhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. } } => {
+                let (exp1, exp2, exp3) = (e1, e2, e3);
+                let e1 = self.expression(exp1, env);
+                let e2 = self.expression(exp2, env);
+                let e3 = self.expression(exp3, env);
+                match (e1, e2, e3) {
+                    (Some(TypeId::i64), Some(TypeId::i64), Some(TypeId::i64)) => Some(TypeId::i64),
+                    (Some(ref e1), Some(ref e2), Some(ref e3)) if is_number(e1) && is_number(e2) && is_number(e3) => Some(TypeId::f64),
+                    // Other operands, like vectors or properties parsed as numbers, are combined one operation at a time
+                    (e1, e2, e3) => {
+                        let e1 = self.operand(exp1, e1, Operator::Mul, &e2, env);
+                        let e2 = self.operand(exp2, e2, Operator::Mul, &e1, env);
+                        let mul = self.binary(e1, Operator::Mul, e2, span);
+                        let e3 = self.operand(exp3, e3, Operator::Add, &mul, env);
+                        self.binary(mul, Operator::Add, e3, span)
+                    },
+                }
+            },
+            // The property is converted to the type of the default, always evaluated
+            ExpressionKind::Binary { ref lhs, op: Operator::Coalesce, ref rhs } => {
+                match (**lhs).clone().split_field() {
+                    Ok((obj, _)) => match self.expression(&obj, env) {
+                        Some(TypeId::Entity) | None => {},
+                        Some(ty) => self.error(obj.span, format!("?? expects a property of an entity, found a field of a {:?}", ty)),
+                    },
+                    Err(lhs) => self.error(lhs.span, "?? expects a property of an entity"),
+                }
+
+                match self.expression(rhs, env) {
+                    Some(ref ty) if !can_cast(&TypeId::String, ty) => {
+                        self.error(rhs.span, format!("cannot use a {:?} as the default of a property", ty));
+                        None
+                    },
+                    ty => ty,
+                }
+            },
+            ExpressionKind::Binary { ref lhs, op, ref rhs } => {
+                let (lhs_exp, rhs_exp) = (lhs, rhs);
+                let lhs = self.expression(lhs_exp, env);
+                let rhs = self.expression(rhs_exp, env);
+                let lhs = self.operand(lhs_exp, lhs, op, &rhs, env);
+                let rhs = self.operand(rhs_exp, rhs, op, &lhs, env);
+                self.binary(lhs, op, rhs, span)
+            },
+            ExpressionKind::Cast { ref expr, ref ty } => {
+                let value = self.expression(expr, env);
+                let ty = match TypeId::from_cast(ty) {
+                    Some(ty) => ty,
+                    None => {
+                        self.error(span, format!("unknown type {}", ty));
+                        return None;
+                    },
+                };
+
+                if let Some(ref value) = value {
+                    if !can_cast(value, &ty) {
+                        self.error(span, format!("cannot convert a {:?} to {:?}", value, ty));
+                    }
+                }
+
+                Some(ty)
+            },
+
+            ExpressionKind::Conditional { ref condition, ref consequent, ref alternate } => {
+                let cond = self.expression(condition, env);
+                self.expect(condition.span, &TypeId::bool, &cond);
+
+                // Both branches must produce the same type of value
+                let then_ty = self.expression(consequent, env);
+                let else_ty = self.expression(alternate, env);
+                match (then_ty, else_ty) {
+                    (Some(TypeId::i64), Some(TypeId::f64)) |
+                    (Some(TypeId::f64), Some(TypeId::i64)) => Some(TypeId::f64),
+                    (Some(TypeId::Void), _) |
+                    (_, Some(TypeId::Void)) => {
+                        self.error(span, "both branches of an if expression must produce a value");
+                        None
+                    },
+                    (Some(then_ty), Some(else_ty)) => {
+                        if then_ty == else_ty {
+                            Some(then_ty)
+                        } else {
+                            self.error(span, format!("if and else branches have different types: {:?} and {:?}", then_ty, else_ty));
+                            None
+                        }
+                    },
+                    _ => None,
+                }
+            },
+
+            ExpressionKind::Unary { op, ref expr } => {
+                match (op, self.expression(expr, env)) {
+                    (UnaryOperator::Not, Some(TypeId::bool)) => Some(TypeId::bool),
+                    (UnaryOperator::Neg, Some(TypeId::f64)) => Some(TypeId::f64),
+                    (UnaryOperator::Neg, Some(ref ty)) if ty.is_vector() => Some(ty.clone()),
+                    (UnaryOperator::Neg, Some(TypeId::i64)) |
+                    (UnaryOperator::BNot, Some(TypeId::i64)) => Some(TypeId::i64),
+                    (op, Some(ty)) => {
+                        self.error(span, format!("Unsupported expression: {}{:?}", op, ty));
+                        None
+                    },
+                    (_, None) => None,
+                }
+            },
+
+            ExpressionKind::Literal(Literal::Number(_)) => Some(TypeId::f64),
+            ExpressionKind::Literal(Literal::Int(_)) => Some(TypeId::i64),
+            ExpressionKind::Literal(Literal::Bool(_)) => Some(TypeId::bool),
+            ExpressionKind::Literal(Literal::String(ref parts)) => {
+                for part in parts {
+                    if let StringPart::Expression(ref e, ref format) = *part {
+                        match self.expression(e, env) {
+                            Some(ref ty) if format.is_some() && !is_number(ty) => {
+                                self.error(e.span, format!("cannot format a {:?} as a number", ty));
+                            },
+                            Some(ref ty) if !is_printable(ty) => {
+                                self.error(e.span, format!("cannot interpolate a {:?} in a string", ty));
+                            },
+                            _ => {},
+                        }
+                    }
+                }
+
+                Some(TypeId::String)
+            },
+        }
+    }
+}
+
+/// Type check a script against the entities of its map,
+/// returning all the errors and warnings found
+pub fn check(script: &Script, entities: &HashMap<Atom, Entity>) -> Vec<Diagnostic> {
+    let env = Env {
+        parent: None,
+        bindings: {
+            entities.keys()
+                .map(|name| (name.clone(), (false, Some(TypeId::Entity))))
+                .collect()
+        },
+        functions: HashMap::new(),
+        event: false,
+        in_loop: false,
+    };
+
+    let mut checker = Checker {
+        diagnostics: Vec::new(),
+        entities,
+        instances: HashMap::new(),
+        frames: Vec::new(),
+        checked: HashSet::new(),
+    };
+
+    checker.statements(&script.body, env);
+
+    // Function bodies checked for several signatures may report the same error
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for diag in checker.diagnostics {
+        if !diagnostics.contains(&diag) {
+            diagnostics.push(diag);
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hct::diagnostic::Level;
+    use hct::parse_source;
+
+    /// Get the messages of the diagnostics of a level for a script, on a map holding a `door` entity
+    fn diagnostics(source: &str, level: Level) -> Vec<String> {
+        let script = parse_source("test.hct", String::from(source)).unwrap();
+
+        let mut entities = HashMap::new();
+        entities.insert(Atom::from("door"), Entity {
+            classname: Atom::from("prop_door_rotating"),
+            targetname: Some(Atom::from("door")),
+            ..Entity::default()
+        });
+
+        check(&script, &entities).into_iter()
+            .filter(|diag| diag.level == level)
+            .map(|diag| diag.message)
+            .collect()
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        diagnostics(source, Level::Error)
+    }
+
+    fn warnings(source: &str) -> Vec<String> {
+        diagnostics(source, Level::Warning)
+    }
+
+    #[test]
+    fn reports_all_type_errors() {
+        assert_eq!(errors("let a = \"x\" * 2.5\nlet b = foo\nif \"yes\" {\n}\n"), vec![
+            "Unsupported expression: String * f64",
+            "value \"foo\" not found",
+            "expected type bool, found String",
+        ]);
+    }
+
+    #[test]
+    fn invalid_values_do_not_cascade() {
+        assert_eq!(errors("let a = foo\nlet b = a * 2.5\ndoor.value = b\n"), vec![
+            "value \"foo\" not found",
+        ]);
+    }
+
+    #[test]
+    fn accepts_valid_scripts() {
+        assert!(errors("let a = 2.5\ndoor.value = a * 2.5\nif a > 1.5 {\n    door.other = \"${a}\"\n}\n").is_empty());
+    }
+
+    #[test]
+    fn checks_user_functions() {
+        assert_eq!(errors("fn add(a, b) {\n    return a + b\n}\ndoor.value = add(1.5)\n"), vec![
+            "add expects 2 arguments, found 1",
+        ]);
+        assert_eq!(errors("fn f(a) {\n    if a {\n        return 1.5\n    }\n}\ndoor.value = f(true)\n"), vec![
+            "function f does not return a value on all paths",
+        ]);
+        assert_eq!(errors("fn f(a) {\n    return 1.5\n    return \"a\"\n}\ndoor.value = f(true)\n"), vec![
+            "mismatched return types: expected f64, found String",
+        ]);
+    }
+
+    #[test]
+    fn functions_do_not_see_the_bindings_of_their_caller() {
+        assert_eq!(errors("let x = 1.5\nfn f() {\n    return x\n}\ndoor.value = f()\n"), vec![
+            "value \"x\" not found",
+        ]);
+        // Entities are still visible
+        assert!(errors("fn f() {\n    door.Open()\n}\nauto {\n    f()\n}\n").is_empty());
+    }
+
+    #[test]
+    fn null_is_not_a_value() {
+        assert_eq!(errors("let a = null\n"), vec![
+            "there is no null value, use `if let` to handle entities that may not exist",
+        ]);
+        assert_eq!(errors("let a = 1\nlet b = a && true\n"), vec![
+            "Unsupported expression: i64 && bool",
+        ]);
+    }
+
+    #[test]
+    fn checks_control_flow() {
+        assert_eq!(errors("break\nwhile true {\n    if true {\n        continue\n    }\n    break\n}\n"), vec![
+            "break outside of a loop",
+        ]);
+        // Function bodies are not part of the loop they are declared in
+        assert_eq!(errors("while true {\n    fn f() {\n        continue\n    }\n    f()\n}\n"), vec![
+            "continue outside of a loop",
+        ]);
+        assert_eq!(errors("return 1\n"), vec![
+            "cannot return a value outside of a function",
+        ]);
+    }
+
+    #[test]
+    fn checks_indices() {
+        assert_eq!(errors("let a = 1.5\nlet b = a[0]\nlet l = [1, 2]\nlet c = l[0.5]\n"), vec![
+            "cannot index a f64",
+            "expected type i64, found f64",
+        ]);
+    }
+
+    #[test]
+    fn fixed_size_arrays_cannot_grow() {
+        assert_eq!(errors("let l = [vec3(0, 0, 0)]\npush(l, vec3(1, 1, 1))\n"), vec![
+            "push expects a growable list, found a fixed-size array: \
+             cannot store a vec3 in a list, which can only hold numbers, booleans, strings and entities",
+        ]);
+        assert_eq!(errors("fn f(x) {\n    return vec3(x, x, x)\n}\nlet l = map([1.5], f)\n"), vec![
+            "cannot store a vec3 in a list, which can only hold numbers, booleans, strings and entities",
+        ]);
+    }
+
+    #[test]
+    fn checks_dictionaries() {
+        assert_eq!(errors("let a: {f64: String} = {}\nlet d: {String: f64} = {}\nlet b = get(d, 1, 0)\n"), vec![
+            "cannot use a f64 as a dictionary key",
+            "expected type String, found i64",
+        ]);
+    }
+
+    #[test]
+    fn checks_assigned_fields() {
+        assert_eq!(errors("let o = { a: 1.5 }\no.b = 2\no.a = \"x\"\nlet n = 1.5\nn.a = 1\n"), vec![
+            "key \"b\" not found in object",
+            "expected type f64, found String",
+            "cannot assign to a field of a f64",
+        ]);
+    }
+
+    #[test]
+    fn checks_patterns() {
+        assert_eq!(errors("let [a, b, c] = [{ x: 1 }, { x: 2 }]\nlet [d] = 1.5\nlet {e} = { f: 1 }\n"), vec![
+            "cannot destructure 3 elements from an array of length 2",
+            "cannot destructure a f64 as a list",
+            "key \"e\" not found in object",
+        ]);
+    }
+
+    #[test]
+    fn checks_ranges() {
+        assert_eq!(errors("for i in range(0, 3, 0) {\n}\nfor i in range(1) {\n}\n"), vec![
+            "range step cannot be zero",
+            "range expects 2 or 3 arguments, found 1",
+        ]);
+    }
+
+    #[test]
+    fn checks_compound_assignments() {
+        assert_eq!(errors("let s = \"a\"\ns -= 1\nlet n = 1\nn += 0.5\n"), vec![
+            "Unsupported expression: String - i64",
+            "expected type i64, found f64",
+        ]);
+    }
+
+    #[test]
+    fn checks_conditionals() {
+        assert_eq!(errors("match true {\n    _ => {\n    }\n}\nmatch 1 {\n    _ => {\n    }\n    1 => {\n    }\n}\n"), vec![
+            "cannot match a bool",
+            "the wildcard must be the last arm of a match",
+        ]);
+        assert_eq!(errors("fn f() {\n}\nlet a = if true { \"a\" } else { 1.5 }\nlet b = if true { f() } else { 1 }\n"), vec![
+            "if and else branches have different types: String and f64",
+            "both branches of an if expression must produce a value",
+        ]);
+        assert_eq!(errors("door.value = if true { 1 } else { 2.5 }\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn checks_string_functions() {
+        assert_eq!(errors("let a = replace(\"x\", \"y\")\nlet b = substr(\"abc\", 0.5, 1)\n"), vec![
+            "replace expects 3 arguments, found 2",
+            "expected type i64, found f64",
+        ]);
+        assert_eq!(errors("let n = find(\"a_b\", \"_\") + len(\"abc\")\ndoor.value = n\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn checks_vectors() {
+        assert_eq!(errors("let v = vec3(1, 2, 3)\nlet a = v + 1\nlet b = v.w\nlet c = dot(v, qangle(0, 0, 0))\n"), vec![
+            "Unsupported expression: vec3 + i64",
+            "unsupported component \"w\" of a vec3",
+            "expected type vec3, found qangle",
+        ]);
+        assert_eq!(
+            errors("door.origin = door.origin + vec3(0, 0, 8) * 2\ndoor.angles.yaw += 90\ndoor.value = -door.origin.z\n"),
+            Vec::<String>::new(),
+        );
+    }
+
+    #[test]
+    fn checks_math_functions() {
+        assert_eq!(errors("let a = mod(1.5, 2)\nlet b = clamp(1, 2)\nlet c = sin(\"a\")\nlet n = abs(-3)\nn = 1.5\n"), vec![
+            "expected type i64, found f64",
+            "clamp expects 3 arguments, found 2",
+            "expected type f64, found String",
+            "expected type i64, found f64",
+        ]);
+    }
+
+    #[test]
+    fn checks_conversions_and_defaults() {
+        assert_eq!(errors("let a = \"1\" + 2\nlet b = 1 ?? 2\nlet c = door.health ?? [1]\nlet d = true as vec3\nlet e = 1 as float\n"), vec![
+            "Unsupported expression: String + i64",
+            "?? expects a property of an entity",
+            "cannot use a Vec { ty: i64 } as the default of a property",
+            "cannot convert a bool to vec3",
+            "unknown type float",
+        ]);
+        // Only entity properties are parsed as numbers
+        assert_eq!(
+            errors("let a = door.health + 10\nlet b = door.spawnflags & 2\nlet c = (door.speed ?? 1.5) * 2\nlet d = \"3\" as int + 1\n"),
+            Vec::<String>::new(),
+        );
+    }
+
+    #[test]
+    fn checks_entity_lookups() {
+        assert_eq!(
+            errors("let n = \"door\"\nlet c = find(n)\nlet d = null\nif let e = 1 {\n}\n"),
+            vec![
+                "there is no null value, use `if let` to handle entities that may not exist",
+                "expected type Entity, found i64",
+            ],
+        );
+        assert_eq!(
+            warnings("let a = find(\"door\")\nlet b = find(\"x\")\nlet c = exists(\"y\")\nif let e = find(\"z\") {\n}\nlet i = 1\nlet d = find(\"lamp_${i}\")\n"),
+            vec![
+                "entity \"x\" not found in the map, use `if let` to look it up if it may not exist",
+                "entity \"y\" not found in the map",
+                "entity \"z\" not found in the map",
+            ],
+        );
+        assert_eq!(
+            errors("let n = \"x\"\nif let e = find(n) {\n    e.value = 1\n} else if exists(\"y\") {\n}\n"),
+            Vec::<String>::new(),
+        );
+    }
+}
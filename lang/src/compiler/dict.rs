@@ -0,0 +1,241 @@
+//! Operations on dictionaries, backed by a vector of keys
+//! and a vector of values sharing the same indices
+
+use std::collections::HashMap;
+
+use atom::*;
+use error::HatchetResult;
+use hct::ast::*;
+
+use super::builder::*;
+use super::expression::*;
+use super::function::*;
+use super::list::*;
+use super::scope::*;
+use super::types::*;
+
+/// Get the vectors of keys and values of a dictionary
+fn dict_parts(dict: &ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<(ValueRef, ValueRef)> {
+    match dict.ty.clone() {
+        TypeId::HashMap { key, value } => Ok((
+            builder.build_extract_value(dict, 0, TypeId::Vec { ty: key }),
+            builder.build_extract_value(dict, 1, TypeId::Vec { ty: value }),
+        )),
+        ty => Err(builder.error(span, format!("expected a dictionary, found {:?}", ty))),
+    }
+}
+
+/// Build a new dictionary holding the given entries
+pub fn build_dict(entries: Vec<(ValueRef, ValueRef)>, key: &TypeId, value: &TypeId, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let (keys, values): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+    let keys = build_vec(keys, key, span, builder)?;
+    let values = build_vec(values, value, span, builder)?;
+
+    let ty = TypeId::HashMap {
+        key: box key.clone(),
+        value: box value.clone(),
+    };
+
+    let init = builder.build_undef(&ty);
+    let dict = builder.build_insert_value(&init, &keys, 0);
+    Ok(builder.build_insert_value(&dict, &values, 1))
+}
+
+/// Build a dictionary from a map literal, converting its
+/// names to the key type of the dictionary
+pub fn dict_literal<'a>(entries: HashMap<Atom, Expression>, key: &TypeId, value: &TypeId, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    entries.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+    let entries = {
+        entries.into_iter()
+            .map(|(name, exp)| {
+                let name = match *key {
+                    TypeId::Atom => builder.build_const_atom(name),
+                    TypeId::Entity => builder.build_const_entity(name),
+                    TypeId::String => builder.build_const_string(name.to_string()),
+                    ref ty => return Err(builder.error(span, format!("cannot use a {:?} as a dictionary key", ty))),
+                };
+
+                let exp_span = exp.span;
+                let exp = expression(exp, scope, builder)?;
+                let exp = widen(exp, value, builder);
+                if exp.ty != *value {
+                    return Err(builder.error(exp_span, format!("expected type {:?}, found {:?}", value, exp.ty)));
+                }
+
+                Ok((name, exp))
+            })
+            .collect::<HatchetResult<_>>()?
+    };
+
+    build_dict(entries, key, value, span, builder)
+}
+
+/// Build a loop running the body on each entry of a dictionary
+pub fn dict_for_each<'a, F>(dict: &ValueRef, span: Span, scope: &Scope<'a>, builder: &mut Builder, body: F) -> HatchetResult<()>
+    where F: FnOnce(&mut Builder, &Scope, ValueRef, ValueRef) -> HatchetResult<()> {
+    let (keys, values) = dict_parts(dict, span, builder)?;
+    for_each(&keys, span, scope, builder, |builder, scope, index, key| {
+        let value = list_get(&values, index, span, builder)?;
+        body(builder, scope, key, value)
+    })
+}
+
+/// Build a conditional on the index of a key, running the
+/// first body if it was found and the second one otherwise
+fn if_found<F, G>(index: &ValueRef, builder: &mut Builder, found: F, missing: G) -> HatchetResult<()>
+    where F: FnOnce(&mut Builder) -> HatchetResult<()>, G: FnOnce(&mut Builder) -> HatchetResult<()> {
+    let zero = builder.build_const_i64(0);
+    let cond = builder.build_int_sge(index, &zero);
+
+    let found_block = builder.append_basic_block();
+    let missing_block = builder.append_basic_block();
+    let next_block = builder.append_basic_block();
+    builder.build_cond_br(&cond, found_block, missing_block);
+
+    builder.position_at_end(found_block);
+    found(builder)?;
+    builder.build_br(next_block);
+
+    builder.position_at_end(missing_block);
+    missing(builder)?;
+    builder.build_br(next_block);
+
+    builder.position_at_end(next_block);
+    Ok(())
+}
+
+/// Build a copy of a vector
+fn copy_vec(list: &ValueRef, ty: &TypeId, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let start = builder.build_const_i64(0);
+    let end = list_len(list, span, builder)?;
+    call_stl(
+        builder,
+        Atom::from(format!("vec_slice.{:?}", ty)),
+        vec![ list, &start, &end ],
+        span,
+    )
+}
+
+/// Build a call to a dictionary operation, or return None if the
+/// called function is not one for these arguments
+pub fn dict_call(name: &Atom, args: &[(ValueRef, Span)], span: Span, builder: &mut Builder) -> HatchetResult<Option<ValueRef>> {
+    let (key, value) = match args.first() {
+        Some(&(ValueRef { ty: TypeId::HashMap { ref key, ref value }, .. }, _)) => ((**key).clone(), (**value).clone()),
+        _ => return Ok(None),
+    };
+
+    let arity = match *name {
+        hct_atom!("len") | hct_atom!("keys") | hct_atom!("values") => 1,
+        hct_atom!("contains") | hct_atom!("remove") => 2,
+        hct_atom!("insert") | hct_atom!("get") => 3,
+        _ => return Ok(None),
+    };
+
+    if args.len() != arity {
+        return Err(builder.error(span, format!("{} expects {} arguments, found {}", name, arity, args.len())));
+    }
+
+    // The arguments following the dictionary are a key and a value
+    for (&(ref arg, span), ty) in args[1..].iter().zip(&[ key.clone(), value.clone() ]) {
+        // Integers are implicitly widened to floats
+        if arg.ty != *ty && !(*ty == TypeId::f64 && arg.ty == TypeId::i64) {
+            return Err(builder.error(span, format!("expected type {:?}, found {:?}", ty, arg.ty)));
+        }
+    }
+
+    let (ref dict, dict_span) = args[0];
+    let (keys, values) = dict_parts(dict, dict_span, builder)?;
+    if *name == hct_atom!("len") {
+        return list_len(&keys, dict_span, builder).map(Some);
+    }
+    if *name == hct_atom!("keys") {
+        return copy_vec(&keys, &key, dict_span, builder).map(Some);
+    }
+    if *name == hct_atom!("values") {
+        return copy_vec(&values, &value, dict_span, builder).map(Some);
+    }
+
+    let key_arg = &args[1].0;
+    if *name == hct_atom!("contains") {
+        return call_stl(
+            builder,
+            Atom::from(format!("vec_contains.{:?}", key)),
+            vec![ &keys, key_arg ],
+            span,
+        ).map(Some);
+    }
+
+    let index = call_stl(
+        builder,
+        Atom::from(format!("vec_find.{:?}", key)),
+        vec![ &keys, key_arg ],
+        span,
+    )?;
+
+    match *name {
+        hct_atom!("get") => {
+            return call_stl(
+                builder,
+                Atom::from(format!("vec_get_or.{:?}", value)),
+                vec![ &values, &index, &args[2].0 ],
+                span,
+            ).map(Some);
+        },
+        hct_atom!("insert") => {
+            let value_arg = &args[2].0;
+            if_found(
+                &index, builder,
+                |builder| {
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_set.{:?}", value)),
+                        vec![ &values, &index, value_arg ],
+                        span,
+                    )?;
+                    Ok(())
+                },
+                |builder| {
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_push.{:?}", key)),
+                        vec![ &keys, key_arg ],
+                        span,
+                    )?;
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_push.{:?}", value)),
+                        vec![ &values, value_arg ],
+                        span,
+                    )?;
+                    Ok(())
+                },
+            )?;
+        },
+        // Removing a missing key does nothing
+        _ => {
+            if_found(
+                &index, builder,
+                |builder| {
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_remove.{:?}", key)),
+                        vec![ &keys, &index ],
+                        span,
+                    )?;
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_remove.{:?}", value)),
+                        vec![ &values, &index ],
+                        span,
+                    )?;
+                    Ok(())
+                },
+                |_| Ok(()),
+            )?;
+        },
+    }
+
+    Ok(Some(builder.build_undef(&TypeId::Void)))
+}
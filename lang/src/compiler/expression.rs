@@ -1,97 +1,482 @@
 //! Resolve the value of an expression
 
 use atom::*;
+use error::HatchetResult;
 use hct::ast::*;
 
 use super::builder::*;
+use super::check::{coerced_type, sub_property, vector_component, vector_property};
 use super::function::*;
+use super::list::*;
 use super::scope::*;
 use super::types::*;
 
-/// Compute the return value of an expression
-pub fn expression<'a>(exp: Expression, scope: &Scope<'a>, builder: &mut Builder) -> ValueRef {
-    match exp {
-        Expression::Call(val) => call(val, scope, builder),
+/// Widen an integer to a float if a float is expected,
+/// returning any other value unchanged
+pub fn widen(value: ValueRef, ty: &TypeId, builder: &mut Builder) -> ValueRef {
+    if value.ty == TypeId::i64 && *ty == TypeId::f64 {
+        builder.build_si_to_fp(&value)
+    } else {
+        value
+    }
+}
+
+/// Compute the value of an index or a slice bound
+pub fn index_value<'a>(exp: Expression, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let span = exp.span;
+    let value = expression(exp, scope, builder)?;
+    if value.ty != TypeId::i64 {
+        return Err(builder.error(span, format!("expected type i64, found {:?}", value.ty)));
+    }
+
+    Ok(value)
+}
+
+/// Get a pointer to an element of an array, checking the index at runtime
+pub fn array_element(list: &ValueRef, index: &ValueRef, len: u32, ty: TypeId, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let len = builder.build_const_i64(i64::from(len));
+    let idx = call_stl(
+        builder,
+        hct_atom!("array_index"),
+        vec![ index, &len ],
+        span,
+    )?;
+
+    let zero = builder.build_const_i32(0);
+    let gep = builder.build_in_bounds_gep(
+        list,
+        vec![ &zero, &idx ],
+    );
+
+    Ok(ValueRef { ty, ptr: gep.ptr })
+}
 
-        Expression::Reference(Path::Binding(name)) => {
-            scope.binding(builder, &name)
-                .expect(&format!("value \"{}\" not found", name))
+/// Get a pointer to a field of an object
+pub fn object_field(obj: &ValueRef, prop: &Atom, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let items = match obj.ty {
+        TypeId::Object { ref items } => items,
+        ref ty => return Err(builder.error(span, format!("expected an object, found {:?}", ty))),
+    };
+
+    let idx = match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+        Ok(idx) => idx,
+        Err(_) => return Err(builder.error(span, format!("key \"{}\" not found in object", prop))),
+    };
+
+    let zero = builder.build_const_i32(0i32);
+    let gep = {
+        let idx = builder.build_const_i32(idx as i32);
+        builder.build_in_bounds_gep(
+            obj,
+            vec![
+                &zero,
+                &idx,
+            ],
+        )
+    };
+
+    Ok(ValueRef { ty: items[idx].1.clone(), ptr: gep.ptr })
+}
+
+/// Get the index of a virtual sub-property of a vector property
+pub fn sub_property_index(prop: &Atom, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    match sub_property(prop) {
+        Some(idx) => Ok(builder.build_const_i64(idx)),
+        None => Err(builder.error(span, format!("unsupported sub-property \"{}\"", prop))),
+    }
+}
+
+/// Compute the value of a field of an object, a property of an
+/// entity, a sub-property of a property or a component of a vector
+pub fn field(obj: ValueRef, prop: Atom, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    match obj.ty.clone() {
+        TypeId::Object { .. } => {
+            let ptr = object_field(&obj, &prop, span, builder)?;
+            Ok(builder.build_load(&ptr))
         },
-        Expression::Reference(Path::Deref(box obj, prop)) => {
-            let obj = expression(Expression::Reference(obj), scope, builder);
-            match &obj.ty {
-                &TypeId::Object { ref items } => {
-                    let idx = {
-                        items.binary_search_by(|&(ref item, _)| item.cmp(&prop))
-                            .expect(&format!("key \"{}\" not found in object", prop))
-                    };
-
-                    let (_, ref ty) = items[idx];
-                    let zero = builder.build_const_i32(0i32);
-                    let idx = builder.build_const_i32(idx as i32);
-                    let gep = builder.build_in_bounds_gep(
-                        &obj,
-                        vec![
-                            &zero,
-                            &idx,
-                        ],
-                    );
 
-                    let res = builder.build_load(&gep);
-                    ValueRef { ty: ty.clone(), ptr: res.ptr }
-                },
+        TypeId::Entity => {
+            let ty = vector_property(&prop);
+            let prop = builder.build_const_atom(prop);
+            let value = call_stl(
+                builder,
+                hct_atom!("get_property"),
+                vec![ &obj, &prop ],
+                span,
+            )?;
 
-                &TypeId::Entity => {
-                    let prop = builder.build_const_atom(prop);
-                    call_stl(
-                        builder,
-                        hct_atom!("get_property"),
-                        vec![ &obj, &prop ],
-                    )
-                },
+            match ty {
+                Some(ty) => call_stl(
+                    builder,
+                    Atom::from(format!("parse_vector.{:?}", ty)),
+                    vec![ &value ],
+                    span,
+                ),
+                None => Ok(value),
+            }
+        },
 
-                &TypeId::String => {
-                    let sub = builder.build_const_i64(match prop {
-                        hct_atom!("x") | hct_atom!("r") | hct_atom!("pitch") => 0,
-                        hct_atom!("y") | hct_atom!("g") | hct_atom!("yaw") => 1,
-                        hct_atom!("z") | hct_atom!("b") | hct_atom!("roll") => 2,
-                        hct_atom!("w") | hct_atom!("a") => 3,
-                        ref sub => panic!("unsupported sub-property \"{}\"", sub),
-                    });
+        TypeId::String => {
+            let sub = sub_property_index(&prop, span, builder)?;
+            call_stl(
+                builder,
+                hct_atom!("get_sub_property"),
+                vec![ &obj, &sub ],
+                span,
+            )
+        },
 
-                    call_stl(
-                        builder,
-                        hct_atom!("get_sub_property"),
-                        vec![ &obj, &sub ],
-                    )
-                },
+        ref ty if ty.is_vector() => {
+            let idx = match vector_component(&prop) {
+                Some(idx) => builder.build_const_i32(idx as i32),
+                None => return Err(builder.error(span, format!("unsupported component \"{}\" of a {:?}", prop, ty))),
+            };
+
+            let zero = builder.build_const_i32(0);
+            let gep = builder.build_in_bounds_gep(
+                &obj,
+                vec![ &zero, &idx ],
+            );
+
+            Ok(builder.build_load(&ValueRef { ty: TypeId::f64, ptr: gep.ptr }))
+        },
+
+        ty => Err(builder.error(span, format!("cannot dereference a {:?}", ty))),
+    }
+}
+
+/// Convert a value interpolated in a string, applying its format to numbers
+fn interpolate<'a>(value: ValueRef, format: Option<FormatSpec>, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    if let Some(format) = format {
+        if value.ty != TypeId::f64 && value.ty != TypeId::i64 {
+            return Err(builder.error(span, format!("cannot format a {:?} as a number", value.ty)));
+        }
+
+        // Missing widths and precisions are passed as -1
+        let value = widen(value, &TypeId::f64, builder);
+        let zero = builder.build_const_bool(format.zero);
+        let width = builder.build_const_i64(format.width.map_or(-1, i64::from));
+        let precision = builder.build_const_i64(format.precision.map_or(-1, i64::from));
+        return call_stl(
+            builder,
+            hct_atom!("format_number"),
+            vec![ &value, &zero, &width, &precision ],
+            span,
+        );
+    }
+
+    match value.ty.clone() {
+        TypeId::String => Ok(value),
+        TypeId::f64 => call_stl(
+            builder,
+            hct_atom!("to_string"),
+            vec![ &value ],
+            span,
+        ),
+        TypeId::i64 => call_stl(
+            builder,
+            hct_atom!("int_to_string"),
+            vec![ &value ],
+            span,
+        ),
+        TypeId::bool => {
+            let yes = builder.build_const_string(String::from("true"));
+            let no = builder.build_const_string(String::from("false"));
+            Ok(builder.build_select(&value, &yes, &no))
+        },
+        TypeId::Entity => call_stl(
+            builder,
+            hct_atom!("entity_name"),
+            vec![ &value ],
+            span,
+        ),
+        TypeId::vec3 | TypeId::qangle => call_stl(
+            builder,
+            Atom::from(format!("vector_to_string.{:?}", value.ty)),
+            vec![ &value ],
+            span,
+        ),
+
+        // The elements are joined in a stack slot updated by the loop
+        TypeId::Array { .. } | TypeId::Vec { .. } => {
+            let res = builder.build_alloca(&TypeId::String);
+            let open = builder.build_const_string(String::from("["));
+            builder.build_store(&open, &res);
+
+            for_each(&value, span, scope, builder, |builder, scope, it, elem| {
+                let item = interpolate(elem, None, span, scope, builder)?;
+
+                let zero = builder.build_const_i64(0);
+                let is_first = builder.build_int_eq(it, &zero);
+                let empty = builder.build_const_string(String::new());
+                let comma = builder.build_const_string(String::from(", "));
+                let sep = builder.build_select(&is_first, &empty, &comma);
+
+                let prev = builder.build_load(&res);
+                let prev = call_stl(
+                    builder,
+                    hct_atom!("concat"),
+                    vec![ &prev, &sep ],
+                    span,
+                )?;
+                let next = call_stl(
+                    builder,
+                    hct_atom!("concat"),
+                    vec![ &prev, &item ],
+                    span,
+                )?;
+                builder.build_store(&next, &res);
+                Ok(())
+            })?;
+
+            let prev = builder.build_load(&res);
+            let close = builder.build_const_string(String::from("]"));
+            call_stl(
+                builder,
+                hct_atom!("concat"),
+                vec![ &prev, &close ],
+                span,
+            )
+        },
+
+        ty => Err(builder.error(span, format!("cannot interpolate a {:?} in a string", ty))),
+    }
+}
+
+/// Parse a string as a number or a vector
+fn parse_value(value: ValueRef, ty: &TypeId, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let name = match *ty {
+        TypeId::f64 => hct_atom!("parse"),
+        TypeId::i64 => hct_atom!("parse_int"),
+        TypeId::bool => hct_atom!("parse_bool"),
+        TypeId::vec3 | TypeId::qangle => Atom::from(format!("parse_vector.{:?}", ty)),
+        _ => return Err(builder.error(span, format!("cannot convert a String to {:?}", ty))),
+    };
+
+    call_stl(builder, name, vec![ &value ], span)
+}
+
+/// Convert a value to a type with `as`
+pub fn cast<'a>(value: ValueRef, ty: &TypeId, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    if value.ty == *ty {
+        return Ok(value);
+    }
+
+    match (value.ty.clone(), ty) {
+        (TypeId::String, ty) => parse_value(value, ty, span, builder),
+        (TypeId::i64, &TypeId::f64) => Ok(widen(value, ty, builder)),
+        (TypeId::f64, &TypeId::i64) => Ok(builder.build_fp_to_si(&value)),
+        (_, &TypeId::String) => interpolate(value, None, span, scope, builder),
+        (from, ty) => Err(builder.error(span, format!("cannot convert a {:?} to {:?}", from, ty))),
+    }
+}
+
+/// Parse the value of an entity property when an operator combines
+/// it with a number, returning any other operand unchanged
+fn operand(value: ValueRef, is_property: bool, op: Operator, other: &ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    match coerced_type(op, &other.ty) {
+        Some(ref ty) if is_property && value.ty == TypeId::String => parse_value(value, ty, span, builder),
+        _ => Ok(value),
+    }
+}
+
+/// Compute the result of a binary operation
+pub fn binary(lhs: ValueRef, op: Operator, rhs: ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    // Integers are widened when mixed with floats
+    let (lhs, rhs) = match (&lhs.ty, &rhs.ty) {
+        (&TypeId::i64, &TypeId::f64) |
+        (&TypeId::f64, &TypeId::i64) => (
+            widen(lhs.clone(), &TypeId::f64, builder),
+            widen(rhs.clone(), &TypeId::f64, builder),
+        ),
+        _ => (lhs.clone(), rhs.clone()),
+    };
+
+    let res = match (&lhs.ty, op, &rhs.ty) {
+        (&TypeId::String, Operator::Add, &TypeId::String) => call_stl(
+            builder,
+            hct_atom!("concat"),
+            vec![
+                &lhs,
+                &rhs,
+            ],
+            span,
+        )?,
+
+        (&TypeId::f64, Operator::Add, &TypeId::f64) => builder.build_fadd(&lhs, &rhs),
+        (&TypeId::f64, Operator::Sub, &TypeId::f64) => builder.build_fsub(&lhs, &rhs),
+        (&TypeId::f64, Operator::Mul, &TypeId::f64) => builder.build_fmul(&lhs, &rhs),
+        (&TypeId::f64, Operator::Div, &TypeId::f64) => builder.build_fdiv(&lhs, &rhs),
+        (&TypeId::f64, Operator::Mod, &TypeId::f64) => builder.build_frem(&lhs, &rhs),
+
+        (&TypeId::i64, Operator::Add, &TypeId::i64) => builder.build_nswadd(&lhs, &rhs),
+        (&TypeId::i64, Operator::Sub, &TypeId::i64) => builder.build_sub(&lhs, &rhs),
+        (&TypeId::i64, Operator::Mul, &TypeId::i64) => builder.build_mul(&lhs, &rhs),
+        // Integer divisions report a runtime error on a zero divisor
+        (&TypeId::i64, Operator::Div, &TypeId::i64) => call_stl(
+            builder,
+            hct_atom!("int_div"),
+            vec![ &lhs, &rhs ],
+            span,
+        )?,
+        (&TypeId::i64, Operator::Mod, &TypeId::i64) => call_stl(
+            builder,
+            hct_atom!("int_rem"),
+            vec![ &lhs, &rhs ],
+            span,
+        )?,
+
+        (&TypeId::i64, Operator::Shl, &TypeId::i64) => builder.build_shl(&lhs, &rhs),
+        (&TypeId::i64, Operator::Shr, &TypeId::i64) => builder.build_lshr(&lhs, &rhs),
+
+        (&TypeId::f64, Operator::Lt, &TypeId::f64) => builder.build_float_lt(&lhs, &rhs),
+        (&TypeId::f64, Operator::Lte, &TypeId::f64) => builder.build_float_le(&lhs, &rhs),
+        (&TypeId::f64, Operator::Gt, &TypeId::f64) => builder.build_float_gt(&lhs, &rhs),
+        (&TypeId::f64, Operator::Gte, &TypeId::f64) => builder.build_float_ge(&lhs, &rhs),
+
+        (&TypeId::i64, Operator::Lt, &TypeId::i64) => builder.build_int_slt(&lhs, &rhs),
+        (&TypeId::i64, Operator::Lte, &TypeId::i64) => builder.build_int_sle(&lhs, &rhs),
+        (&TypeId::i64, Operator::Gt, &TypeId::i64) => builder.build_int_sgt(&lhs, &rhs),
+        (&TypeId::i64, Operator::Gte, &TypeId::i64) => builder.build_int_sge(&lhs, &rhs),
+
+        (&TypeId::bool, Operator::And, &TypeId::bool) |
+        (&TypeId::i64, Operator::BAnd, &TypeId::i64) => builder.build_and(&lhs, &rhs),
+        (&TypeId::bool, Operator::Or, &TypeId::bool) |
+        (&TypeId::i64, Operator::BOr, &TypeId::i64) => builder.build_or(&lhs, &rhs),
+        (&TypeId::i64, Operator::BXor, &TypeId::i64) => builder.build_xor(&lhs, &rhs),
+
+        (&TypeId::f64, Operator::Eq, &TypeId::f64) => builder.build_float_eq(&lhs, &rhs),
+        (&TypeId::f64, Operator::Neq, &TypeId::f64) => builder.build_float_ne(&lhs, &rhs),
+
+        (&TypeId::bool, Operator::Eq, &TypeId::bool) |
+        (&TypeId::i64, Operator::Eq, &TypeId::i64) => builder.build_int_eq(&lhs, &rhs),
+        (&TypeId::bool, Operator::Neq, &TypeId::bool) |
+        (&TypeId::i64, Operator::Neq, &TypeId::i64) => builder.build_int_ne(&lhs, &rhs),
+
+        (lty, Operator::Add, rty) if lty.is_vector() && lty == rty => call_stl(
+            builder,
+            Atom::from(format!("vector_add.{:?}", lty)),
+            vec![ &lhs, &rhs ],
+            span,
+        )?,
+        (lty, Operator::Sub, rty) if lty.is_vector() && lty == rty => call_stl(
+            builder,
+            Atom::from(format!("vector_sub.{:?}", lty)),
+            vec![ &lhs, &rhs ],
+            span,
+        )?,
+        (lty, Operator::Mul, &TypeId::f64) |
+        (lty, Operator::Mul, &TypeId::i64) if lty.is_vector() => call_stl(
+            builder,
+            Atom::from(format!("vector_scale.{:?}", lty)),
+            vec![ &lhs, &rhs ],
+            span,
+        )?,
+        (&TypeId::f64, Operator::Mul, rty) |
+        (&TypeId::i64, Operator::Mul, rty) if rty.is_vector() => call_stl(
+            builder,
+            Atom::from(format!("vector_scale.{:?}", rty)),
+            vec![ &rhs, &lhs ],
+            span,
+        )?,
+        // Vectors are divided by scaling them with the inverse of the divisor
+        (lty, Operator::Div, &TypeId::f64) |
+        (lty, Operator::Div, &TypeId::i64) if lty.is_vector() => {
+            let one = builder.build_const_f64(1.0);
+            let rhs = widen(rhs.clone(), &TypeId::f64, builder);
+            let inverse = builder.build_fdiv(&one, &rhs);
+            call_stl(
+                builder,
+                Atom::from(format!("vector_scale.{:?}", lty)),
+                vec![ &lhs, &inverse ],
+                span,
+            )?
+        },
+
+        (lty, op @ Operator::Neq, rty) |
+        (lty, op @ Operator::Eq, rty) if lty == rty => {
+            let res = call_stl(
+                builder,
+                Atom::from(format!("eq.{:?}", lty)),
+                vec![
+                    &lhs,
+                    &rhs,
+                ],
+                span,
+            )?;
+
+            if op == Operator::Neq {
+                builder.build_not(&res)
+            } else {
+                res
+            }
+        },
 
-                ty => panic!("cannot dereference a {:?}", ty),
+        (lty, op, rty) => return Err(builder.error(span, format!("Unsupported expression: {:?} {} {:?}", lty, op, rty))),
+    };
+
+    Ok(res)
+}
+
+/// Compute the return value of an expression
+pub fn expression<'a>(exp: Expression, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let Expression { kind, span } = exp;
+    let res = match kind {
+        ExpressionKind::Call(val) => call(val, scope, builder)?,
+
+        ExpressionKind::Reference(Path { kind: PathKind::Binding(name), span }) => {
+            match scope.binding(builder, &name) {
+                Some(val) => val,
+                None => return Err(builder.error(span, format!("value \"{}\" not found", name))),
             }
         },
-        Expression::Reference(val) => unimplemented!("{:?}", val),
+        ExpressionKind::Reference(Path { kind: PathKind::Deref(box obj, prop), span }) => {
+            let obj = expression(
+                Expression {
+                    span: obj.span,
+                    kind: ExpressionKind::Reference(obj),
+                },
+                scope, builder,
+            )?;
+            field(obj, prop, span, builder)?
+        },
+        ExpressionKind::Field { box expr, name } => {
+            let obj = expression(expr, scope, builder)?;
+            field(obj, name, span, builder)?
+        },
+        ExpressionKind::Reference(path) => return Err(builder.error(path.span, format!("cannot use {} as a value", path))),
 
-        Expression::Array(val) => {
+        ExpressionKind::Array(val) => {
             let values: Vec<_> = {
                 val.into_iter()
                     .map(|elem| expression(elem, scope, builder))
-                    .collect()
+                    .collect::<HatchetResult<_>>()?
             };
 
+            let mut elem = None;
+            for val in &values {
+                if let Some(ref prev) = elem {
+                    if *prev != val.ty {
+                        return Err(builder.error(span, format!("heterogeneous array literal: found {:?} and {:?}", prev, val.ty)));
+                    }
+                }
+
+                if elem.is_none() {
+                    elem = Some(val.ty.clone());
+                }
+            }
+
+            let elem = elem.unwrap_or(TypeId::Void);
+
+            // Literals of scalar values are growable lists,
+            // other arrays are allocated on the stack
+            if elem.is_vec_element() {
+                return build_vec(values, &elem, span, builder);
+            }
+
             let ty = TypeId::Array {
                 len: values.len() as u32,
-                ty: box {
-                    values.iter()
-                        .fold(None, |prev, val| {
-                            if let Some(prev) = prev {
-                                assert_eq!(prev, val.ty, "heterogeneous array literal");
-                                Some(prev)
-                            } else {
-                                Some(val.ty.clone())
-                            }
-                        })
-                        .unwrap_or(TypeId::Void)
-                },
+                ty: box elem,
             };
 
             let arr_elem = builder.get_element_type(&ty);
@@ -114,14 +499,14 @@ pub fn expression<'a>(exp: Expression, scope: &Scope<'a>, builder: &mut Builder)
 
             alloc
         },
-        Expression::Map(val) => {
+        ExpressionKind::Map(val) => {
             let mut data: Vec<_> = {
                 val.into_iter()
                     .map(|(k, v)| {
-                        let val = expression(v, scope, builder);
-                        ((k.clone(), val.ty.clone()), val)
+                        let val = expression(v, scope, builder)?;
+                        Ok(((k.clone(), val.ty.clone()), val))
                     })
-                    .collect()
+                    .collect::<HatchetResult<_>>()?
             };
 
             data.sort_by(|&((ref a, _), _), &((ref b, _), _)| a.cmp(b));
@@ -152,117 +537,241 @@ pub fn expression<'a>(exp: Expression, scope: &Scope<'a>, builder: &mut Builder)
             alloc
         },
 
-        Expression::Binary { lhs: box Expression::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, op: Operator::Add, rhs: box ref e3 } |
-        Expression::Binary { lhs: box ref e3, op: Operator::Add, rhs: box Expression::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 } } => {
-            let e1 = expression(e1.clone(), scope, builder);
-            let e2 = expression(e2.clone(), scope, builder);
-            let e3 = expression(e3.clone(), scope, builder);
+        ExpressionKind::Index { box expr, box index } => {
+            let list_span = expr.span;
+            let list = expression(expr, scope, builder)?;
+            let index = index_value(index, scope, builder)?;
+
+            match list.ty.clone() {
+                TypeId::Array { len, ty } => {
+                    let ptr = array_element(&list, &index, len, *ty, span, builder)?;
+                    builder.build_load(&ptr)
+                },
+                TypeId::Vec { ty } => call_stl(
+                    builder,
+                    Atom::from(format!("vec_get.{:?}", ty)),
+                    vec![ &list, &index ],
+                    span,
+                )?,
+                ty => return Err(builder.error(list_span, format!("cannot index a {:?}", ty))),
+            }
+        },
+        ExpressionKind::Slice { box expr, start, end } => {
+            let list_span = expr.span;
+            let list = expression(expr, scope, builder)?;
+            let ty = match list.ty.clone() {
+                TypeId::Vec { ty } => ty,
+                ty => return Err(builder.error(list_span, format!("cannot slice a {:?}", ty))),
+            };
+
+            let start = match start {
+                Some(box start) => index_value(start, scope, builder)?,
+                None => builder.build_const_i64(0),
+            };
+            let end = match end {
+                Some(box end) => index_value(end, scope, builder)?,
+                None => list_len(&list, list_span, builder)?,
+            };
+
             call_stl(
                 builder,
-                hct_atom!("fmuladd"),
-                vec![ &e1, &e2, &e3 ],
-            )
+                Atom::from(format!("vec_slice.{:?}", ty)),
+                vec![ &list, &start, &end ],
+                span,
+            )?
         },
-        Expression::Binary { box lhs, op, box rhs } => {
-            let lhs = expression(lhs, scope, builder);
-            let rhs = expression(rhs, scope, builder);
-            match (&lhs.ty, op, &rhs.ty) {
-                (&TypeId::String, Operator::Add, &TypeId::String) => call_stl(
+        ExpressionKind::Range { .. } => return Err(builder.error(span, "ranges can only be iterated by a for loop")),
+
+        ExpressionKind::Binary { lhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. }, op: Operator::Add, rhs: box ref e3 } |
+        ExpressionKind::Binary { lhs: box ref e3, op: Operator::Add, rhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. } } => {
+            // The checker only lets the fields of entities be parsed as numbers
+            let fields = (e1.is_field(), e2.is_field(), e3.is_field());
+            let e1 = expression(e1.clone(), scope, builder)?;
+            let e2 = expression(e2.clone(), scope, builder)?;
+            let e3 = expression(e3.clone(), scope, builder)?;
+            let is_number = |ty: &TypeId| *ty == TypeId::f64 || *ty == TypeId::i64;
+            if e1.ty == TypeId::i64 && e2.ty == TypeId::i64 && e3.ty == TypeId::i64 {
+                let mul = builder.build_mul(&e1, &e2);
+                builder.build_nswadd(&mul, &e3)
+            } else if is_number(&e1.ty) && is_number(&e2.ty) && is_number(&e3.ty) {
+                let e1 = widen(e1, &TypeId::f64, builder);
+                let e2 = widen(e2, &TypeId::f64, builder);
+                let e3 = widen(e3, &TypeId::f64, builder);
+                call_stl(
                     builder,
-                    hct_atom!("concat"),
-                    vec![
-                        &lhs,
-                        &rhs,
-                    ],
-                ),
+                    hct_atom!("fmuladd"),
+                    vec![ &e1, &e2, &e3 ],
+                    span,
+                )?
+            } else {
+                // Other operands, like vectors or properties parsed as numbers, are combined one operation at a time
+                let e1 = operand(e1, fields.0, Operator::Mul, &e2, span, builder)?;
+                let e2 = operand(e2, fields.1, Operator::Mul, &e1, span, builder)?;
+                let mul = binary(e1, Operator::Mul, e2, span, builder)?;
+                let e3 = operand(e3, fields.2, Operator::Add, &mul, span, builder)?;
+                binary(mul, Operator::Add, e3, span, builder)?
+            }
+        },
+        ExpressionKind::Conditional { box condition, box consequent, box alternate } => {
+            let cond_span = condition.span;
+            let cond = expression(condition, scope, builder)?;
+            if cond.ty != TypeId::bool {
+                return Err(builder.error(cond_span, format!("expected a condition, found {:?}", cond.ty)));
+            }
 
-                (&TypeId::f64, Operator::Add, &TypeId::f64) => builder.build_fadd(&lhs, &rhs),
-                (&TypeId::f64, Operator::Sub, &TypeId::f64) => builder.build_fsub(&lhs, &rhs),
-                (&TypeId::f64, Operator::Mul, &TypeId::f64) => builder.build_fmul(&lhs, &rhs),
-                (&TypeId::f64, Operator::Div, &TypeId::f64) => builder.build_fdiv(&lhs, &rhs),
-                (&TypeId::f64, Operator::Mod, &TypeId::f64) => builder.build_frem(&lhs, &rhs),
-
-                (&TypeId::i64, Operator::Shl, &TypeId::i64) => builder.build_shl(&lhs, &rhs),
-                (&TypeId::i64, Operator::Shr, &TypeId::i64) => builder.build_lshr(&lhs, &rhs),
-
-                (&TypeId::f64, Operator::Lt, &TypeId::f64) => builder.build_float_lt(&lhs, &rhs),
-                (&TypeId::f64, Operator::Lte, &TypeId::f64) => builder.build_float_le(&lhs, &rhs),
-                (&TypeId::f64, Operator::Gt, &TypeId::f64) => builder.build_float_gt(&lhs, &rhs),
-                (&TypeId::f64, Operator::Gte, &TypeId::f64) => builder.build_float_ge(&lhs, &rhs),
-
-                (&TypeId::bool, Operator::And, &TypeId::bool) |
-                (&TypeId::i64, Operator::BAnd, &TypeId::i64) => builder.build_and(&lhs, &rhs),
-                (&TypeId::bool, Operator::Or, &TypeId::bool) |
-                (&TypeId::i64, Operator::BOr, &TypeId::i64) => builder.build_or(&lhs, &rhs),
-                (&TypeId::i64, Operator::BXor, &TypeId::i64) => builder.build_xor(&lhs, &rhs),
-
-                (&TypeId::f64, Operator::Eq, &TypeId::f64) => builder.build_float_eq(&lhs, &rhs),
-                (&TypeId::f64, Operator::Neq, &TypeId::f64) => builder.build_float_ne(&lhs, &rhs),
-
-                (&TypeId::bool, Operator::Eq, &TypeId::bool) |
-                (&TypeId::i64, Operator::Eq, &TypeId::i64) => builder.build_int_eq(&lhs, &rhs),
-                (&TypeId::bool, Operator::Neq, &TypeId::bool) |
-                (&TypeId::i64, Operator::Neq, &TypeId::i64) => builder.build_int_ne(&lhs, &rhs),
-
-                (lty, op @ Operator::Neq, rty) |
-                (lty, op @ Operator::Eq, rty) if lty == rty => {
-                    let res = call_stl(
-                        builder,
-                        Atom::from(format!("eq.{:?}", lty)),
-                        vec![
-                            &lhs,
-                            &rhs,
-                        ],
-                    );
+            let then_block = builder.append_basic_block();
+            let else_block = builder.append_basic_block();
+            let next_block = builder.append_basic_block();
+            builder.build_cond_br(&cond, then_block, else_block);
 
-                    if op == Operator::Neq {
-                        builder.build_not(&res)
-                    } else {
-                        res
-                    }
-                },
+            builder.position_at_end(then_block);
+            let then_val = expression(consequent, scope, builder)?;
+            let then_end = builder.get_insert_block();
+
+            builder.position_at_end(else_block);
+            let else_val = expression(alternate, scope, builder)?;
+
+            // Integers are widened when mixed with floats
+            let ty = if then_val.ty == TypeId::i64 && else_val.ty == TypeId::f64 {
+                TypeId::f64
+            } else {
+                then_val.ty.clone()
+            };
+
+            let else_val = widen(else_val, &ty, builder);
+            let else_end = builder.get_insert_block();
+            builder.build_br(next_block);
+
+            builder.position_at_end(then_end);
+            let then_val = widen(then_val, &ty, builder);
+            builder.build_br(next_block);
 
-                (lty, op, rty) => panic!("Unsuported expression: {:?} {} {:?}", lty, op, rty),
+            if ty == TypeId::Void {
+                return Err(builder.error(span, "both branches of an if expression must produce a value"));
+            }
+            if else_val.ty != ty {
+                return Err(builder.error(span, format!("if and else branches have different types: {:?} and {:?}", ty, else_val.ty)));
+            }
+
+            builder.position_at_end(next_block);
+            let res = builder.build_phi(ty);
+            builder.add_incoming(&res, then_val, then_end);
+            builder.add_incoming(&res, else_val, else_end);
+            res
+        },
+        ExpressionKind::Unary { op, box expr } => {
+            let val = expression(expr, scope, builder)?;
+            match (op, &val.ty) {
+                (UnaryOperator::Not, &TypeId::bool) |
+                (UnaryOperator::BNot, &TypeId::i64) => builder.build_not(&val),
+                (UnaryOperator::Neg, &TypeId::f64) => builder.build_fneg(&val),
+                (UnaryOperator::Neg, &TypeId::i64) => builder.build_neg(&val),
+                (UnaryOperator::Neg, ty) if ty.is_vector() => {
+                    let factor = builder.build_const_f64(-1.0);
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vector_scale.{:?}", ty)),
+                        vec![ &val, &factor ],
+                        span,
+                    )?
+                },
+                (op, ty) => return Err(builder.error(span, format!("Unsupported expression: {}{:?}", op, ty))),
             }
         },
+        // The property is converted to the type of the default, always evaluated
+        ExpressionKind::Binary { box lhs, op: Operator::Coalesce, box rhs } => {
+            let (obj, prop) = match lhs.split_field() {
+                Ok(res) => res,
+                Err(lhs) => return Err(builder.error(lhs.span, "?? expects a property of an entity")),
+            };
 
-        Expression::Literal(Literal::Number(ref val)) => builder.build_const_f64(*val),
-        Expression::Literal(Literal::String(ref val)) => {
-            let val = {
-                val.into_iter()
-                    .fold(None, |prev, item| {
-                        let item = match *item {
-                            StringPart::String(ref s) => builder.build_const_string(s),
-                            StringPart::Expression(ref e) => {
-                                let exp = expression(e.clone(), scope, builder);
-                                match exp.ty {
-                                    TypeId::String => exp,
-                                    TypeId::f64 => call_stl(
-                                        builder,
-                                        hct_atom!("to_string"),
-                                        vec![ &exp ],
-                                    ),
-                                    _ => unimplemented!(),
-                                }
-                            },
-                        };
-
-                        Some(if let Some(prev) = prev {
-                            call_stl(
-                                builder,
-                                hct_atom!("concat"),
-                                vec![
-                                    &prev,
-                                    &item,
-                                ],
-                            )
-                        } else {
-                            item
-                        })
-                    })
+            let obj_span = obj.span;
+            let obj = expression(obj, scope, builder)?;
+            if obj.ty != TypeId::Entity {
+                return Err(builder.error(obj_span, format!("?? expects a property of an entity, found a field of a {:?}", obj.ty)));
+            }
+
+            let default = expression(rhs, scope, builder)?;
+            let key = builder.build_const_string(prop.to_string());
+            let has = call_stl(
+                builder,
+                hct_atom!("has"),
+                vec![ &obj, &key ],
+                span,
+            )?;
+
+            let else_end = builder.get_insert_block();
+            let then_block = builder.append_basic_block();
+            let next_block = builder.append_basic_block();
+            builder.build_cond_br(&has, then_block, next_block);
+
+            builder.position_at_end(then_block);
+            let key = builder.build_const_atom(prop);
+            let value = call_stl(
+                builder,
+                hct_atom!("get_property"),
+                vec![ &obj, &key ],
+                span,
+            )?;
+            let value = cast(value, &default.ty, span, scope, builder)?;
+            let then_end = builder.get_insert_block();
+            builder.build_br(next_block);
+
+            builder.position_at_end(next_block);
+            let res = builder.build_phi(default.ty.clone());
+            builder.add_incoming(&res, value, then_end);
+            builder.add_incoming(&res, default, else_end);
+            res
+        },
+        ExpressionKind::Binary { box lhs, op, box rhs } => {
+            let fields = (lhs.is_field(), rhs.is_field());
+            let lhs = expression(lhs, scope, builder)?;
+            let rhs = expression(rhs, scope, builder)?;
+            let lhs = operand(lhs, fields.0, op, &rhs, span, builder)?;
+            let rhs = operand(rhs, fields.1, op, &lhs, span, builder)?;
+            binary(lhs, op, rhs, span, builder)?
+        },
+        ExpressionKind::Cast { box expr, ty } => {
+            let ty = match TypeId::from_cast(&ty) {
+                Some(ty) => ty,
+                None => return Err(builder.error(span, format!("unknown type {}", ty))),
             };
 
-            val.unwrap_or_else(|| builder.build_const_string(String::new()))
+            let value = expression(expr, scope, builder)?;
+            cast(value, &ty, span, scope, builder)?
         },
-    }
+
+        ExpressionKind::Literal(Literal::Number(ref val)) => builder.build_const_f64(*val),
+        ExpressionKind::Literal(Literal::Int(ref val)) => builder.build_const_i64(*val),
+        ExpressionKind::Literal(Literal::Bool(ref val)) => builder.build_const_bool(*val),
+        ExpressionKind::Literal(Literal::String(ref val)) => {
+            let mut res: Option<ValueRef> = None;
+            for item in val {
+                let item = match *item {
+                    StringPart::String(ref s) => builder.build_const_string(s),
+                    StringPart::Expression(ref e, format) => {
+                        let exp = expression(e.clone(), scope, builder)?;
+                        interpolate(exp, format, e.span, scope, builder)?
+                    },
+                };
+
+                res = Some(match res {
+                    Some(prev) => call_stl(
+                        builder,
+                        hct_atom!("concat"),
+                        vec![
+                            &prev,
+                            &item,
+                        ],
+                        span,
+                    )?,
+                    None => item,
+                });
+            }
+
+            res.unwrap_or_else(|| builder.build_const_string(String::new()))
+        },
+    };
+
+    Ok(res)
 }
@@ -1,44 +1,68 @@
 //! Function call utilities
 
 use atom::*;
+use error::HatchetResult;
 use hct::ast::*;
+use runtime::stl::Externals;
 use super::builder::*;
 use super::expression::*;
+use super::dict::*;
+use super::list::*;
+use super::math::*;
 use super::path::*;
 use super::scope::*;
+use super::statements::*;
+use super::string::*;
 use super::types::*;
 
-/// Create a call to an STL function
-pub fn call_stl<'a, A>(builder: &mut Builder, name: Atom, args: A) -> ValueRef
+/// Leave the current function if a called function reported a runtime error
+fn check_error(span: Span, builder: &mut Builder) -> HatchetResult<()> {
+    let failed = call_stl(builder, hct_atom!("has_error"), vec![], span)?;
+    let exit_block = builder.get_exit_block();
+    let next_block = builder.append_basic_block();
+    builder.build_cond_br(&failed, exit_block, next_block);
+    builder.position_at_end(next_block);
+    Ok(())
+}
+
+/// Create a call to an STL function, reporting arguments
+/// not matching its signature at the location of the call
+pub fn call_stl<'a, A>(builder: &mut Builder, name: Atom, args: A, span: Span) -> HatchetResult<ValueRef>
     where A: IntoIterator<Item=&'a ValueRef>, Type: 'a, Value: 'a {
     let mut args = args.into_iter().peekable();
 
     match name {
         hct_atom!("length") => {
-            let sum = {
-                args.fold(None, |prev: Option<ValueRef>, val| {
-                    let two = builder.build_const_f64(2.0);
-                    let pow = call_stl(
-                        builder,
-                        hct_atom!("pow"),
-                        vec![ val, &two ],
-                    );
-
-                    if let Some(prev) = prev {
-                        Some(builder.build_fadd(&prev, &pow))
-                    } else {
-                        Some(pow)
-                    }
-                })
-            };
+            // The length of a vector is computed from its components by the STL
+            let is_vector = args.peek().map_or(false, |val| val.ty == TypeId::vec3);
+            if is_vector {
+                let val = args.next();
+                return call_stl(builder, hct_atom!("vector_length"), val, span);
+            }
+
+            let mut sum: Option<ValueRef> = None;
+            for val in args {
+                let two = builder.build_const_f64(2.0);
+                let pow = call_stl(
+                    builder,
+                    hct_atom!("pow"),
+                    vec![ val, &two ],
+                    span,
+                )?;
 
-            return call_stl(builder, hct_atom!("sqrt"), sum.iter());
+                sum = Some(match sum {
+                    Some(prev) => builder.build_fadd(&prev, &pow),
+                    None => pow,
+                });
+            }
+
+            return call_stl(builder, hct_atom!("sqrt"), sum.iter(), span);
         },
 
         hct_atom!("to_string") => {
             if let Some(val) = args.peek() {
                 if let Some(val) = builder.get_const_f64(val) {
-                    return builder.build_const_string(val.to_string());
+                    return Ok(builder.build_const_string(val.to_string()));
                 }
             }
         },
@@ -48,7 +72,11 @@ pub fn call_stl<'a, A>(builder: &mut Builder, name: Atom, args: A) -> ValueRef
 
     //trace!("{}", name);
 
-    let ext = builder.get_external(name);
+    if Externals::signature(&name).is_none() {
+        return Err(builder.error(span, format!("unknown function {}", name)));
+    }
+
+    let ext = builder.get_external(name.clone());
     let ctx = match ext.args.first() {
         Some(arg) if *arg == TypeId::Context => {
             Some(builder.get_runtime_context())
@@ -56,6 +84,9 @@ pub fn call_stl<'a, A>(builder: &mut Builder, name: Atom, args: A) -> ValueRef
         _ => None,
     };
 
+    // Only the externals taking the context can report runtime errors
+    let fallible = ctx.is_some() && name != hct_atom!("has_error");
+
     let args = {
         ctx.into_iter()
             .chain(args.cloned())
@@ -65,44 +96,254 @@ pub fn call_stl<'a, A>(builder: &mut Builder, name: Atom, args: A) -> ValueRef
             //.inspect(|arg| trace!("{:?}", arg))
             .enumerate()
             .map(|(i, (val, arg))| {
+                let val = widen(val, &arg, builder);
                 if val.ty == arg {
                     Ok(val)
                 } else {
-                    Err(format!("Expected type {:?} for argument {}, found {:?}", val.ty, i, arg))
+                    Err(builder.error(span, format!("expected type {:?} for argument {} of {}, found {:?}", arg, i, name, val.ty)))
                 }
             })
-            .collect::<Result<_, _>>()
-            .unwrap()
+            .collect::<HatchetResult<_>>()?
     };
 
-    builder.build_call(ext, args)
+    let res = builder.build_call(ext, args);
+
+    if fallible {
+        check_error(span, builder)?;
+    }
+
+    Ok(res)
+}
+
+/// Check the arguments of a call against the signature of the called
+/// function, reporting mismatched types at the location of the argument
+pub fn check_args(builder: &Builder, name: &Atom, args: &[(ValueRef, Span)], span: Span) -> HatchetResult<()> {
+    if *name == hct_atom!("length") {
+        if args.len() == 1 && args[0].0.ty == TypeId::vec3 {
+            return Ok(());
+        }
+
+        for &(ref arg, span) in args {
+            if arg.ty != TypeId::f64 && arg.ty != TypeId::i64 {
+                return Err(builder.error(span, format!("expected type f64, found {:?}", arg.ty)));
+            }
+        }
+
+        return Ok(());
+    }
+
+    let params = match Externals::signature(name) {
+        Some((params, _)) => params,
+        None => return Err(builder.error(span, format!("unknown function {}", name))),
+    };
+
+    let params: Vec<_> = {
+        params.into_iter()
+            .filter(|ty| *ty != TypeId::Context)
+            .collect()
+    };
+
+    if params.len() != args.len() {
+        return Err(builder.error(span, format!("{} expects {} arguments, found {}", name, params.len(), args.len())));
+    }
+
+    for (param, &(ref arg, span)) in params.iter().zip(args) {
+        // Integers are implicitly widened to floats
+        if *param != arg.ty && !(*param == TypeId::f64 && arg.ty == TypeId::i64) {
+            return Err(builder.error(span, format!("expected type {:?}, found {:?}", param, arg.ty)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the instance of a user function for the types of its arguments,
+/// returning it along with its return type
+fn build_function<'a>(func: &Function, instance: Instance, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<(ValueRef, TypeId)> {
+    // Functions called from an event block receive it along with the current delay
+    let mut params = vec![ TypeId::Context ];
+    if instance.2 {
+        params.push(TypeId::Entity);
+        params.push(TypeId::Atom);
+        params.push(TypeId::f64);
+    }
+
+    let offset = params.len();
+    params.extend(instance.1.iter().cloned());
+    params.push(builder.get_opaque_pointer_type());
+
+    let ty = builder.get_function_type(TypeId::Void, &params);
+    let function = builder.add_function(ty, &format!("hct.fn.{}", func.name));
+
+    let state = builder.enter_function(instance.clone(), function.clone());
+
+    let mut body = if instance.2 {
+        scope.function_body(
+            Some((
+                builder.get_param(1, &TypeId::Entity),
+                builder.get_param(2, &TypeId::Atom),
+            )),
+            Some(builder.get_param(3, &TypeId::f64)),
+        )
+    } else {
+        scope.function_body(None, None)
+    };
+
+    for (i, (name, ty)) in func.args.iter().zip(&instance.1).enumerate() {
+        let value = builder.get_param((offset + i) as u32, ty);
+        body.set_binding(builder, name.clone(), &value);
+    }
+
+    statements(func.body.clone(), body, builder)?;
+
+    let ret = builder.leave_function(state).unwrap_or(TypeId::Void);
+    Ok((function, ret))
+}
+
+/// Get the event a user function called from this scope is connected to,
+/// along with the current delay
+fn function_event<'a>(scope: &Scope<'a>, builder: &mut Builder) -> Option<(ValueRef, ValueRef, ValueRef)> {
+    match scope.event() {
+        Some((from, trigger)) => if from.ty == TypeId::Entity {
+            let delay = scope.delay().unwrap_or_else(|| builder.build_const_f64(0.0));
+            Some((from, trigger, delay))
+        } else {
+            None
+        },
+        None => None,
+    }
+}
+
+/// Get the instance of a user function for the types of its arguments,
+/// building it if needed, along with its return type
+fn function_instance<'a>(func: &Function, args: Vec<TypeId>, event: bool, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<(ValueRef, TypeId)> {
+    if func.args.len() != args.len() {
+        return Err(builder.error(span, format!("{} expects {} arguments, found {}", func.name, func.args.len(), args.len())));
+    }
+
+    let instance = (func.span, args, event);
+    match builder.get_user_function(&instance) {
+        Some((function, Some(ret))) => Ok((function, ret)),
+        Some((_, None)) => Err(builder.error(span, format!("cannot infer the return type of the recursive function {}", func.name))),
+        None => build_function(func, instance, scope, builder),
+    }
+}
+
+/// Get the return type of a user function called with arguments of the given types
+pub fn return_type<'a>(func: &Function, args: Vec<TypeId>, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<TypeId> {
+    let event = function_event(scope, builder).is_some();
+    Ok(function_instance(func, args, event, span, scope, builder)?.1)
+}
+
+/// Call a user function, building its instance for the types of the arguments if needed
+pub fn call_function<'a>(func: &Function, args: Vec<(ValueRef, Span)>, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let event = function_event(scope, builder);
+    let (function, ret) = function_instance(
+        func,
+        args.iter().map(|&(ref val, _)| val.ty.clone()).collect(),
+        event.is_some(),
+        span, scope, builder,
+    )?;
+
+    let mut call_args = vec![ builder.get_runtime_context() ];
+    if let Some((from, trigger, delay)) = event {
+        call_args.push(from);
+        call_args.push(trigger);
+        call_args.push(delay);
+    }
+
+    call_args.extend(args.into_iter().map(|(val, _)| val));
+
+    let slot_ty = builder.get_opaque_pointer_type();
+    let slot = if ret == TypeId::Void {
+        None
+    } else {
+        Some(builder.build_alloca(&ret))
+    };
+
+    call_args.push(match slot {
+        Some(ref slot) => builder.build_bitcast(slot, slot_ty),
+        None => builder.build_undef(&slot_ty),
+    });
+
+    let arg_types = call_args.iter().map(|val| val.ty.clone()).collect();
+    let res = builder.build_call(
+        ::runtime::types::Function {
+            ptr: function.ptr,
+            args: arg_types,
+            ret: TypeId::Void,
+        },
+        call_args,
+    );
+
+    // The runtime errors of the function are propagated to the caller
+    check_error(span, builder)?;
+
+    Ok(match slot {
+        Some(slot) => builder.build_load(&slot),
+        None => res,
+    })
 }
 
 /// Execute an AST Call node
-pub fn call<'a>(Call { path, args }: Call, scope: &Scope<'a>, builder: &mut Builder) -> ValueRef {
+pub fn call<'a>(Call { path, args, span }: Call, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    if let PathKind::Binding(ref name) = path.kind {
+        if scope.function(name).is_none() && is_higher_order(name) {
+            return higher_order_call(name, args, span, scope, builder);
+        }
+    }
+
     let args = {
         args.into_iter()
-            .map(|arg| expression(arg, scope, builder))
-            .collect::<Vec<_>>()
+            .map(|arg| {
+                let span = arg.span;
+                Ok((expression(arg, scope, builder)?, span))
+            })
+            .collect::<HatchetResult<Vec<_>>>()?
     };
 
+    if let PathKind::Binding(ref name) = path.kind {
+        if let Some(func) = scope.function(name) {
+            return call_function(&func, args, span, scope, builder);
+        }
+    }
+
+    // List, dictionary, string and math operations are not entity methods, even in an event block
+    if let PathKind::Binding(ref name) = path.kind {
+        if let Some(res) = dict_call(name, &args, span, builder)? {
+            return Ok(res);
+        }
+        if let Some(res) = string_call(name, &args, span, builder)? {
+            return Ok(res);
+        }
+        if let Some(res) = math_call(name, &args, span, builder)? {
+            return Ok(res);
+        }
+        if let Some(res) = list_call(name, &args, span, builder)? {
+            return Ok(res);
+        }
+    }
+
     if let Some((from, trigger)) = scope.event() {
         if from.ty == TypeId::Entity {
-            let (entity, method) = event(path, scope, builder);
+            let (entity, method) = event(path, scope, builder)?;
             let delay = scope.delay().unwrap_or_else(|| builder.build_const_f64(0.0));
-            let arg = {
-                args.get(0)
-                    .map(|val| match val.ty {
-                        TypeId::String => val.clone(),
-                        TypeId::f64 => call_stl(
+            let arg = match args.get(0) {
+                Some(&(ref val, span)) => match val.ty {
+                    TypeId::String => val.clone(),
+                    TypeId::f64 | TypeId::i64 => {
+                        let val = widen(val.clone(), &TypeId::f64, builder);
+                        call_stl(
                             builder,
                             hct_atom!("to_string"),
-                            vec![ val ],
-                        ),
+                            vec![ &val ],
+                            span,
+                        )?
+                    },
 
-                        ref ty => panic!("Unsupported event argument {:?}", ty),
-                    })
-                    .unwrap_or_else(|| builder.build_const_string(String::from("")))
+                    ref ty => return Err(builder.error(span, format!("Unsupported event argument {:?}", ty))),
+                },
+                None => builder.build_const_string(String::from("")),
             };
 
             return call_stl(
@@ -117,16 +358,16 @@ pub fn call<'a>(Call { path, args }: Call, scope: &Scope<'a>, builder: &mut Buil
                     &delay,
                     // TODO: once
                 ],
+                span,
             );
         }
     }
 
-    let name = match path {
-        Path::Binding(name) => name,
-        ref path => {
-            panic!("not yet implemented: {:?}", path)
-        },
+    let name = match path.kind {
+        PathKind::Binding(ref name) => name.clone(),
+        _ => return Err(builder.error(path.span, format!("{} is not a function", path))),
     };
 
-    call_stl(builder, name, args.iter())
+    check_args(builder, &name, &args, span)?;
+    call_stl(builder, name, args.iter().map(|&(ref val, _)| val), span)
 }
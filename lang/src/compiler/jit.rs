@@ -0,0 +1,153 @@
+//! LLVM backend, compiling scripts to native code run with MCJIT
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem;
+
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use llvm_sys::execution_engine::*;
+use llvm_sys::transforms::pass_manager_builder::*;
+use llvm_sys::target::*;
+use log::LogLevel::Trace;
+
+use atom::Atom;
+use error::*;
+use logging::*;
+use hct::ast;
+use vmf::ir;
+use vmf::*;
+
+use super::builder::*;
+use super::scope::*;
+use super::statements::*;
+use super::types::*;
+use runtime::types::*;
+use runtime::stl::Externals;
+
+fn codegen(name: &str, script: ast::Script, entities: HashMap<Atom, Entity>) -> HatchetResult<BuilderResult> {
+    let mut builder = Builder::new(name, script.file, entities);
+
+    let scope = Scope::root(&mut builder);
+    statements(
+        script.body,
+        scope,
+        &mut builder,
+    )?;
+
+    Ok(builder.finalize())
+}
+
+fn optimize(module: LLVMModuleRef) {
+    unsafe {
+        let pm = LLVMCreatePassManager();
+
+        let builder = LLVMPassManagerBuilderCreate();
+        LLVMPassManagerBuilderSetOptLevel(builder, 2);
+        LLVMPassManagerBuilderPopulateFunctionPassManager(builder, pm);
+        LLVMPassManagerBuilderPopulateModulePassManager(builder, pm);
+        LLVMPassManagerBuilderDispose(builder);
+
+        LLVMRunPassManager(pm, module);
+    }
+}
+
+type LinkResult = (LLVMExecutionEngineRef, extern "C" fn(&mut Context) -> (), Context);
+fn link(ent: ir::Script, module: LLVMModuleRef, entities: HashMap<Atom, Entity>, globals: HashMap<Global, LLVMValueRef>, externals: &Externals) -> HatchetResult<LinkResult> {
+    unsafe {
+        LLVMLinkInMCJIT();
+        LLVM_InitializeNativeTarget();
+        LLVM_InitializeNativeAsmPrinter();
+    }
+
+    // Register external functions
+    externals.register_symbols();
+
+    let engine = unsafe {
+        let mut engine = mem::uninitialized();
+        let mut out = mem::zeroed();
+
+        let res = LLVMCreateExecutionEngineForModule(&mut engine, module, &mut out);
+        if res == 0 {
+            engine
+        } else {
+            let message = CStr::from_ptr(out).to_string_lossy().into_owned();
+            LLVMDisposeMessage(out);
+
+            return Err(HatchetError::Link {
+                script: ent.script,
+                message,
+            });
+        }
+    };
+
+    // Map globals to arena memory
+    let arenas = Arenas::with_globals(module, engine, globals);
+
+    let fname_s = CString::new("main").unwrap();
+    let main_fn = unsafe {
+        let addr = LLVMGetFunctionAddress(engine, fname_s.as_ptr());
+        if addr == 0 {
+            return Err(HatchetError::Link {
+                script: ent.script,
+                message: String::from("main function not found"),
+            });
+        } else {
+            mem::transmute(addr)
+        }
+    };
+
+    let ctx = Context::new(ent, arenas, entities);
+    Ok((engine, main_fn, ctx))
+}
+
+fn module_to_string(module: LLVMModuleRef) -> String {
+    unsafe {
+        let c_str = LLVMPrintModuleToString(module);
+        let val = CStr::from_ptr(c_str).to_string_lossy().into_owned();
+        LLVMDisposeMessage(c_str);
+        val
+    }
+}
+
+/// Compiles and run a script on a map
+pub fn apply(ent: ir::Script, script: ast::Script, entities: HashMap<Atom, Entity>) -> HatchetResult<HashMap<Atom, Entity>> {
+    let codegen_start = timer_start!();
+
+    let (module, entities, globals, externals) = codegen(&ent.script, script, entities)?;
+
+    let opt_start = timer_chain!(codegen_start, time, "Codegen time: {}", time);
+
+    let pre_opt: Option<String> = if log_enabled!(Trace) {
+        let pre_opt = module_to_string(module.ptr);
+        trace!("Codegen output:\n{}", pre_opt);
+
+        Some(pre_opt)
+    } else {
+        None
+    };
+
+    optimize(module.ptr);
+
+    if let Some(pre_opt) = pre_opt {
+        trace!("Optimizer output:");
+        print_diff(&pre_opt, &module_to_string(module.ptr));
+    }
+
+    let link_start = timer_chain!(opt_start, time, "Optimization time: {}", time);
+
+    let script = ent.script.clone();
+    let (_ee, main_fn, mut ctx) = link(ent, module.ptr, entities, globals, &externals)?;
+
+    let exec_start = timer_chain!(link_start, time, "Linking time: {}", time);
+
+    main_fn(&mut ctx);
+
+    timer_end!(exec_start, time, "Execution time: {}", time);
+
+    if let Some(message) = ctx.error {
+        return Err(HatchetError::Runtime { script, message });
+    }
+
+    Ok(ctx.entities)
+}
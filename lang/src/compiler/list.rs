@@ -0,0 +1,282 @@
+//! Operations on lists, backed by the vectors of the STL
+
+use std::rc::Rc;
+
+use atom::*;
+use error::HatchetResult;
+use hct::ast::*;
+
+use super::builder::*;
+use super::check::element_error;
+use super::expression::*;
+use super::function::*;
+use super::scope::*;
+use super::statements::*;
+use super::types::*;
+
+/// Get the length of an array or a vector as an integer
+pub fn list_len(list: &ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    match list.ty.clone() {
+        TypeId::Array { len, .. } => Ok(builder.build_const_i64(i64::from(len))),
+        TypeId::Vec { ty } => call_stl(
+            builder,
+            Atom::from(format!("vec_len.{:?}", ty)),
+            vec![ list ],
+            span,
+        ),
+        ty => Err(builder.error(span, format!("cannot get the length of a {:?}", ty))),
+    }
+}
+
+/// Get the element of a list at an index known to be in bounds
+pub fn list_get(list: &ValueRef, index: &ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    match list.ty.clone() {
+        TypeId::Array { ty, .. } => {
+            let zero = builder.build_const_i32(0);
+            let gep = builder.build_in_bounds_gep(
+                list,
+                vec![ &zero, index ],
+            );
+
+            let res = builder.build_load(&gep);
+            Ok(ValueRef { ty: *ty, ptr: res.ptr })
+        },
+        TypeId::Vec { ty } => call_stl(
+            builder,
+            Atom::from(format!("vec_get.{:?}", ty)),
+            vec![ list, index ],
+            span,
+        ),
+        ty => Err(builder.error(span, format!("Tried to iterate on a non-array: {:?}", ty))),
+    }
+}
+
+/// Build a new vector holding the given values
+pub fn build_vec(values: Vec<ValueRef>, ty: &TypeId, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    let capacity = builder.build_const_i64(values.len() as i64);
+    let vec = call_stl(
+        builder,
+        Atom::from(format!("vec_new.{:?}", ty)),
+        vec![ &capacity ],
+        span,
+    )?;
+
+    let push = Atom::from(format!("vec_push.{:?}", ty));
+    for value in values {
+        call_stl(
+            builder,
+            push.clone(),
+            vec![ &vec, &value ],
+            span,
+        )?;
+    }
+
+    Ok(vec)
+}
+
+/// Build a loop running the body on each element of a list and its index
+/// The length is read on each iteration, as the list may change in the body
+pub fn for_each<'a, F>(list: &ValueRef, span: Span, scope: &Scope<'a>, builder: &mut Builder, body: F) -> HatchetResult<()>
+    where F: FnOnce(&mut Builder, &Scope, &ValueRef, ValueRef) -> HatchetResult<()> {
+    let start_val = builder.build_const_i64(0);
+
+    iterator(
+        builder, scope,
+        start_val.clone(),
+        |builder| {
+            let len = list_len(list, span, builder)?;
+            Ok(builder.build_int_lt(
+                &start_val,
+                &len,
+            ))
+        },
+        |builder| {
+            builder.build_phi(
+                TypeId::i64,
+            )
+        },
+        |builder, scope, it| {
+            let value = list_get(list, it, span, builder)?;
+            body(builder, scope, it, value)
+        },
+        |builder, it| {
+            let inc = builder.build_const_i64(1);
+            builder.build_nswadd(
+                it,
+                &inc,
+            )
+        },
+        |builder, next_val| {
+            let len = list_len(list, span, builder)?;
+            Ok(builder.build_int_lt(
+                next_val,
+                &len,
+            ))
+        },
+    )
+}
+
+/// Check if a function is a list operation taking a function as argument
+pub fn is_higher_order(name: &Atom) -> bool {
+    *name == hct_atom!("map") || *name == hct_atom!("filter")
+}
+
+/// Resolve the user function passed by name to a list operation
+fn function_arg<'a>(arg: &Expression, scope: &Scope<'a>, builder: &Builder) -> HatchetResult<Rc<Function>> {
+    if let ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), .. }) = arg.kind {
+        if let Some(func) = scope.function(name) {
+            return Ok(func);
+        }
+    }
+
+    Err(builder.error(arg.span, "expected the name of a function"))
+}
+
+/// Build a call to map or filter, creating a new list from the
+/// results of a user function on each element of a list
+pub fn higher_order_call<'a>(name: &Atom, args: Vec<Expression>, span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    if args.len() != 2 {
+        return Err(builder.error(span, format!("{} expects 2 arguments, found {}", name, args.len())));
+    }
+
+    let mut args = args.into_iter();
+    let list = args.next().unwrap();
+    let func = function_arg(&args.next().unwrap(), scope, builder)?;
+
+    let list_span = list.span;
+    let list = expression(list, scope, builder)?;
+    let elem = match list.ty {
+        TypeId::Array { ref ty, .. } |
+        TypeId::Vec { ref ty } => (**ty).clone(),
+        ref ty => return Err(builder.error(list_span, format!("Tried to iterate on a non-array: {:?}", ty))),
+    };
+
+    let ty = if *name == hct_atom!("map") {
+        return_type(&func, vec![ elem ], span, scope, builder)?
+    } else {
+        elem
+    };
+
+    if !ty.is_vec_element() {
+        return Err(builder.error(span, element_error(&ty, "list")));
+    }
+
+    let res = build_vec(vec![], &ty, span, builder)?;
+    let push = Atom::from(format!("vec_push.{:?}", ty));
+    let is_map = *name == hct_atom!("map");
+
+    for_each(&list, list_span, scope, builder, |builder, scope, _, value| {
+        let ret = call_function(&func, vec![ (value.clone(), list_span) ], span, scope, builder)?;
+        if is_map {
+            call_stl(
+                builder,
+                push,
+                vec![ &res, &ret ],
+                span,
+            )?;
+            return Ok(());
+        }
+
+        if ret.ty != TypeId::bool {
+            return Err(builder.error(span, format!("expected a condition, found {:?}", ret.ty)));
+        }
+
+        let push_block = builder.append_basic_block();
+        let next_block = builder.append_basic_block();
+        builder.build_cond_br(&ret, push_block, next_block);
+
+        builder.position_at_end(push_block);
+        call_stl(
+            builder,
+            push,
+            vec![ &res, &value ],
+            span,
+        )?;
+        builder.build_br(next_block);
+
+        builder.position_at_end(next_block);
+        Ok(())
+    })?;
+
+    Ok(res)
+}
+
+/// Build a call to a list operation, or return None if the
+/// called function is not one for these arguments
+pub fn list_call(name: &Atom, args: &[(ValueRef, Span)], span: Span, builder: &mut Builder) -> HatchetResult<Option<ValueRef>> {
+    let (arity, ext) = match *name {
+        hct_atom!("len") => (1, "vec_len"),
+        hct_atom!("pop") => (1, "vec_pop"),
+        hct_atom!("sort") => (1, "vec_sort"),
+        hct_atom!("choose") => (1, "vec_choose"),
+        hct_atom!("shuffle") => (1, "vec_shuffle"),
+        hct_atom!("push") => (2, "vec_push"),
+        hct_atom!("contains") => (2, "vec_contains"),
+        hct_atom!("choose_weighted") => (2, "vec_get"),
+        // Strings are concatenated by the STL
+        hct_atom!("concat") => match args.first() {
+            Some(&(ValueRef { ty: TypeId::Vec { .. }, .. }, _)) => (2, "vec_concat"),
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    if args.len() != arity {
+        return Err(builder.error(span, format!("{} expects {} arguments, found {}", name, arity, args.len())));
+    }
+
+    let (ref list, list_span) = args[0];
+    if *name == hct_atom!("len") {
+        return list_len(list, list_span, builder).map(Some);
+    }
+
+    let ty = match list.ty {
+        TypeId::Vec { ref ty } => (**ty).clone(),
+        TypeId::Array { ref ty, .. } => return Err(builder.error(list_span, format!("{} expects a growable list, found a fixed-size array: {}", name, element_error(ty, "list")))),
+        ref ty => return Err(builder.error(list_span, format!("expected a list, found {:?}", ty))),
+    };
+
+    // The index of the chosen element is drawn from the weights, then read from the list
+    if *name == hct_atom!("choose_weighted") {
+        let (ref weights, weights_span) = args[1];
+        let index = match weights.ty {
+            TypeId::Vec { ty: ref weight } if **weight == TypeId::f64 || **weight == TypeId::i64 => {
+                let len = list_len(list, list_span, builder)?;
+                call_stl(
+                    builder,
+                    Atom::from(format!("weighted_index.{:?}", weight)),
+                    vec![ weights, &len ],
+                    span,
+                )?
+            },
+            ref ty => return Err(builder.error(weights_span, format!("expected a list of numbers, found {:?}", ty))),
+        };
+
+        return call_stl(
+            builder,
+            Atom::from(format!("{}.{:?}", ext, ty)),
+            vec![ list, &index ],
+            span,
+        ).map(Some);
+    }
+
+    if let Some(&(ref value, span)) = args.get(1) {
+        let expected = if *name == hct_atom!("concat") {
+            list.ty.clone()
+        } else {
+            ty.clone()
+        };
+
+        // Integers are implicitly widened to floats
+        if value.ty != expected && !(expected == TypeId::f64 && value.ty == TypeId::i64) {
+            return Err(builder.error(span, format!("expected type {:?}, found {:?}", expected, value.ty)));
+        }
+    }
+
+    call_stl(
+        builder,
+        Atom::from(format!("{}.{:?}", ext, ty)),
+        args.iter().map(|&(ref val, _)| val),
+        span,
+    ).map(Some)
+}
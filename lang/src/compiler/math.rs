@@ -0,0 +1,97 @@
+//! Math functions, implemented by LLVM intrinsics or by the STL
+//! and evaluated at compile time when all their arguments are constant
+
+use atom::*;
+use error::HatchetResult;
+use hct::ast::*;
+use runtime::stl::*;
+
+use super::builder::*;
+use super::function::*;
+use super::types::ValueRef;
+
+/// Evaluate an integer math function at compile time, or
+/// return None if some of its arguments are not constant
+fn fold_int(ext: &Atom, args: &[i64]) -> Option<i64> {
+    match (ext.clone(), args.len()) {
+        (hct_atom!("int_abs"), 1) => Some(args[0].wrapping_abs()),
+        (hct_atom!("int_sign"), 1) => Some(args[0].signum()),
+        (hct_atom!("int_min"), 2) => Some(args[0].min(args[1])),
+        (hct_atom!("int_max"), 2) => Some(args[0].max(args[1])),
+        (hct_atom!("int_clamp"), 3) => Some(args[0].max(args[1]).min(args[2])),
+        // Divisions by zero are left to report their error at runtime
+        (hct_atom!("int_mod"), 2) if args[1] != 0 => Some(modulo(args[0], args[1])),
+        _ => None,
+    }
+}
+
+/// Evaluate a floating point math function at compile time, or
+/// return None if some of its arguments are not constant
+fn fold_f64(ext: &Atom, args: &[f64]) -> Option<f64> {
+    match (ext.clone(), args.len()) {
+        (hct_atom!("exp"), 1) => Some(args[0].exp()),
+        (hct_atom!("sqrt"), 1) => Some(args[0].sqrt()),
+        (hct_atom!("sin"), 1) => Some(args[0].sin()),
+        (hct_atom!("cos"), 1) => Some(args[0].cos()),
+        (hct_atom!("tan"), 1) => Some(args[0].tan()),
+        (hct_atom!("asin"), 1) => Some(args[0].asin()),
+        (hct_atom!("acos"), 1) => Some(args[0].acos()),
+        (hct_atom!("floor"), 1) => Some(args[0].floor()),
+        (hct_atom!("ceil"), 1) => Some(args[0].ceil()),
+        (hct_atom!("round"), 1) => Some(args[0].round()),
+        (hct_atom!("abs"), 1) => Some(args[0].abs()),
+        (hct_atom!("log"), 1) => Some(args[0].ln()),
+        (hct_atom!("sign"), 1) => Some(sign(args[0])),
+        (hct_atom!("deg"), 1) => Some(args[0].to_degrees()),
+        (hct_atom!("rad"), 1) => Some(args[0].to_radians()),
+        (hct_atom!("pow"), 2) => Some(args[0].powf(args[1])),
+        (hct_atom!("atan2"), 2) => Some(args[0].atan2(args[1])),
+        (hct_atom!("min"), 2) => Some(args[0].min(args[1])),
+        (hct_atom!("max"), 2) => Some(args[0].max(args[1])),
+        (hct_atom!("clamp"), 3) => Some(args[0].max(args[1]).min(args[2])),
+        (hct_atom!("lerp"), 3) => Some(args[0] + (args[1] - args[0]) * args[2]),
+        _ => None,
+    }
+}
+
+/// Evaluate a math function at compile time, or return
+/// None if some of its arguments are not constant
+fn fold(ext: &Atom, args: &[(ValueRef, Span)], builder: &mut Builder) -> Option<ValueRef> {
+    let ints: Option<Vec<_>> = args.iter().map(|&(ref val, _)| builder.get_const_i64(val)).collect();
+    if let Some(ints) = ints {
+        if let Some(res) = fold_int(ext, &ints) {
+            return Some(builder.build_const_i64(res));
+        }
+    }
+
+    // Integer constants are widened like the arguments of the call
+    let floats: Option<Vec<_>> = {
+        args.iter()
+            .map(|&(ref val, _)| match builder.get_const_i64(val) {
+                Some(val) => Some(val as f64),
+                None => builder.get_const_f64(val),
+            })
+            .collect()
+    };
+
+    match floats {
+        Some(floats) => fold_f64(ext, &floats).map(|res| builder.build_const_f64(res)),
+        None => None,
+    }
+}
+
+/// Build a call to a math function, or return None if the
+/// called function is not one for these arguments
+pub fn math_call(name: &Atom, args: &[(ValueRef, Span)], span: Span, builder: &mut Builder) -> HatchetResult<Option<ValueRef>> {
+    let ext = match math_function(name, args.iter().map(|&(ref val, _)| &val.ty)) {
+        Some(ext) => ext,
+        None => return Ok(None),
+    };
+
+    check_args(builder, &ext, args, span)?;
+    if let Some(res) = fold(&ext, args, builder) {
+        return Ok(Some(res));
+    }
+
+    call_stl(builder, ext, args.iter().map(|&(ref val, _)| val), span).map(Some)
+}
@@ -1,24 +1,24 @@
-use std::collections::VecDeque;
 use std::borrow::Borrow;
 
-use hct::ast::Path;
+use error::HatchetResult;
+use hct::ast::{Path, PathKind};
 use super::builder::*;
 use super::function::*;
 use super::scope::Scope;
 use super::types::*;
 use atom::*;
 
-pub fn resolve_path<'a, P: Borrow<Path>>(path: P, scope: &Scope<'a>, builder: &mut Builder) -> ValueRef {
+pub fn resolve_path<'a, P: Borrow<Path>>(path: P, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
     let path = path.borrow();
-    match *path {
-        Path::Deref(ref obj, ref prop) => {
-            let res = resolve_path(obj.borrow(), scope, builder);
+    match path.kind {
+        PathKind::Deref(ref obj, ref prop) => {
+            let res = resolve_path(obj.borrow(), scope, builder)?;
             if res.ty == TypeId::Entity {
-                res
+                Ok(res)
             } else if let TypeId::Object { ref items } = res.ty {
-                let idx = {
-                    items.binary_search_by(|&(ref item, _)| item.cmp(prop))
-                        .expect(&format!("key \"{}\" not found in object", prop))
+                let idx = match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+                    Ok(idx) => idx,
+                    Err(_) => return Err(builder.error(path.span, format!("key \"{}\" not found in object", prop))),
                 };
 
                 let (_, ref ty) = items[idx];
@@ -33,19 +33,19 @@ pub fn resolve_path<'a, P: Borrow<Path>>(path: P, scope: &Scope<'a>, builder: &m
                 );
 
                 let res = builder.build_load(&gep);
-                ValueRef { ty: ty.clone(), ptr: res.ptr }
+                Ok(ValueRef { ty: ty.clone(), ptr: res.ptr })
             } else {
-                panic!("trying to deref a non-map value {}", *path)
+                Err(builder.error(path.span, format!("trying to deref a non-map value {}", *path)))
             }
         },
-        Path::Instance(ref obj) => resolve_path(obj.borrow(), scope, builder),
-        Path::Binding(ref name) => {
+        PathKind::Instance(ref obj) => resolve_path(obj.borrow(), scope, builder),
+        PathKind::Binding(ref name) => {
             match scope.binding(builder, name) {
-                Some(val) => val,
+                Some(val) => Ok(val),
                 None => if name.starts_with('@') {
-                    builder.build_const_entity(name)
+                    Ok(builder.build_const_entity(name))
                 } else {
-                    panic!("entity {} not found", *path)
+                    Err(builder.error(path.span, format!("entity {} not found", *path)))
                 }
             }
         },
@@ -54,36 +54,39 @@ pub fn resolve_path<'a, P: Borrow<Path>>(path: P, scope: &Scope<'a>, builder: &m
 
 type Trigger<'a> = (Option<ValueRef>, Option<ValueRef>, Option<Atom>);
 
-fn break_trigger<'a>(path: Path, scope: &Scope<'a>, builder: &mut Builder) -> Trigger<'a> {
-    match path {
-        Path::Deref(obj, prop) => match break_trigger(*obj, scope, builder) {
-            (a, Some(b), None) => (a, Some(b), Some(prop)),
-            (a, None, None) => (a, Some(builder.build_const_entity(prop)), None),
-            path => panic!("invalid path {:?}", path),
+fn break_trigger<'a>(path: Path, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<Trigger<'a>> {
+    let Path { kind, span } = path;
+    match kind {
+        PathKind::Deref(obj, prop) => match break_trigger(*obj, scope, builder)? {
+            (a, Some(b), None) => Ok((a, Some(b), Some(prop))),
+            (a, None, None) => Ok((a, Some(builder.build_const_entity(prop)), None)),
+            _ => Err(builder.error(span, "invalid event path")),
         },
-        Path::Instance(pat) => (Some(resolve_path(pat, scope, builder)), None, None),
-        Path::Binding(_) => (None, Some(resolve_path(&path, scope, builder)), None),
+        PathKind::Instance(pat) => Ok((Some(resolve_path(pat, scope, builder)?), None, None)),
+        kind @ PathKind::Binding(_) => Ok((None, Some(resolve_path(Path { kind, span }, scope, builder)?), None)),
     }
 }
 
 /// Get the entity referenced by a path and its associated event string
 /// If no event is explicitly called, "Trigger" will be used
-pub fn event<'a>(path: Path, scope: &Scope<'a>, builder: &mut Builder) -> (ValueRef, ValueRef) {
-    match break_trigger(path, scope, builder) {
+pub fn event<'a>(path: Path, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<(ValueRef, ValueRef)> {
+    let span = path.span;
+    match break_trigger(path, scope, builder)? {
         (Some(inst), Some(ent), Some(method)) => {
             let method = builder.build_const_atom(method);
             let method = call_stl(
                 builder,
                 hct_atom!("get_instance"),
                 vec![ &ent, &method ],
-            );
-            (inst, method)
+                span,
+            )?;
+            Ok((inst, method))
         },
 
-        (None, Some(ent), Some(method)) => (
+        (None, Some(ent), Some(method)) => Ok((
             ent,
             builder.build_const_atom(method),
-        ),
+        )),
 
         (Some(inst), Some(ent), None) => {
             let method = builder.build_const_atom(hct_atom!("Trigger"));
@@ -91,32 +94,16 @@ pub fn event<'a>(path: Path, scope: &Scope<'a>, builder: &mut Builder) -> (Value
                 builder,
                 hct_atom!("get_instance"),
                 vec![ &ent, &method ],
-            );
-            (inst, method)
+                span,
+            )?;
+            Ok((inst, method))
         },
 
-        (None, Some(ent), None) => (
+        (None, Some(ent), None) => Ok((
             ent,
             builder.build_const_atom(hct_atom!("Trigger")),
-        ),
+        )),
 
-        path => panic!("invalid path {:?}", path),
-    }
-}
-
-/// Recursively transforms a Path to a list
-pub fn unwind_path(path: Path) -> VecDeque<Atom> {
-    match path {
-        Path::Deref(obj, prop) => {
-            let mut res = unwind_path(*obj);
-            res.push_back(prop);
-            res
-        },
-        Path::Binding(name) => {
-            let mut res = VecDeque::new();
-            res.push_back(name);
-            res
-        },
-        Path::Instance(_) => unimplemented!(),
+        _ => Err(builder.error(span, "invalid event path")),
     }
 }
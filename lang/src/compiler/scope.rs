@@ -1,16 +1,21 @@
 use std::collections::HashMap;
+use std::rc::Rc;
 
 use super::builder::*;
 use super::types::*;
 use atom::*;
+use hct::ast::Function;
 
 /// Represent a scope (group of bindings in a block) in a script
 pub struct Scope<'a> {
     parent: Option<&'a Scope<'a>>,
     bindings: HashMap<Atom, (bool, ValueRef)>,
+    functions: HashMap<Atom, Rc<Function>>,
 
     event: Option<(ValueRef, ValueRef)>,
     delay: Option<ValueRef>,
+    /// Blocks targeted by break and continue in the innermost loop
+    loop_: Option<(Block, Block)>,
 }
 
 impl<'a> Scope<'a> {
@@ -28,8 +33,11 @@ impl<'a> Scope<'a> {
                     .collect()
             },
 
+            functions: Default::default(),
+
             event: None,
             delay: None,
+            loop_: None,
         }
     }
 
@@ -39,8 +47,11 @@ impl<'a> Scope<'a> {
             parent: Some(self),
             bindings: Default::default(),
 
+            functions: Default::default(),
+
             event: None,
             delay: None,
+            loop_: None,
         }
     }
 
@@ -50,19 +61,21 @@ impl<'a> Scope<'a> {
         (false, ptr)
     }
 
-    /// Creates a new scope with an additional value binding
-    pub fn with_binding(&'a self, builder: &mut Builder, key: Atom, value: &ValueRef) -> Scope<'a> {
+    /// Creates a new scope with several additional value bindings
+    pub fn with_bindings(&'a self, builder: &mut Builder, values: Vec<(Atom, ValueRef)>) -> Scope<'a> {
         Scope {
             parent: Some(self),
             bindings: {
-                let mut res = HashMap::new();
-                let value = Scope::store_binding(builder, value);
-                res.insert(key, value);
-                res
+                values.into_iter()
+                    .map(|(key, value)| (key, Scope::store_binding(builder, &value)))
+                    .collect()
             },
 
+            functions: Default::default(),
+
             event: None,
             delay: None,
+            loop_: None,
         }
     }
 
@@ -72,6 +85,57 @@ impl<'a> Scope<'a> {
         self.bindings.insert(key, value);
     }
 
+    /// Mutate this scope to add a new constant binding
+    pub fn set_const(&mut self, key: Atom, value: ValueRef) {
+        self.bindings.insert(key, (true, value));
+    }
+
+    /// Mutate this scope to declare a user function
+    pub fn set_function(&mut self, key: Atom, func: Rc<Function>) {
+        self.functions.insert(key, func);
+    }
+
+    /// Get the declaration of a user function
+    pub fn function(&self, key: &Atom) -> Option<Rc<Function>> {
+        self.functions.get(key).cloned()
+            .or_else(|| {
+                self.parent.and_then(|parent| parent.function(key))
+            })
+    }
+
+    /// Creates the scope of a user function body, which only sees the
+    /// constant bindings and the functions visible from its call site
+    pub fn function_body(&self, event: Option<(ValueRef, ValueRef)>, delay: Option<ValueRef>) -> Scope<'static> {
+        let mut bindings = HashMap::new();
+        let mut functions = HashMap::new();
+
+        let mut current = Some(self);
+        while let Some(scope) = current {
+            for (key, value) in &scope.bindings {
+                bindings.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            for (key, func) in &scope.functions {
+                functions.entry(key.clone()).or_insert_with(|| func.clone());
+            }
+
+            current = scope.parent;
+        }
+
+        Scope {
+            parent: None,
+            bindings: {
+                bindings.into_iter()
+                    .filter(|&(_, (is_const, _))| is_const)
+                    .collect()
+            },
+            functions,
+
+            event,
+            delay,
+            loop_: None,
+        }
+    }
+
     fn binding_ptr(&self, key: &Atom) -> Option<(bool, ValueRef)> {
         self.bindings.get(key).cloned()
             .or_else(|| {
@@ -117,17 +181,35 @@ impl<'a> Scope<'a> {
         })
     }
 
+    /// Get the blocks to branch to on a break and a continue
+    /// in the innermost loop containing this scope
+    pub fn loop_blocks(&self) -> Option<(Block, Block)> {
+        self.loop_.or_else(|| {
+            self.parent.and_then(|parent| parent.loop_blocks())
+        })
+    }
+
+    /// Creates a new scope for the body of a loop
+    pub fn with_loop(&'a self, break_block: Block, continue_block: Block) -> Scope<'a> {
+        Scope {
+            loop_: Some((break_block, continue_block)),
+            .. self.fork()
+        }
+    }
+
     /// Creates a new scope for the execution of an auto block
     pub fn auto(&'a self, builder: &mut Builder) -> Scope<'a> {
         Scope {
             parent: Some(self),
             bindings: Default::default(),
+            functions: Default::default(),
 
             event: Some((
                 builder.build_const_entity(hct_atom!("")),
                 builder.build_const_atom(hct_atom!("OnMapSpawn")),
             )),
             delay: None,
+            loop_: None,
         }
     }
 
@@ -136,12 +218,14 @@ impl<'a> Scope<'a> {
         Scope {
             parent: Some(self),
             bindings: Default::default(),
+            functions: Default::default(),
 
             event: Some((
                 builder.build_const_entity(ent),
                 builder.build_const_atom(hct_atom!("OnTrigger")),
             )),
             delay: None,
+            loop_: None,
         }
     }
 
@@ -158,9 +242,11 @@ impl<'a> Scope<'a> {
         Scope {
             parent: Some(self),
             bindings: Default::default(),
+            functions: Default::default(),
 
             event: Some((entity, method)),
             delay: None,
+            loop_: None,
         }
     }
 
@@ -171,6 +257,7 @@ impl<'a> Scope<'a> {
         Scope {
             parent: Some(self),
             bindings: Default::default(),
+            functions: Default::default(),
 
             event: None,
             delay: Some(
@@ -180,6 +267,7 @@ impl<'a> Scope<'a> {
                         |val| builder.build_fadd(&val, &time),
                     )
             ),
+            loop_: None,
         }
     }
 }
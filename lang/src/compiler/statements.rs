@@ -1,27 +1,45 @@
 //! Transform a block (scoped list of statements) to LLVM IR
 
+use std::iter::once;
+use std::rc::Rc;
+
 use atom::*;
+use error::HatchetResult;
 use hct::ast::*;
 use vmf::ir::Entity;
 
 use super::builder::*;
+use super::check::vector_property;
+use super::dict::*;
 use super::expression::*;
 use super::function::*;
+use super::list::*;
 use super::path::*;
 use super::scope::*;
 use super::types::*;
 
-fn loop_<EC, PB, LC, R>(builder: &mut Builder, entry_cond: EC, print_body: PB, loop_cond: LC)
-    where EC: FnOnce(&mut Builder) -> ValueRef,
-          PB: FnOnce(&mut Builder) -> R, LC: FnOnce(&mut Builder, &R) -> ValueRef {
+/// Build a loop, returning the result of the body along with the blocks
+/// branching to the loop from the entry and from the end of the body
+/// Those may differ from the original blocks, as calls to external
+/// functions split the current block to check for runtime errors
+/// The body receives the blocks to branch to on a break and a continue
+fn loop_<EC, PB, LC, R>(builder: &mut Builder, entry_cond: EC, print_body: PB, loop_cond: LC) -> HatchetResult<(R, Block, Block)>
+    where EC: FnOnce(&mut Builder) -> HatchetResult<ValueRef>,
+          PB: FnOnce(&mut Builder, Block, Block) -> HatchetResult<R>,
+          LC: FnOnce(&mut Builder, &R) -> HatchetResult<ValueRef> {
     let entry_block = builder.get_insert_block();
 
     let loop_block = builder.append_basic_block();
+    let next_block = builder.append_basic_block();
+    let end_block = builder.append_basic_block();
+
     builder.position_at_end(loop_block);
-    let r = print_body(builder);
+    let r = print_body(builder, end_block, next_block)?;
+    builder.build_br(next_block);
 
-    let end_block = builder.append_basic_block();
-    let loop_cond = loop_cond(builder, &r);
+    builder.position_at_end(next_block);
+    let loop_cond = loop_cond(builder, &r)?;
+    let latch_block = builder.get_insert_block();
     builder.build_cond_br(
         &loop_cond,
         loop_block,
@@ -29,7 +47,8 @@ fn loop_<EC, PB, LC, R>(builder: &mut Builder, entry_cond: EC, print_body: PB, l
     );
 
     builder.position_at_end(entry_block);
-    let entry_cond = entry_cond(builder);
+    let entry_cond = entry_cond(builder)?;
+    let entry_block = builder.get_insert_block();
     builder.build_cond_br(
         &entry_cond,
         loop_block,
@@ -37,49 +56,558 @@ fn loop_<EC, PB, LC, R>(builder: &mut Builder, entry_cond: EC, print_body: PB, l
     );
 
     builder.position_at_end(end_block);
+    Ok((r, entry_block, latch_block))
 }
 
-#[cfg_attr(feature="clippy", allow(too_many_arguments))]
-fn iterator<EC, BP, PB, NV, LC>(
+#[cfg_attr(feature="cargo-clippy", allow(too_many_arguments))]
+pub fn iterator<EC, BP, PB, NV, LC>(
     builder: &mut Builder, scope: &Scope,
     start_val: ValueRef,
-    entry_cmp: EC, build_phi: BP, print_body: PB, next_val: NV, loop_cmp: LC)
-    where EC: FnOnce(&mut Builder) -> ValueRef,
+    entry_cmp: EC, build_phi: BP, print_body: PB, next_val: NV, loop_cmp: LC) -> HatchetResult<()>
+    where EC: FnOnce(&mut Builder) -> HatchetResult<ValueRef>,
           BP: FnOnce(&mut Builder) -> ValueRef,
-          PB: FnOnce(&mut Builder, &Scope, &ValueRef),
+          PB: FnOnce(&mut Builder, &Scope, &ValueRef) -> HatchetResult<()>,
           NV: FnOnce(&mut Builder, &ValueRef) -> ValueRef,
-          LC: FnOnce(&mut Builder, &ValueRef) -> ValueRef {
+          LC: FnOnce(&mut Builder, &ValueRef) -> HatchetResult<ValueRef> {
 
-    let prev_block = builder.get_insert_block();
-    loop_(
+    // The next value is computed in the continue block, after the body
+    let mut next = None;
+    let (it, entry_block, latch_block) = loop_(
         builder,
         entry_cmp,
-        |builder| {
+        |builder, break_block, continue_block| {
             let it = build_phi(builder);
-            builder.add_incoming(&it, start_val, prev_block);
+            print_body(builder, &scope.with_loop(break_block, continue_block), &it)?;
+            Ok(it)
+        },
+        |builder, it| {
+            let next_val = next_val(builder, it);
+            let cond = loop_cmp(builder, &next_val)?;
+            next = Some(next_val);
+            Ok(cond)
+        },
+    )?;
 
-            print_body(builder, scope, &it);
+    builder.add_incoming(&it, start_val, entry_block);
+    builder.add_incoming(&it, next.unwrap(), latch_block);
+    Ok(())
+}
+
+/// Store a value through a pointer, widening it to the pointed type
+fn store(value: ValueRef, ptr: &ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<()> {
+    let value = widen(value, &ptr.ty, builder);
+    if value.ty != ptr.ty {
+        return Err(builder.error(span, format!("expected type {:?}, found {:?}", ptr.ty, value.ty)));
+    }
 
-            let body_block = builder.get_insert_block();
+    builder.build_store(&value, ptr);
+    Ok(())
+}
 
-            let next_val = next_val(builder, &it);
-            builder.add_incoming(&it, next_val.clone(), body_block);
+/// Convert a value assigned to an entity property to a string
+fn property_value(value: ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    if value.ty == TypeId::String {
+        Ok(value)
+    } else if value.ty == TypeId::f64 || value.ty == TypeId::i64 {
+        let value = widen(value, &TypeId::f64, builder);
+        call_stl(
+            builder,
+            hct_atom!("to_string"),
+            vec![ &value ],
+            span,
+        )
+    } else if value.ty.is_vector() {
+        call_stl(
+            builder,
+            Atom::from(format!("vector_to_string.{:?}", value.ty)),
+            vec![ &value ],
+            span,
+        )
+    } else {
+        Err(builder.error(span, format!("cannot assign a {:?} to a property", value.ty)))
+    }
+}
 
-            next_val
+/// Compute the value stored by an assignment, applying the
+/// operator of a compound assignment to the current value
+fn compound<F>(op: Option<Operator>, value: ValueRef, span: Span, builder: &mut Builder, current: F) -> HatchetResult<ValueRef>
+    where F: FnOnce(&mut Builder) -> HatchetResult<ValueRef> {
+    match op {
+        Some(op) => {
+            let current = current(builder)?;
+            binary(current, op, value, span, builder)
         },
-        loop_cmp,
-    );
+        None => Ok(value),
+    }
+}
+
+/// Compute the value stored in an entity property by an assignment, the
+/// current value being parsed as a vector or a number unless a string
+/// is appended to it
+fn property_compound<F>(op: Option<Operator>, prop: &Atom, value: ValueRef, span: Span, builder: &mut Builder, current: F) -> HatchetResult<ValueRef>
+    where F: FnOnce(&mut Builder) -> HatchetResult<ValueRef> {
+    let parse = if value.ty == TypeId::String {
+        None
+    } else {
+        match vector_property(prop) {
+            Some(ty) => Some(Atom::from(format!("parse_vector.{:?}", ty))),
+            None => Some(hct_atom!("parse")),
+        }
+    };
+
+    compound(op, value, span, builder, |builder| {
+        let current = current(builder)?;
+        match parse {
+            Some(parse) => call_stl(
+                builder,
+                parse,
+                vec![ &current ],
+                span,
+            ),
+            None => Ok(current),
+        }
+    })
+}
+
+/// Build the assignment of a value to a binding, an element
+/// of a list, a field of an object or an entity property,
+/// reading and writing the target once for compound assignments
+fn assignment<'a>(target: Expression, op: Option<Operator>, value: ValueRef, value_span: Span, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<()> {
+    let Expression { kind, span } = target;
+    let target = match kind {
+        ExpressionKind::Reference(Path { kind: PathKind::Binding(name), span }) => {
+            let ptr = match scope.binding_mut(&name) {
+                Some(ptr) => ptr,
+                None => return Err(builder.error(span, format!("binding \"{}\" not found", name))),
+            };
+
+            let value = compound(op, value, span, builder, |builder| Ok(builder.build_load(&ptr)))?;
+            return store(value, &ptr, value_span, builder);
+        },
+
+        ExpressionKind::Index { box expr, box index } => {
+            let list_span = expr.span;
+            let list = expression(expr, scope, builder)?;
+            let index = index_value(index, scope, builder)?;
+
+            match list.ty.clone() {
+                TypeId::Array { len, ty } => {
+                    let ptr = array_element(&list, &index, len, *ty, span, builder)?;
+                    let value = compound(op, value, span, builder, |builder| Ok(builder.build_load(&ptr)))?;
+                    store(value, &ptr, value_span, builder)?;
+                },
+                TypeId::Vec { ty } => {
+                    let value = compound(op, value, span, builder, |builder| call_stl(
+                        builder,
+                        Atom::from(format!("vec_get.{:?}", ty)),
+                        vec![ &list, &index ],
+                        span,
+                    ))?;
+
+                    let value = widen(value, &ty, builder);
+                    if value.ty != *ty {
+                        return Err(builder.error(value_span, format!("expected type {:?}, found {:?}", ty, value.ty)));
+                    }
+
+                    call_stl(
+                        builder,
+                        Atom::from(format!("vec_set.{:?}", ty)),
+                        vec![ &list, &index, &value ],
+                        span,
+                    )?;
+                },
+                ty => return Err(builder.error(list_span, format!("cannot index a {:?}", ty))),
+            }
+
+            return Ok(());
+        },
+
+        kind => Expression { kind, span },
+    };
+
+    let (obj, prop) = match target.split_field() {
+        Ok(field) => field,
+        Err(target) => return Err(builder.error(target.span, "cannot assign to this expression")),
+    };
+
+    // Sub-properties are set on the entity holding the property
+    let obj_span = obj.span;
+    let obj = match obj.split_field() {
+        Ok((base, key)) => {
+            let base = expression(base, scope, builder)?;
+            if base.ty == TypeId::Entity {
+                let key = builder.build_const_atom(key);
+                let sub = sub_property_index(&prop, span, builder)?;
+                let value = property_compound(op, &prop, value, span, builder, |builder| {
+                    let current = call_stl(
+                        builder,
+                        hct_atom!("get_property"),
+                        vec![ &base, &key ],
+                        span,
+                    )?;
+
+                    call_stl(
+                        builder,
+                        hct_atom!("get_sub_property"),
+                        vec![ &current, &sub ],
+                        span,
+                    )
+                })?;
+
+                let value = property_value(value, value_span, builder)?;
+                call_stl(
+                    builder,
+                    hct_atom!("set_sub_property"),
+                    vec![ &base, &key, &sub, &value ],
+                    span,
+                )?;
+                return Ok(());
+            }
+
+            field(base, key, obj_span, builder)?
+        },
+        Err(obj) => expression(obj, scope, builder)?,
+    };
+
+    match obj.ty.clone() {
+        TypeId::Object { .. } => {
+            let ptr = object_field(&obj, &prop, span, builder)?;
+            let value = compound(op, value, span, builder, |builder| Ok(builder.build_load(&ptr)))?;
+            store(value, &ptr, value_span, builder)
+        },
+        TypeId::Entity => {
+            let key = builder.build_const_atom(prop.clone());
+            let value = property_compound(op, &prop, value, span, builder, |builder| call_stl(
+                builder,
+                hct_atom!("get_property"),
+                vec![ &obj, &key ],
+                span,
+            ))?;
+
+            let value = property_value(value, value_span, builder)?;
+            call_stl(
+                builder,
+                hct_atom!("set_property"),
+                vec![ &obj, &key, &value ],
+                span,
+            )?;
+            Ok(())
+        },
+        ty => Err(builder.error(span, format!("cannot assign to a field of a {:?}", ty))),
+    }
+}
+
+/// Get the values bound to the names of a pattern
+fn destructure(pattern: Pattern, value: ValueRef, span: Span, builder: &mut Builder) -> HatchetResult<Vec<(Atom, ValueRef)>> {
+    match pattern {
+        Pattern::Name(name) => Ok(vec![ (name, value) ]),
+        Pattern::Object(fields) => {
+            fields.into_iter()
+                .map(|(key, name)| Ok((name, field(value.clone(), key, span, builder)?)))
+                .collect()
+        },
+        Pattern::Array(names) => {
+            match value.ty {
+                TypeId::Array { len, .. } => if names.len() > len as usize {
+                    return Err(builder.error(span, format!("cannot destructure {} elements from an array of length {}", names.len(), len)));
+                },
+                // The length of vectors is checked by vec_get
+                TypeId::Vec { .. } => {},
+                ref ty => return Err(builder.error(span, format!("cannot destructure a {:?} as a list", ty))),
+            }
+
+            names.into_iter()
+                .enumerate()
+                .map(|(i, name)| {
+                    let index = builder.build_const_i64(i as i64);
+                    Ok((name, list_get(&value, &index, span, builder)?))
+                })
+                .collect()
+        },
+    }
+}
+
+/// Build a loop over a range of numbers, going from the start towards the end
+/// Ranges of integers are iterated with integers, and with floats otherwise
+fn range_loop<'a>(var: Pattern, range: Range, span: Span, body: Vec<Statement>, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<()> {
+    let Range { start, end, step, inclusive } = range;
+
+    let bounds: Vec<_> = {
+        Some(start).into_iter().chain(Some(end)).chain(step)
+            .map(|exp| {
+                let span = exp.span;
+                let val = expression(exp, scope, builder)?;
+                if val.ty != TypeId::f64 && val.ty != TypeId::i64 {
+                    return Err(builder.error(span, format!("expected type f64, found {:?}", val.ty)));
+                }
+
+                Ok(val)
+            })
+            .collect::<HatchetResult<_>>()?
+    };
+
+    let ty = if bounds.iter().all(|val| val.ty == TypeId::i64) {
+        TypeId::i64
+    } else {
+        TypeId::f64
+    };
+
+    let bounds: Vec<_> = {
+        bounds.into_iter()
+            .map(|val| widen(val, &ty, builder))
+            .collect()
+    };
+
+    let is_int = ty == TypeId::i64;
+    let (zero, one, minus_one) = if is_int {
+        (builder.build_const_i64(0), builder.build_const_i64(1), builder.build_const_i64(-1))
+    } else {
+        (builder.build_const_f64(0.0), builder.build_const_f64(1.0), builder.build_const_f64(-1.0))
+    };
+
+    let start_val = &bounds[0];
+    let end_val = &bounds[1];
+    let step = match bounds.get(2) {
+        Some(step) => {
+            // A zero step would never reach the end of the range
+            let check = widen(step.clone(), &TypeId::f64, builder);
+            call_stl(
+                builder,
+                hct_atom!("range_step"),
+                vec![ &check ],
+                span,
+            )?;
+
+            step.clone()
+        },
+        None => {
+            let is_ascending = if is_int {
+                builder.build_int_sle(start_val, end_val)
+            } else {
+                builder.build_float_le(start_val, end_val)
+            };
+
+            builder.build_select(&is_ascending, &one, &minus_one)
+        },
+    };
+
+    let is_ascending = if is_int {
+        builder.build_int_sgt(&step, &zero)
+    } else {
+        builder.build_float_gt(&step, &zero)
+    };
+
+    // Check if a value has not yet passed the end in the direction of the step
+    let in_range = |builder: &mut Builder, it: &ValueRef| -> HatchetResult<ValueRef> {
+        let (ascending, descending) = match (is_int, inclusive) {
+            (true, false) => (builder.build_int_slt(it, end_val), builder.build_int_sgt(it, end_val)),
+            (true, true) => (builder.build_int_sle(it, end_val), builder.build_int_sge(it, end_val)),
+            (false, false) => (builder.build_float_lt(it, end_val), builder.build_float_gt(it, end_val)),
+            (false, true) => (builder.build_float_le(it, end_val), builder.build_float_ge(it, end_val)),
+        };
+
+        Ok(builder.build_select(&is_ascending, &ascending, &descending))
+    };
+
+    iterator(
+        builder, scope,
+        start_val.clone(),
+        |builder| in_range(builder, start_val),
+        |builder| {
+            builder.build_phi(
+                ty.clone(),
+            )
+        },
+        |builder, scope, it| {
+            let values = destructure(var, it.clone(), span, builder)?;
+            statements(
+                body,
+                scope.with_bindings(builder, values),
+                builder,
+            )
+        },
+        |builder, it| {
+            if is_int {
+                builder.build_nswadd(it, &step)
+            } else {
+                builder.build_fadd(it, &step)
+            }
+        },
+        |builder, next_val| in_range(builder, next_val),
+    )
+}
+
+/// Build a loop running the body for each element of a list or each entry
+/// of a dictionary, also binding the index of the elements of an enumerated list
+fn iterate<'a>(var: Pattern, value: Option<Pattern>, array: Expression, span: Span, body: Vec<Statement>, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<()> {
+    let array = match array {
+        Expression { kind: ExpressionKind::Call(Call { path: Path { kind: PathKind::Binding(hct_atom!("enumerate")), .. }, mut args, span: call_span }), .. } => {
+            if args.len() != 1 {
+                return Err(builder.error(call_span, format!("enumerate expects 1 argument, found {}", args.len())));
+            }
+
+            let list = args.remove(0);
+            let list_span = list.span;
+            let list = expression(list, scope, builder)?;
+            return for_each(&list, list_span, scope, builder, |builder, scope, index, elem| {
+                let mut values = destructure(var, index.clone(), span, builder)?;
+                if let Some(value) = value {
+                    values.extend(destructure(value, elem, span, builder)?);
+                }
+
+                statements(
+                    body,
+                    scope.with_bindings(builder, values),
+                    builder,
+                )
+            });
+        },
+        array => array,
+    };
+
+    let array_span = array.span;
+    let array = expression(array, scope, builder)?;
+    match value {
+        Some(value_var) => {
+            dict_for_each(&array, array_span, scope, builder, |builder, scope, key, value| {
+                let mut values = destructure(var, key, span, builder)?;
+                values.extend(destructure(value_var, value, span, builder)?);
+                statements(
+                    body,
+                    scope.with_bindings(builder, values),
+                    builder,
+                )
+            })
+        },
+
+        // Dictionaries are iterated on their keys
+        None => if let TypeId::HashMap { .. } = array.ty {
+            dict_for_each(&array, array_span, scope, builder, |builder, scope, key, _| {
+                let values = destructure(var, key, span, builder)?;
+                statements(
+                    body,
+                    scope.with_bindings(builder, values),
+                    builder,
+                )
+            })
+        } else {
+            for_each(&array, array_span, scope, builder, |builder, scope, _, value| {
+                let values = destructure(var, value, span, builder)?;
+                statements(
+                    body,
+                    scope.with_bindings(builder, values),
+                    builder,
+                )
+            })
+        },
+    }
+}
+
+/// Compute the value of a binding with a type annotation
+fn annotated_value<'a>(value: Expression, ty: &TypeId, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<ValueRef> {
+    // Empty lists take their element type from the annotation
+    if let TypeId::Vec { ty: ref elem } = *ty {
+        let is_empty = match value.kind {
+            ExpressionKind::Array(ref values) => values.is_empty(),
+            _ => false,
+        };
+
+        if is_empty {
+            return build_vec(vec![], elem, value.span, builder);
+        }
+    }
+
+    // Map literals are converted to the annotated dictionary
+    if let TypeId::HashMap { ref key, value: ref elem } = *ty {
+        if let Expression { kind: ExpressionKind::Map(entries), span } = value {
+            return dict_literal(entries, key, elem, span, scope, builder);
+        }
+    }
+
+    let span = value.span;
+    let value = expression(value, scope, builder)?;
+    let value = widen(value, ty, builder);
+    if value.ty != *ty {
+        return Err(builder.error(span, format!("expected type {:?}, found {:?}", ty, value.ty)));
+    }
+
+    Ok(value)
+}
+
+/// Build a conditional branch, binding a value in the consequent block if any
+fn branch<'a>(cond: &ValueRef, binding: Option<(Atom, ValueRef)>, consequent: Vec<Statement>, alternate: Option<Vec<Statement>>, scope: &Scope<'a>, builder: &mut Builder) -> HatchetResult<()> {
+    let entry_block = builder.get_insert_block();
+
+    let then_block = {
+        let block = builder.append_basic_block();
+        builder.position_at_end(block);
+
+        let mut inner = scope.fork();
+        if let Some((name, value)) = binding {
+            inner.set_binding(builder, name, &value);
+        }
+
+        statements(
+            consequent,
+            inner,
+            builder,
+        )?;
+
+        (block, builder.get_insert_block())
+    };
+
+    let alt_block = match alternate {
+        Some(alternate) => {
+            let block = builder.append_basic_block();
+            builder.position_at_end(block);
+
+            statements(
+                alternate,
+                scope.fork(),
+                builder,
+            )?;
+
+            Some((block, builder.get_insert_block()))
+        },
+        None => None,
+    };
+
+    let next_block = builder.append_basic_block();
+
+    builder.position_at_end(entry_block);
+    if let Some((alt_start, alt_end)) = alt_block {
+        builder.build_cond_br(
+            cond,
+            then_block.0,
+            alt_start,
+        );
+
+        builder.position_at_end(alt_end);
+        builder.build_br(next_block);
+    } else {
+        builder.build_cond_br(
+            cond,
+            then_block.0,
+            next_block,
+        );
+    }
+
+    builder.position_at_end(then_block.1);
+    builder.build_br(next_block);
+
+    builder.position_at_end(next_block);
+    Ok(())
 }
 
 /// Execute a list of script statements in order
-pub fn statements<'a>(list: Vec<Statement>, mut scope: Scope<'a>, builder: &mut Builder) {
+pub fn statements<'a>(list: Vec<Statement>, mut scope: Scope<'a>, builder: &mut Builder) -> HatchetResult<()> {
     // Hoist the entity declarations to the top of the block
     for stmt in &list {
-        match *stmt {
+        match stmt.kind {
             // Register the new Relay entities
-            Statement::Relay { ref name, .. } => {
+            StatementKind::Relay { ref name, .. } => {
                 let value = builder.build_const_entity(name);
-                scope.set_binding(builder, name.clone(), &value);
+                scope.set_const(name.clone(), value);
 
                 builder.add_entity(name.clone(), Entity {
                     classname: hct_atom!("logic_relay"),
@@ -91,370 +619,245 @@ pub fn statements<'a>(list: Vec<Statement>, mut scope: Scope<'a>, builder: &mut
             // Insert the logic_auto entity if needed
             // The empty string can be used as a name without collision risk,
             // as empty buildernames are filtered out when the AST is built
-            Statement::Auto { .. } => {
+            StatementKind::Auto { .. } => {
                 builder.add_auto_entity();
             },
 
+            // Functions are built on their first call, for the types of its arguments
+            StatementKind::Function(ref func) => {
+                scope.set_function(func.name.clone(), Rc::new(func.clone()));
+            },
+
             _ => {},
         }
     }
 
-    for stmt in list {
-        match stmt {
-            Statement::Binding { name, value } => {
-                let value = expression(value, &scope, builder);
-                scope.set_binding(builder, name, &value);
+    for Statement { kind, span } in list {
+        match kind {
+            StatementKind::Binding { pattern, ty, value } => {
+                let value = match ty {
+                    Some(ty) => {
+                        let ty = match TypeId::from_annotation(&ty) {
+                            Some(ty) => ty,
+                            None => return Err(builder.error(span, format!("unknown type {}", ty))),
+                        };
+
+                        annotated_value(value, &ty, &scope, builder)?
+                    },
+                    None => expression(value, &scope, builder)?,
+                };
+
+                for (name, value) in destructure(pattern, value, span, builder)? {
+                    scope.set_binding(builder, name, &value);
+                }
             },
 
-            Statement::Relay { name, body } => {
+            StatementKind::Relay { name, body } => {
                 let scope = scope.relay(builder, &name);
-                statements(body, scope, builder);
+                statements(body, scope, builder)?;
             },
 
-            Statement::Auto { body } => {
-                statements(body, scope.auto(builder), builder);
+            StatementKind::Auto { body } => {
+                statements(body, scope.auto(builder), builder)?;
             },
 
-            Statement::Subscriber { path, body } => {
-                let (entity, method) = event(path, &scope, builder);
-                statements(body, scope.subscriber(entity, method), builder);
+            StatementKind::Subscriber { path, body } => {
+                let (entity, method) = event(path, &scope, builder)?;
+                statements(body, scope.subscriber(entity, method), builder)?;
             },
 
-            Statement::Delay { body, time } => {
-                let time = expression(time, &scope, builder);
+            StatementKind::Delay { body, time } => {
+                let time_span = time.span;
+                let time = expression(time, &scope, builder)?;
+                let time = widen(time, &TypeId::f64, builder);
+                if time.ty != TypeId::f64 {
+                    return Err(builder.error(time_span, format!("Delay is not a number: found {:?}", time.ty)));
+                }
+
                 let scope = scope.with_delay(builder, time);
-                statements(body, scope, builder);
+                statements(body, scope, builder)?;
             },
 
-            Statement::Loop { condition, body } => {
+            StatementKind::Loop { condition, body } => {
                 let entry_cond = condition.clone();
                 loop_(
                     builder,
                     |builder| {
-                        let entry_cond = expression(entry_cond, &scope, builder);
-                        assert_eq!(entry_cond.ty, TypeId::bool);
-                        entry_cond
+                        let cond_span = entry_cond.span;
+                        let entry_cond = expression(entry_cond, &scope, builder)?;
+                        if entry_cond.ty != TypeId::bool {
+                            return Err(builder.error(cond_span, format!("expected a condition, found {:?}", entry_cond.ty)));
+                        }
+
+                        Ok(entry_cond)
                     },
-                    |builder| {
+                    |builder, break_block, continue_block| {
                         statements(
                             body,
-                            scope.fork(),
+                            scope.with_loop(break_block, continue_block),
                             builder,
-                        );
+                        )
                     },
                     |builder, _| {
-                        let loop_cond = expression(condition, &scope, builder);
-                        assert_eq!(loop_cond.ty, TypeId::bool);
-                        loop_cond
+                        let cond_span = condition.span;
+                        let loop_cond = expression(condition, &scope, builder)?;
+                        if loop_cond.ty != TypeId::bool {
+                            return Err(builder.error(cond_span, format!("expected a condition, found {:?}", loop_cond.ty)));
+                        }
+
+                        Ok(loop_cond)
                     },
-                );
+                )?;
             },
 
-            Statement::Iterator { var, array: Expression::Call(Call { path: Path::Binding(hct_atom!("range")), args }), body } => {
-                let args: Vec<_> = {
-                    args.into_iter()
-                        .map(|arg| expression(arg, &scope, builder))
-                        .collect()
-                };
+            StatementKind::Iterator { var, value, array, body } => {
+                match array.range() {
+                    Some(range) => {
+                        let range = match range {
+                            Ok(range) => range,
+                            Err(msg) => return Err(builder.error(array.span, msg)),
+                        };
 
-                assert_eq!(args.len(), 2);
-                let start_val = &args[0];
-                let end_val = &args[1];
-                assert_eq!(start_val.ty, TypeId::f64);
-                assert_eq!(end_val.ty, TypeId::f64);
-
-                iterator(
-                    builder, &scope,
-                    start_val.clone(),
-                    move |builder| {
-                        builder.build_float_lt(
-                            start_val,
-                            end_val,
-                        )
-                    },
-                    |builder| {
-                        builder.build_phi(
-                            TypeId::f64,
-                        )
-                    },
-                    |builder, scope, it| {
-                        statements(
-                            body.clone(),
-                            scope.with_binding(
-                                builder,
-                                var.clone(),
-                                it,
-                            ),
-                            builder,
-                        );
-                    },
-                    |builder, it| {
-                        let inc = builder.build_const_f64(1.0);
-                        builder.build_fadd(
-                            it,
-                            &inc,
-                        )
-                    },
-                    move |builder, next_val| {
-                        builder.build_float_lt(
-                            next_val,
-                            end_val,
-                        )
-                    },
-                );
-            },
-            Statement::Iterator { var, array, body } => {
-                let array = expression(array, &scope, builder);
-                match array.ty.clone() {
-                    TypeId::Array { len, ty } => {
-                        let start_val = builder.build_const_i64(0);
-                        let end_val = builder.build_const_i64(i64::from(len));
-                        let ty = *ty;
-
-                        iterator(
-                            builder, &scope,
-                            start_val.clone(),
-                            |builder| {
-                                builder.build_int_lt(
-                                    &start_val,
-                                    &end_val,
-                                )
-                            },
-                            |builder| {
-                                builder.build_phi(
-                                    TypeId::i64,
-                                )
-                            },
-                            |builder, scope, it| {
-                                let value = {
-                                    let zero = builder.build_const_i32(0);
-                                    let gep = builder.build_in_bounds_gep(
-                                        &array,
-                                        vec![ &zero, it ],
-                                    );
-
-                                    let res = builder.build_load(&gep);
-                                    ValueRef { ty, ptr: res.ptr }
-                                };
-
-                                statements(
-                                    body,
-                                    scope.with_binding(
-                                        builder,
-                                        var.clone(),
-                                        &value,
-                                    ),
-                                    builder,
-                                );
-                            },
-                            |builder, it| {
-                                let inc = builder.build_const_i64(1);
-                                builder.build_nswadd(
-                                    it,
-                                    &inc,
-                                )
-                            },
-                            |builder, next_val| {
-                                builder.build_int_lt(
-                                    next_val,
-                                    &end_val,
-                                )
-                            },
-                        );
-                    },
+                        if value.is_some() {
+                            return Err(builder.error(span, "only dictionaries and enumerated lists can be iterated with two bindings"));
+                        }
 
-                    TypeId::Vec { ty } => {
-                        let start_val = builder.build_const_i64(0);
-                        let vec_len_0 = Atom::from(format!("vec_len.{:?}", ty));
-                        let vec_len_1 = vec_len_0.clone();
-                        let vec_get = Atom::from(format!("vec_get.{:?}", ty));
-
-                        iterator(
-                            builder, &scope,
-                            start_val.clone(),
-                            |builder| {
-                                let vec_len = call_stl(
-                                    builder,
-                                    vec_len_0,
-                                    vec![ &array ],
-                                );
-
-                                builder.build_int_lt(
-                                    &start_val,
-                                    &vec_len,
-                                )
-                            },
-                            |builder| {
-                                builder.build_phi(
-                                    TypeId::i64,
-                                )
-                            },
-                            |builder, scope, it| {
-                                let value = call_stl(
-                                    builder,
-                                    vec_get,
-                                    vec![
-                                        &array,
-                                        it,
-                                    ],
-                                );
-                                statements(
-                                    body.clone(),
-                                    scope.with_binding(
-                                        builder,
-                                        var.clone(),
-                                        &value,
-                                    ),
-                                    builder,
-                                );
-                            },
-                            |builder, it| {
-                                let inc = builder.build_const_i64(1);
-                                builder.build_nswadd(
-                                    it,
-                                    &inc,
-                                )
-                            },
-                            |builder, next_val| {
-                                let vec_len = call_stl(
-                                    builder,
-                                    vec_len_1,
-                                    vec![ &array ],
-                                );
-
-                                builder.build_int_lt(
-                                    next_val,
-                                    &vec_len,
-                                )
-                            },
-                        );
+                        range_loop(var, range, span, body, &scope, builder)?;
                     },
-
-                    _ => panic!("Tried to iterate on a non-array"),
+                    None => iterate(var, value, array, span, body, &scope, builder)?,
                 }
-
             },
 
-            Statement::Branch { condition, consequent, alternate } => {
-                let cond = expression(condition, &scope, builder);
-                let entry_block = builder.get_insert_block();
+            StatementKind::Branch { condition, consequent, alternate } => {
+                let cond_span = condition.span;
+                let cond = expression(condition, &scope, builder)?;
+                if cond.ty != TypeId::bool {
+                    return Err(builder.error(cond_span, format!("expected a condition, found {:?}", cond.ty)));
+                }
 
-                let then_block = {
-                    let block = builder.append_basic_block();
-                    builder.position_at_end(block);
+                branch(&cond, None, consequent, alternate, &scope, builder)?;
+            },
 
-                    statements(
-                        consequent,
-                        scope.fork(),
-                        builder,
-                    );
+            StatementKind::BranchLet { name, value, consequent, alternate } => {
+                let value_span = value.span;
+                let value = expression(value, &scope, builder)?;
+                if value.ty != TypeId::Entity {
+                    return Err(builder.error(value_span, format!("expected an entity, found {:?}", value.ty)));
+                }
 
-                    (block, builder.get_insert_block())
-                };
+                let cond = call_stl(builder, hct_atom!("entity_exists"), once(&value), value_span)?;
+                branch(&cond, Some((name, value)), consequent, alternate, &scope, builder)?;
+            },
 
-                let alt_block = alternate.map(|alternate| {
-                    let block = builder.append_basic_block();
-                    builder.position_at_end(block);
+            StatementKind::Match { value, arms } => {
+                let value = expression(value, &scope, builder)?;
+                let next_block = builder.append_basic_block();
 
+                // Arms are tested in order, the wildcard always matching
+                for MatchArm { values, body, .. } in arms {
+                    let cond = match values {
+                        Some(values) => {
+                            let mut cond: Option<ValueRef> = None;
+                            for exp in values {
+                                let exp_span = exp.span;
+                                let exp = expression(exp, &scope, builder)?;
+                                let eq = binary(value.clone(), Operator::Eq, exp, exp_span, builder)?;
+                                cond = Some(match cond {
+                                    Some(prev) => builder.build_or(&prev, &eq),
+                                    None => eq,
+                                });
+                            }
+
+                            cond.expect("empty match arm")
+                        },
+                        None => builder.build_const_bool(true),
+                    };
+
+                    let arm_block = builder.append_basic_block();
+                    let test_block = builder.append_basic_block();
+                    builder.build_cond_br(&cond, arm_block, test_block);
+
+                    builder.position_at_end(arm_block);
                     statements(
-                        alternate,
+                        body,
                         scope.fork(),
                         builder,
-                    );
-
-                    (block, builder.get_insert_block())
-                });
-
-                let next_block = builder.append_basic_block();
-
-                builder.position_at_end(entry_block);
-                if let Some((alt_start, alt_end)) = alt_block {
-                    builder.build_cond_br(
-                        &cond,
-                        then_block.0,
-                        alt_start,
-                    );
-
-                    builder.position_at_end(alt_end);
+                    )?;
                     builder.build_br(next_block);
-                } else {
-                    builder.build_cond_br(
-                        &cond,
-                        then_block.0,
-                        next_block,
-                    );
+
+                    builder.position_at_end(test_block);
                 }
 
-                builder.position_at_end(then_block.1);
                 builder.build_br(next_block);
-
                 builder.position_at_end(next_block);
             },
 
-            Statement::Assignment { prop, value } => {
-                let value = expression(value, &scope, builder);
-                let mut prop = unwind_path(prop);
-
-                let head = prop.pop_front().unwrap();
-                let head = {
-                    scope.binding_mut(&head)
-                        .expect(&format!("binding \"{}\" not found", head))
-                };
+            StatementKind::Assignment { target, op, value } => {
+                let value_span = value.span;
+                let value = expression(value, &scope, builder)?;
+                assignment(target, op, value, value_span, &scope, builder)?;
+            },
 
-                match head.ty {
-                    _ if prop.is_empty() => {
-                        assert_eq!(head.ty, value.ty);
-                        builder.build_store(&value, &head);
-                    },
+            StatementKind::Call(val) => {
+                call(val, &scope, builder)?;
+            },
 
-                    TypeId::Entity => {
-                        let value = if value.ty == TypeId::String {
-                            value
-                        } else if value.ty == TypeId::f64 {
-                            call_stl(
-                                builder,
-                                hct_atom!("to_string"),
-                                vec![ &value ],
-                            )
-                        } else {
-                            panic!("{:?}", value)
-                        };
+            StatementKind::Function(_) => {},
 
-                        let head = builder.build_load(&head);
-
-                        let key = prop.pop_front().unwrap();
-                        let key = builder.build_const_atom(key);
-                        if prop.is_empty() {
-                            call_stl(
-                                builder,
-                                hct_atom!("set_property"),
-                                vec![ &head, &key, &value ],
-                            );
-                        } else {
-                            let sub = prop.pop_front().unwrap();
-                            let sub = builder.build_const_i64(match sub {
-                                hct_atom!("x") | hct_atom!("r") | hct_atom!("pitch") => 0,
-                                hct_atom!("y") | hct_atom!("g") | hct_atom!("yaw") => 1,
-                                hct_atom!("z") | hct_atom!("b") | hct_atom!("roll") => 2,
-                                hct_atom!("w") | hct_atom!("a") => 3,
-                                sub => panic!("unsupported sub-property \"{}\"", sub),
-                            });
-
-                            call_stl(
-                                builder,
-                                hct_atom!("set_sub_property"),
-                                vec![ &head, &key, &sub, &value ],
-                            );
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => {
+                        let value_span = value.span;
+                        let value = expression(value, &scope, builder)?;
+                        match value.ty {
+                            TypeId::Array { .. } | TypeId::Object { .. } => {
+                                return Err(builder.error(value_span, format!("cannot return a {:?} from a function", value.ty)));
+                            },
+                            _ => {},
                         }
+
+                        builder.set_return_type(span, &value.ty)?;
+                        let slot = builder.get_return_slot(&value.ty);
+                        builder.build_store(&value, &slot);
+                    },
+                    // Returning from the main function stops the script
+                    None => if builder.in_function() {
+                        builder.set_return_type(span, &TypeId::Void)?;
                     },
+                }
+
+                // The following statements are unreachable, but still need a block
+                builder.build_ret_void();
+                let block = builder.append_basic_block();
+                builder.position_at_end(block);
+            },
 
-                    _ => panic!("Unsupported assignment")
+            StatementKind::Break => {
+                match scope.loop_blocks() {
+                    Some((break_block, _)) => builder.build_br(break_block),
+                    None => return Err(builder.error(span, "break outside of a loop")),
                 }
+
+                // The following statements are unreachable, but still need a block
+                let block = builder.append_basic_block();
+                builder.position_at_end(block);
             },
+            StatementKind::Continue => {
+                match scope.loop_blocks() {
+                    Some((_, continue_block)) => builder.build_br(continue_block),
+                    None => return Err(builder.error(span, "continue outside of a loop")),
+                }
 
-            Statement::Call(val) => {
-                call(val, &scope, builder);
+                let block = builder.append_basic_block();
+                builder.position_at_end(block);
             },
+
+            // Imports are resolved when loading the script
+            StatementKind::Import { .. } => {},
+
+            StatementKind::Error(message) => return Err(builder.error(span, message)),
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,77 @@
+//! Operations on strings, implemented by the STL and evaluated
+//! at compile time when all their arguments are constant
+
+use atom::*;
+use error::HatchetResult;
+use hct::ast::*;
+use runtime::stl::*;
+
+use super::builder::*;
+use super::function::*;
+use super::types::ValueRef;
+
+/// Evaluate a string function at compile time, or return
+/// None if some of its arguments are not constant
+fn fold(ext: &Atom, args: &[(ValueRef, Span)], builder: &mut Builder) -> Option<ValueRef> {
+    let strings: Vec<_> = args.iter().map(|&(ref val, _)| builder.get_const_string(val)).collect();
+    let ints: Vec<_> = args.iter().map(|&(ref val, _)| builder.get_const_i64(val)).collect();
+
+    let value = match strings.first() {
+        Some(&Some(ref value)) => value.clone(),
+        _ => return None,
+    };
+
+    match *ext {
+        hct_atom!("str_len") => return Some(builder.build_const_i64(value.chars().count() as i64)),
+        hct_atom!("str_trim") => return Some(builder.build_const_string(value.trim().to_string())),
+        hct_atom!("str_upper") => return Some(builder.build_const_string(value.to_uppercase())),
+        hct_atom!("str_lower") => return Some(builder.build_const_string(value.to_lowercase())),
+        // Out of bounds substrings are left to report their error at runtime
+        hct_atom!("str_substr") => return match (ints[1], ints[2]) {
+            (Some(start), Some(length)) => {
+                substring(&value, start, length)
+                    .map(|res| builder.build_const_string(res))
+            },
+            _ => None,
+        },
+        _ => {},
+    }
+
+    let pattern = match strings.get(1) {
+        Some(&Some(ref pattern)) => pattern.clone(),
+        _ => return None,
+    };
+
+    match *ext {
+        hct_atom!("str_contains") => Some(builder.build_const_bool(value.contains(pattern.as_str()))),
+        hct_atom!("str_find") => Some(builder.build_const_i64(string_find(&value, &pattern))),
+        hct_atom!("str_starts_with") => Some(builder.build_const_bool(value.starts_with(pattern.as_str()))),
+        hct_atom!("str_ends_with") => Some(builder.build_const_bool(value.ends_with(pattern.as_str()))),
+        hct_atom!("str_matches") => Some(builder.build_const_bool(glob_match(&pattern, &value))),
+        hct_atom!("str_replace") => match strings[2] {
+            Some(ref replacement) => Some(builder.build_const_string(value.replace(pattern.as_str(), replacement))),
+            None => None,
+        },
+        // Splitting builds a list, which is never constant
+        _ => None,
+    }
+}
+
+/// Build a call to a string function, or return None if the
+/// called function is not one for these arguments
+pub fn string_call(name: &Atom, args: &[(ValueRef, Span)], span: Span, builder: &mut Builder) -> HatchetResult<Option<ValueRef>> {
+    let ext = match args.first() {
+        Some(&(ref first, _)) => match string_function(name, args.len(), &first.ty) {
+            Some(ext) => ext,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    check_args(builder, &ext, args, span)?;
+    if let Some(res) = fold(&ext, args, builder) {
+        return Ok(Some(res));
+    }
+
+    call_stl(builder, ext, args.iter().map(|&(ref val, _)| val), span).map(Some)
+}
@@ -1,9 +1,12 @@
 use std::ops::Deref;
+#[cfg(feature = "jit")]
 use std::ffi::CStr;
 use std::fmt::*;
 
+#[cfg(feature = "jit")]
 use compiler::builder::{Type, Value};
 use atom::Atom;
+use hct::ast::TypeName;
 
 #[allow(non_camel_case_types)]
 #[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
@@ -17,6 +20,10 @@ pub enum TypeId {
     Atom,
     Entity,
     String,
+    /// Position or direction in the world
+    vec3,
+    /// Pitch, yaw and roll in degrees
+    qangle,
 
     Array {
         len: u32,
@@ -29,21 +36,86 @@ pub enum TypeId {
     Object {
         items: Vec<(Atom, TypeId)>,
     },
-    /* TODO: HashMap {
-        key: TypeId,
-        value: TypeId,
-    },*/
+    /// Dictionary keeping its entries in insertion order
+    HashMap {
+        key: Box<TypeId>,
+        value: Box<TypeId>,
+    },
 
+    #[cfg(feature = "jit")]
     Other(Type),
 }
 
+impl TypeId {
+    /// Check if values of this type can be stored in a vector
+    pub fn is_vec_element(&self) -> bool {
+        match *self {
+            TypeId::f64 | TypeId::bool | TypeId::i64 |
+            TypeId::Atom | TypeId::Entity | TypeId::String => true,
+            _ => false,
+        }
+    }
+
+    /// Check if this type is a vector or an angle
+    pub fn is_vector(&self) -> bool {
+        *self == TypeId::vec3 || *self == TypeId::qangle
+    }
+
+    /// Check if values of this type can be used as the keys of a dictionary
+    pub fn is_dict_key(&self) -> bool {
+        match *self {
+            TypeId::Atom | TypeId::Entity | TypeId::String => true,
+            _ => false,
+        }
+    }
+
+    /// Resolve the type named by a conversion, which also
+    /// accepts the plain names of the scalar types
+    pub fn from_cast(ty: &TypeName) -> Option<TypeId> {
+        match *ty {
+            TypeName::Named(hct_atom!("number")) => Some(TypeId::f64),
+            TypeName::Named(hct_atom!("int")) => Some(TypeId::i64),
+            TypeName::Named(hct_atom!("string")) => Some(TypeId::String),
+            _ => TypeId::from_annotation(ty),
+        }
+    }
+
+    /// Resolve the type named by an annotation
+    pub fn from_annotation(ty: &TypeName) -> Option<TypeId> {
+        match *ty {
+            TypeName::Named(ref name) => match &**name {
+                "f64" => Some(TypeId::f64),
+                "bool" => Some(TypeId::bool),
+                "i64" => Some(TypeId::i64),
+                "Entity" => Some(TypeId::Entity),
+                "String" => Some(TypeId::String),
+                "vec3" => Some(TypeId::vec3),
+                "qangle" => Some(TypeId::qangle),
+                _ => None,
+            },
+            TypeName::List(ref ty) => {
+                TypeId::from_annotation(ty)
+                    .map(|ty| TypeId::Vec { ty: box ty })
+            },
+            TypeName::Dict(ref key, ref value) => {
+                match (TypeId::from_annotation(key), TypeId::from_annotation(value)) {
+                    (Some(key), Some(value)) => Some(TypeId::HashMap { key: box key, value: box value }),
+                    _ => None,
+                }
+            },
+        }
+    }
+}
+
 /// Wrapper for LLVM values with type metadata
+#[cfg(feature = "jit")]
 #[derive(Clone)]
 pub struct ValueRef {
     pub ty: TypeId,
     pub ptr: Value,
 }
 
+#[cfg(feature = "jit")]
 impl Debug for ValueRef {
     fn fmt(&self, fmt: &mut Formatter) -> Result {
         use llvm_sys::core::*;
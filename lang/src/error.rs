@@ -0,0 +1,218 @@
+//! Errors reported by the compiler driver
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use hct::SourceFile;
+use hct::diagnostic::Diagnostic;
+
+#[derive(Debug)]
+pub enum HatchetError {
+    /// A file could not be read or written
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A map file is not a valid VMF
+    Vmf {
+        path: PathBuf,
+        message: String,
+    },
+    /// A script has syntax errors
+    Parse {
+        file: Arc<SourceFile>,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// A script is not correctly typed
+    Type {
+        file: Arc<SourceFile>,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// A compiled script could not be loaded in the execution engine
+    Link {
+        script: String,
+        message: String,
+    },
+    /// A script failed during its execution
+    Runtime {
+        script: String,
+        message: String,
+    },
+    /// Several instances of a map failed to build
+    Instances {
+        path: PathBuf,
+        errors: Vec<HatchetError>,
+    },
+}
+
+pub type HatchetResult<T> = Result<T, HatchetError>;
+
+impl HatchetError {
+    pub fn io<P: Into<PathBuf>>(path: P, error: io::Error) -> HatchetError {
+        HatchetError::Io {
+            path: path.into(),
+            error,
+        }
+    }
+
+    pub fn vmf<P: Into<PathBuf>, S: Into<String>>(path: P, message: S) -> HatchetError {
+        HatchetError::Vmf {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Render the diagnostics attached to this error, if any
+    pub fn details(&self) -> Vec<String> {
+        match *self {
+            HatchetError::Parse { ref file, ref diagnostics } |
+            HatchetError::Type { ref file, ref diagnostics } => {
+                diagnostics.iter()
+                    .map(|diag| diag.render(file))
+                    .collect()
+            },
+            // The errors of each instance are listed with their own details
+            HatchetError::Instances { ref errors, .. } => {
+                errors.iter()
+                    .flat_map(|err| {
+                        let mut res = err.details();
+                        res.push(err.to_string());
+                        res
+                    })
+                    .collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The I/O errors are copied with their kind and message, the only
+/// parts of them reported to the user
+impl Clone for HatchetError {
+    fn clone(&self) -> HatchetError {
+        match *self {
+            HatchetError::Io { ref path, ref error } => {
+                HatchetError::io(path.clone(), io::Error::new(error.kind(), error.to_string()))
+            },
+            HatchetError::Vmf { ref path, ref message } => HatchetError::Vmf {
+                path: path.clone(),
+                message: message.clone(),
+            },
+            HatchetError::Parse { ref file, ref diagnostics } => HatchetError::Parse {
+                file: file.clone(),
+                diagnostics: diagnostics.clone(),
+            },
+            HatchetError::Type { ref file, ref diagnostics } => HatchetError::Type {
+                file: file.clone(),
+                diagnostics: diagnostics.clone(),
+            },
+            HatchetError::Link { ref script, ref message } => HatchetError::Link {
+                script: script.clone(),
+                message: message.clone(),
+            },
+            HatchetError::Runtime { ref script, ref message } => HatchetError::Runtime {
+                script: script.clone(),
+                message: message.clone(),
+            },
+            HatchetError::Instances { ref path, ref errors } => HatchetError::Instances {
+                path: path.clone(),
+                errors: errors.clone(),
+            },
+        }
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}
+
+impl Display for HatchetError {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            HatchetError::Io { ref path, ref error } => {
+                write!(fmt, "could not access {}: {}", path.display(), error)
+            },
+            HatchetError::Vmf { ref path, ref message } => {
+                write!(fmt, "invalid map file {}: {}", path.display(), message)
+            },
+            HatchetError::Parse { ref file, ref diagnostics } => {
+                write!(
+                    fmt, "{} syntax error{} in {}",
+                    diagnostics.len(), plural(diagnostics.len()),
+                    file.path.display(),
+                )
+            },
+            HatchetError::Type { ref file, ref diagnostics } => {
+                write!(
+                    fmt, "{} error{} in script {}",
+                    diagnostics.len(), plural(diagnostics.len()),
+                    file.path.display(),
+                )
+            },
+            HatchetError::Link { ref script, ref message } => {
+                write!(fmt, "could not link script {}: {}", script, message)
+            },
+            HatchetError::Runtime { ref script, ref message } => {
+                write!(fmt, "runtime error in script {}: {}", script, message)
+            },
+            HatchetError::Instances { ref path, ref errors } => {
+                write!(fmt, "{} instances of {} failed to build", errors.len(), path.display())
+            },
+        }
+    }
+}
+
+impl Error for HatchetError {
+    fn description(&self) -> &str {
+        match *self {
+            HatchetError::Io { .. } => "io error",
+            HatchetError::Vmf { .. } => "invalid map file",
+            HatchetError::Parse { .. } => "syntax error",
+            HatchetError::Type { .. } => "type error",
+            HatchetError::Link { .. } => "link error",
+            HatchetError::Runtime { .. } => "runtime error",
+            HatchetError::Instances { .. } => "instance errors",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            HatchetError::Io { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hct::parse_source;
+
+    #[test]
+    fn instances_list_the_details_of_each_error() {
+        let parse = parse_source("a.hct", String::from("let = 1\n")).unwrap_err();
+        assert_eq!(parse.to_string(), "1 syntax error in a.hct");
+
+        let runtime = HatchetError::Runtime {
+            script: String::from("b.hct"),
+            message: String::from("division by zero"),
+        };
+
+        let err = HatchetError::Instances {
+            path: PathBuf::from("map.vmf"),
+            errors: vec![parse, runtime],
+        };
+
+        assert_eq!(err.to_string(), "2 instances of map.vmf failed to build");
+        assert_eq!(err.details(), vec![
+            String::from(
+                "error: expected a binding name, found `=`\n --> a.hct:1:5\n  |\n1 | let = 1\n  |     ^"
+            ),
+            String::from("1 syntax error in a.hct"),
+            String::from("runtime error in script b.hct: division by zero"),
+        ]);
+    }
+}
@@ -2,12 +2,33 @@
 
 use std::fmt::{self, Display, Formatter};
 use std::collections::HashMap;
+use std::cmp;
+use std::sync::Arc;
 use atom::Atom;
+use super::SourceFile;
+
+/// Location of a node in its source file, as byte offsets from its start
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates a span covering both self and other
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: cmp::min(self.start, other.start),
+            end: cmp::max(self.end, other.end),
+        }
+    }
+}
 
 /// Main script entry point
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Script {
     pub body: Vec<Statement>,
+    pub file: Arc<SourceFile>,
 }
 
 /// Function call statement / expression
@@ -15,6 +36,16 @@ pub struct Script {
 pub struct Call {
     pub path: Path,
     pub args: Vec<Expression>,
+    pub span: Span,
+}
+
+/// User function declaration
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Function {
+    pub name: Atom,
+    pub args: Vec<Atom>,
+    pub body: Vec<Statement>,
+    pub span: Span,
 }
 
 /// Binary operator
@@ -38,6 +69,8 @@ pub enum Operator {
     BOr,
     And,
     Or,
+    /// Value of an entity property, or a default if it is missing
+    Coalesce,
 }
 
 impl Display for Operator {
@@ -61,13 +94,56 @@ impl Display for Operator {
             Operator::BOr => write!(fmt, "|"),
             Operator::And => write!(fmt, "&&"),
             Operator::Or => write!(fmt, "||"),
+            Operator::Coalesce => write!(fmt, "??"),
+        }
+    }
+}
+
+/// Unary operator
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnaryOperator {
+    Not,
+    Neg,
+    BNot,
+}
+
+impl Display for UnaryOperator {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            UnaryOperator::Not => write!(fmt, "!"),
+            UnaryOperator::Neg => write!(fmt, "-"),
+            UnaryOperator::BNot => write!(fmt, "~"),
+        }
+    }
+}
+
+/// Type annotation of a binding
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TypeName {
+    Named(Atom),
+    List(Box<TypeName>),
+    Dict(Box<TypeName>, Box<TypeName>),
+}
+
+impl Display for TypeName {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            TypeName::Named(ref name) => write!(fmt, "{}", name),
+            TypeName::List(ref ty) => write!(fmt, "[{}]", ty),
+            TypeName::Dict(ref key, ref value) => write!(fmt, "{{{}: {}}}", key, value),
         }
     }
 }
 
 /// Script expression resolving to a value
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Expression {
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExpressionKind {
     Call(Call),
 
     Array(Vec<Expression>),
@@ -75,6 +151,43 @@ pub enum Expression {
 
     Reference(Path),
 
+    Index {
+        expr: Box<Expression>,
+        index: Box<Expression>,
+    },
+    Slice {
+        expr: Box<Expression>,
+        start: Option<Box<Expression>>,
+        end: Option<Box<Expression>>,
+    },
+    /// Field of a value that is not a path, like an element of a list
+    Field {
+        expr: Box<Expression>,
+        name: Atom,
+    },
+    /// Range of numbers iterated by a for loop
+    Range {
+        start: Box<Expression>,
+        end: Box<Expression>,
+        inclusive: bool,
+    },
+
+    /// Choice between two values depending on a condition
+    Conditional {
+        condition: Box<Expression>,
+        consequent: Box<Expression>,
+        alternate: Box<Expression>,
+    },
+
+    Unary {
+        op: UnaryOperator,
+        expr: Box<Expression>,
+    },
+    /// Conversion of a value to another type
+    Cast {
+        expr: Box<Expression>,
+        ty: TypeName,
+    },
     Binary {
         lhs: Box<Expression>,
         op: Operator,
@@ -84,9 +197,78 @@ pub enum Expression {
     Literal(Literal),
 }
 
+/// Bounds of a range of numbers iterated by a for loop
+pub struct Range {
+    pub start: Expression,
+    pub end: Expression,
+    /// Defaults to 1 or -1 depending on the order of the bounds
+    pub step: Option<Expression>,
+    pub inclusive: bool,
+}
+
+impl Expression {
+    /// Get the bounds of a range literal or of a call to range, or the
+    /// error message of a call with the wrong number of arguments
+    pub fn range(&self) -> Option<Result<Range, String>> {
+        match self.kind {
+            ExpressionKind::Range { ref start, ref end, inclusive } => Some(Ok(Range {
+                start: (**start).clone(),
+                end: (**end).clone(),
+                step: None,
+                inclusive,
+            })),
+            ExpressionKind::Call(Call { path: Path { kind: PathKind::Binding(hct_atom!("range")), .. }, ref args, .. }) => {
+                if args.len() != 2 && args.len() != 3 {
+                    return Some(Err(format!("range expects 2 or 3 arguments, found {}", args.len())));
+                }
+
+                Some(Ok(Range {
+                    start: args[0].clone(),
+                    end: args[1].clone(),
+                    step: args.get(2).cloned(),
+                    inclusive: false,
+                }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether this expression reads a field of a value
+    pub fn is_field(&self) -> bool {
+        match self.kind {
+            ExpressionKind::Reference(Path { kind: PathKind::Deref(..), .. }) |
+            ExpressionKind::Field { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Split an access to a field into the expression of the object
+    /// and the name of the field, or give back any other expression
+    pub fn split_field(self) -> Result<(Expression, Atom), Expression> {
+        let span = self.span;
+        match self.kind {
+            ExpressionKind::Reference(Path { kind: PathKind::Deref(box obj, prop), .. }) => Ok((
+                Expression {
+                    span: obj.span,
+                    kind: ExpressionKind::Reference(obj),
+                },
+                prop,
+            )),
+            ExpressionKind::Field { box expr, name } => Ok((expr, name)),
+            kind => Err(Expression { kind, span }),
+        }
+    }
+}
+
 /// Defines a path to an entity or method
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
-pub enum Path {
+pub struct Path {
+    pub kind: PathKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PathKind {
     Deref(Box<Path>, Atom),
     Instance(Box<Path>),
     Binding(Atom),
@@ -94,23 +276,78 @@ pub enum Path {
 
 impl Display for Path {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        match *self {
-            Path::Deref(ref obj, ref prop) => {
-                if let Path::Instance(_) = **obj {
+        match self.kind {
+            PathKind::Deref(ref obj, ref prop) => {
+                if let PathKind::Instance(_) = obj.kind {
                     write!(fmt, "{}{}", obj, prop)
                 } else {
                     write!(fmt, "{}.{}", obj, prop)
                 }
             },
-            Path::Instance(ref pat) => write!(fmt, "{}:", pat),
-            Path::Binding(ref name) => write!(fmt, "{}", name),
+            PathKind::Instance(ref pat) => write!(fmt, "{}:", pat),
+            PathKind::Binding(ref name) => write!(fmt, "{}", name),
+        }
+    }
+}
+
+/// Names bound by a let statement or a for loop
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Pattern {
+    Name(Atom),
+    /// Fields of an object, each with the name it is bound to
+    Object(Vec<(Atom, Atom)>),
+    /// Leading elements of a list
+    Array(Vec<Atom>),
+}
+
+impl Pattern {
+    /// Get the names bound by this pattern
+    pub fn names(&self) -> Vec<&Atom> {
+        match *self {
+            Pattern::Name(ref name) => vec![ name ],
+            Pattern::Object(ref fields) => fields.iter().map(|&(_, ref name)| name).collect(),
+            Pattern::Array(ref names) => names.iter().collect(),
+        }
+    }
+
+    /// Rename the bindings of this pattern
+    pub fn map<F>(self, mut func: F) -> Pattern where F: FnMut(Atom) -> Atom {
+        match self {
+            Pattern::Name(name) => Pattern::Name(func(name)),
+            Pattern::Object(fields) => Pattern::Object(
+                fields.into_iter()
+                    .map(|(field, name)| (field, func(name)))
+                    .collect()
+            ),
+            Pattern::Array(names) => Pattern::Array(names.into_iter().map(func).collect()),
         }
     }
 }
 
+/// Arm of a match statement
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MatchArm {
+    /// Values compared to the matched value, or None for the `_` wildcard
+    pub values: Option<Vec<Expression>>,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
 /// Executable script statements
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Statement {
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StatementKind {
+    /// Import of another script file, resolved when loading the script
+    Import {
+        path: String,
+        alias: Option<Atom>,
+    },
+
     Auto {
         body: Vec<Statement>,
     },
@@ -133,7 +370,10 @@ pub enum Statement {
         body: Vec<Statement>,
     },
     Iterator {
-        var: Atom,
+        var: Pattern,
+        /// Binding for the values when iterating on a dictionary,
+        /// or for the elements when enumerating a list
+        value: Option<Pattern>,
         array: Expression,
         body: Vec<Statement>,
     },
@@ -142,21 +382,81 @@ pub enum Statement {
         consequent: Vec<Statement>,
         alternate: Option<Vec<Statement>>,
     },
-    Binding {
+    /// Execution of a block with an entity bound to a name, if it exists
+    BranchLet {
         name: Atom,
         value: Expression,
+        consequent: Vec<Statement>,
+        alternate: Option<Vec<Statement>>,
+    },
+    /// Execution of the first arm holding a value equal to the matched one
+    Match {
+        value: Expression,
+        arms: Vec<MatchArm>,
+    },
+    Binding {
+        pattern: Pattern,
+        ty: Option<TypeName>,
+        value: Expression,
     },
     Assignment {
-        prop: Path,
+        target: Expression,
+        /// Operator of a compound assignment, applied to the current value
+        op: Option<Operator>,
         value: Expression,
     },
     Call(Call),
+
+    Function(Function),
+    Return(Option<Expression>),
+    Break,
+    Continue,
+
+    /// Placeholder for a statement that could not be parsed
+    Error(String),
+}
+
+impl StatementKind {
+    /// Get the nested blocks of this statement
+    pub fn blocks(&self) -> Vec<&Vec<Statement>> {
+        match *self {
+            StatementKind::Auto { ref body } |
+            StatementKind::Relay { ref body, .. } |
+            StatementKind::Subscriber { ref body, .. } |
+            StatementKind::Delay { ref body, .. } |
+            StatementKind::Loop { ref body, .. } |
+            StatementKind::Iterator { ref body, .. } |
+            StatementKind::Function(Function { ref body, .. }) => vec![ body ],
+
+            StatementKind::Branch { ref consequent, ref alternate, .. } |
+            StatementKind::BranchLet { ref consequent, ref alternate, .. } => {
+                let mut res = vec![ consequent ];
+                if let Some(ref alternate) = *alternate {
+                    res.push(alternate);
+                }
+
+                res
+            },
+            StatementKind::Match { ref arms, .. } => arms.iter().map(|arm| &arm.body).collect(),
+
+            StatementKind::Import { .. } |
+            StatementKind::Binding { .. } |
+            StatementKind::Assignment { .. } |
+            StatementKind::Call(_) |
+            StatementKind::Return(_) |
+            StatementKind::Break |
+            StatementKind::Continue |
+            StatementKind::Error(_) => Vec::new(),
+        }
+    }
 }
 
 /// Literal values, used as arguments for method calls
 #[derive(Clone, Debug, PartialEq)]
 pub enum Literal {
     Number(f64),
+    Int(i64),
+    Bool(bool),
     String(Vec<StringPart>),
 }
 
@@ -164,6 +464,32 @@ impl Eq for Literal {}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StringPart {
-    Expression(Expression),
+    /// Interpolated value, with the format of a number
+    Expression(Expression, Option<FormatSpec>),
     String(String),
 }
+
+/// Format of an interpolated number, written after a colon like `${x:03.2}`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FormatSpec {
+    /// Pad with zeros instead of spaces
+    pub zero: bool,
+    pub width: Option<u32>,
+    pub precision: Option<u32>,
+}
+
+impl Display for FormatSpec {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        if self.zero {
+            write!(fmt, "0")?;
+        }
+        if let Some(width) = self.width {
+            write!(fmt, "{}", width)?;
+        }
+        if let Some(precision) = self.precision {
+            write!(fmt, ".{}", precision)?;
+        }
+
+        Ok(())
+    }
+}
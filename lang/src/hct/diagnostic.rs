@@ -0,0 +1,122 @@
+//! Diagnostics attached to a location in a script
+
+use std::fmt::Write;
+use std::cmp;
+
+use super::SourceFile;
+use super::ast::Span;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// A message pointing at a span of a script
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error<S: Into<String>>(span: Span, message: S) -> Diagnostic {
+        Diagnostic {
+            level: Level::Error,
+            message: message.into(),
+            span,
+        }
+    }
+
+    pub fn warning<S: Into<String>>(span: Span, message: S) -> Diagnostic {
+        Diagnostic {
+            level: Level::Warning,
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// Render this diagnostic with the offending line and a caret underline
+    /// The span is first resolved to the imported module it belongs to, if any
+    pub fn render(&self, file: &SourceFile) -> String {
+        let (file, Span { start, end }) = file.resolve(self.span);
+        let (line, column) = file.location(start);
+
+        let line_start = file.text[..start].rfind('\n').map_or(0, |pos| pos + 1);
+        let line_end = file.text[start..].find('\n').map_or(file.text.len(), |pos| start + pos);
+        let source = file.text[line_start..line_end].trim_right();
+
+        let margin = line.to_string().len();
+        let mut res = String::new();
+
+        writeln!(
+            res, "{}: {}",
+            match self.level {
+                Level::Error => "error",
+                Level::Warning => "warning",
+            },
+            self.message,
+        ).unwrap();
+        writeln!(res, "{:w$}--> {}:{}:{}", "", file.path.display(), line, column, w = margin).unwrap();
+        writeln!(res, "{:w$} |", "", w = margin).unwrap();
+        writeln!(res, "{} | {}", line, source).unwrap();
+        write!(res, "{:w$} | ", "", w = margin).unwrap();
+
+        // Keep the tabs of the source line so the carets stay aligned
+        for chr in file.text[line_start..start].chars() {
+            res.push(if chr == '\t' { '\t' } else { ' ' });
+        }
+
+        let stop = cmp::min(end, line_end);
+        let width = file.text[start..stop].chars().count();
+        for _ in 0..cmp::max(width, 1) {
+            res.push('^');
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn render_underlines_the_span() {
+        let file = SourceFile {
+            path: PathBuf::from("test.hct"),
+            text: String::from("let a = 1\nlet b = foo\n"),
+            imports: Vec::new(),
+        };
+
+        let diag = Diagnostic::error(Span { start: 18, end: 21 }, "value \"foo\" not found");
+        assert_eq!(diag.render(&file), concat!(
+            "error: value \"foo\" not found\n",
+            " --> test.hct:2:9\n",
+            "  |\n",
+            "2 | let b = foo\n",
+            "  |         ^^^",
+        ));
+    }
+
+    #[test]
+    fn render_keeps_tabs() {
+        let file = SourceFile {
+            path: PathBuf::from("test.hct"),
+            text: String::from("auto {\n\tfoo\n}"),
+            imports: Vec::new(),
+        };
+
+        let diag = Diagnostic::warning(Span { start: 8, end: 11 }, "unused");
+        assert_eq!(diag.render(&file), concat!(
+            "warning: unused\n",
+            " --> test.hct:2:2\n",
+            "  |\n",
+            "2 | \tfoo\n",
+            "  | \t^^^",
+        ));
+    }
+}
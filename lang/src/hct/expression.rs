@@ -1,7 +1,7 @@
 use synom::IResult;
 use either::*;
 
-use super::ast::{Operator, Expression, Literal};
+use super::ast::{Operator, Expression, ExpressionKind};
 
 type Parser = Box<Fn(&str) -> IResult<&str, Expression> + Sync + 'static>;
 type PunctParser = Box<Fn(&str) -> IResult<&str, &str> + Sync + 'static>;
@@ -47,15 +47,22 @@ impl OpParser {
         }
     }
 
+    /// Push the next operand or operator on the stack, depending on the
+    /// previous item, returning the remaining input if one was parsed
     fn shift<'a>(&self, stack: &mut Stack<'a>, i: &'a str) -> Option<&'a str> {
-        for &(op, ref parser) in &self.op_parsers {
-            if let IResult::Done(s, _) = (*parser)(i) {
-                stack.push((s, Either::Right(op)));
-                return Some(s);
-            }
-        }
+        let operand = match stack.last() {
+            Some(&(_, Either::Left(_))) => false,
+            _ => true,
+        };
 
-        if let IResult::Done(s, m) = (*self.atom_parser)(i) {
+        if !operand {
+            for &(op, ref parser) in &self.op_parsers {
+                if let IResult::Done(s, _) = (*parser)(i) {
+                    stack.push((s, Either::Right(op)));
+                    return Some(s);
+                }
+            }
+        } else if let IResult::Done(s, m) = (*self.atom_parser)(i) {
             stack.push((s, Either::Left(m)));
             return Some(s);
         }
@@ -69,6 +76,16 @@ impl OpParser {
             input = rest;
         }
 
+        // A trailing operator is left in the input
+        let trailing = match stack.last() {
+            Some(&(_, Either::Right(_))) => true,
+            _ => false,
+        };
+
+        if trailing {
+            stack.pop();
+        }
+
         for &(_, ref ops) in &self.sorted_ops {
             let mut i = 0;
             while i < stack.len() {
@@ -76,19 +93,20 @@ impl OpParser {
                     (_, Either::Right(op)) if ops.contains(&op) => {
                         let (from, to, val) = match (i.checked_sub(1).and_then(|i| stack.get(i)), op, stack.get(i + 1)) {
                             (Some(&(_, ref left)), op, Some(&(rem, ref right))) => {
+                                let lhs = left.clone().left().unwrap();
+                                let rhs = right.clone().left().unwrap();
+                                let span = lhs.span.to(rhs.span);
                                 (i - 1, i + 2, Some((rem, Either::Left(
-                                    Expression::Binary {
-                                        lhs: box left.clone().left().unwrap(),
-                                        op,
-                                        rhs: box right.clone().left().unwrap(),
+                                    Expression {
+                                        kind: ExpressionKind::Binary {
+                                            lhs: box lhs,
+                                            op,
+                                            rhs: box rhs,
+                                        },
+                                        span,
                                     }
                                 ))))
                             },
-                            (None, Operator::Sub, Some(&(rem, Either::Left(Expression::Literal(Literal::Number(val)))))) => {
-                                (i, i + 2, Some((rem, Either::Left(
-                                    Expression::Literal(Literal::Number(-val))
-                                ))))
-                            },
                             (lhs, op, rhs) => panic!("{:?} {:?} {:?}", lhs, op, rhs),
                         };
 
@@ -114,10 +132,10 @@ impl OpParser {
 lazy_static!{
     pub static ref EXPRESSION_PARSER: OpParser = {
         use super::ast::Operator::*;
-        use super::parser::atomic_expression;
+        use super::parser::cast_expression;
 
         OpParser::new(
-            box atomic_expression,
+            box cast_expression,
             vec![
                 (Mul,  5,  box |i| punct!(i, "*")),
                 (Div,  5,  box |i| punct!(i, "/")),
@@ -134,6 +152,7 @@ lazy_static!{
                 (Neq,  9,  box |i| punct!(i, "!=")),
                 (And,  13, box |i| punct!(i, "&&")),
                 (Or,   14, box |i| punct!(i, "||")),
+                (Coalesce, 15, box |i| punct!(i, "??")),
                 (BAnd, 10, box |i| punct!(i, "&")),
                 (BXor, 11, box |i| punct!(i, "^")),
                 (BOr,  12, box |i| punct!(i, "|")),
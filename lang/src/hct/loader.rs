@@ -0,0 +1,716 @@
+//! Resolve the imports of the scripts, merging the imported modules in their AST
+//! The top-level declarations of a module are prefixed with its name, and the
+//! paths starting with the name of an import are resolved to those prefixed names
+
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use synom::IResult;
+
+use atom::Atom;
+use error::*;
+use logging::path_fmt;
+use super::ast::*;
+use super::diagnostic::Diagnostic;
+use super::parser::name;
+use super::{parse_file, SourceFile};
+
+/// Module imported by a script
+struct Import {
+    /// Canonical path of the imported file
+    path: PathBuf,
+    alias: Atom,
+}
+
+/// A parsed script file, along with its resolved imports
+struct Module {
+    script: Script,
+    imports: Vec<Import>,
+}
+
+/// Result of parsing a file, computed by the first thread needing it
+type ParsedFile = Arc<Mutex<Option<HatchetResult<Script>>>>;
+
+/// Loads the script files, parsing each of them only once
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    /// Files parsed so far, each one behind its own lock
+    files: Mutex<HashMap<PathBuf, ParsedFile>>,
+    modules: Mutex<HashMap<PathBuf, Arc<Module>>>,
+}
+
+/// Get the name of a module from its file name, if it is a valid name
+fn default_alias(target: &str) -> Option<Atom> {
+    let stem = FsPath::new(target).file_stem().and_then(|stem| stem.to_str());
+    match stem.map(name) {
+        Some(IResult::Done(rem, alias)) if rem.is_empty() => Some(alias),
+        _ => None,
+    }
+}
+
+/// Report the imports that are not at the top level of a script
+fn nested_imports(body: &[Statement], diagnostics: &mut Vec<Diagnostic>) {
+    for stmt in body {
+        if let StatementKind::Import { .. } = stmt.kind {
+            diagnostics.push(Diagnostic::error(stmt.span, "imports are only allowed at the top level of a script"));
+        }
+
+        for block in stmt.kind.blocks() {
+            nested_imports(block, diagnostics);
+        }
+    }
+}
+
+impl Loader {
+    pub fn new(search_paths: Vec<PathBuf>) -> Loader {
+        Loader {
+            search_paths,
+            files: Default::default(),
+            modules: Default::default(),
+        }
+    }
+
+    /// Find a file imported by a script, relative to the script itself first
+    /// and then to each of the library search paths
+    /// Returns the path of the file along with its canonical form
+    fn find(&self, from: &FsPath, target: &str) -> Option<(PathBuf, PathBuf)> {
+        from.parent()
+            .map(|dir| dir.join(target))
+            .into_iter()
+            .chain(self.search_paths.iter().map(|dir| dir.join(target)))
+            .filter(|path| path.is_file())
+            .filter_map(|path| {
+                fs::canonicalize(&path).ok()
+                    .map(|key| (path, key))
+            })
+            .next()
+    }
+
+    /// Parse a script file, or get the result of its first parsing
+    /// The lock of the file is held while it is parsed, so the threads
+    /// importing it at the same time wait for the first one to finish
+    fn parse(&self, path: &FsPath, key: &FsPath) -> HatchetResult<Script> {
+        let file = {
+            let mut files = self.files.lock().unwrap();
+            files.entry(key.to_path_buf()).or_insert_with(Default::default).clone()
+        };
+
+        let mut file = file.lock().unwrap();
+        let res = match file.take() {
+            Some(res) => res,
+            None => parse_file(path),
+        };
+
+        *file = Some(res.clone());
+        res
+    }
+
+    /// Parse a script file and the modules it imports, recursively
+    /// The stack holds the files being loaded, to detect import cycles
+    fn load(&self, path: &FsPath, key: PathBuf, stack: &mut Vec<PathBuf>) -> HatchetResult<Arc<Module>> {
+        if let Some(module) = self.modules.lock().unwrap().get(&key) {
+            return Ok(module.clone());
+        }
+
+        let mut script = self.parse(path, &key)?;
+        let mut imports: Vec<Import> = Vec::new();
+        let mut diagnostics = Vec::new();
+
+        stack.push(key.clone());
+
+        let body = mem::replace(&mut script.body, Vec::new());
+        for Statement { kind, span } in body {
+            let (target, alias) = match kind {
+                StatementKind::Import { path, alias } => (path, alias),
+                kind => {
+                    for block in kind.blocks() {
+                        nested_imports(block, &mut diagnostics);
+                    }
+
+                    script.body.push(Statement { kind, span });
+                    continue;
+                },
+            };
+
+            let alias = match alias.or_else(|| default_alias(&target)) {
+                Some(alias) => alias,
+                None => {
+                    diagnostics.push(Diagnostic::error(span, format!("cannot use the file name of \"{}\" as a module name, add `as <name>`", target)));
+                    continue;
+                },
+            };
+
+            if imports.iter().any(|import| import.alias == alias) {
+                diagnostics.push(Diagnostic::error(span, format!("module name {} is already used", alias)));
+                continue;
+            }
+
+            let (import_path, import_key) = match self.find(path, &target) {
+                Some(res) => res,
+                None => {
+                    diagnostics.push(Diagnostic::error(span, format!("module \"{}\" not found", target)));
+                    continue;
+                },
+            };
+
+            if let Some(pos) = stack.iter().position(|item| *item == import_key) {
+                let cycle: Vec<_> = {
+                    stack[pos..].iter()
+                        .chain(Some(&import_key))
+                        .map(path_fmt)
+                        .collect()
+                };
+
+                diagnostics.push(Diagnostic::error(span, format!("import cycle: {}", cycle.join(" -> "))));
+                continue;
+            }
+
+            self.load(&import_path, import_key.clone(), stack)?;
+            imports.push(Import {
+                path: import_key,
+                alias,
+            });
+        }
+
+        stack.pop();
+
+        if !diagnostics.is_empty() {
+            return Err(HatchetError::Type {
+                file: script.file,
+                diagnostics,
+            });
+        }
+
+        let module = Arc::new(Module {
+            script,
+            imports,
+        });
+
+        // The imports of a module loaded from several threads at once may be
+        // resolved twice, but its file is only parsed once and the first result kept
+        let mut modules = self.modules.lock().unwrap();
+        Ok(modules.entry(key).or_insert(module).clone())
+    }
+
+    /// Load a script, merging all the modules it imports in its body
+    pub fn script<P: AsRef<FsPath>>(&self, path: P) -> HatchetResult<Script> {
+        let path = path.as_ref();
+        let key = fs::canonicalize(path).map_err(|err| HatchetError::io(path, err))?;
+
+        let root = self.load(path, key, &mut Vec::new())?;
+
+        let modules = self.modules.lock().unwrap();
+        Ok(link(&root, &modules))
+    }
+}
+
+/// Get the dependencies of a module, each module coming after its own dependencies
+fn dependencies<'a>(module: &'a Module, modules: &'a HashMap<PathBuf, Arc<Module>>, order: &mut Vec<&'a PathBuf>) {
+    for import in &module.imports {
+        if !order.contains(&&import.path) {
+            dependencies(&modules[&import.path], modules, order);
+            order.push(&import.path);
+        }
+    }
+}
+
+/// Get the name of a declaration of a module in the merged script
+/// Relays become entities of the map, so the separator has to be valid in
+/// a targetname: this is the one VBSP uses to prefix the entities of instances
+fn qualify(prefix: &Atom, name: &Atom) -> Atom {
+    Atom::from(format!("{}-{}", prefix, name))
+}
+
+/// Merge the modules imported by a script in its body, in dependency order
+/// The code of each module is only included once, even if imported several times
+fn link(root: &Module, modules: &HashMap<PathBuf, Arc<Module>>) -> Script {
+    if root.imports.is_empty() {
+        return root.script.clone();
+    }
+
+    let mut order = Vec::new();
+    dependencies(root, modules, &mut order);
+
+    // Modules are prefixed with their file name, made unique
+    let mut prefixes: HashMap<&PathBuf, Atom> = HashMap::new();
+    for path in &order {
+        let stem: String = {
+            modules[*path].script.file.path
+                .file_stem()
+                .map_or(String::from("module"), |stem| stem.to_string_lossy().into_owned())
+                .chars()
+                .map(|chr| match chr {
+                    'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => chr,
+                    _ => '_',
+                })
+                .collect()
+        };
+
+        let mut prefix = Atom::from(stem.clone());
+        let mut index = 1;
+        while prefixes.values().any(|used| *used == prefix) {
+            index += 1;
+            prefix = Atom::from(format!("{}-{}", stem, index));
+        }
+
+        prefixes.insert(*path, prefix);
+    }
+
+    let aliases = |module: &Module| -> HashMap<Atom, Atom> {
+        module.imports.iter()
+            .map(|import| (import.alias.clone(), prefixes[&import.path].clone()))
+            .collect()
+    };
+
+    let mut body = Vec::new();
+    let mut imports = Vec::new();
+    let mut offset = root.script.file.text.len() + 1;
+
+    for path in &order {
+        let module = &modules[*path];
+        let rewriter = Rewriter {
+            prefix: Some(prefixes[path].clone()),
+            aliases: aliases(module),
+            offset,
+        };
+
+        body.extend(rewriter.block(module.script.body.clone(), &mut Vec::new(), true));
+        imports.push((offset, module.script.file.clone()));
+        offset += module.script.file.text.len() + 1;
+    }
+
+    let rewriter = Rewriter {
+        prefix: None,
+        aliases: aliases(root),
+        offset: 0,
+    };
+
+    body.extend(rewriter.block(root.script.body.clone(), &mut Vec::new(), true));
+
+    Script {
+        body,
+        file: Arc::new(SourceFile {
+            path: root.script.file.path.clone(),
+            text: root.script.file.text.clone(),
+            imports,
+        }),
+    }
+}
+
+/// Names declared in each nested block, mapped to their new name if they are renamed
+type Names = Vec<HashMap<Atom, Option<Atom>>>;
+
+/// Rewrites the AST of a file to be merged in a script
+struct Rewriter {
+    /// Prefix of the top-level declarations of a module,
+    /// None for the main script which is not renamed
+    prefix: Option<Atom>,
+    /// Prefixes of the modules imported by this file
+    aliases: HashMap<Atom, Atom>,
+    /// Offset added to the spans of this file
+    offset: usize,
+}
+
+impl Rewriter {
+    fn span(&self, span: Span) -> Span {
+        Span {
+            start: span.start + self.offset,
+            end: span.end + self.offset,
+        }
+    }
+
+    /// Declare a name in the current block, prefixing
+    /// it if it is a declaration visible from other files
+    fn declare(&self, names: &mut Names, name: &Atom, public: bool) {
+        let renamed = match self.prefix {
+            Some(ref prefix) if public => Some(qualify(prefix, name)),
+            _ => None,
+        };
+
+        names.last_mut().unwrap().insert(name.clone(), renamed);
+    }
+
+    fn resolve(&self, names: &Names, name: Atom) -> Atom {
+        for block in names.iter().rev() {
+            if let Some(renamed) = block.get(&name) {
+                return renamed.clone().unwrap_or(name);
+            }
+        }
+
+        name
+    }
+
+    fn path(&self, Path { kind, span }: Path, names: &Names) -> Path {
+        let span = self.span(span);
+        let kind = match kind {
+            PathKind::Deref(obj, prop) => {
+                if let PathKind::Binding(ref module) = obj.kind {
+                    if let Some(prefix) = self.aliases.get(module) {
+                        return Path {
+                            kind: PathKind::Binding(qualify(prefix, &prop)),
+                            span,
+                        };
+                    }
+                }
+
+                PathKind::Deref(box self.path(*obj, names), prop)
+            },
+            PathKind::Instance(obj) => PathKind::Instance(box self.path(*obj, names)),
+            PathKind::Binding(name) => PathKind::Binding(self.resolve(names, name)),
+        };
+
+        Path { kind, span }
+    }
+
+    fn call(&self, Call { path, args, span }: Call, names: &Names) -> Call {
+        Call {
+            path: self.path(path, names),
+            args: {
+                args.into_iter()
+                    .map(|arg| self.expression(arg, names))
+                    .collect()
+            },
+            span: self.span(span),
+        }
+    }
+
+    fn expression(&self, Expression { kind, span }: Expression, names: &Names) -> Expression {
+        let kind = match kind {
+            ExpressionKind::Call(call) => ExpressionKind::Call(self.call(call, names)),
+            ExpressionKind::Array(values) => ExpressionKind::Array(
+                values.into_iter()
+                    .map(|value| self.expression(value, names))
+                    .collect()
+            ),
+            ExpressionKind::Map(values) => ExpressionKind::Map(
+                values.into_iter()
+                    .map(|(key, value)| (key, self.expression(value, names)))
+                    .collect()
+            ),
+            ExpressionKind::Reference(path) => ExpressionKind::Reference(self.path(path, names)),
+            ExpressionKind::Index { expr, index } => ExpressionKind::Index {
+                expr: box self.expression(*expr, names),
+                index: box self.expression(*index, names),
+            },
+            ExpressionKind::Slice { expr, start, end } => ExpressionKind::Slice {
+                expr: box self.expression(*expr, names),
+                start: start.map(|start| box self.expression(*start, names)),
+                end: end.map(|end| box self.expression(*end, names)),
+            },
+            ExpressionKind::Field { expr, name } => ExpressionKind::Field {
+                expr: box self.expression(*expr, names),
+                name,
+            },
+            ExpressionKind::Range { start, end, inclusive } => ExpressionKind::Range {
+                start: box self.expression(*start, names),
+                end: box self.expression(*end, names),
+                inclusive,
+            },
+            ExpressionKind::Conditional { condition, consequent, alternate } => ExpressionKind::Conditional {
+                condition: box self.expression(*condition, names),
+                consequent: box self.expression(*consequent, names),
+                alternate: box self.expression(*alternate, names),
+            },
+            ExpressionKind::Unary { op, expr } => ExpressionKind::Unary {
+                op,
+                expr: box self.expression(*expr, names),
+            },
+            ExpressionKind::Cast { expr, ty } => ExpressionKind::Cast {
+                expr: box self.expression(*expr, names),
+                ty,
+            },
+            ExpressionKind::Binary { lhs, op, rhs } => ExpressionKind::Binary {
+                lhs: box self.expression(*lhs, names),
+                op,
+                rhs: box self.expression(*rhs, names),
+            },
+            ExpressionKind::Literal(Literal::String(parts)) => ExpressionKind::Literal(Literal::String(
+                parts.into_iter()
+                    .map(|part| match part {
+                        StringPart::Expression(exp, format) => StringPart::Expression(self.expression(exp, names), format),
+                        part => part,
+                    })
+                    .collect()
+            )),
+            kind @ ExpressionKind::Literal(_) => kind,
+        };
+
+        Expression {
+            kind,
+            span: self.span(span),
+        }
+    }
+
+    /// Rewrite a nested block, with its own declarations
+    fn nested(&self, list: Vec<Statement>, names: &mut Names) -> Vec<Statement> {
+        self.block(list, names, false)
+    }
+
+    fn block(&self, list: Vec<Statement>, names: &mut Names, top_level: bool) -> Vec<Statement> {
+        names.push(HashMap::new());
+
+        // Relays are entities of the map and always prefixed, functions and
+        // bindings are only prefixed at the top level of a module
+        for stmt in &list {
+            match stmt.kind {
+                StatementKind::Relay { ref name, .. } => self.declare(names, name, true),
+                StatementKind::Function(Function { ref name, .. }) if top_level => self.declare(names, name, true),
+                StatementKind::Binding { ref pattern, .. } if top_level => {
+                    for name in pattern.names() {
+                        self.declare(names, name, true);
+                    }
+                },
+                StatementKind::Function(Function { ref name, .. }) => self.declare(names, name, false),
+                _ => {},
+            }
+        }
+
+        let res = {
+            list.into_iter()
+                .map(|Statement { kind, span }| {
+                    let kind = match kind {
+                        StatementKind::Auto { body } => StatementKind::Auto {
+                            body: self.nested(body, names),
+                        },
+                        StatementKind::Relay { name, body } => StatementKind::Relay {
+                            name: self.resolve(names, name),
+                            body: self.nested(body, names),
+                        },
+                        StatementKind::Subscriber { path, body } => StatementKind::Subscriber {
+                            path: self.path(path, names),
+                            body: self.nested(body, names),
+                        },
+                        StatementKind::Delay { time, body } => StatementKind::Delay {
+                            time: self.expression(time, names),
+                            body: self.nested(body, names),
+                        },
+                        StatementKind::Loop { condition, body } => StatementKind::Loop {
+                            condition: self.expression(condition, names),
+                            body: self.nested(body, names),
+                        },
+                        StatementKind::Iterator { var, value, array, body } => {
+                            let array = self.expression(array, names);
+
+                            names.push(HashMap::new());
+                            for name in var.names().into_iter().chain(value.iter().flat_map(Pattern::names)) {
+                                self.declare(names, name, false);
+                            }
+                            let body = self.nested(body, names);
+                            names.pop();
+
+                            StatementKind::Iterator { var, value, array, body }
+                        },
+                        StatementKind::Branch { condition, consequent, alternate } => StatementKind::Branch {
+                            condition: self.expression(condition, names),
+                            consequent: self.nested(consequent, names),
+                            alternate: alternate.map(|alternate| self.nested(alternate, names)),
+                        },
+                        StatementKind::BranchLet { name, value, consequent, alternate } => {
+                            let value = self.expression(value, names);
+
+                            names.push(HashMap::new());
+                            self.declare(names, &name, false);
+                            let consequent = self.nested(consequent, names);
+                            names.pop();
+
+                            StatementKind::BranchLet {
+                                name, value, consequent,
+                                alternate: alternate.map(|alternate| self.nested(alternate, names)),
+                            }
+                        },
+                        StatementKind::Match { value, arms } => StatementKind::Match {
+                            value: self.expression(value, names),
+                            arms: arms.into_iter()
+                                .map(|MatchArm { values, body, span }| {
+                                    let values = values.map(|values| {
+                                        values.into_iter()
+                                            .map(|value| self.expression(value, names))
+                                            .collect()
+                                    });
+
+                                    MatchArm {
+                                        values,
+                                        body: self.nested(body, names),
+                                        span: self.span(span),
+                                    }
+                                })
+                                .collect(),
+                        },
+                        StatementKind::Binding { pattern, ty, value } => {
+                            let value = self.expression(value, names);
+                            if !top_level {
+                                for name in pattern.names() {
+                                    self.declare(names, name, false);
+                                }
+                            }
+
+                            StatementKind::Binding {
+                                pattern: pattern.map(|name| self.resolve(names, name)),
+                                ty,
+                                value,
+                            }
+                        },
+                        StatementKind::Assignment { target, op, value } => StatementKind::Assignment {
+                            target: self.expression(target, names),
+                            op,
+                            value: self.expression(value, names),
+                        },
+                        StatementKind::Call(call) => StatementKind::Call(self.call(call, names)),
+                        StatementKind::Function(Function { name, args, body, span }) => {
+                            names.push(HashMap::new());
+                            for arg in &args {
+                                self.declare(names, arg, false);
+                            }
+                            let body = self.nested(body, names);
+                            names.pop();
+
+                            StatementKind::Function(Function {
+                                name: self.resolve(names, name),
+                                args,
+                                body,
+                                span: self.span(span),
+                            })
+                        },
+                        StatementKind::Return(value) => StatementKind::Return(
+                            value.map(|value| self.expression(value, names))
+                        ),
+                        kind @ StatementKind::Break |
+                        kind @ StatementKind::Continue |
+                        kind @ StatementKind::Import { .. } |
+                        kind @ StatementKind::Error(_) => kind,
+                    };
+
+                    Statement {
+                        kind,
+                        span: self.span(span),
+                    }
+                })
+                .collect()
+        };
+
+        names.pop();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use rayon::prelude::*;
+
+    use super::*;
+
+    /// Write the files of a test in a fresh temporary directory
+    fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hatchet-loader-{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        for &(name, text) in files {
+            File::create(dir.join(name)).unwrap()
+                .write_all(text.as_bytes()).unwrap();
+        }
+
+        dir
+    }
+
+    /// Get the messages of the errors reported when loading a script
+    fn errors(path: PathBuf) -> Vec<String> {
+        match Loader::new(Vec::new()).script(path) {
+            Err(HatchetError::Type { diagnostics, .. }) => {
+                diagnostics.into_iter()
+                    .map(|diag| diag.message)
+                    .collect()
+            },
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the script was loaded"),
+        }
+    }
+
+    fn call_name(stmt: &Statement) -> Atom {
+        match stmt.kind {
+            StatementKind::Call(Call { path: Path { kind: PathKind::Binding(ref name), .. }, .. }) => name.clone(),
+            ref kind => panic!("expected a call, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn prefixes_module_declarations() {
+        let dir = files("prefix", &[
+            ("doors.hct", "relay open {\n    door.Open()\n}\nfn close() {\n    door.Close()\n}\n"),
+            ("main.hct", "import \"doors.hct\"\nimport \"doors.hct\" as d\nauto {\n    doors.open()\n    d.close()\n}\n"),
+        ]);
+
+        let script = Loader::new(Vec::new()).script(dir.join("main.hct")).unwrap();
+
+        // The module is only included once
+        assert_eq!(script.body.len(), 3);
+        match script.body[0].kind {
+            StatementKind::Relay { ref name, .. } => assert_eq!(*name, Atom::from("doors-open")),
+            ref kind => panic!("expected a relay, found {:?}", kind),
+        }
+        match script.body[1].kind {
+            StatementKind::Function(ref func) => assert_eq!(func.name, Atom::from("doors-close")),
+            ref kind => panic!("expected a function, found {:?}", kind),
+        }
+        match script.body[2].kind {
+            StatementKind::Auto { ref body } => {
+                assert_eq!(call_name(&body[0]), Atom::from("doors-open"));
+                assert_eq!(call_name(&body[1]), Atom::from("doors-close"));
+            },
+            ref kind => panic!("expected an auto block, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn reports_missing_modules() {
+        let dir = files("missing", &[
+            ("main.hct", "import \"missing.hct\"\nimport \"my.doors.hct\"\n"),
+        ]);
+
+        assert_eq!(errors(dir.join("main.hct")), vec![
+            "module \"missing.hct\" not found",
+            "cannot use the file name of \"my.doors.hct\" as a module name, add `as <name>`",
+        ]);
+    }
+
+    #[test]
+    fn reports_import_cycles() {
+        let dir = files("cycle", &[
+            ("a.hct", "import \"b.hct\"\n"),
+            ("b.hct", "import \"a.hct\"\n"),
+        ]);
+
+        let errors = errors(dir.join("a.hct"));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("import cycle: "), "{}", errors[0]);
+        assert!(errors[0].ends_with("a.hct"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn parses_each_file_once() {
+        let mains: Vec<_> = (0..8).map(|idx| format!("main{}.hct", idx)).collect();
+        let mut sources = vec![
+            ("shared.hct", "relay open {\n}\n"),
+            ("late.hct", "import \"shared.hct\"\n"),
+        ];
+        sources.extend(mains.iter().map(|name| (name.as_str(), "import \"shared.hct\"\n")));
+        let dir = files("once", &sources);
+
+        let loader = Loader::new(Vec::new());
+        let loaded: Vec<_> = mains.par_iter().map(|name| loader.script(dir.join(name)).is_ok()).collect();
+        assert_eq!(loaded, vec![true; mains.len()]);
+
+        // Later imports get the module parsed by the first one
+        File::create(dir.join("shared.hct")).unwrap()
+            .write_all(b"relay {\n").unwrap();
+        let script = loader.script(dir.join("late.hct")).unwrap();
+        assert_eq!(script.body.len(), 1);
+    }
+}
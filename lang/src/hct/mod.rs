@@ -1,40 +1,241 @@
 pub mod ast;
+pub mod diagnostic;
+pub mod loader;
 pub mod parser;
 mod expression;
 
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::File;
-use synom::*;
-use synom::space::*;
+use std::sync::Arc;
 use log::LogLevel::Trace;
 
+use error::*;
 use logging::*;
-use self::ast::Script;
+use self::ast::{Script, Span, Statement, StatementKind};
+use self::diagnostic::Diagnostic;
 use self::parser::*;
 
+/// A script source file, kept around to render diagnostics
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub text: String,
+    /// Files of the modules merged in this script, along with the
+    /// offset added to their spans to keep them apart from this file
+    pub imports: Vec<(usize, Arc<SourceFile>)>,
+}
+
+impl SourceFile {
+    /// Get the 1-based line and column of a byte offset in this file
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = match before.rfind('\n') {
+            Some(pos) => before[pos + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+
+        (line, column)
+    }
+
+    /// Get the file a span of this script belongs to, along
+    /// with the span relative to the start of that file
+    pub fn resolve(&self, span: Span) -> (&SourceFile, Span) {
+        for &(offset, ref file) in &self.imports {
+            if span.start >= offset && span.start <= offset + file.text.len() {
+                return (file, Span {
+                    start: span.start - offset,
+                    end: span.end - offset,
+                });
+            }
+        }
+
+        (self, span)
+    }
+}
+
+/// Recursively gather the syntax errors of a parsed block
+fn collect_errors(body: &[Statement], errors: &mut Vec<Diagnostic>) {
+    for stmt in body {
+        if let StatementKind::Error(ref message) = stmt.kind {
+            errors.push(Diagnostic::error(stmt.span, message.clone()));
+        }
+
+        for block in stmt.kind.blocks() {
+            collect_errors(block, errors);
+        }
+    }
+}
+
 /// Parses a script file, returning its AST
-pub fn parse_file<P>(path: P) -> Script where P: AsRef<Path> {
-    let mut f = File::open(path).expect("could not open file");
+pub fn parse_file<P>(path: P) -> HatchetResult<Script> where P: AsRef<Path> {
+    let path = path.as_ref();
     let mut s = String::new();
-    f.read_to_string(&mut s).expect("could not read file");
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|err| HatchetError::io(path, err))?;
+
+    parse_source(path, s)
+}
+
+/// Parses the source of a script, reporting all of its syntax errors
+pub fn parse_source<P>(path: P, text: String) -> HatchetResult<Script> where P: AsRef<Path> {
+    let file = Arc::new(SourceFile {
+        path: path.as_ref().to_path_buf(),
+        text,
+        imports: Vec::new(),
+    });
 
     let parse_start = timer_start!();
 
-    let res = match script(&s) {
-        IResult::Done(rem, script) => {
-            if log_enabled!(Trace) {
-                trace!("Parser output:");
-                print_script(&script).unwrap();
-            }
+    let body = script(&file.text);
+
+    let mut diagnostics = Vec::new();
+    collect_errors(&body, &mut diagnostics);
+    if !diagnostics.is_empty() {
+        return Err(HatchetError::Parse {
+            file,
+            diagnostics,
+        });
+    }
 
-            assert_eq!(skip_whitespace(rem), "");
-            script
-        },
-        IResult::Error => panic!("hatchet parser error"),
+    let res = Script {
+        body,
+        file,
     };
 
+    if log_enabled!(Trace) {
+        trace!("Parser output:");
+        print_script(&res).unwrap();
+    }
+
     timer_end!(parse_start, time, "Parsing time: {}", time);
 
-    res
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ast::*;
+
+    /// Get the text of a span in a source
+    fn text(source: &str, span: Span) -> &str {
+        &source[span.start..span.end]
+    }
+
+    /// Get the line and message of each syntax error in a source
+    fn errors(source: &str) -> Vec<(usize, String)> {
+        match parse_source("test.hct", String::from(source)) {
+            Err(HatchetError::Parse { file, diagnostics }) => {
+                diagnostics.into_iter()
+                    .map(|diag| (file.location(diag.span.start).0, diag.message))
+                    .collect()
+            },
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn spans_cover_their_nodes() {
+        let source = "let a = b + 1\nfoo(a)\n";
+        let script = parse_source("test.hct", String::from(source)).unwrap();
+
+        assert_eq!(text(source, script.body[0].span), "let a = b + 1");
+        match script.body[0].kind {
+            StatementKind::Binding { ref value, .. } => {
+                assert_eq!(text(source, value.span), "b + 1");
+                match value.kind {
+                    ExpressionKind::Binary { ref lhs, ref rhs, .. } => {
+                        assert_eq!(text(source, lhs.span), "b");
+                        assert_eq!(text(source, rhs.span), "1");
+                    },
+                    ref kind => panic!("expected a binary expression, found {:?}", kind),
+                }
+            },
+            ref kind => panic!("expected a binding, found {:?}", kind),
+        }
+
+        match script.body[1].kind {
+            StatementKind::Call(ref call) => {
+                assert_eq!(text(source, call.path.span), "foo");
+                assert_eq!(text(source, call.args[0].span), "a");
+            },
+            ref kind => panic!("expected a call, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn spans_are_offsets_from_the_start() {
+        let source = "let a = \"n = ${b * 2:03}\"\n";
+        let script = parse_source("test.hct", String::from(source)).unwrap();
+
+        assert_eq!(script.body[0].span, Span { start: 0, end: 25 });
+        match script.body[0].kind {
+            StatementKind::Binding { value: Expression { kind: ExpressionKind::Literal(Literal::String(ref parts)), .. }, .. } => {
+                match parts[1] {
+                    StringPart::Expression(ref exp, _) => {
+                        assert_eq!(exp.span, Span { start: 15, end: 20 });
+                        assert_eq!(text(source, exp.span), "b * 2");
+                    },
+                    ref part => panic!("expected an interpolation, found {:?}", part),
+                }
+            },
+            ref kind => panic!("expected a string binding, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn locations_are_one_based() {
+        let file = SourceFile {
+            path: PathBuf::from("test.hct"),
+            text: String::from("a\nbé c"),
+            imports: Vec::new(),
+        };
+
+        assert_eq!(file.location(0), (1, 1));
+        assert_eq!(file.location(2), (2, 1));
+        // Columns count characters, not bytes
+        assert_eq!(file.location(6), (2, 4));
+    }
+
+    #[test]
+    fn reports_every_syntax_error() {
+        let source = "let = 1\nlet b = 2\nauto {\n    let = 3\n    let d = 4\n}\nlet = 5\n";
+        let message = String::from("expected a binding name, found `=`");
+        assert_eq!(errors(source), vec![
+            (1, message.clone()),
+            (4, message.clone()),
+            (7, message),
+        ]);
+    }
+
+    #[test]
+    fn recovery_stops_at_the_end_of_the_block() {
+        let source = "auto {\n    foo(\n}\nlet x = 1\n";
+        assert_eq!(errors(source), vec![
+            (3, String::from("expected an expression, found `}`")),
+        ]);
+
+        let body = parser::script(source);
+        assert_eq!(body.len(), 2);
+        match body[0].kind {
+            StatementKind::Auto { ref body } => {
+                assert_eq!(body.len(), 1);
+                assert!(match body[0].kind { StatementKind::Error(_) => true, _ => false });
+            },
+            ref kind => panic!("expected an auto block, found {:?}", kind),
+        }
+        assert!(match body[1].kind { StatementKind::Binding { .. } => true, _ => false });
+    }
+
+    #[test]
+    fn recovery_skips_braces_in_strings() {
+        let source = "auto {\n    foo(\"}\", \"${bar(\"}\")}\",\n}\nlet x = 1\n";
+        assert_eq!(errors(source), vec![
+            (3, String::from("expected an expression, found `}`")),
+        ]);
+    }
 }
@@ -1,9 +1,37 @@
+use std::cell::Cell;
+
 use synom::*;
 use synom::space::*;
 use super::ast::*;
 use super::expression::*;
 use atom::Atom;
 
+thread_local! {
+    /// Offset in the source file of the end of the input being parsed
+    static INPUT_END: Cell<usize> = Cell::new(0);
+}
+
+/// Runs a parser on an input ending at the given offset of the source file
+fn ending_at<T, F>(end: usize, parser: F) -> T where F: FnOnce() -> T {
+    let outer = INPUT_END.with(|cell| cell.replace(end));
+    let res = parser();
+    INPUT_END.with(|cell| cell.set(outer));
+    res
+}
+
+/// Get the offset in the source file of the start of the remaining input
+fn offset(rem: &str) -> usize {
+    INPUT_END.with(|cell| cell.get() - rem.len())
+}
+
+/// Creates a span between the start of two slices of the same input
+fn span_between(start: &str, end: &str) -> Span {
+    Span {
+        start: offset(start),
+        end: offset(end),
+    }
+}
+
 fn position<P>(input: &str, predicate: P) -> Option<usize> where P: Fn(&str) -> IResult<&str, &str> {
     for o in 0..input.len() {
         if let IResult::Done(_, _) = predicate(&input[o..]) {
@@ -26,27 +54,204 @@ macro_rules! take_till {
     };
 }
 
+/// Find the closing quote of a string literal, the input starting after the
+/// opening one. Escaped characters are skipped, as well as the strings nested
+/// in interpolations
+fn string_end(input: &str) -> Option<usize> {
+    let mut idx = 0;
+    while let Some(chr) = input[idx..].chars().next() {
+        match chr {
+            '"' => return Some(idx),
+            '\\' => {
+                idx += 1;
+                if let Some(next) = input[idx..].chars().next() {
+                    idx += next.len_utf8();
+                }
+
+                continue;
+            },
+            '$' if input[idx + 1..].starts_with('{') => {
+                let start = idx + 2;
+                match interpolation_end(&input[start..]) {
+                    Some(end) => idx = start + end + 1,
+                    None => return None,
+                }
+
+                continue;
+            },
+            _ => {},
+        }
+
+        idx += chr.len_utf8();
+    }
+
+    None
+}
+
+/// Find the closing brace of an interpolation, skipping nested blocks and strings
+fn interpolation_end(input: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut idx = 0;
+    while let Some(chr) = input[idx..].chars().next() {
+        match chr {
+            '"' => match string_end(&input[idx + 1..]) {
+                Some(end) => {
+                    idx += end + 2;
+                    continue;
+                },
+                None => return None,
+            },
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(idx),
+            '}' => depth -= 1,
+            _ => {},
+        }
+
+        idx += chr.len_utf8();
+    }
+
+    None
+}
+
+/// Parses the format of an interpolated number, like `03` or `.2`
+fn format_spec(input: &str) -> Option<FormatSpec> {
+    let input = input.trim();
+    let (width, precision) = match input.find('.') {
+        Some(idx) => (&input[..idx], Some(&input[idx + 1..])),
+        None => (input, None),
+    };
+
+    let is_digits = |val: &str| !val.is_empty() && val.chars().all(|c| c.is_digit(10));
+    let is_valid = match precision {
+        Some(precision) => (width.is_empty() || is_digits(width)) && is_digits(precision),
+        None => is_digits(width),
+    };
+
+    if !is_valid {
+        return None;
+    }
+
+    Some(FormatSpec {
+        zero: width.len() > 1 && width.starts_with('0'),
+        width: width.parse().ok(),
+        precision: precision.and_then(|val| val.parse().ok()),
+    })
+}
+
+/// Parses an interpolated expression, with an optional format after a colon
+/// The format is split off first, as the colon would otherwise be read as an
+/// instance path
+fn interpolation(input: &str) -> IResult<&str, StringPart> {
+    let input = match tag!(input, "${") {
+        IResult::Done(rem, _) => rem,
+        IResult::Error => return IResult::Error,
+    };
+
+    let end = match interpolation_end(input) {
+        Some(end) => end,
+        None => return IResult::Error,
+    };
+
+    let inner = &input[..end];
+    let (source, format) = match inner.rfind(':') {
+        Some(idx) => match format_spec(&inner[idx + 1..]) {
+            Some(format) => (&inner[..idx], Some(format)),
+            None => (inner, None),
+        },
+        None => (inner, None),
+    };
+
+    let exp = match ending_at(offset(input) + source.len(), || expression(source)) {
+        IResult::Done(rem, exp) => if rem.trim().is_empty() {
+            Some(exp)
+        } else {
+            None
+        },
+        IResult::Error => None,
+    };
+
+    match exp {
+        Some(exp) => IResult::Done(&input[end + 1..], StringPart::Expression(exp, format)),
+        None => IResult::Error,
+    }
+}
+
+/// Parses an escape sequence in a string literal
+fn escape(input: &str) -> IResult<&str, char> {
+    let mut chars = input.chars();
+    if chars.next() != Some('\\') {
+        return IResult::Error;
+    }
+
+    let chr = match chars.next() {
+        Some('"') => '"',
+        Some('\\') => '\\',
+        Some('$') => '$',
+        Some('n') => '\n',
+        Some('t') => '\t',
+        _ => return IResult::Error,
+    };
+
+    IResult::Done(chars.as_str(), chr)
+}
+
+/// Join the consecutive text parts of a string literal
+fn merge_strings(parts: Vec<StringPart>) -> Vec<StringPart> {
+    let mut res = Vec::with_capacity(parts.len());
+    for part in parts {
+        if let StringPart::String(ref s) = part {
+            if let Some(&mut StringPart::String(ref mut prev)) = res.last_mut() {
+                prev.push_str(s);
+                continue;
+            }
+        }
+
+        res.push(part);
+    }
+
+    res
+}
+
 named!(
     pub string -> Vec<StringPart>,
-    delimited!(
-        punct!("\""),
-        many0!(alt!(
-            delimited!(
-                punct!("${"),
-                expression,
-                punct!("}")
-            ) => { StringPart::Expression } |
+    map!(
+        delimited!(
+            punct!("\""),
+            many0!(alt!(
+                interpolation |
 
-            take_till!(alt!(
-                tag!("\"") | tag!("${")
-            )) => { |s| {
-                StringPart::String(String::from(s))
-            }}
-        )),
-        punct!("\"")
+                escape => { |chr: char| StringPart::String(chr.to_string()) } |
+
+                take_till!(alt!(
+                    tag!("\"") | tag!("${") | tag!("\\")
+                )) => { |s| {
+                    StringPart::String(String::from(s))
+                }}
+            )),
+            punct!("\"")
+        ),
+        merge_strings
     )
 );
 
+/// Parses a string literal without interpolations
+pub fn plain_string(input: &str) -> IResult<&str, String> {
+    match string(input) {
+        IResult::Done(rem, parts) => {
+            let mut res = String::new();
+            for part in parts {
+                match part {
+                    StringPart::String(s) => res.push_str(&s),
+                    StringPart::Expression(..) => return IResult::Error,
+                }
+            }
+
+            IResult::Done(rem, res)
+        },
+        IResult::Error => IResult::Error,
+    }
+}
+
 pub fn digits(input: &str) -> IResult<&str, &str> {
     let input = skip_whitespace(input);
     let input_length = input.len();
@@ -70,6 +275,20 @@ pub fn digits(input: &str) -> IResult<&str, &str> {
     IResult::Done(&input[input_length..], input)
 }
 
+/// Wraps the result of a parser with the span of input it consumed
+macro_rules! spanned {
+    ($i:expr, $submac:ident!( $($args:tt)* )) => {{
+        let start = skip_whitespace($i);
+        match $submac!(start, $($args)*) {
+            IResult::Done(rem, val) => IResult::Done(rem, (val, span_between(start, rem))),
+            IResult::Error => IResult::Error,
+        }
+    }};
+    ($i:expr, $f:expr) => {
+        spanned!($i, call!($f))
+    };
+}
+
 macro_rules! recognize (
     ($i:expr, $submac:ident!( $($args:tt)* )) => ({
         match $submac!($i, $($args)*) {
@@ -104,47 +323,90 @@ named!(
     )
 );
 
-pub fn name(input: &str) -> IResult<&str, Atom> {
+/// Parses an integer literal, in decimal or hexadecimal notation
+pub fn integer(input: &str) -> IResult<&str, i64> {
     let input = skip_whitespace(input);
-    let input_length = input.len();
-    if input_length == 0 {
+    let (digits, radix) = if input.starts_with("0x") || input.starts_with("0X") {
+        (&input[2..], 16)
+    } else {
+        (input, 10)
+    };
+
+    let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or_else(|| digits.len());
+    if len == 0 {
         return IResult::Error
     }
 
-    for (idx, item) in input.chars().enumerate() {
-        match item {
-            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' | '$' | '@' => {},
-            _ => {
-                if idx == 0 {
-                    return IResult::Error
-                } else {
-                    return IResult::Done(&input[idx..], Atom::from(&input[..idx]))
-                }
-            },
-        }
+    // Numbers with a fractional part or an exponent are floats,
+    // but an integer can start a range
+    let rem = &digits[len..];
+    let fraction = rem.starts_with('.') && !rem.starts_with("..");
+    if radix == 10 && (fraction || rem.starts_with('e') || rem.starts_with('E')) {
+        return IResult::Error
     }
 
-    match input {
-        "auto" | "relay" | "delay" |
-        "while" | "for" | "in" | "if" | "else" | "let" => IResult::Error,
-        name => IResult::Done(&input[input_length..], Atom::from(name)),
+    match i64::from_str_radix(&digits[..len], radix) {
+        Ok(val) => IResult::Done(rem, val),
+        Err(_) => IResult::Error,
+    }
+}
+
+/// Reserved words that cannot be used as names
+pub static KEYWORDS: &'static [&'static str] = &[
+    "auto", "relay", "delay", "fn", "return", "import", "as",
+    "while", "for", "in", "if", "else", "let", "break", "continue",
+    "true", "false", "match",
+];
+
+fn is_name_char(chr: char) -> bool {
+    match chr {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' | '$' | '@' => true,
+        _ => false,
+    }
+}
+
+pub fn name(input: &str) -> IResult<&str, Atom> {
+    let input = skip_whitespace(input);
+    let len = input.find(|c| !is_name_char(c)).unwrap_or_else(|| input.len());
+    if len == 0 {
+        return IResult::Error
+    }
+
+    let (name, rem) = input.split_at(len);
+    if KEYWORDS.contains(&name) {
+        IResult::Error
+    } else {
+        IResult::Done(rem, Atom::from(name))
     }
 }
 
 named!(
-    name_chain -> Vec<Atom>,
+    spanned_name -> (Atom, Span),
+    spanned!(name)
+);
+
+named!(
+    name_chain -> Vec<(Atom, Span)>,
     separated_nonempty_list!(
         punct!("."),
-        name
+        spanned_name
     )
 );
 
 #[inline]
-fn fold_path(path: Option<Path>, name: Atom) -> Option<Path> {
-    let name = Atom::from(name);
+fn fold_path(path: Option<Path>, (name, span): (Atom, Span)) -> Option<Path> {
     Some(match path {
-        Some(p) => Path::Deref(box p, name),
-        None => Path::Binding(name),
+        Some(p) => {
+            let span = p.span.to(span);
+            Path {
+                kind: PathKind::Deref(box p, name),
+                span,
+            }
+        },
+        None => Path {
+            kind: PathKind::Binding(name),
+            span,
+        },
     })
 }
 
@@ -159,7 +421,13 @@ named!(
                     inst.into_iter()
                         .flat_map(|v| v)
                         .fold(None, fold_path)
-                        .map(|p| Path::Instance(box p)),
+                        .map(|p| {
+                            let span = p.span;
+                            Path {
+                                kind: PathKind::Instance(box p),
+                                span,
+                            }
+                        }),
                     fold_path
                 )
                 .expect("empty list")
@@ -168,31 +436,64 @@ named!(
 );
 
 named!(
-    literal -> Expression,
+    literal -> ExpressionKind,
     alt!(
-        number => { |val| Expression::Literal(Literal::Number(val)) } |
-        string => { |val|  Expression::Literal(Literal::String(val)) }
+        keyword!("true") => { |_| ExpressionKind::Literal(Literal::Bool(true)) } |
+        keyword!("false") => { |_| ExpressionKind::Literal(Literal::Bool(false)) } |
+        integer => { |val| ExpressionKind::Literal(Literal::Int(val)) } |
+        number => { |val| ExpressionKind::Literal(Literal::Number(val)) } |
+        string => { |val| ExpressionKind::Literal(Literal::String(val)) }
     )
 );
 
 named!(
     call -> Call,
+    map!(
+        spanned!(do_parse!(
+            path: path >>
+            args: delimited!(
+                punct!("("),
+                terminated_list!(
+                    punct!(","),
+                    expression
+                ),
+                punct!(")")
+            ) >>
+            (path, args)
+        )),
+        |((path, args), span): ((Path, Vec<Expression>), Span)| Call { path, args, span }
+    )
+);
+
+named!(
+    conditional -> ExpressionKind,
     do_parse!(
-        path: path >>
-        args: delimited!(
-            punct!("("),
-            terminated_list!(
-                punct!(","),
-                expression
-            ),
-            punct!(")")
+        keyword!("if") >>
+        condition: expression >>
+        consequent: delimited!(
+            punct!("{"),
+            expression,
+            punct!("}")
+        ) >>
+        keyword!("else") >>
+        alternate: alt!(
+            spanned!(conditional) => { |(kind, span): (ExpressionKind, Span)| Expression { kind, span } } |
+            delimited!(
+                punct!("{"),
+                expression,
+                punct!("}")
+            )
         ) >>
-        (Call { path, args })
+        (ExpressionKind::Conditional {
+            condition: box condition,
+            consequent: box consequent,
+            alternate: box alternate,
+        })
     )
 );
 
 named!(
-    array -> Expression,
+    array -> ExpressionKind,
     map!(
         delimited!(
             punct!("["),
@@ -202,12 +503,12 @@ named!(
             ),
             punct!("]")
         ),
-        Expression::Array
+        ExpressionKind::Array
     )
 );
 
 named!(
-    map -> Expression,
+    map -> ExpressionKind,
     map!(
         delimited!(
             punct!("{"),
@@ -221,155 +522,951 @@ named!(
             ),
             punct!("}")
         ),
-        |val: Vec<_>| Expression::Map(
+        |val: Vec<_>| ExpressionKind::Map(
             val.into_iter().map(|(a, _, b)| (a, b)).collect()
         )
     )
 );
 
 named!(
-    pub atomic_expression -> Expression,
+    atomic_kind -> ExpressionKind,
     alt!(
         delimited!(
             punct!("("),
             expression,
             punct!(")")
-        ) |
+        ) => { |exp: Expression| exp.kind } |
         literal |
-        call => { Expression::Call } |
+        conditional |
+        call => { ExpressionKind::Call } |
         array | map |
-        path => { Expression::Reference }
+        path => { ExpressionKind::Reference }
+    )
+);
+
+named!(
+    pub atomic_expression -> Expression,
+    map!(
+        spanned!(atomic_kind),
+        |(kind, span): (ExpressionKind, Span)| Expression { kind, span }
     )
 );
 
+/// Index or range between brackets, or field name following an expression
+enum Suffix {
+    Index(Expression),
+    Slice(Option<Expression>, Option<Expression>),
+    Field(Atom),
+}
+
+named!(
+    suffix -> Suffix,
+    alt!(
+        delimited!(
+            punct!("["),
+            alt!(
+                do_parse!(
+                    start: option!(expression) >>
+                    punct!("..") >>
+                    end: option!(expression) >>
+                    (Suffix::Slice(start, end))
+                ) |
+                expression => { Suffix::Index }
+            ),
+            punct!("]")
+        ) |
+        preceded!(punct!("."), name) => { Suffix::Field }
+    )
+);
+
+/// Parses an atomic expression followed by any number of index, slice or field suffixes
+pub fn postfix_expression(input: &str) -> IResult<&str, Expression> {
+    let (mut rem, mut expr) = match atomic_expression(input) {
+        IResult::Done(rem, expr) => (rem, expr),
+        IResult::Error => return IResult::Error,
+    };
+
+    while let IResult::Done(next, (suffix, span)) = spanned!(rem, suffix) {
+        let span = expr.span.to(span);
+        let kind = match suffix {
+            Suffix::Index(index) => ExpressionKind::Index {
+                expr: box expr,
+                index: box index,
+            },
+            Suffix::Slice(start, end) => ExpressionKind::Slice {
+                expr: box expr,
+                start: start.map(Box::new),
+                end: end.map(Box::new),
+            },
+            Suffix::Field(name) => ExpressionKind::Field {
+                expr: box expr,
+                name,
+            },
+        };
+
+        expr = Expression { kind, span };
+        rem = next;
+    }
+
+    IResult::Done(rem, expr)
+}
+
+named!(
+    unary_operator -> UnaryOperator,
+    alt!(
+        punct!("!") => { |_| UnaryOperator::Not } |
+        punct!("-") => { |_| UnaryOperator::Neg } |
+        punct!("~") => { |_| UnaryOperator::BNot }
+    )
+);
+
+/// Parses a postfix expression preceded by any number of unary operators
+pub fn unary_expression(input: &str) -> IResult<&str, Expression> {
+    let (rem, (op, span)) = match spanned!(input, unary_operator) {
+        IResult::Done(rem, res) => (rem, res),
+        IResult::Error => return postfix_expression(input),
+    };
+
+    match unary_expression(rem) {
+        IResult::Done(rem, Expression { kind, span: inner }) => {
+            let kind = match (op, kind) {
+                // Negative literals are folded
+                (UnaryOperator::Neg, ExpressionKind::Literal(Literal::Number(val))) => {
+                    ExpressionKind::Literal(Literal::Number(-val))
+                },
+                (UnaryOperator::Neg, ExpressionKind::Literal(Literal::Int(val))) => {
+                    ExpressionKind::Literal(Literal::Int(val.wrapping_neg()))
+                },
+                (op, kind) => ExpressionKind::Unary {
+                    op,
+                    expr: box Expression { kind, span: inner },
+                },
+            };
+
+            IResult::Done(rem, Expression {
+                kind,
+                span: span.to(inner),
+            })
+        },
+        IResult::Error => IResult::Error,
+    }
+}
+
+/// Parses a unary expression followed by any number of conversions to a type
+pub fn cast_expression(input: &str) -> IResult<&str, Expression> {
+    let (mut rem, mut expr) = match unary_expression(input) {
+        IResult::Done(rem, expr) => (rem, expr),
+        IResult::Error => return IResult::Error,
+    };
+
+    while let IResult::Done(next, (ty, span)) = spanned!(rem, preceded!(keyword!("as"), type_annotation)) {
+        let span = expr.span.to(span);
+        expr = Expression {
+            kind: ExpressionKind::Cast {
+                expr: box expr,
+                ty,
+            },
+            span,
+        };
+        rem = next;
+    }
+
+    IResult::Done(rem, expr)
+}
+
 fn expression(input: &str) -> IResult<&str, Expression> {
     EXPRESSION_PARSER.parse(input)
 }
 
+fn block(input: &str) -> IResult<&str, Vec<Statement>> {
+    match punct!(input, "{") {
+        IResult::Done(rem, _) => {
+            let (rem, body) = statement_list(rem);
+            match punct!(rem, "}") {
+                IResult::Done(rem, _) => IResult::Done(rem, body),
+                IResult::Error => IResult::Error,
+            }
+        },
+        IResult::Error => IResult::Error,
+    }
+}
+
 named!(
-    block -> Vec<Statement>,
-    delimited!(
-        punct!("{"),
-        many0!(statement),
-        punct!("}")
+    import -> StatementKind,
+    do_parse!(
+        keyword!("import") >>
+        path: plain_string >>
+        alias: option!(preceded!(keyword!("as"), name)) >>
+        (StatementKind::Import { path, alias })
     )
 );
 
 named!(
-    auto -> Statement,
+    auto -> StatementKind,
     do_parse!(
         keyword!("auto") >>
         body: block >>
-        (Statement::Auto { body })
+        (StatementKind::Auto { body })
     )
 );
 
 named!(
-    relay -> Statement,
+    relay -> StatementKind,
     do_parse!(
         keyword!("relay") >>
         name: name >>
         body: block >>
-        (Statement::Relay { name, body })
+        (StatementKind::Relay { name, body })
     )
 );
 
 named!(
-    event -> Statement,
+    event -> StatementKind,
     do_parse!(
         path: path >>
         body: block >>
-        (Statement::Subscriber { path, body })
+        (StatementKind::Subscriber { path, body })
     )
 );
 
 named!(
-    delay -> Statement,
+    delay -> StatementKind,
     do_parse!(
         keyword!("delay") >>
         time: expression >>
         body: block >>
-        (Statement::Delay { time, body })
+        (StatementKind::Delay { time, body })
     )
 );
 
 named!(
-    loop_ -> Statement,
+    loop_ -> StatementKind,
     do_parse!(
         keyword!("while") >>
         condition: expression >>
         body: block >>
-        (Statement::Loop { condition, body })
+        (StatementKind::Loop { condition, body })
     )
 );
 
 named!(
-    iterator -> Statement,
+    pattern -> Pattern,
+    alt!(
+        delimited!(
+            punct!("{"),
+            terminated_list!(
+                punct!(","),
+                do_parse!(
+                    field: name >>
+                    binding: option!(preceded!(punct!(":"), name)) >>
+                    ({
+                        let binding = binding.unwrap_or_else(|| field.clone());
+                        (field, binding)
+                    })
+                )
+            ),
+            punct!("}")
+        ) => { Pattern::Object } |
+        delimited!(
+            punct!("["),
+            terminated_list!(
+                punct!(","),
+                name
+            ),
+            punct!("]")
+        ) => { Pattern::Array } |
+        name => { Pattern::Name }
+    )
+);
+
+named!(
+    range -> Expression,
+    map!(
+        spanned!(do_parse!(
+            start: expression >>
+            inclusive: alt!(
+                punct!("..=") => { |_| true } |
+                punct!("..") => { |_| false }
+            ) >>
+            end: expression >>
+            (ExpressionKind::Range { start: box start, end: box end, inclusive })
+        )),
+        |(kind, span): (ExpressionKind, Span)| Expression { kind, span }
+    )
+);
+
+named!(
+    iterator -> StatementKind,
     do_parse!(
         keyword!("for") >>
-        var: name >>
+        var: pattern >>
+        value: option!(preceded!(punct!(","), pattern)) >>
         keyword!("in") >>
-        array: expression >>
+        array: alt!(range | expression) >>
         body: block >>
-        (Statement::Iterator { var, array, body })
+        (StatementKind::Iterator { var, value, array, body })
     )
 );
 
 named!(
-    branch -> Statement,
+    branch -> StatementKind,
     do_parse!(
         keyword!("if") >>
+        binding: option!(
+            delimited!(
+                keyword!("let"),
+                name,
+                punct!("=")
+            )
+        ) >>
         condition: expression >>
         consequent: block >>
         alternate: option!(
             preceded!(
                 keyword!("else"),
                 alt!(
-                    branch => { |alt| vec![ alt ] } |
+                    spanned!(branch) => { |(kind, span): (StatementKind, Span)| vec![ Statement { kind, span } ] } |
                     block
                 )
             )
         ) >>
-        (Statement::Branch { condition, consequent, alternate })
+        (match binding {
+            Some(name) => StatementKind::BranchLet { name, value: condition, consequent, alternate },
+            None => StatementKind::Branch { condition, consequent, alternate },
+        })
+    )
+);
+
+named!(
+    match_arm -> MatchArm,
+    map!(
+        spanned!(do_parse!(
+            values: alt!(
+                keyword!("_") => { |_| None } |
+                separated_nonempty_list!(
+                    punct!("|"),
+                    unary_expression
+                ) => { Some }
+            ) >>
+            punct!("=>") >>
+            body: block >>
+            (values, body)
+        )),
+        |((values, body), span): ((Option<Vec<Expression>>, Vec<Statement>), Span)| MatchArm { values, body, span }
+    )
+);
+
+named!(
+    match_ -> StatementKind,
+    do_parse!(
+        keyword!("match") >>
+        value: expression >>
+        punct!("{") >>
+        arms: many0!(terminated!(
+            match_arm,
+            option!(punct!(","))
+        )) >>
+        punct!("}") >>
+        (StatementKind::Match { value, arms })
+    )
+);
+
+named!(
+    type_annotation -> TypeName,
+    alt!(
+        delimited!(
+            punct!("["),
+            type_annotation,
+            punct!("]")
+        ) => { |ty| TypeName::List(box ty) } |
+        delimited!(
+            punct!("{"),
+            tuple!(
+                type_annotation,
+                punct!(":"),
+                type_annotation
+            ),
+            punct!("}")
+        ) => { |(key, _, value)| TypeName::Dict(box key, box value) } |
+        name => { TypeName::Named }
     )
 );
 
 named!(
-    binding -> Statement,
+    binding -> StatementKind,
     do_parse!(
         keyword!("let") >>
-        name: name >>
+        pattern: pattern >>
+        ty: option!(preceded!(punct!(":"), type_annotation)) >>
         punct!("=") >>
         value: expression >>
-        (Statement::Binding { name, value })
+        (StatementKind::Binding { pattern, ty, value })
     )
 );
 
 named!(
-    assignment -> Statement,
+    assignment -> StatementKind,
     do_parse!(
-        prop: path >>
-        punct!("=") >>
+        target: postfix_expression >>
+        op: alt!(
+            punct!("+=") => { |_| Some(Operator::Add) } |
+            punct!("-=") => { |_| Some(Operator::Sub) } |
+            punct!("*=") => { |_| Some(Operator::Mul) } |
+            punct!("/=") => { |_| Some(Operator::Div) } |
+            punct!("%=") => { |_| Some(Operator::Mod) } |
+            punct!("=") => { |_| None }
+        ) >>
         value: expression >>
-        (Statement::Assignment { prop, value })
+        (StatementKind::Assignment { target, op, value })
     )
 );
 
 named!(
-    call_statement -> Statement,
-    map!(call, Statement::Call)
+    function -> StatementKind,
+    map!(
+        spanned!(do_parse!(
+            keyword!("fn") >>
+            fn_name: name >>
+            args: delimited!(
+                punct!("("),
+                terminated_list!(
+                    punct!(","),
+                    name
+                ),
+                punct!(")")
+            ) >>
+            body: block >>
+            (fn_name, args, body)
+        )),
+        |((name, args, body), span): ((Atom, Vec<Atom>, Vec<Statement>), Span)| {
+            StatementKind::Function(Function { name, args, body, span })
+        }
+    )
 );
 
+/// Parses an expression starting on the current line, so that a
+/// bare `return` does not take the statement following it as its value
+fn same_line_expression(input: &str) -> IResult<&str, Expression> {
+    let rest = skip_whitespace(input);
+    if input[..input.len() - rest.len()].contains('\n') {
+        return IResult::Error;
+    }
+
+    expression(input)
+}
+
 named!(
-    statement -> Statement,
+    return_ -> StatementKind,
+    do_parse!(
+        keyword!("return") >>
+        value: option!(same_line_expression) >>
+        (StatementKind::Return(value))
+    )
+);
+
+named!(
+    break_ -> StatementKind,
+    map!(keyword!("break"), |_| StatementKind::Break)
+);
+
+named!(
+    continue_ -> StatementKind,
+    map!(keyword!("continue"), |_| StatementKind::Continue)
+);
+
+named!(
+    call_statement -> StatementKind,
+    map!(call, StatementKind::Call)
+);
+
+named!(
+    statement_kind -> StatementKind,
     alt!(
-        auto | relay | event | delay |
-        loop_ | iterator | branch | binding | assignment |
+        import | auto | relay | function | return_ | break_ | continue_ | event | delay |
+        loop_ | iterator | branch | match_ | binding | assignment |
         call_statement
     )
 );
 
 named!(
-    pub script -> Script,
-    map!(many0!(statement), |body| Script { body })
+    statement -> Statement,
+    map!(
+        spanned!(statement_kind),
+        |(kind, span): (StatementKind, Span)| Statement { kind, span }
+    )
 );
+
+/// Describe the token at the start of an input for error messages
+fn describe(input: &str) -> (String, Span) {
+    let input = skip_whitespace(input);
+    let len = match input.chars().next() {
+        None => return (String::from("end of file"), span_between(input, input)),
+        Some(chr) if is_name_char(chr) => input.find(|c| !is_name_char(c)).unwrap_or_else(|| input.len()),
+        Some(chr) => chr.len_utf8(),
+    };
+
+    let (token, rem) = input.split_at(len);
+    (format!("`{}`", token), span_between(input, rem))
+}
+
+fn expected(input: &str, what: &str) -> Statement {
+    let (found, span) = describe(input);
+    Statement {
+        kind: StatementKind::Error(format!("expected {}, found {}", what, found)),
+        span,
+    }
+}
+
+/// Run a parser on the input, or return an "expected" error from the enclosing function
+macro_rules! expect {
+    ($i:expr, $what:expr, $submac:ident!( $($args:tt)* )) => {
+        match $submac!($i, $($args)*) {
+            IResult::Done(rem, _) => rem,
+            IResult::Error => return expected($i, $what),
+        }
+    };
+    ($i:expr, $what:expr, $f:expr) => {
+        expect!($i, $what, call!($f))
+    };
+}
+
+/// Find the first token of an invalid statement that does not match the grammar
+fn diagnose(input: &str) -> Statement {
+    fn block_end(i: &str) -> Statement {
+        let i = expect!(i, "`{`", punct!("{"));
+        let (i, _) = statement_list(i);
+        let i = expect!(i, "`}`", punct!("}"));
+        expected(i, "a new statement")
+    }
+
+    if let IResult::Done(i, _) = keyword!(input, "import") {
+        let i = expect!(i, "a module path", plain_string);
+        match keyword!(i, "as") {
+            IResult::Done(i, _) => {
+                let i = expect!(i, "a module name", name);
+                expected(i, "a new statement")
+            },
+            IResult::Error => expected(i, "a new statement"),
+        }
+    } else if let IResult::Done(i, _) = keyword!(input, "auto") {
+        block_end(i)
+    } else if let IResult::Done(i, _) = keyword!(input, "relay") {
+        let i = expect!(i, "a relay name", name);
+        block_end(i)
+    } else if let IResult::Done(i, _) = keyword!(input, "fn") {
+        let i = expect!(i, "a function name", name);
+        let mut i = expect!(i, "`(`", punct!("("));
+        if let IResult::Done(rem, _) = punct!(i, ")") {
+            return block_end(rem);
+        }
+
+        loop {
+            i = expect!(i, "a parameter name", name);
+            if let IResult::Done(rem, _) = punct!(i, ")") {
+                return block_end(rem);
+            }
+
+            i = expect!(i, "`,` or `)`", punct!(","));
+        }
+    } else if let IResult::Done(i, _) = keyword!(input, "delay") {
+        let i = expect!(i, "an expression", expression);
+        block_end(i)
+    } else if let IResult::Done(i, _) = keyword!(input, "while") {
+        let i = expect!(i, "a condition", expression);
+        block_end(i)
+    } else if let IResult::Done(i, _) = keyword!(input, "for") {
+        let i = expect!(i, "a binding name", name);
+        let i = expect!(i, "`in`", keyword!("in"));
+        let i = expect!(i, "an expression", expression);
+        block_end(i)
+    } else if let IResult::Done(i, _) = keyword!(input, "if") {
+        let i = if let IResult::Done(i, _) = keyword!(i, "let") {
+            let i = expect!(i, "a binding name", name);
+            let i = expect!(i, "`=`", punct!("="));
+            expect!(i, "an expression", expression)
+        } else {
+            expect!(i, "a condition", expression)
+        };
+        let i = expect!(i, "a block", block);
+        match keyword!(i, "else") {
+            IResult::Done(i, _) => if let IResult::Done(_, _) = keyword!(i, "if") {
+                diagnose(skip_whitespace(i))
+            } else {
+                block_end(i)
+            },
+            IResult::Error => expected(i, "a new statement"),
+        }
+    } else if let IResult::Done(i, _) = keyword!(input, "let") {
+        let i = expect!(i, "a binding name", name);
+        let i = expect!(i, "`=`", punct!("="));
+        let i = expect!(i, "an expression", expression);
+        expected(i, "a new statement")
+    } else {
+        let i = expect!(input, "a statement", path);
+        if let IResult::Done(mut i, _) = punct!(i, "(") {
+            if let IResult::Done(rem, _) = punct!(i, ")") {
+                return expected(rem, "a new statement");
+            }
+
+            loop {
+                i = expect!(i, "an expression", expression);
+                if let IResult::Done(rem, _) = punct!(i, ")") {
+                    return expected(rem, "a new statement");
+                }
+
+                i = expect!(i, "`,` or `)`", punct!(","));
+            }
+        } else if let IResult::Done(i, _) = punct!(i, "=") {
+            let i = expect!(i, "an expression", expression);
+            expected(i, "a new statement")
+        } else if let IResult::Done(_, _) = punct!(i, "{") {
+            block_end(i)
+        } else {
+            expected(i, "`(`, `=` or `{`")
+        }
+    }
+}
+
+fn starts_with_keyword(input: &str) -> bool {
+    let len = input.find(|c| !is_name_char(c)).unwrap_or_else(|| input.len());
+    KEYWORDS.contains(&&input[..len])
+}
+
+/// Skip the input up to the next statement or block boundary
+fn resync(input: &str) -> &str {
+    // Closing delimiters expected for the brackets opened so far
+    let mut open = Vec::new();
+    let mut idx = 0;
+    while let Some(chr) = input[idx..].chars().next() {
+        match chr {
+            // An unterminated string runs to the end of the input
+            '"' => match string_end(&input[idx + 1..]) {
+                Some(end) => {
+                    idx += end + 2;
+                    continue;
+                },
+                None => break,
+            },
+            '{' => open.push('}'),
+            '(' => open.push(')'),
+            '[' => open.push(']'),
+            // A closing brace also ends the unbalanced parens or brackets
+            // opened after its block, and the enclosing block if none is open
+            '}' => match open.iter().rposition(|&c| c == '}') {
+                Some(pos) => {
+                    open.truncate(pos);
+                    if open.is_empty() {
+                        return &input[idx + 1..];
+                    }
+                },
+                None => return &input[idx..],
+            },
+            ')' | ']' => if open.last() == Some(&chr) {
+                open.pop();
+            },
+            '\n' => {
+                let next = skip_whitespace(&input[idx..]);
+                if starts_with_keyword(next) {
+                    return &input[idx..];
+                }
+
+                if open.is_empty() {
+                    if let IResult::Done(_, _) = statement(next) {
+                        return &input[idx..];
+                    }
+                }
+            },
+            _ => {},
+        }
+
+        idx += chr.len_utf8();
+    }
+
+    &input[input.len()..]
+}
+
+/// Parses a list of statements up to the end of the input or the end of the
+/// enclosing block, replacing invalid statements with error nodes
+fn statement_list(mut input: &str) -> (&str, Vec<Statement>) {
+    let mut list = Vec::new();
+    loop {
+        let rem = skip_whitespace(input);
+        if rem.is_empty() || rem.starts_with('}') {
+            return (rem, list);
+        }
+
+        match statement(rem) {
+            IResult::Done(rem, stmt) => {
+                list.push(stmt);
+                input = rem;
+            },
+            IResult::Error => {
+                list.push(diagnose(rem));
+                input = resync(rem);
+            },
+        }
+    }
+}
+
+/// Parses a whole script, recovering from syntax errors
+pub fn script(input: &str) -> Vec<Statement> {
+    ending_at(input.len(), || statements(input))
+}
+
+/// Parses the statements of a script, reporting each unexpected closing brace
+fn statements(mut input: &str) -> Vec<Statement> {
+    let mut body = Vec::new();
+    loop {
+        let (rem, mut list) = statement_list(input);
+        body.append(&mut list);
+
+        if rem.is_empty() {
+            return body;
+        }
+
+        // statement_list only stops early on a closing brace
+        let next = &rem[1..];
+        body.push(Statement {
+            kind: StatementKind::Error(String::from("unexpected `}`")),
+            span: span_between(rem, next),
+        });
+
+        input = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a parser on the start of a source
+    fn run<'a, T>(parser: fn(&'a str) -> IResult<&'a str, T>, source: &'a str) -> IResult<&'a str, T> {
+        ending_at(source.len(), || parser(source))
+    }
+
+    /// Parse a whole expression
+    fn parse(source: &str) -> ExpressionKind {
+        match run(expression, source) {
+            IResult::Done(rem, exp) => {
+                assert!(rem.trim().is_empty(), "{:?} was not parsed", rem);
+                exp.kind
+            },
+            IResult::Error => panic!("cannot parse {:?}", source),
+        }
+    }
+
+    fn literal(source: &str) -> Literal {
+        match parse(source) {
+            ExpressionKind::Literal(lit) => lit,
+            kind => panic!("expected a literal, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_literals() {
+        assert_eq!(literal("42"), Literal::Int(42));
+        assert_eq!(literal("0xFF"), Literal::Int(255));
+        assert_eq!(literal("-7"), Literal::Int(-7));
+        assert_eq!(literal("1.5"), Literal::Number(1.5));
+        assert_eq!(literal("2e3"), Literal::Number(2000.0));
+        assert_eq!(literal("true"), Literal::Bool(true));
+        assert_eq!(literal("false"), Literal::Bool(false));
+    }
+
+    #[test]
+    fn unary_operators_bind_tighter_than_binary_ones() {
+        match parse("-a * ~b") {
+            ExpressionKind::Binary { lhs, op: Operator::Mul, rhs } => {
+                assert!(match lhs.kind { ExpressionKind::Unary { op: UnaryOperator::Neg, .. } => true, _ => false });
+                assert!(match rhs.kind { ExpressionKind::Unary { op: UnaryOperator::BNot, .. } => true, _ => false });
+            },
+            kind => panic!("expected a multiplication, found {:?}", kind),
+        }
+
+        match parse("!!true") {
+            ExpressionKind::Unary { op: UnaryOperator::Not, expr } => {
+                assert!(match expr.kind { ExpressionKind::Unary { op: UnaryOperator::Not, .. } => true, _ => false });
+            },
+            kind => panic!("expected a negation, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_indices_and_slices() {
+        match parse("list[i + 1]") {
+            ExpressionKind::Index { index, .. } => {
+                assert!(match index.kind { ExpressionKind::Binary { op: Operator::Add, .. } => true, _ => false });
+            },
+            kind => panic!("expected an index, found {:?}", kind),
+        }
+
+        // An integer is not read as a float when it starts a range
+        match parse("list[1..]") {
+            ExpressionKind::Slice { start: Some(start), end: None, .. } => {
+                assert_eq!(start.kind, ExpressionKind::Literal(Literal::Int(1)));
+            },
+            kind => panic!("expected a slice, found {:?}", kind),
+        }
+        assert!(match parse("list[..-1]") {
+            ExpressionKind::Slice { start: None, end: Some(_), .. } => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn parses_ranges() {
+        // The start of the range is an integer, not the float `1.`
+        match run(range, "1..5") {
+            IResult::Done(rem, Expression { kind: ExpressionKind::Range { start, end, inclusive }, .. }) => {
+                assert!(rem.is_empty());
+                assert_eq!(start.kind, ExpressionKind::Literal(Literal::Int(1)));
+                assert_eq!(end.kind, ExpressionKind::Literal(Literal::Int(5)));
+                assert!(!inclusive);
+            },
+            res => panic!("expected a range, found {:?}", res),
+        }
+
+        match run(range, "0..=n - 1") {
+            IResult::Done(_, Expression { kind: ExpressionKind::Range { end, inclusive, .. }, .. }) => {
+                assert!(match end.kind { ExpressionKind::Binary { op: Operator::Sub, .. } => true, _ => false });
+                assert!(inclusive);
+            },
+            res => panic!("expected a range, found {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parses_compound_assignments() {
+        match run(statement, "rooms[0].lights -= 1\n") {
+            IResult::Done(_, Statement { kind: StatementKind::Assignment { target, op, .. }, .. }) => {
+                assert_eq!(op, Some(Operator::Sub));
+                assert!(match target.kind { ExpressionKind::Field { .. } => true, _ => false });
+            },
+            res => panic!("expected an assignment, found {:?}", res),
+        }
+
+        match run(statement, "a = -1\n") {
+            IResult::Done(_, Statement { kind: StatementKind::Assignment { op, .. }, .. }) => assert_eq!(op, None),
+            res => panic!("expected an assignment, found {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parses_conditionals() {
+        match parse("if a { 1 } else if b { 2 } else { 3 }") {
+            ExpressionKind::Conditional { box alternate, .. } => match alternate.kind {
+                ExpressionKind::Conditional { box consequent, box alternate, .. } => {
+                    assert_eq!(consequent.kind, ExpressionKind::Literal(Literal::Int(2)));
+                    assert_eq!(alternate.kind, ExpressionKind::Literal(Literal::Int(3)));
+                },
+                kind => panic!("expected an else if, found {:?}", kind),
+            },
+            kind => panic!("expected a conditional, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_match_arms() {
+        let source = "match x {\n    \"a\" | \"b\" => {\n    }\n    -1 => {\n    },\n    _ => {\n    }\n}";
+        match run(match_, source) {
+            IResult::Done(rem, StatementKind::Match { arms, .. }) => {
+                assert!(rem.trim().is_empty(), "{:?} was not parsed", rem);
+                assert_eq!(arms.len(), 3);
+                assert_eq!(arms[0].values.as_ref().map(Vec::len), Some(2));
+
+                let values = arms[1].values.as_ref().unwrap();
+                assert_eq!(values[0].kind, ExpressionKind::Literal(Literal::Int(-1)));
+
+                assert!(arms[2].values.is_none());
+            },
+            res => panic!("expected a match, found {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parses_escapes() {
+        assert_eq!(
+            literal(r#""a\"b\n\${c}""#),
+            Literal::String(vec![ StringPart::String(String::from("a\"b\n${c}")) ]),
+        );
+        assert_eq!(plain_string(r#""a\q""#), IResult::Error);
+    }
+
+    #[test]
+    fn parses_format_specs() {
+        assert_eq!(format_spec("03"), Some(FormatSpec { zero: true, width: Some(3), precision: None }));
+        assert_eq!(format_spec("3"), Some(FormatSpec { zero: false, width: Some(3), precision: None }));
+        assert_eq!(format_spec(".2"), Some(FormatSpec { zero: false, width: None, precision: Some(2) }));
+        assert_eq!(format_spec("05.1"), Some(FormatSpec { zero: true, width: Some(5), precision: Some(1) }));
+        assert_eq!(format_spec("3."), None);
+        assert_eq!(format_spec("x"), None);
+
+        match literal(r#""n = ${n:03}""#) {
+            Literal::String(parts) => match parts[1] {
+                StringPart::Expression(_, format) => assert_eq!(format, format_spec("03")),
+                ref part => panic!("expected an interpolation, found {:?}", part),
+            },
+            lit => panic!("expected a string, found {:?}", lit),
+        }
+    }
+
+    #[test]
+    fn finds_the_end_of_strings() {
+        assert_eq!(string_end(r#"a\"b" c"#), Some(4));
+        assert_eq!(string_end(r#"a ${f("}")} b" c"#), Some(13));
+        assert_eq!(string_end(r#"a ${f("}"#), None);
+    }
+
+    #[test]
+    fn parses_conversions_and_defaults() {
+        match parse("-x as int as string") {
+            ExpressionKind::Cast { box expr, ty } => {
+                assert_eq!(ty, TypeName::Named(Atom::from("string")));
+                match expr.kind {
+                    ExpressionKind::Cast { box expr, ty } => {
+                        assert_eq!(ty, TypeName::Named(Atom::from("int")));
+                        assert!(match expr.kind { ExpressionKind::Unary { op: UnaryOperator::Neg, .. } => true, _ => false });
+                    },
+                    kind => panic!("expected a conversion, found {:?}", kind),
+                }
+            },
+            kind => panic!("expected a conversion, found {:?}", kind),
+        }
+
+        match parse("door.health ?? 100") {
+            ExpressionKind::Binary { op, box rhs, .. } => {
+                assert_eq!(op, Operator::Coalesce);
+                assert_eq!(rhs.kind, ExpressionKind::Literal(Literal::Int(100)));
+            },
+            kind => panic!("expected a default, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_entity_lookups() {
+        match run(statement, "if let e = find(\"x\") {\n} else {\n}\n") {
+            IResult::Done(_, Statement { kind: StatementKind::BranchLet { name, value, alternate, .. }, .. }) => {
+                assert_eq!(name, Atom::from("e"));
+                assert!(match value.kind { ExpressionKind::Call(_) => true, _ => false });
+                assert_eq!(alternate, Some(vec![]));
+            },
+            res => panic!("expected an if let, found {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parses_return_values_on_the_same_line() {
+        match run(return_, "return\nout.value = 2") {
+            IResult::Done(rem, StatementKind::Return(None)) => assert_eq!(rem, "\nout.value = 2"),
+            res => panic!("expected a bare return, found {:?}", res),
+        }
+        match run(return_, "return // done\n    a") {
+            IResult::Done(_, StatementKind::Return(None)) => {},
+            res => panic!("expected a bare return, found {:?}", res),
+        }
+        match run(return_, "return a +\n    1") {
+            IResult::Done(rem, StatementKind::Return(Some(_))) => assert!(rem.is_empty()),
+            res => panic!("expected a returned value, found {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Operations on dictionaries, mutating the shared entries in place
+
+use std::collections::HashMap;
+
+use atom::*;
+use compiler::types::TypeId;
+use hct::ast::*;
+use runtime::types::Context;
+
+use super::expression::*;
+use super::scope::*;
+use super::value::*;
+
+/// Build a dictionary from a map literal, converting its
+/// names to the key type of the dictionary
+pub fn dict_literal<'a>(entries: &HashMap<Atom, Expression>, key: &TypeId, value: &TypeId, scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    let mut entries: Vec<_> = entries.iter().collect();
+    entries.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+
+    let mut res = Vec::with_capacity(entries.len());
+    for (name, exp) in entries {
+        let name = match *key {
+            TypeId::Atom => Value::Atom(name.clone()),
+            TypeId::Entity => Value::Entity(name.clone()),
+            TypeId::String => Value::String(name.to_string()),
+            ref ty => return Err(format!("cannot use a {:?} as a dictionary key", ty)),
+        };
+
+        let exp = expression(exp, scope, ctx)?;
+        let exp = if *value == TypeId::f64 {
+            Value::f64(exp.as_f64())
+        } else {
+            exp
+        };
+
+        res.push((name, exp));
+    }
+
+    Ok(Value::dict(res, key.clone(), value.clone()))
+}
+
+/// Execute a dictionary operation, or return None if the
+/// called function is not one for these arguments
+pub fn dict_call(name: &Atom, args: &[Value]) -> Option<Eval<Value>> {
+    let (entries, key_ty, value_ty) = match args.first() {
+        Some(&Value::Dict(ref entries, ref key, ref value)) => (entries.clone(), key.clone(), value.clone()),
+        _ => return None,
+    };
+
+    let arity = match *name {
+        hct_atom!("len") | hct_atom!("keys") | hct_atom!("values") => 1,
+        hct_atom!("contains") | hct_atom!("remove") => 2,
+        hct_atom!("insert") | hct_atom!("get") => 3,
+        _ => return None,
+    };
+
+    if args.len() != arity {
+        return Some(Err(format!("{} expects {} arguments, found {}", name, arity, args.len())));
+    }
+
+    let index = args.get(1).and_then(|key| {
+        entries.borrow().iter()
+            .position(|&(ref entry, _)| entry == key)
+    });
+
+    let res = match *name {
+        hct_atom!("len") => Value::i64(entries.borrow().len() as i64),
+        hct_atom!("keys") => Value::vec(entries.borrow().iter().map(|&(ref key, _)| key.clone()).collect(), key_ty),
+        hct_atom!("values") => Value::vec(entries.borrow().iter().map(|&(_, ref value)| value.clone()).collect(), value_ty),
+        hct_atom!("contains") => Value::bool(index.is_some()),
+        // Integers are widened to the type of the values, like the type checker allows
+        hct_atom!("get") => match index {
+            Some(index) => entries.borrow()[index].1.clone(),
+            None => args[2].clone().widen_to(&value_ty),
+        },
+        hct_atom!("insert") => {
+            let mut entries = entries.borrow_mut();
+            let value = args[2].clone().widen_to(&value_ty);
+
+            match index {
+                Some(index) => entries[index].1 = value,
+                None => entries.push((args[1].clone(), value)),
+            }
+
+            Value::Void
+        },
+        // Removing a missing key does nothing
+        _ => {
+            if let Some(index) = index {
+                entries.borrow_mut().remove(index);
+            }
+
+            Value::Void
+        },
+    };
+
+    Some(Ok(res))
+}
@@ -0,0 +1,699 @@
+//! Evaluate the value of an expression
+
+use atom::*;
+use compiler::check::{coerced_type, vector_component, vector_property};
+use compiler::types::TypeId;
+use hct::ast::*;
+use runtime::stl::{Externals, list_index, list_range, math_function};
+use runtime::types::Context;
+
+use super::dict::*;
+use super::intrinsics;
+use super::list::*;
+use super::math::*;
+use super::path::*;
+use super::scope::*;
+use super::statements::*;
+use super::string::*;
+use super::value::*;
+
+/// Result of an evaluation, failing with a runtime error message
+pub type Eval<T> = Result<T, String>;
+
+/// Call an STL function
+pub fn call_stl(ctx: &mut Context, name: Atom, args: Vec<Value>) -> Eval<Value> {
+    if name == hct_atom!("length") {
+        // The length of a vector is computed from its components by the STL
+        let is_vector = match args.first() {
+            Some(&Value::vec3(_)) => true,
+            _ => false,
+        };
+        if is_vector {
+            return call_stl(ctx, hct_atom!("vector_length"), args);
+        }
+
+        let sum: f64 = {
+            args.iter()
+                .map(|val| intrinsics::pow(val.as_f64(), 2.0))
+                .sum()
+        };
+
+        return Ok(Value::f64(intrinsics::sqrt(sum)));
+    }
+
+    match Externals::call(ctx, &name, args) {
+        Some(res) => res,
+        None => Err(format!("unknown function {}", name)),
+    }
+}
+
+/// Call a user function
+pub fn call_function<'a>(func: &Function, args: Vec<Value>, scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    if func.args.len() != args.len() {
+        return Err(format!("{} expects {} arguments, found {}", func.name, func.args.len(), args.len()));
+    }
+
+    let mut body = scope.function_body();
+    for (name, value) in func.args.iter().zip(args) {
+        body.set_binding(name.clone(), value);
+    }
+
+    match statements(&func.body, body, ctx)? {
+        Flow::Return(value) => Ok(value),
+        // Loops cannot be left from a function body
+        Flow::Next | Flow::Break | Flow::Continue => Ok(Value::Void),
+    }
+}
+
+/// Execute an AST Call node
+pub fn call<'a>(&Call { ref path, ref args, .. }: &Call, scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    if let PathKind::Binding(ref name) = path.kind {
+        if scope.function(name).is_none() && is_higher_order(name) {
+            return higher_order_call(name, args, scope, ctx);
+        }
+    }
+
+    let args = {
+        args.iter()
+            .map(|arg| expression(arg, scope, ctx))
+            .collect::<Eval<Vec<_>>>()?
+    };
+
+    if let PathKind::Binding(ref name) = path.kind {
+        if let Some(func) = scope.function(name) {
+            return call_function(&func, args, scope, ctx);
+        }
+    }
+
+    // List, dictionary, string and math operations are not entity methods, even in an event block
+    if let PathKind::Binding(ref name) = path.kind {
+        if let Some(res) = dict_call(name, &args) {
+            return res;
+        }
+        if let Some(res) = string_call(name, &args, ctx) {
+            return res;
+        }
+        if let Some(res) = math_call(name, &args, ctx) {
+            return res;
+        }
+        if let Some(res) = list_call(name, &args, ctx) {
+            return res;
+        }
+    }
+
+    if let Some((from, trigger)) = scope.event() {
+        if let Value::Entity(_) = from {
+            let (entity, method) = event(path, scope, ctx)?;
+            let delay = scope.delay().unwrap_or(0.0);
+            let arg = match args.into_iter().next() {
+                Some(val @ Value::String(_)) => val,
+                Some(val @ Value::f64(_)) |
+                Some(val @ Value::i64(_)) => call_stl(
+                    ctx,
+                    hct_atom!("to_string"),
+                    vec![ Value::f64(val.as_f64()) ],
+                )?,
+
+                Some(val) => return Err(format!("Unsupported event argument {:?}", val)),
+                None => Value::String(String::new()),
+            };
+
+            return call_stl(
+                ctx,
+                hct_atom!("create_connection"),
+                vec![
+                    from,
+                    trigger,
+                    entity,
+                    method,
+                    arg,
+                    Value::f64(delay),
+                ],
+            );
+        }
+    }
+
+    match path.kind {
+        PathKind::Binding(ref name) => call_stl(ctx, name.clone(), args),
+        _ => Err(format!("{} is not a function", path)),
+    }
+}
+
+/// Index of a virtual sub-property of a vector property
+pub fn sub_property(name: &Atom) -> Eval<i64> {
+    match *name {
+        hct_atom!("x") | hct_atom!("r") | hct_atom!("pitch") => Ok(0),
+        hct_atom!("y") | hct_atom!("g") | hct_atom!("yaw") => Ok(1),
+        hct_atom!("z") | hct_atom!("b") | hct_atom!("roll") => Ok(2),
+        hct_atom!("w") | hct_atom!("a") => Ok(3),
+        ref sub => Err(format!("unsupported sub-property \"{}\"", sub)),
+    }
+}
+
+/// Get the name of the instance of a generic vector
+/// function for the type of a vector or an angle
+pub fn vector_function(name: &str, value: &Value) -> Atom {
+    match *value {
+        Value::qangle(_) => Atom::from(format!("{}.qangle", name)),
+        _ => Atom::from(format!("{}.vec3", name)),
+    }
+}
+
+/// Multiply the components of a vector or an angle by a factor
+fn vector_scale(value: Value, factor: f64, ctx: &mut Context) -> Eval<Value> {
+    let name = vector_function("vector_scale", &value);
+    call_stl(ctx, name, vec![ value, Value::f64(factor) ])
+}
+
+/// Get the value of a field of an object, a property of an
+/// entity, a sub-property of a property or a component of a vector
+pub fn field(obj: Value, prop: &Atom, ctx: &mut Context) -> Eval<Value> {
+    match obj {
+        Value::Object(items) => {
+            let items = items.borrow();
+            match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+                Ok(idx) => Ok(items[idx].1.clone()),
+                Err(_) => Err(format!("key \"{}\" not found in object", prop)),
+            }
+        },
+
+        obj @ Value::Entity(_) => {
+            let value = call_stl(
+                ctx,
+                hct_atom!("get_property"),
+                vec![ obj, Value::Atom(prop.clone()) ],
+            )?;
+
+            match vector_property(prop) {
+                Some(ty) => call_stl(
+                    ctx,
+                    Atom::from(format!("parse_vector.{:?}", ty)),
+                    vec![ value ],
+                ),
+                None => Ok(value),
+            }
+        },
+
+        obj @ Value::String(_) => {
+            let sub = sub_property(prop)?;
+            call_stl(
+                ctx,
+                hct_atom!("get_sub_property"),
+                vec![ obj, Value::i64(sub) ],
+            )
+        },
+
+        Value::vec3(val) | Value::qangle(val) => match vector_component(prop) {
+            Some(idx) => Ok(Value::f64(val[idx as usize])),
+            None => Err(format!("unsupported component \"{}\" of a vector", prop)),
+        },
+
+        obj => Err(format!("cannot dereference a {:?}", obj)),
+    }
+}
+
+/// Widen an integer to a float if a float is expected,
+/// returning any other value unchanged
+pub fn widen(value: Value, expected: &Value) -> Value {
+    match (value, expected) {
+        (Value::i64(val), &Value::f64(_)) => Value::f64(val as f64),
+        (value, _) => value,
+    }
+}
+
+/// Convert a value interpolated in a string, applying its format to numbers
+fn interpolate(value: Value, format: Option<FormatSpec>, ctx: &mut Context) -> Eval<String> {
+    if let Some(format) = format {
+        return match value {
+            Value::f64(_) | Value::i64(_) => {
+                let res = call_stl(
+                    ctx,
+                    hct_atom!("format_number"),
+                    vec![
+                        Value::f64(value.as_f64()),
+                        Value::bool(format.zero),
+                        Value::i64(format.width.map_or(-1, i64::from)),
+                        Value::i64(format.precision.map_or(-1, i64::from)),
+                    ],
+                )?;
+
+                Ok(res.as_string())
+            },
+            value => Err(format!("cannot format a {:?} as a number", value)),
+        };
+    }
+
+    let res = match value {
+        Value::String(val) => val,
+        Value::f64(_) => call_stl(ctx, hct_atom!("to_string"), vec![ value ])?.as_string(),
+        Value::i64(_) => call_stl(ctx, hct_atom!("int_to_string"), vec![ value ])?.as_string(),
+        Value::bool(val) => val.to_string(),
+        Value::Entity(_) => call_stl(ctx, hct_atom!("entity_name"), vec![ value ])?.as_string(),
+        Value::vec3(_) | Value::qangle(_) => {
+            let name = vector_function("vector_to_string", &value);
+            call_stl(ctx, name, vec![ value ])?.as_string()
+        },
+        Value::Array(items) | Value::Vec(items, _) => {
+            let items = items.borrow().clone();
+            let mut res = Vec::with_capacity(items.len());
+            for item in items {
+                res.push(interpolate(item, None, ctx)?);
+            }
+
+            format!("[{}]", res.join(", "))
+        },
+        value => return Err(format!("cannot interpolate a {:?} in a string", value)),
+    };
+
+    Ok(res)
+}
+
+/// Get the value of a binding or of a field of an object held by
+/// a binding, or None if it can only be known by evaluating code
+fn peek(exp: &Expression, scope: &Scope) -> Option<Value> {
+    match exp.kind {
+        ExpressionKind::Reference(ref path) => peek_path(path, scope),
+        ExpressionKind::Field { ref expr, ref name } => object_field(peek(expr, scope), name),
+        _ => None,
+    }
+}
+
+fn peek_path(path: &Path, scope: &Scope) -> Option<Value> {
+    match path.kind {
+        PathKind::Binding(ref name) => scope.binding(name),
+        PathKind::Deref(ref obj, ref prop) => object_field(peek_path(obj, scope), prop),
+        PathKind::Instance(_) => None,
+    }
+}
+
+fn object_field(obj: Option<Value>, name: &Atom) -> Option<Value> {
+    match obj {
+        Some(Value::Object(items)) => {
+            let value = items.borrow().iter()
+                .find(|&&(ref key, _)| key == name)
+                .map(|&(_, ref value)| value.clone());
+            value
+        },
+        _ => None,
+    }
+}
+
+/// Whether an expression has a float value, found without evaluating it
+/// The JIT widens the integer branch of an if expression when the other one
+/// is a float, which is found here from the current values of the bindings
+/// and the signatures of the STL. The values of user functions, list and
+/// dictionary operations and entity properties are taken as integers, as
+/// they are only known by calling them
+fn is_float(exp: &Expression, scope: &Scope) -> bool {
+    match exp.kind {
+        ExpressionKind::Literal(Literal::Number(_)) => true,
+        ExpressionKind::Reference(_) | ExpressionKind::Field { .. } => match peek(exp, scope) {
+            Some(Value::f64(_)) => true,
+            _ => false,
+        },
+        ExpressionKind::Index { ref expr, .. } => match peek(expr, scope) {
+            Some(Value::Vec(_, ty)) => ty == TypeId::f64,
+            Some(Value::Array(items)) => {
+                let first = items.borrow().first().cloned();
+                match first {
+                    Some(Value::f64(_)) => true,
+                    _ => false,
+                }
+            },
+            _ => false,
+        },
+        ExpressionKind::Conditional { ref consequent, ref alternate, .. } => {
+            is_float(consequent, scope) || is_float(alternate, scope)
+        },
+        ExpressionKind::Unary { op: UnaryOperator::Neg, ref expr } => is_float(expr, scope),
+        ExpressionKind::Cast { ref ty, .. } => TypeId::from_cast(ty) == Some(TypeId::f64),
+        ExpressionKind::Binary { ref lhs, op, ref rhs } => match op {
+            Operator::Add | Operator::Sub | Operator::Mul | Operator::Div | Operator::Mod => {
+                is_float(lhs, scope) || is_float(rhs, scope)
+            },
+            Operator::Coalesce => is_float(rhs, scope),
+            _ => false,
+        },
+        ExpressionKind::Call(Call { path: Path { kind: PathKind::Binding(ref name), .. }, ref args, .. }) => {
+            if scope.function(name).is_some() {
+                return false;
+            }
+
+            let args: Vec<_> = {
+                args.iter()
+                    .map(|arg| if is_float(arg, scope) { TypeId::f64 } else { TypeId::i64 })
+                    .collect()
+            };
+
+            let name = math_function(name, &args).unwrap_or_else(|| name.clone());
+            match Externals::signature(&name) {
+                Some((_, ret)) => ret == TypeId::f64,
+                None => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Parse a string as a number or a vector
+fn parse_value(value: Value, ty: &TypeId, ctx: &mut Context) -> Eval<Value> {
+    let name = match *ty {
+        TypeId::i64 => hct_atom!("parse_int"),
+        TypeId::bool => hct_atom!("parse_bool"),
+        TypeId::vec3 | TypeId::qangle => Atom::from(format!("parse_vector.{:?}", ty)),
+        _ => hct_atom!("parse"),
+    };
+
+    call_stl(ctx, name, vec![ value ])
+}
+
+/// Convert a value to a type with `as`
+pub fn cast(value: Value, ty: &TypeId, ctx: &mut Context) -> Eval<Value> {
+    match (value, ty) {
+        (value @ Value::String(_), &TypeId::String) => Ok(value),
+        (value @ Value::String(_), ty) => parse_value(value, ty, ctx),
+        (Value::i64(val), &TypeId::f64) => Ok(Value::f64(val as f64)),
+        (Value::f64(val), &TypeId::i64) => Ok(Value::i64(val as i64)),
+        (value, &TypeId::String) => Ok(Value::String(interpolate(value, None, ctx)?)),
+        // The other conversions are to the same type, the script being type checked
+        (value, _) => Ok(value),
+    }
+}
+
+/// Parse the value of an entity property when an operator combines
+/// it with a number, returning any other operand unchanged
+fn operand(value: Value, is_property: bool, op: Operator, other: &Value, ctx: &mut Context) -> Eval<Value> {
+    let other = match *other {
+        Value::f64(_) if is_property => TypeId::f64,
+        Value::i64(_) if is_property => TypeId::i64,
+        _ => return Ok(value),
+    };
+
+    match (value, coerced_type(op, &other)) {
+        (value @ Value::String(_), Some(ty)) => parse_value(value, &ty, ctx),
+        (value, _) => Ok(value),
+    }
+}
+
+/// Compute the result of a binary operation
+pub fn binary(lhs: Value, op: Operator, rhs: Value, ctx: &mut Context) -> Eval<Value> {
+    // Integers are widened when mixed with floats
+    let lhs = widen(lhs, &rhs);
+    let rhs = widen(rhs, &lhs);
+
+    Ok(match (lhs, op, rhs) {
+        (lhs @ Value::String(_), Operator::Add, rhs @ Value::String(_)) => {
+            return call_stl(
+                ctx,
+                hct_atom!("concat"),
+                vec![ lhs, rhs ],
+            );
+        },
+
+        (Value::f64(lhs), Operator::Add, Value::f64(rhs)) => Value::f64(lhs + rhs),
+        (Value::f64(lhs), Operator::Sub, Value::f64(rhs)) => Value::f64(lhs - rhs),
+        (Value::f64(lhs), Operator::Mul, Value::f64(rhs)) => Value::f64(lhs * rhs),
+        (Value::f64(lhs), Operator::Div, Value::f64(rhs)) => Value::f64(lhs / rhs),
+        (Value::f64(lhs), Operator::Mod, Value::f64(rhs)) => Value::f64(lhs % rhs),
+
+        (Value::i64(lhs), Operator::Add, Value::i64(rhs)) => Value::i64(lhs.wrapping_add(rhs)),
+        (Value::i64(lhs), Operator::Sub, Value::i64(rhs)) => Value::i64(lhs.wrapping_sub(rhs)),
+        (Value::i64(lhs), Operator::Mul, Value::i64(rhs)) => Value::i64(lhs.wrapping_mul(rhs)),
+        (lhs @ Value::i64(_), Operator::Div, rhs @ Value::i64(_)) => {
+            return call_stl(ctx, hct_atom!("int_div"), vec![ lhs, rhs ]);
+        },
+        (lhs @ Value::i64(_), Operator::Mod, rhs @ Value::i64(_)) => {
+            return call_stl(ctx, hct_atom!("int_rem"), vec![ lhs, rhs ]);
+        },
+
+        (Value::i64(lhs), Operator::Shl, Value::i64(rhs)) => Value::i64(lhs.wrapping_shl(rhs as u32)),
+        (Value::i64(lhs), Operator::Shr, Value::i64(rhs)) => Value::i64((lhs as u64).wrapping_shr(rhs as u32) as i64),
+
+        (Value::f64(lhs), Operator::Lt, Value::f64(rhs)) => Value::bool(lhs < rhs),
+        (Value::f64(lhs), Operator::Lte, Value::f64(rhs)) => Value::bool(lhs <= rhs),
+        (Value::f64(lhs), Operator::Gt, Value::f64(rhs)) => Value::bool(lhs > rhs),
+        (Value::f64(lhs), Operator::Gte, Value::f64(rhs)) => Value::bool(lhs >= rhs),
+        (Value::i64(lhs), Operator::Lt, Value::i64(rhs)) => Value::bool(lhs < rhs),
+        (Value::i64(lhs), Operator::Lte, Value::i64(rhs)) => Value::bool(lhs <= rhs),
+        (Value::i64(lhs), Operator::Gt, Value::i64(rhs)) => Value::bool(lhs > rhs),
+        (Value::i64(lhs), Operator::Gte, Value::i64(rhs)) => Value::bool(lhs >= rhs),
+
+        (Value::bool(lhs), Operator::And, Value::bool(rhs)) => Value::bool(lhs && rhs),
+        (Value::i64(lhs), Operator::BAnd, Value::i64(rhs)) => Value::i64(lhs & rhs),
+        (Value::bool(lhs), Operator::Or, Value::bool(rhs)) => Value::bool(lhs || rhs),
+        (Value::i64(lhs), Operator::BOr, Value::i64(rhs)) => Value::i64(lhs | rhs),
+        (Value::i64(lhs), Operator::BXor, Value::i64(rhs)) => Value::i64(lhs ^ rhs),
+
+        (lhs @ Value::vec3(_), Operator::Add, rhs @ Value::vec3(_)) |
+        (lhs @ Value::qangle(_), Operator::Add, rhs @ Value::qangle(_)) => {
+            let name = vector_function("vector_add", &lhs);
+            return call_stl(ctx, name, vec![ lhs, rhs ]);
+        },
+        (lhs @ Value::vec3(_), Operator::Sub, rhs @ Value::vec3(_)) |
+        (lhs @ Value::qangle(_), Operator::Sub, rhs @ Value::qangle(_)) => {
+            let name = vector_function("vector_sub", &lhs);
+            return call_stl(ctx, name, vec![ lhs, rhs ]);
+        },
+        (lhs @ Value::vec3(_), Operator::Mul, rhs @ Value::f64(_)) |
+        (lhs @ Value::vec3(_), Operator::Mul, rhs @ Value::i64(_)) |
+        (lhs @ Value::qangle(_), Operator::Mul, rhs @ Value::f64(_)) |
+        (lhs @ Value::qangle(_), Operator::Mul, rhs @ Value::i64(_)) => {
+            return vector_scale(lhs, rhs.as_f64(), ctx);
+        },
+        (lhs @ Value::f64(_), Operator::Mul, rhs @ Value::vec3(_)) |
+        (lhs @ Value::i64(_), Operator::Mul, rhs @ Value::vec3(_)) |
+        (lhs @ Value::f64(_), Operator::Mul, rhs @ Value::qangle(_)) |
+        (lhs @ Value::i64(_), Operator::Mul, rhs @ Value::qangle(_)) => {
+            return vector_scale(rhs, lhs.as_f64(), ctx);
+        },
+        (lhs @ Value::vec3(_), Operator::Div, rhs @ Value::f64(_)) |
+        (lhs @ Value::vec3(_), Operator::Div, rhs @ Value::i64(_)) |
+        (lhs @ Value::qangle(_), Operator::Div, rhs @ Value::f64(_)) |
+        (lhs @ Value::qangle(_), Operator::Div, rhs @ Value::i64(_)) => {
+            return vector_scale(lhs, 1.0 / rhs.as_f64(), ctx);
+        },
+
+        (lhs, Operator::Eq, rhs) => Value::bool(lhs == rhs),
+        (lhs, Operator::Neq, rhs) => Value::bool(lhs != rhs),
+
+        (lhs, op, rhs) => return Err(format!("Unsupported expression: {:?} {} {:?}", lhs, op, rhs)),
+    })
+}
+
+/// Compute the value of an expression
+pub fn expression<'a>(exp: &Expression, scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    match exp.kind {
+        ExpressionKind::Call(ref val) => call(val, scope, ctx),
+
+        ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), .. }) => {
+            match scope.binding(name) {
+                Some(val) => Ok(val),
+                None => Err(format!("value \"{}\" not found", name)),
+            }
+        },
+        ExpressionKind::Reference(Path { kind: PathKind::Deref(ref obj, ref prop), .. }) => {
+            let obj = expression(
+                &Expression {
+                    span: obj.span,
+                    kind: ExpressionKind::Reference((**obj).clone()),
+                },
+                scope, ctx,
+            )?;
+
+            field(obj, prop, ctx)
+        },
+        ExpressionKind::Field { ref expr, ref name } => {
+            let obj = expression(expr, scope, ctx)?;
+            field(obj, name, ctx)
+        },
+        ExpressionKind::Reference(ref path) => Err(format!("cannot use {} as a value", path)),
+
+        ExpressionKind::Array(ref val) => {
+            let values = {
+                val.iter()
+                    .map(|elem| expression(elem, scope, ctx))
+                    .collect::<Eval<Vec<_>>>()?
+            };
+
+            // Literals of scalar values are growable lists
+            let elem = values.first().and_then(Value::vec_element);
+            match elem {
+                Some(ty) => Ok(Value::vec(values, ty)),
+                None => Ok(Value::array(values)),
+            }
+        },
+        ExpressionKind::Map(ref val) => {
+            let items = {
+                val.iter()
+                    .map(|(k, v)| {
+                        let val = expression(v, scope, ctx)?;
+                        Ok((k.clone(), val))
+                    })
+                    .collect::<Eval<Vec<_>>>()?
+            };
+
+            Ok(Value::object(items))
+        },
+
+        ExpressionKind::Index { ref expr, ref index } => {
+            let list = expression(expr, scope, ctx)?.as_list();
+            let index = expression(index, scope, ctx)?.as_i64();
+
+            let list = list.borrow();
+            let value = match list_index(index, list.len()) {
+                Some(idx) => list[idx].clone(),
+                None => return Err(format!("index {} is out of bounds for an array of length {}", index, list.len())),
+            };
+
+            Ok(value)
+        },
+        ExpressionKind::Slice { ref expr, ref start, ref end } => {
+            let list = expression(expr, scope, ctx)?;
+            let ty = list.element_type();
+            let list = list.as_list();
+            let start = match *start {
+                Some(ref start) => expression(start, scope, ctx)?.as_i64(),
+                None => 0,
+            };
+            let end = match *end {
+                Some(ref end) => Some(expression(end, scope, ctx)?.as_i64()),
+                None => None,
+            };
+
+            let list = list.borrow();
+            let end = end.unwrap_or(list.len() as i64);
+            let values = match list_range(start, end, list.len()) {
+                Some((start, end)) => list[start..end].to_vec(),
+                None => return Err(format!("range {}..{} is out of bounds for an array of length {}", start, end, list.len())),
+            };
+
+            Ok(Value::vec(values, ty))
+        },
+        ExpressionKind::Range { .. } => Err(String::from("ranges can only be iterated by a for loop")),
+
+        ExpressionKind::Binary { lhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. }, op: Operator::Add, rhs: box ref e3 } |
+        ExpressionKind::Binary { lhs: box ref e3, op: Operator::Add, rhs: box Expression { kind: ExpressionKind::Binary { lhs: box ref e1, op: Operator::Mul, rhs: box ref e2 }, .. } } => {
+            // The checker only lets the fields of entities be parsed as numbers
+            let fields = (e1.is_field(), e2.is_field(), e3.is_field());
+            let e1 = expression(e1, scope, ctx)?;
+            let e2 = expression(e2, scope, ctx)?;
+            let e3 = expression(e3, scope, ctx)?;
+            let is_number = |val: &Value| match *val {
+                Value::f64(_) | Value::i64(_) => true,
+                _ => false,
+            };
+
+            match (e1, e2, e3) {
+                (Value::i64(e1), Value::i64(e2), Value::i64(e3)) => Ok(Value::i64(e1.wrapping_mul(e2).wrapping_add(e3))),
+                (e1, e2, e3) => if is_number(&e1) && is_number(&e2) && is_number(&e3) {
+                    Ok(Value::f64(intrinsics::fmuladd(e1.as_f64(), e2.as_f64(), e3.as_f64())))
+                } else {
+                    // Other operands, like vectors or properties parsed as numbers, are combined one operation at a time
+                    let e1 = operand(e1, fields.0, Operator::Mul, &e2, ctx)?;
+                    let e2 = operand(e2, fields.1, Operator::Mul, &e1, ctx)?;
+                    let mul = binary(e1, Operator::Mul, e2, ctx)?;
+                    let e3 = operand(e3, fields.2, Operator::Add, &mul, ctx)?;
+                    binary(mul, Operator::Add, e3, ctx)
+                },
+            }
+        },
+        ExpressionKind::Conditional { ref condition, ref consequent, ref alternate } => {
+            let (branch, other) = if expression(condition, scope, ctx)?.as_bool() {
+                (consequent, alternate)
+            } else {
+                (alternate, consequent)
+            };
+
+            // Integers are widened when mixed with floats
+            match expression(branch, scope, ctx)? {
+                value @ Value::i64(_) => if is_float(other, scope) {
+                    Ok(value.widen_to(&TypeId::f64))
+                } else {
+                    Ok(value)
+                },
+                value => Ok(value),
+            }
+        },
+        ExpressionKind::Unary { op, ref expr } => {
+            Ok(match (op, expression(expr, scope, ctx)?) {
+                (UnaryOperator::Not, Value::bool(val)) => Value::bool(!val),
+                (UnaryOperator::Neg, Value::f64(val)) => Value::f64(-val),
+                (UnaryOperator::Neg, Value::i64(val)) => Value::i64(val.wrapping_neg()),
+                (UnaryOperator::Neg, val @ Value::vec3(_)) |
+                (UnaryOperator::Neg, val @ Value::qangle(_)) => return vector_scale(val, -1.0, ctx),
+                (UnaryOperator::BNot, Value::i64(val)) => Value::i64(!val),
+                (op, val) => return Err(format!("Unsupported expression: {}{:?}", op, val)),
+            })
+        },
+        // The property is converted to the type of the default, always evaluated
+        ExpressionKind::Binary { ref lhs, op: Operator::Coalesce, ref rhs } => {
+            let (obj, prop) = match (**lhs).clone().split_field() {
+                Ok(res) => res,
+                Err(_) => return Err(String::from("?? expects a property of an entity")),
+            };
+
+            let obj = expression(&obj, scope, ctx)?;
+            let default = expression(rhs, scope, ctx)?;
+            let has = call_stl(
+                ctx,
+                hct_atom!("has"),
+                vec![ obj.clone(), Value::String(prop.to_string()) ],
+            )?;
+
+            if let Value::bool(false) = has {
+                return Ok(default);
+            }
+
+            let ty = match default {
+                Value::f64(_) => TypeId::f64,
+                Value::i64(_) => TypeId::i64,
+                Value::bool(_) => TypeId::bool,
+                Value::vec3(_) => TypeId::vec3,
+                Value::qangle(_) => TypeId::qangle,
+                _ => TypeId::String,
+            };
+
+            let value = call_stl(
+                ctx,
+                hct_atom!("get_property"),
+                vec![ obj, Value::Atom(prop) ],
+            )?;
+            cast(value, &ty, ctx)
+        },
+        ExpressionKind::Binary { ref lhs, op, ref rhs } => {
+            let fields = (lhs.is_field(), rhs.is_field());
+            let lhs = expression(lhs, scope, ctx)?;
+            let rhs = expression(rhs, scope, ctx)?;
+            let lhs = operand(lhs, fields.0, op, &rhs, ctx)?;
+            let rhs = operand(rhs, fields.1, op, &lhs, ctx)?;
+            binary(lhs, op, rhs, ctx)
+        },
+        ExpressionKind::Cast { ref expr, ref ty } => {
+            let ty = match TypeId::from_cast(ty) {
+                Some(ty) => ty,
+                None => return Err(format!("unknown type {}", ty)),
+            };
+
+            let value = expression(expr, scope, ctx)?;
+            cast(value, &ty, ctx)
+        },
+
+        ExpressionKind::Literal(Literal::Number(val)) => Ok(Value::f64(val)),
+        ExpressionKind::Literal(Literal::Int(val)) => Ok(Value::i64(val)),
+        ExpressionKind::Literal(Literal::Bool(val)) => Ok(Value::bool(val)),
+        ExpressionKind::Literal(Literal::String(ref val)) => {
+            let mut res = String::new();
+            for item in val {
+                match *item {
+                    StringPart::String(ref s) => res.push_str(s),
+                    StringPart::Expression(ref e, format) => {
+                        let val = expression(e, scope, ctx)?;
+                        res.push_str(&interpolate(val, format, ctx)?);
+                    },
+                }
+            }
+
+            Ok(Value::String(res))
+        },
+    }
+}
@@ -0,0 +1,53 @@
+//! Native implementation of the LLVM intrinsics declared in the STL
+
+pub fn exp(val: f64) -> f64 {
+    val.exp()
+}
+
+pub fn sqrt(val: f64) -> f64 {
+    val.sqrt()
+}
+
+pub fn pow(val: f64, exp: f64) -> f64 {
+    val.powf(exp)
+}
+
+pub fn sin(val: f64) -> f64 {
+    val.sin()
+}
+
+pub fn cos(val: f64) -> f64 {
+    val.cos()
+}
+
+pub fn floor(val: f64) -> f64 {
+    val.floor()
+}
+
+pub fn ceil(val: f64) -> f64 {
+    val.ceil()
+}
+
+pub fn round(val: f64) -> f64 {
+    val.round()
+}
+
+pub fn fmuladd(a: f64, b: f64, c: f64) -> f64 {
+    a.mul_add(b, c)
+}
+
+pub fn abs(val: f64) -> f64 {
+    val.abs()
+}
+
+pub fn log(val: f64) -> f64 {
+    val.ln()
+}
+
+pub fn min(lhs: f64, rhs: f64) -> f64 {
+    lhs.min(rhs)
+}
+
+pub fn max(lhs: f64, rhs: f64) -> f64 {
+    lhs.max(rhs)
+}
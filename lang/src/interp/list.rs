@@ -0,0 +1,161 @@
+//! Operations on lists, mutating the shared elements in place
+
+use std::cmp::Ordering;
+
+use rand::Rng;
+
+use atom::*;
+use compiler::types::TypeId;
+use hct::ast::*;
+use runtime::stl::weighted_choice;
+use runtime::types::Context;
+
+use super::expression::*;
+use super::scope::*;
+use super::value::*;
+
+/// Check if a function is a list operation taking a function as argument
+pub fn is_higher_order(name: &Atom) -> bool {
+    *name == hct_atom!("map") || *name == hct_atom!("filter")
+}
+
+/// Compare two elements of a list
+fn compare(lhs: &Value, rhs: &Value) -> Ordering {
+    match (lhs, rhs) {
+        (&Value::i64(lhs), &Value::i64(rhs)) => lhs.cmp(&rhs),
+        (&Value::bool(lhs), &Value::bool(rhs)) => lhs.cmp(&rhs),
+        (&Value::String(ref lhs), &Value::String(ref rhs)) => lhs.cmp(rhs),
+        (&Value::Atom(ref lhs), &Value::Atom(ref rhs)) |
+        (&Value::Entity(ref lhs), &Value::Entity(ref rhs)) => lhs.cmp(rhs),
+        _ => {
+            lhs.as_f64()
+                .partial_cmp(&rhs.as_f64())
+                .unwrap_or(Ordering::Equal)
+        },
+    }
+}
+
+/// Execute a call to map or filter, creating a new list from the
+/// results of a user function on each element of a list
+pub fn higher_order_call<'a>(name: &Atom, args: &[Expression], scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    if args.len() != 2 {
+        return Err(format!("{} expects 2 arguments, found {}", name, args.len()));
+    }
+
+    let func = match args[1].kind {
+        ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), .. }) => scope.function(name),
+        _ => None,
+    };
+
+    let func = match func {
+        Some(func) => func,
+        None => return Err(String::from("expected the name of a function")),
+    };
+
+    let list = expression(&args[0], scope, ctx)?;
+    let is_map = *name == hct_atom!("map");
+
+    // Filtered lists keep the type of their elements, while the type of mapped
+    // ones is the one of the results, unknown when the function is never called
+    let ty = match list {
+        Value::Vec(_, ref ty) if !is_map => Some(ty.clone()),
+        _ => None,
+    };
+    let list = list.as_list();
+
+    let mut res = Vec::new();
+    let mut index = 0;
+
+    // The length is read on each iteration, as the list may change in the function
+    while index < list.borrow().len() {
+        let value = list.borrow()[index].clone();
+        let ret = call_function(&func, vec![ value.clone() ], scope, ctx)?;
+
+        if is_map {
+            res.push(ret);
+        } else if ret.as_bool() {
+            res.push(value);
+        }
+
+        index += 1;
+    }
+
+    let ty = match ty {
+        Some(ty) => ty,
+        None => res.first().and_then(Value::vec_element).unwrap_or(TypeId::Void),
+    };
+
+    Ok(Value::vec(res, ty))
+}
+
+/// Execute a list operation, or return None if the called
+/// function is not one for these arguments
+pub fn list_call(name: &Atom, args: &[Value], ctx: &mut Context) -> Option<Eval<Value>> {
+    let arity = match *name {
+        hct_atom!("len") | hct_atom!("pop") | hct_atom!("sort") |
+        hct_atom!("choose") | hct_atom!("shuffle") => 1,
+        hct_atom!("push") | hct_atom!("contains") | hct_atom!("choose_weighted") => 2,
+        // Strings are concatenated by the STL
+        hct_atom!("concat") => match args.first() {
+            Some(&Value::Vec(..)) => 2,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    if args.len() != arity {
+        return Some(Err(format!("{} expects {} arguments, found {}", name, arity, args.len())));
+    }
+
+    let list = args[0].as_list();
+    let res = match *name {
+        hct_atom!("len") => Ok(Value::i64(list.borrow().len() as i64)),
+        hct_atom!("pop") => {
+            list.borrow_mut().pop()
+                .ok_or_else(|| String::from("cannot pop from an empty list"))
+        },
+        hct_atom!("sort") => {
+            list.borrow_mut().sort_by(compare);
+            Ok(Value::Void)
+        },
+        // The random numbers are drawn like the STL does, for the same results
+        hct_atom!("choose") => {
+            let list = list.borrow();
+            if list.is_empty() {
+                Err(String::from("cannot choose from an empty list"))
+            } else {
+                Ok(list[ctx.rng.gen_range(0, list.len())].clone())
+            }
+        },
+        hct_atom!("shuffle") => {
+            ctx.rng.shuffle(&mut list.borrow_mut()[..]);
+            Ok(Value::Void)
+        },
+        hct_atom!("choose_weighted") => {
+            let weights: Vec<_> = args[1].as_list().borrow().iter().map(Value::as_f64).collect();
+            let sample = ctx.rng.next_f64();
+            let list = list.borrow();
+            weighted_choice(&weights, list.len(), sample)
+                .map(|index| list[index].clone())
+        },
+        // Integers are widened to the type of the elements, like the type checker allows
+        hct_atom!("push") => {
+            let value = args[1].clone().widen_to(&args[0].element_type());
+            list.borrow_mut().push(value);
+            Ok(Value::Void)
+        },
+        hct_atom!("contains") => {
+            let value = args[1].clone().widen_to(&args[0].element_type());
+            Ok(Value::bool(list.borrow().contains(&value)))
+        },
+        hct_atom!("concat") => {
+            // Both operands may be the same list
+            let mut res = list.borrow().clone();
+            res.extend(args[1].as_list().borrow().iter().cloned());
+            Ok(Value::vec(res, args[0].element_type()))
+        },
+        _ => unreachable!(),
+    };
+
+    Some(res)
+}
@@ -0,0 +1,29 @@
+//! Math functions, implemented by the intrinsics or by the STL
+
+use atom::*;
+use compiler::types::TypeId;
+use runtime::stl::math_function;
+use runtime::types::Context;
+
+use super::expression::*;
+use super::value::*;
+
+/// Execute a math function, or return None if the called
+/// function is not one for these arguments
+pub fn math_call(name: &Atom, args: &[Value], ctx: &mut Context) -> Option<Eval<Value>> {
+    // Only the numeric types select the instance of a math function
+    let types: Vec<_> = {
+        args.iter()
+            .map(|val| match *val {
+                Value::i64(_) => TypeId::i64,
+                Value::vec3(_) => TypeId::vec3,
+                _ => TypeId::f64,
+            })
+            .collect()
+    };
+
+    match math_function(name, &types) {
+        Some(ext) => Some(call_stl(ctx, ext, args.to_vec())),
+        None => None,
+    }
+}
@@ -0,0 +1,451 @@
+//! Interpreter backend, running scripts directly from their AST
+//! It shares the runtime Context and the STL with the LLVM backend
+
+pub mod intrinsics;
+pub mod value;
+mod dict;
+mod expression;
+mod list;
+mod math;
+mod path;
+mod scope;
+mod statements;
+mod string;
+
+use std::collections::HashMap;
+
+use atom::Atom;
+use error::*;
+use hct::ast;
+use runtime::types::*;
+use vmf::ir::{self, Entity};
+
+use self::scope::Scope;
+use self::statements::statements;
+
+/// Run a script on a map
+pub fn apply(ent: ir::Script, script: ast::Script, entities: HashMap<Atom, Entity>) -> HatchetResult<HashMap<Atom, Entity>> {
+    let exec_start = timer_start!();
+
+    let name = ent.script.clone();
+    let keys: Vec<_> = entities.keys().cloned().collect();
+    let mut ctx = Context::new(ent, Arenas::new(), entities);
+
+    let res = statements(&script.body, Scope::root(keys), &mut ctx);
+
+    timer_end!(exec_start, time, "Execution time: {}", time);
+
+    match res {
+        Ok(_) => Ok(ctx.entities),
+        Err(message) => Err(HatchetError::Runtime {
+            script: name,
+            message,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use atom::Atom;
+    use compiler::check::check;
+    use error::*;
+    use hct::{ast, parse_source};
+    use hct::diagnostic::Level;
+    use vmf::ir::{self, Entity};
+
+    /// Build a map holding the entity the test scripts write their results to
+    fn entities() -> HashMap<Atom, Entity> {
+        let mut entities = HashMap::new();
+        entities.insert(Atom::from("out"), Entity {
+            classname: Atom::from("logic_relay"),
+            targetname: Some(Atom::from("out")),
+            ..Entity::default()
+        });
+
+        entities
+    }
+
+    /// Check the JIT backend gives the same result as the interpreter
+    #[cfg(feature = "jit")]
+    fn parity(ent: ir::Script, script: ast::Script, res: &HatchetResult<HashMap<Atom, Entity>>) {
+        let jit = ::compiler::apply(ent, script, entities());
+        match (res, &jit) {
+            (&Ok(ref interp), &Ok(ref jit)) => assert_eq!(interp, jit),
+            (&Err(ref interp), &Err(ref jit)) => assert_eq!(interp.to_string(), jit.to_string()),
+            _ => panic!("interp returned {:?} but jit returned {:?}", res, jit),
+        }
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn parity(_: ir::Script, _: ast::Script, _: &HatchetResult<HashMap<Atom, Entity>>) {}
+
+    /// Run a script with the interpreter, and with the JIT when it is built
+    fn execute(source: &str) -> HatchetResult<HashMap<Atom, Entity>> {
+        let script = parse_source("test.hct", String::from(source)).unwrap();
+        let errors: Vec<_> = {
+            check(&script, &entities()).into_iter()
+                .filter(|diag| diag.level == Level::Error)
+                .collect()
+        };
+        assert!(errors.is_empty(), "unexpected errors {:?}", errors);
+
+        let ent = ir::Script {
+            script: String::from("test.hct"),
+            seed: 0,
+        };
+
+        let res = super::apply(ent.clone(), script.clone(), entities());
+        parity(ent, script, &res);
+        res
+    }
+
+    /// Get the properties written on the `out` entity by a script
+    fn run(source: &str) -> HashMap<Atom, String> {
+        match execute(source) {
+            Ok(mut entities) => entities.remove(&Atom::from("out")).unwrap().properties,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Get the value written to `out.value` by a script
+    fn value(source: &str) -> String {
+        run(source).remove(&Atom::from("value")).expect("out.value was not set")
+    }
+
+    /// Get the runtime error raised by a script
+    fn error(source: &str) -> String {
+        match execute(source) {
+            Err(HatchetError::Runtime { message, .. }) => message,
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("the script did not fail"),
+        }
+    }
+
+    #[test]
+    fn stl_errors_stop_the_script() {
+        assert_eq!(
+            error("out.value = parse(\"abc\")\nout.other = 1"),
+            "parse: \"abc\" is not a number",
+        );
+        assert_eq!(
+            error("let a = 1\nlet b = a - 1\nout.value = a / b"),
+            "division by zero: 1 / 0",
+        );
+    }
+
+    #[test]
+    fn runs_statements() {
+        assert_eq!(value("let a = 2.5\nout.value = a * 2.5"), "6.25");
+        assert_eq!(
+            value("let i = 0.5\nlet s = \"\"\nwhile i < 4.5 {\n    s = s + \"${i}/\"\n    i = i + 1.5\n}\nout.value = s"),
+            "0.5/2/3.5/",
+        );
+        assert_eq!(
+            value("if 1.5 > 2.5 {\n    out.value = \"a\"\n} else {\n    out.value = \"b\"\n}"),
+            "b",
+        );
+    }
+
+    #[test]
+    fn relays_connect_their_calls() {
+        let mut entities = execute("relay go {\n    out.Trigger()\n}").unwrap();
+        let relay = entities.remove(&Atom::from("go")).expect("the relay was not created");
+        assert_eq!(relay.classname, Atom::from("logic_relay"));
+        assert_eq!(relay.connections.len(), 1);
+        assert_eq!(relay.connections[0].entity, Atom::from("out"));
+        assert_eq!(relay.connections[0].method, Atom::from("Trigger"));
+    }
+
+    #[test]
+    fn calls_user_functions() {
+        assert_eq!(value("fn add(a, b) {\n    return a + b\n}\nout.value = add(1.5, 2)"), "3.5");
+        assert_eq!(
+            value("fn fact(n) {\n    if n <= 1 {\n        return 1\n    }\n    return n * fact(n - 1)\n}\nout.value = fact(5)"),
+            "120",
+        );
+
+        // Calls from an event block connect the calls made by the function
+        let mut entities = execute("fn open() {\n    out.Trigger()\n}\nrelay go {\n    open()\n}").unwrap();
+        let relay = entities.remove(&Atom::from("go")).unwrap();
+        assert_eq!(relay.connections.len(), 1);
+        assert_eq!(relay.connections[0].method, Atom::from("Trigger"));
+    }
+
+    #[test]
+    fn computes_with_integers_and_booleans() {
+        assert_eq!(value("out.value = 7 / 2"), "3");
+        assert_eq!(value("out.value = 7 / 2.0"), "3.5");
+        assert_eq!(value("out.value = -7 % 3"), "-1");
+        assert_eq!(value("out.value = (0xFF & ~0x0F) >> 4"), "15");
+        assert_eq!(value("let a = 1\nout.value = -a + 0.5"), "-0.5");
+        assert_eq!(value("out.value = \"${!(1 < 2) || 3 == 3}\""), "true");
+    }
+
+    #[test]
+    fn leaves_loops_and_scripts() {
+        assert_eq!(
+            value("let s = \"\"\nfor i in range(0, 10) {\n    if i == 2 {\n        continue\n    }\n    if i == 5 {\n        break\n    }\n    s = s + \"${i}\"\n}\nout.value = s"),
+            "0134",
+        );
+        assert_eq!(
+            value("let i = 0\nwhile true {\n    i = i + 1\n    if i > 3 {\n        break\n    }\n}\nout.value = i"),
+            "4",
+        );
+        assert_eq!(value("out.value = 1\nreturn\nout.value = 2"), "1");
+    }
+
+    #[test]
+    fn indexes_and_slices_lists() {
+        assert_eq!(value("let l = [1.5, 2.5, 3.5]\nout.value = l[-1] + l[0]"), "5");
+        assert_eq!(
+            value("let l = [1, 2, 3, 4]\nlet s = l[1..3]\nout.value = \"${s} ${len(s)} ${len(l[2..])}\""),
+            "[2, 3] 2 2",
+        );
+        assert_eq!(
+            error("let l = [1, 2]\nout.value = l[2]"),
+            "index 2 is out of bounds for an array of length 2",
+        );
+    }
+
+    #[test]
+    fn grows_lists() {
+        assert_eq!(
+            value("let l = [3, 1, 2]\nsort(l)\npush(l, 4)\nlet last = pop(l)\nlet c = concat(l, [5])\nout.value = \"${l} ${last} ${c} ${contains(c, 5)}\""),
+            "[1, 2, 3] 4 [1, 2, 3, 5] true",
+        );
+        assert_eq!(
+            value("fn double(x) {\n    return x * 2\n}\nfn big(x) {\n    return x > 2\n}\nout.value = \"${filter(map([1, 2, 3], double), big)}\""),
+            "[4, 6]",
+        );
+    }
+
+    #[test]
+    fn widens_integers_stored_in_float_lists() {
+        assert_eq!(value("let l: [f64] = []\npush(l, 1)\nlet x = l[0]\nout.value = x / 2"), "0.5");
+        assert_eq!(value("let l = [0.5]\npush(l, 3)\nout.value = l[1] / 2"), "1.5");
+    }
+
+    #[test]
+    fn uses_dictionaries() {
+        assert_eq!(
+            value("let d: {String: i64} = { b: 1 }\ninsert(d, \"a\", 2)\ninsert(d, \"c\", 3)\nremove(d, \"a\")\nlet s = \"\"\nfor k, v in d {\n    s = s + \"${k}=${v} \"\n}\nlet has = contains(d, \"c\")\nout.value = s + \"${len(d)} ${has} ${keys(d)} ${values(d)}\""),
+            "b=1 c=3 2 true [b, c] [1, 3]",
+        );
+    }
+
+    #[test]
+    fn widens_integers_stored_in_float_dictionaries() {
+        let source = "let d: {String: f64} = { a: 1 }\ninsert(d, \"b\", 3)\n";
+        assert_eq!(value(&format!("{}out.value = get(d, \"a\", 0) / 2", source)), "0.5");
+        assert_eq!(value(&format!("{}out.value = get(d, \"b\", 0) / 2", source)), "1.5");
+        assert_eq!(value(&format!("{}out.value = get(d, \"c\", 1) / 2", source)), "0.5");
+    }
+
+    #[test]
+    fn assigns_nested_targets() {
+        assert_eq!(
+            value("let rooms = [{ lights: 1.5 }, { lights: 2.5 }]\nrooms[1].lights = 4\nout.value = rooms[1].lights / 8 + rooms[0].lights"),
+            "2",
+        );
+        assert_eq!(
+            value("let state = { doors: [1, 2] }\nstate.doors[-1] = 5\nout.value = state.doors[0] + state.doors[1]"),
+            "6",
+        );
+    }
+
+    #[test]
+    fn destructures_values() {
+        assert_eq!(value("let {a, b: c} = { a: 1.5, b: 2.5 }\nout.value = a + c"), "4");
+        assert_eq!(value("let [x, y] = [1, 2, 3]\nout.value = x * 10 + y"), "12");
+        assert_eq!(
+            value("let s = \"\"\nfor i, x in enumerate([5, 6]) {\n    s = s + \"${i}:${x} \"\n}\nout.value = s"),
+            "0:5 1:6 ",
+        );
+        assert_eq!(
+            value("let s = 0\nfor {a, b} in [{ a: 1, b: 2 }, { a: 3, b: 4 }] {\n    s = s + a * b\n}\nout.value = s"),
+            "14",
+        );
+        assert_eq!(
+            error("let [a, b, c] = [1, 2]"),
+            "index 2 is out of bounds for an array of length 2",
+        );
+    }
+
+    #[test]
+    fn iterates_ranges() {
+        let join = |range: &str| value(&format!(
+            "let s = \"\"\nfor i in {} {{\n    s = s + \"${{i}} \"\n}}\nout.value = s",
+            range,
+        ));
+
+        assert_eq!(join("0..3"), "0 1 2 ");
+        assert_eq!(join("3..0"), "3 2 1 ");
+        assert_eq!(join("0..=2"), "0 1 2 ");
+        assert_eq!(join("range(0, 1, 0.25)"), "0 0.25 0.5 0.75 ");
+        assert_eq!(join("range(6, 0, -2)"), "6 4 2 ");
+
+        // Integer ranges are iterated with integers
+        assert_eq!(
+            value("let s = \"\"\nfor i in 0..3 {\n    s = s + \"${i / 2}\"\n}\nout.value = s"),
+            "001",
+        );
+        assert_eq!(
+            error("let n = 0\nfor i in range(0, 3, n) {\n}"),
+            "range step cannot be zero",
+        );
+    }
+
+    #[test]
+    fn applies_compound_assignments() {
+        assert_eq!(value("let a = 1\na += 2\na *= 3\na -= 1\na %= 5\nout.value = a"), "3");
+        assert_eq!(
+            value("let l = [1.5]\nl[0] += 1\nlet o = { x: 3 }\no.x /= 2\nout.value = l[0] + o.x"),
+            "3.5",
+        );
+
+        let props = run("out.value = \"1.5\"\nout.value += 2\nout.name = \"a\"\nout.name += \"b\"");
+        assert_eq!(props[&Atom::from("value")], "3.5");
+        assert_eq!(props[&Atom::from("name")], "ab");
+    }
+
+    #[test]
+    fn branches_on_values() {
+        assert_eq!(value("let x = 2\nout.value = if x > 1 { \"big\" } else { \"small\" }"), "big");
+        assert_eq!(
+            value("let s = \"\"\nfor i in [1, 2, 3, 4] {\n    match i {\n        1 | 2 => {\n            s = s + \"a\"\n        }\n        3 => {\n            s = s + \"b\"\n        }\n        _ => {\n            s = s + \"c\"\n        }\n    }\n}\nout.value = s"),
+            "aabc",
+        );
+    }
+
+    #[test]
+    fn widens_integer_branches() {
+        assert_eq!(value("let x = if true { 1 } else { 2.5 }\nout.value = x / 2"), "0.5");
+        assert_eq!(value("let f = 1.5\nlet x = if true { 3 } else { f }\nout.value = x / 2"), "1.5");
+        assert_eq!(value("let x = if true { 3 } else { 2 }\nout.value = x / 2"), "1");
+    }
+
+    #[test]
+    fn formats_interpolated_numbers() {
+        assert_eq!(
+            value("let x = 1.5\nlet i = 7\nout.value = \"${x:.2} ${i:03} ${i:3}|${x}\""),
+            "1.50 007   7|1.5",
+        );
+        assert_eq!(value("out.value = \"a\\\"b\\\\c\\${d}\""), "a\"b\\c${d}");
+        assert_eq!(value("let l = [1, 2]\nout.value = \"${l} ${l[0] > 1}\""), "[1, 2] false");
+    }
+
+    #[test]
+    fn calls_string_functions() {
+        assert_eq!(
+            value("let parts = split(\"button_3_panel\", \"_\")\nout.value = \"${parts[1]} ${len(parts[0])} ${join(parts, \"-\")}\""),
+            "3 6 button-3-panel",
+        );
+        assert_eq!(
+            value("let s = \"  Door_A  \"\nout.value = upper(trim(s)) + lower(\"B\") + replace(\"a.b.c\", \".\", \"\") + substr(\"hello\", -4, 3)"),
+            "DOOR_Ababcell",
+        );
+        assert_eq!(
+            value("let s = \"door_12\"\nout.value = \"${find(s, \"_\")} ${find(s, \"x\")} ${contains(s, \"or\")} ${starts_with(s, \"do\")} ${ends_with(s, \"1\")} ${matches(s, \"door_*\")}\""),
+            "4 -1 true true false true",
+        );
+        assert_eq!(
+            error("out.value = substr(\"abc\", 2, 5)"),
+            "substring 2+5 is out of bounds for a string of length 3",
+        );
+    }
+
+    #[test]
+    fn computes_with_vectors() {
+        assert_eq!(
+            value("let a = vec3(1, 2, 3)\nlet b = vec3(4, 5, 6)\nout.value = a + b * 2 - vec3(1, 1, 1)"),
+            "8 11 14",
+        );
+        assert_eq!(
+            value("let a = vec3(1, 0, 0)\nlet b = vec3(0, 1, 0)\nout.value = \"${dot(a, b)} ${cross(a, b)} ${length(vec3(3, 4, 0))} ${distance(a, a + vec3(0, 3, 4))} ${normalize(vec3(0, 0, 2))} ${lerp(a, b, 0.5)}\""),
+            "0 0 0 1 5 5 0 0 1 0.5 0.5 0",
+        );
+        assert_eq!(
+            value("let r = rotate(vec3(1, 0, 0), qangle(0, 90, 0))\nout.value = \"${round(r.y)} ${abs(r.x) + abs(r.z) < 0.000001}\""),
+            "1 true",
+        );
+    }
+
+    #[test]
+    fn converts_vector_properties() {
+        let props = run("out.origin = vec3(1, 2, 3)\nout.origin.z += 1\nout.angles = qangle(0, 90, 0)\nout.value = out.origin.x + out.angles.yaw");
+        assert_eq!(props[&Atom::from("origin")], "1 2 4");
+        assert_eq!(props[&Atom::from("angles")], "0 90 0");
+        assert_eq!(props[&Atom::from("value")], "91");
+
+        assert_eq!(
+            error("out.origin = \"1 2\"\nout.value = out.origin.x"),
+            "\"1 2\" is not a vector of three numbers",
+        );
+    }
+
+    #[test]
+    fn calls_math_functions() {
+        assert_eq!(
+            value("out.value = \"${min(7, 5) / 2} ${max(1.5, 2)} ${clamp(12, 0, 10)} ${abs(-4)} ${sign(-2.5)} ${mod(-7, 3)}\""),
+            "2 2 10 4 -1 2",
+        );
+        assert_eq!(
+            value("out.value = \"${round(deg(atan2(1, 1)))} ${round(deg(rad(90)))} ${lerp(2, 4, 0.25)} ${floor(log(8) / log(2) + 0.5)}\""),
+            "45 90 2.5 3",
+        );
+        assert_eq!(error("let z = 0\nout.value = mod(5, z)"), "division by zero: mod(5, 0)");
+    }
+
+    #[test]
+    fn draws_seeded_random_values() {
+        let source = "let l = [1, 2, 3, 4, 5]\nshuffle(l)\nout.shuffled = \"${l}\"\nsort(l)\nout.value = \"${l}\"\nout.drawn = \"${rand_int(0, 10)} ${choose(l)} ${chance(0.5)} ${gaussian(1, 0)} ${noise(0.5, 0.5)}\"";
+        let props = run(source);
+        assert_eq!(props[&Atom::from("value")], "[1, 2, 3, 4, 5]");
+        // The seed of the script gives the same values on every run
+        assert_eq!(props, run(source));
+
+        assert_eq!(
+            value("let valid = true\nfor i in 0..50 {\n    let n = rand_int(2, 5)\n    if n < 2 || n >= 5 {\n        valid = false\n    }\n}\nout.value = \"${valid}\""),
+            "true",
+        );
+        assert_eq!(error("out.value = rand_int(3, 3)"), "rand_int: empty range 3 .. 3");
+        assert_eq!(error("let l = [1]\npop(l)\nout.value = choose(l)"), "cannot choose from an empty list");
+    }
+
+    #[test]
+    fn converts_properties() {
+        assert_eq!(value("out.health = \"40\"\nout.value = out.health + 10"), "50");
+        assert_eq!(value("out.flags = \"3\"\nout.value = out.flags & 1 | 4"), "5");
+        assert_eq!(
+            value("out.health = \"40\"\nout.value = \"${out.missing ?? 5} ${out.health ?? 1.5} ${has(out, \"health\")} ${has(out, \"missing\")}\""),
+            "5 40 true false",
+        );
+        assert_eq!(error("out.value = out.missing + 1"), "property \"missing\" not found");
+    }
+
+    #[test]
+    fn converts_values() {
+        assert_eq!(
+            value("let n = \"2.5\"\nlet i = \"7.9\"\nlet b = \"true\"\nout.value = \"${n as number * 2} ${i as int} ${b as bool} ${1.5 as int} ${3 as string + \"x\"}\""),
+            "5 7 true 1 3x",
+        );
+        assert_eq!(error("let s = \"yes\"\nlet b = s as bool"), "parse_bool: \"yes\" is not a boolean");
+        assert_eq!(
+            value("let t = \"1\"\nout.value = \"${parse_bool(t)} ${parse_bool(\"false\")}\""),
+            "true false",
+        );
+        assert_eq!(error("let s = \"2\"\nlet b = parse_bool(s)"), "parse_bool: \"2\" is not a boolean");
+        assert_eq!(error("let s = \"a\"\nlet n = s as number"), "parse: \"a\" is not a number");
+    }
+
+    #[test]
+    fn looks_up_entities() {
+        let lookup = "if let e = find(n) {\n    e.value = \"found\"\n} else {\n    out.value = \"missing\"\n}";
+        assert_eq!(value(&format!("let n = \"out\"\n{}", lookup)), "found");
+        assert_eq!(value(&format!("let n = \"door\"\n{}", lookup)), "missing");
+
+        assert_eq!(value("out.value = \"${exists(\"out\")} ${exists(\"door\")} ${exists(\"!player\")}\""), "true false true");
+    }
+}
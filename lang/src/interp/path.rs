@@ -0,0 +1,86 @@
+use hct::ast::{Path, PathKind};
+use runtime::types::Context;
+use atom::*;
+
+use super::expression::*;
+use super::scope::Scope;
+use super::value::*;
+
+pub fn resolve_path<'a>(path: &Path, scope: &Scope<'a>) -> Eval<Value> {
+    match path.kind {
+        PathKind::Deref(ref obj, ref prop) => {
+            match resolve_path(obj, scope)? {
+                res @ Value::Entity(_) => Ok(res),
+                Value::Object(items) => {
+                    let items = items.borrow();
+                    match items.binary_search_by(|&(ref item, _)| item.cmp(prop)) {
+                        Ok(idx) => Ok(items[idx].1.clone()),
+                        Err(_) => Err(format!("key \"{}\" not found in object", prop)),
+                    }
+                },
+                _ => Err(format!("trying to deref a non-map value {}", path)),
+            }
+        },
+        PathKind::Instance(ref obj) => resolve_path(obj, scope),
+        PathKind::Binding(ref name) => {
+            match scope.binding(name) {
+                Some(val) => Ok(val),
+                None => if name.starts_with('@') {
+                    Ok(Value::Entity(name.clone()))
+                } else {
+                    Err(format!("entity {} not found", path))
+                }
+            }
+        },
+    }
+}
+
+type Trigger = (Option<Value>, Option<Value>, Option<Atom>);
+
+fn break_trigger<'a>(path: &Path, scope: &Scope<'a>) -> Eval<Trigger> {
+    match path.kind {
+        PathKind::Deref(ref obj, ref prop) => match break_trigger(obj, scope)? {
+            (a, Some(b), None) => Ok((a, Some(b), Some(prop.clone()))),
+            (a, None, None) => Ok((a, Some(Value::Entity(prop.clone())), None)),
+            _ => Err(String::from("invalid event path")),
+        },
+        PathKind::Instance(ref pat) => Ok((Some(resolve_path(pat, scope)?), None, None)),
+        PathKind::Binding(_) => Ok((None, Some(resolve_path(path, scope)?), None)),
+    }
+}
+
+/// Get the entity referenced by a path and its associated event string
+/// If no event is explicitly called, "Trigger" will be used
+pub fn event<'a>(path: &Path, scope: &Scope<'a>, ctx: &mut Context) -> Eval<(Value, Value)> {
+    match break_trigger(path, scope)? {
+        (Some(inst), Some(ent), Some(method)) => {
+            let method = call_stl(
+                ctx,
+                hct_atom!("get_instance"),
+                vec![ ent, Value::Atom(method) ],
+            )?;
+            Ok((inst, method))
+        },
+
+        (None, Some(ent), Some(method)) => Ok((
+            ent,
+            Value::Atom(method),
+        )),
+
+        (Some(inst), Some(ent), None) => {
+            let method = call_stl(
+                ctx,
+                hct_atom!("get_instance"),
+                vec![ ent, Value::Atom(hct_atom!("Trigger")) ],
+            )?;
+            Ok((inst, method))
+        },
+
+        (None, Some(ent), None) => Ok((
+            ent,
+            Value::Atom(hct_atom!("Trigger")),
+        )),
+
+        _ => Err(String::from("invalid event path")),
+    }
+}
@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use atom::*;
+use hct::ast::Function;
+use super::value::*;
+
+/// Represent a scope (group of bindings in a block) in a running script
+pub struct Scope<'a> {
+    parent: Option<&'a Scope<'a>>,
+    bindings: HashMap<Atom, (bool, Rc<RefCell<Value>>)>,
+    functions: HashMap<Atom, Rc<Function>>,
+
+    event: Option<(Value, Value)>,
+    delay: Option<f64>,
+}
+
+impl<'a> Scope<'a> {
+    /// Creates the root scope for a script using a map's entity list
+    pub fn root<I: IntoIterator<Item=Atom>>(keys: I) -> Scope<'a> {
+        Scope {
+            parent: None,
+            bindings: {
+                keys.into_iter()
+                    .map(|k| {
+                        let val = Value::Entity(k.clone());
+                        (k, (true, Rc::new(RefCell::new(val))))
+                    })
+                    .collect()
+            },
+
+            functions: Default::default(),
+
+            event: None,
+            delay: None,
+        }
+    }
+
+    /// Create a new child scope
+    pub fn fork(&'a self) -> Scope<'a> {
+        Scope {
+            parent: Some(self),
+            bindings: Default::default(),
+
+            functions: Default::default(),
+
+            event: None,
+            delay: None,
+        }
+    }
+
+    /// Creates a new scope with several additional value bindings
+    pub fn with_bindings(&'a self, values: Vec<(Atom, Value)>) -> Scope<'a> {
+        let mut res = self.fork();
+        for (key, value) in values {
+            res.set_binding(key, value);
+        }
+        res
+    }
+
+    /// Mutate this scope to add a new value binding
+    pub fn set_binding(&mut self, key: Atom, value: Value) {
+        self.bindings.insert(key, (false, Rc::new(RefCell::new(value))));
+    }
+
+    /// Mutate this scope to add a new constant binding
+    pub fn set_const(&mut self, key: Atom, value: Value) {
+        self.bindings.insert(key, (true, Rc::new(RefCell::new(value))));
+    }
+
+    /// Mutate this scope to declare a user function
+    pub fn set_function(&mut self, key: Atom, func: Rc<Function>) {
+        self.functions.insert(key, func);
+    }
+
+    /// Get the declaration of a user function
+    pub fn function(&self, key: &Atom) -> Option<Rc<Function>> {
+        self.functions.get(key).cloned()
+            .or_else(|| {
+                self.parent.and_then(|parent| parent.function(key))
+            })
+    }
+
+    /// Creates the scope of a user function body, which only sees the
+    /// constant bindings and the functions visible from its call site
+    /// The event and delay of the caller are kept, so calls in the
+    /// body of the function are still translated to connections
+    pub fn function_body(&self) -> Scope<'static> {
+        let mut bindings = HashMap::new();
+        let mut functions = HashMap::new();
+
+        let mut current = Some(self);
+        while let Some(scope) = current {
+            for (key, value) in &scope.bindings {
+                bindings.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            for (key, func) in &scope.functions {
+                functions.entry(key.clone()).or_insert_with(|| func.clone());
+            }
+
+            current = scope.parent;
+        }
+
+        Scope {
+            parent: None,
+            bindings: {
+                bindings.into_iter()
+                    .filter(|&(_, (is_const, _))| is_const)
+                    .collect()
+            },
+            functions,
+
+            event: self.event(),
+            delay: self.delay(),
+        }
+    }
+
+    fn binding_ptr(&self, key: &Atom) -> Option<(bool, Rc<RefCell<Value>>)> {
+        self.bindings.get(key).cloned()
+            .or_else(|| {
+                self.parent.and_then(|parent| parent.binding_ptr(key))
+            })
+    }
+
+    /// Get the value of a binding
+    pub fn binding(&self, key: &Atom) -> Option<Value> {
+        self.binding_ptr(key)
+            .map(|(_, value)| value.borrow().clone())
+    }
+
+    /// Get the mutable cell of a binding
+    pub fn binding_mut(&self, key: &Atom) -> Option<Rc<RefCell<Value>>> {
+        self.binding_ptr(key)
+            .and_then(|(is_const, value)| {
+                if is_const {
+                    None
+                } else {
+                    Some(value)
+                }
+            })
+    }
+
+    /// Get the event of this scope
+    pub fn event(&self) -> Option<(Value, Value)> {
+        self.event.clone().or_else(|| {
+            self.parent.and_then(|parent| parent.event())
+        })
+    }
+
+    /// Get the delay of this scope
+    pub fn delay(&self) -> Option<f64> {
+        self.delay.or_else(|| {
+            self.parent.and_then(|parent| parent.delay())
+        })
+    }
+
+    /// Creates a new scope for the execution of an auto block
+    pub fn auto(&'a self) -> Scope<'a> {
+        self.subscriber(
+            Value::Entity(hct_atom!("")),
+            Value::Atom(hct_atom!("OnMapSpawn")),
+        )
+    }
+
+    /// Creates a new scope for the execution of a relay block
+    pub fn relay(&'a self, ent: &Atom) -> Scope<'a> {
+        self.subscriber(
+            Value::Entity(ent.clone()),
+            Value::Atom(hct_atom!("OnTrigger")),
+        )
+    }
+
+    /// Creates a new scope for the execution of a subscriber block
+    pub fn subscriber(&'a self, entity: Value, method: Value) -> Scope<'a> {
+        Scope {
+            parent: Some(self),
+            bindings: Default::default(),
+            functions: Default::default(),
+
+            event: Some((entity, method)),
+            delay: None,
+        }
+    }
+
+    /// Creates a new scope for the execution of an inner delay block
+    /// Like the compiled code, only the delay of this exact scope is accumulated
+    pub fn with_delay(&'a self, time: f64) -> Scope<'a> {
+        Scope {
+            parent: Some(self),
+            bindings: Default::default(),
+            functions: Default::default(),
+
+            event: None,
+            delay: Some(self.delay.map_or(time, |val| val + time)),
+        }
+    }
+}
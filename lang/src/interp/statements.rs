@@ -0,0 +1,520 @@
+//! Execute a block (scoped list of statements)
+
+use std::iter::once;
+use std::rc::Rc;
+
+use atom::*;
+use compiler::check::vector_property;
+use compiler::types::TypeId;
+use hct::ast::*;
+use runtime::stl::list_index;
+use runtime::types::Context;
+use vmf::ir::Entity;
+
+use super::dict::*;
+use super::expression::*;
+use super::path::*;
+use super::scope::*;
+use super::value::*;
+
+/// Control flow after the execution of a block
+pub enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// Execute a nested block, leaving the current one if it returned
+macro_rules! nested {
+    ($e:expr) => {
+        match $e? {
+            Flow::Next => {},
+            flow => return Ok(flow),
+        }
+    };
+}
+
+/// Execute the body of a loop, leaving the loop on a
+/// break and the current block on a return
+macro_rules! loop_body {
+    ($e:expr) => {
+        match $e? {
+            Flow::Next | Flow::Continue => {},
+            Flow::Break => break,
+            flow => return Ok(flow),
+        }
+    };
+}
+
+/// Get the values bound to the names of a pattern
+fn destructure(pattern: &Pattern, value: Value, ctx: &mut Context) -> Eval<Vec<(Atom, Value)>> {
+    match *pattern {
+        Pattern::Name(ref name) => Ok(vec![ (name.clone(), value) ]),
+        Pattern::Object(ref fields) => {
+            fields.iter()
+                .map(|&(ref key, ref name)| Ok((name.clone(), field(value.clone(), key, ctx)?)))
+                .collect()
+        },
+        Pattern::Array(ref names) => {
+            let list = match value {
+                Value::Array(list) | Value::Vec(list, _) => list,
+                value => return Err(format!("cannot destructure a {:?} as a list", value)),
+            };
+
+            // Like an index past the end, as the JIT reads the elements with vec_get
+            let list = list.borrow();
+            if names.len() > list.len() {
+                return Err(format!("index {} is out of bounds for an array of length {}", list.len(), list.len()));
+            }
+
+            let values = names.iter().cloned().zip(list.iter().cloned()).collect();
+            Ok(values)
+        },
+    }
+}
+
+/// Run the body of a loop for each number of a range, going from the start towards the end
+/// Ranges of integers are iterated with integers, and with floats otherwise
+fn range_loop<'a>(var: &Pattern, range: &Range, body: &[Statement], scope: &Scope<'a>, ctx: &mut Context) -> Eval<Flow> {
+    let mut bounds = Vec::with_capacity(3);
+    for exp in once(&range.start).chain(once(&range.end)).chain(range.step.as_ref()) {
+        bounds.push(expression(exp, scope, ctx)?);
+    }
+
+    let is_int = bounds.iter().all(|val| match *val {
+        Value::i64(_) => true,
+        _ => false,
+    });
+
+    let start = bounds[0].as_f64();
+    let end = bounds[1].as_f64();
+    let step = match bounds.get(2) {
+        Some(step) => step.as_f64(),
+        None if start <= end => 1.0,
+        None => -1.0,
+    };
+
+    // A zero step would never reach the end of the range
+    if step == 0.0 {
+        return Err(String::from("range step cannot be zero"));
+    }
+
+    let mut it = start;
+    loop {
+        let in_range = match (step > 0.0, range.inclusive) {
+            (true, false) => it < end,
+            (true, true) => it <= end,
+            (false, false) => it > end,
+            (false, true) => it >= end,
+        };
+
+        if !in_range {
+            break;
+        }
+
+        let value = if is_int {
+            Value::i64(it as i64)
+        } else {
+            Value::f64(it)
+        };
+
+        it += step;
+
+        let values = destructure(var, value, ctx)?;
+        loop_body!(statements(body, scope.with_bindings(values), ctx));
+    }
+
+    Ok(Flow::Next)
+}
+
+/// Run the body of a loop for each element of a list or each entry of a dictionary,
+/// also binding the index of the elements of an enumerated list
+/// The length is read on each iteration, as the list may change in the body
+fn iterate<'a>(var: &Pattern, value_var: &Option<Pattern>, list: &Value, enumerate: bool, body: &[Statement], scope: &Scope<'a>, ctx: &mut Context) -> Eval<Flow> {
+    if let Value::Dict(ref entries, ..) = *list {
+        let mut index = 0;
+        while index < entries.borrow().len() {
+            let (key, value) = entries.borrow()[index].clone();
+            index += 1;
+
+            let mut values = destructure(var, key, ctx)?;
+            if let Some(ref value_var) = *value_var {
+                values.extend(destructure(value_var, value, ctx)?);
+            }
+
+            loop_body!(statements(body, scope.with_bindings(values), ctx));
+        }
+
+        return Ok(Flow::Next);
+    }
+
+    if value_var.is_some() && !enumerate {
+        return Err(String::from("only dictionaries and enumerated lists can be iterated with two bindings"));
+    }
+
+    let list = list.as_list();
+    let mut index = 0;
+    while index < list.borrow().len() {
+        let value = list.borrow()[index].clone();
+        index += 1;
+
+        let values = if enumerate {
+            let mut values = destructure(var, Value::i64(index as i64 - 1), ctx)?;
+            if let Some(ref value_var) = *value_var {
+                values.extend(destructure(value_var, value, ctx)?);
+            }
+
+            values
+        } else {
+            destructure(var, value, ctx)?
+        };
+
+        loop_body!(statements(body, scope.with_bindings(values), ctx));
+    }
+
+    Ok(Flow::Next)
+}
+
+/// Compute the value of a binding with a type annotation
+fn annotated_value<'a>(value: &Expression, ty: &TypeId, scope: &Scope<'a>, ctx: &mut Context) -> Eval<Value> {
+    match (ty, &value.kind) {
+        // Empty lists take their element type from the annotation
+        (&TypeId::Vec { ref ty }, &ExpressionKind::Array(ref values)) if values.is_empty() => Ok(Value::vec(Vec::new(), (**ty).clone())),
+        // Map literals are converted to the annotated dictionary
+        (&TypeId::HashMap { ref key, value: ref elem }, &ExpressionKind::Map(ref entries)) => dict_literal(entries, key, elem, scope, ctx),
+        (&TypeId::f64, _) => Ok(Value::f64(expression(value, scope, ctx)?.as_f64())),
+        _ => expression(value, scope, ctx),
+    }
+}
+
+/// Convert a value assigned to an entity property to a string
+fn property_value(value: Value, ctx: &mut Context) -> Eval<Value> {
+    match value {
+        Value::String(_) => Ok(value),
+        Value::f64(_) | Value::i64(_) => call_stl(
+            ctx,
+            hct_atom!("to_string"),
+            vec![ Value::f64(value.as_f64()) ],
+        ),
+        Value::vec3(_) | Value::qangle(_) => {
+            let name = vector_function("vector_to_string", &value);
+            call_stl(ctx, name, vec![ value ])
+        },
+        _ => Err(format!("cannot assign a {:?} to a property", value)),
+    }
+}
+
+/// Compute the value stored by an assignment, applying the
+/// operator of a compound assignment to the current value
+fn compound<F>(op: Option<Operator>, value: Value, ctx: &mut Context, current: F) -> Eval<Value>
+    where F: FnOnce(&mut Context) -> Eval<Value> {
+    match op {
+        Some(op) => {
+            let current = current(ctx)?;
+            binary(current, op, value, ctx)
+        },
+        None => Ok(value),
+    }
+}
+
+/// Compute the value stored in an entity property by an assignment, the
+/// current value being parsed as a vector or a number unless a string
+/// is appended to it
+fn property_compound<F>(op: Option<Operator>, prop: &Atom, value: Value, ctx: &mut Context, current: F) -> Eval<Value>
+    where F: FnOnce(&mut Context) -> Eval<Value> {
+    let parse = match value {
+        Value::String(_) => None,
+        _ => match vector_property(prop) {
+            Some(ty) => Some(Atom::from(format!("parse_vector.{:?}", ty))),
+            None => Some(hct_atom!("parse")),
+        },
+    };
+
+    compound(op, value, ctx, |ctx| {
+        let current = current(ctx)?;
+        match parse {
+            Some(parse) => call_stl(ctx, parse, vec![ current ]),
+            None => Ok(current),
+        }
+    })
+}
+
+/// Assign a value to a binding, an element of a list,
+/// a field of an object or an entity property, reading
+/// and writing the target once for compound assignments
+fn assignment<'a>(target: &Expression, op: Option<Operator>, value: Value, scope: &Scope<'a>, ctx: &mut Context) -> Eval<()> {
+    match target.kind {
+        ExpressionKind::Reference(Path { kind: PathKind::Binding(ref name), .. }) => {
+            let head = match scope.binding_mut(name) {
+                Some(head) => head,
+                None => return Err(format!("binding \"{}\" not found", name)),
+            };
+
+            let value = compound(op, value, ctx, |_| Ok(head.borrow().clone()))?;
+            let value = widen(value, &head.borrow());
+            *head.borrow_mut() = value;
+            return Ok(());
+        },
+
+        ExpressionKind::Index { ref expr, ref index } => {
+            let list = expression(expr, scope, ctx)?.as_list();
+            let index = expression(index, scope, ctx)?.as_i64();
+
+            let mut list = list.borrow_mut();
+            let len = list.len();
+            return match list_index(index, len) {
+                Some(idx) => {
+                    let value = compound(op, value, ctx, |_| Ok(list[idx].clone()))?;
+                    let value = widen(value, &list[idx]);
+                    list[idx] = value;
+                    Ok(())
+                },
+                None => Err(format!("index {} is out of bounds for an array of length {}", index, len)),
+            };
+        },
+
+        _ => {},
+    }
+
+    let (obj, prop) = match target.clone().split_field() {
+        Ok(field) => field,
+        Err(_) => return Err(String::from("cannot assign to this expression")),
+    };
+
+    // Sub-properties are set on the entity holding the property
+    let obj = match obj.split_field() {
+        Ok((base, key)) => {
+            let base = expression(&base, scope, ctx)?;
+            if let Value::Entity(_) = base {
+                let sub = sub_property(&prop)?;
+                // The raw string of the property is read, like it is written
+                let value = property_compound(op, &prop, value, ctx, |ctx| {
+                    let current = call_stl(
+                        ctx,
+                        hct_atom!("get_property"),
+                        vec![ base.clone(), Value::Atom(key.clone()) ],
+                    )?;
+                    call_stl(
+                        ctx,
+                        hct_atom!("get_sub_property"),
+                        vec![ current, Value::i64(sub) ],
+                    )
+                })?;
+
+                let value = property_value(value, ctx)?;
+                call_stl(
+                    ctx,
+                    hct_atom!("set_sub_property"),
+                    vec![ base, Value::Atom(key), Value::i64(sub), value ],
+                )?;
+                return Ok(());
+            }
+
+            field(base, &key, ctx)?
+        },
+        Err(obj) => expression(&obj, scope, ctx)?,
+    };
+
+    match obj {
+        Value::Object(items) => {
+            let mut items = items.borrow_mut();
+            match items.binary_search_by(|&(ref item, _)| item.cmp(&prop)) {
+                Ok(idx) => {
+                    let value = compound(op, value, ctx, |_| Ok(items[idx].1.clone()))?;
+                    let value = widen(value, &items[idx].1);
+                    items[idx].1 = value;
+                    Ok(())
+                },
+                Err(_) => Err(format!("key \"{}\" not found in object", prop)),
+            }
+        },
+        obj @ Value::Entity(_) => {
+            let value = property_compound(op, &prop, value, ctx, |ctx| call_stl(
+                ctx,
+                hct_atom!("get_property"),
+                vec![ obj.clone(), Value::Atom(prop.clone()) ],
+            ))?;
+            let value = property_value(value, ctx)?;
+            call_stl(
+                ctx,
+                hct_atom!("set_property"),
+                vec![ obj, Value::Atom(prop), value ],
+            )?;
+            Ok(())
+        },
+        obj => Err(format!("cannot assign to a field of a {:?}", obj)),
+    }
+}
+
+/// Execute a list of script statements in order
+pub fn statements<'a>(list: &[Statement], mut scope: Scope<'a>, ctx: &mut Context) -> Eval<Flow> {
+    // Hoist the entity declarations to the top of the block
+    for stmt in list {
+        match stmt.kind {
+            // Register the new Relay entities
+            StatementKind::Relay { ref name, .. } => {
+                scope.set_const(name.clone(), Value::Entity(name.clone()));
+
+                ctx.entities.insert(name.clone(), Entity {
+                    classname: hct_atom!("logic_relay"),
+                    targetname: Some(name.clone()),
+                    .. Default::default()
+                });
+            },
+
+            // Insert the logic_auto entity if needed
+            StatementKind::Auto { .. } => {
+                ctx.entities
+                    .entry(hct_atom!(""))
+                    .or_insert(Entity {
+                        classname: hct_atom!("logic_auto"),
+                        .. Default::default()
+                    });
+            },
+
+            StatementKind::Function(ref func) => {
+                scope.set_function(func.name.clone(), Rc::new(func.clone()));
+            },
+
+            _ => {},
+        }
+    }
+
+    for stmt in list {
+        match stmt.kind {
+            StatementKind::Binding { ref pattern, ref ty, ref value } => {
+                let value = match ty.as_ref().and_then(TypeId::from_annotation) {
+                    Some(ty) => annotated_value(value, &ty, &scope, ctx)?,
+                    None => expression(value, &scope, ctx)?,
+                };
+
+                for (name, value) in destructure(pattern, value, ctx)? {
+                    scope.set_binding(name, value);
+                }
+            },
+
+            StatementKind::Relay { ref name, ref body } => {
+                nested!(statements(body, scope.relay(name), ctx));
+            },
+
+            StatementKind::Auto { ref body } => {
+                nested!(statements(body, scope.auto(), ctx));
+            },
+
+            StatementKind::Subscriber { ref path, ref body } => {
+                let (entity, method) = event(path, &scope, ctx)?;
+                nested!(statements(body, scope.subscriber(entity, method), ctx));
+            },
+
+            StatementKind::Delay { ref body, ref time } => {
+                let time = expression(time, &scope, ctx)?.as_f64();
+                nested!(statements(body, scope.with_delay(time), ctx));
+            },
+
+            StatementKind::Loop { ref condition, ref body } => {
+                while expression(condition, &scope, ctx)?.as_bool() {
+                    loop_body!(statements(body, scope.fork(), ctx));
+                }
+            },
+
+            StatementKind::Iterator { ref var, ref value, array: Expression { kind: ExpressionKind::Call(Call { path: Path { kind: PathKind::Binding(hct_atom!("enumerate")), .. }, ref args, .. }), .. }, ref body } => {
+                if args.len() != 1 {
+                    return Err(format!("enumerate expects 1 argument, found {}", args.len()));
+                }
+
+                let list = expression(&args[0], &scope, ctx)?;
+                nested!(iterate(var, value, &list, true, body, &scope, ctx));
+            },
+            StatementKind::Iterator { ref var, ref value, ref array, ref body } => {
+                if let Some(range) = array.range() {
+                    if value.is_some() {
+                        return Err(String::from("only dictionaries and enumerated lists can be iterated with two bindings"));
+                    }
+
+                    nested!(range_loop(var, &range?, body, &scope, ctx));
+                } else {
+                    let array = expression(array, &scope, ctx)?;
+                    nested!(iterate(var, value, &array, false, body, &scope, ctx));
+                }
+            },
+
+            StatementKind::Branch { ref condition, ref consequent, ref alternate } => {
+                if expression(condition, &scope, ctx)?.as_bool() {
+                    nested!(statements(consequent, scope.fork(), ctx));
+                } else if let Some(ref alternate) = *alternate {
+                    nested!(statements(alternate, scope.fork(), ctx));
+                }
+            },
+
+            StatementKind::BranchLet { ref name, ref value, ref consequent, ref alternate } => {
+                let value = expression(value, &scope, ctx)?;
+                if call_stl(ctx, hct_atom!("entity_exists"), vec![ value.clone() ])?.as_bool() {
+                    let mut inner = scope.fork();
+                    inner.set_binding(name.clone(), value);
+                    nested!(statements(consequent, inner, ctx));
+                } else if let Some(ref alternate) = *alternate {
+                    nested!(statements(alternate, scope.fork(), ctx));
+                }
+            },
+
+            StatementKind::Match { ref value, ref arms } => {
+                let value = expression(value, &scope, ctx)?;
+
+                // Arms are tested in order, the wildcard always matching
+                for arm in arms {
+                    let matched = match arm.values {
+                        Some(ref values) => {
+                            let mut matched = false;
+                            for exp in values {
+                                let exp = expression(exp, &scope, ctx)?;
+                                if binary(value.clone(), Operator::Eq, exp, ctx)?.as_bool() {
+                                    matched = true;
+                                    break;
+                                }
+                            }
+
+                            matched
+                        },
+                        None => true,
+                    };
+
+                    if matched {
+                        nested!(statements(&arm.body, scope.fork(), ctx));
+                        break;
+                    }
+                }
+            },
+
+            StatementKind::Assignment { ref target, op, ref value } => {
+                let value = expression(value, &scope, ctx)?;
+                assignment(target, op, value, &scope, ctx)?;
+            },
+
+            StatementKind::Call(ref val) => {
+                call(val, &scope, ctx)?;
+            },
+
+            StatementKind::Function(_) => {},
+
+            StatementKind::Return(ref value) => {
+                let value = match *value {
+                    Some(ref value) => expression(value, &scope, ctx)?,
+                    None => Value::Void,
+                };
+
+                return Ok(Flow::Return(value));
+            },
+
+            StatementKind::Break => return Ok(Flow::Break),
+            StatementKind::Continue => return Ok(Flow::Continue),
+
+            // Imports are resolved when loading the script
+            StatementKind::Import { .. } => {},
+
+            StatementKind::Error(ref message) => return Err(message.clone()),
+        }
+    }
+
+    Ok(Flow::Next)
+}
@@ -0,0 +1,25 @@
+//! Operations on strings, implemented by the STL
+
+use atom::*;
+use compiler::types::TypeId;
+use runtime::stl::string_function;
+use runtime::types::Context;
+
+use super::expression::*;
+use super::value::*;
+
+/// Execute a string function, or return None if the called
+/// function is not one for these arguments
+pub fn string_call(name: &Atom, args: &[Value], ctx: &mut Context) -> Option<Eval<Value>> {
+    let first = match args.first() {
+        Some(&Value::String(_)) => TypeId::String,
+        // Only lists of strings pass the type checker as strings to join
+        Some(&Value::Vec(..)) => TypeId::Vec { ty: box TypeId::String },
+        _ => return None,
+    };
+
+    match string_function(name, args.len(), &first) {
+        Some(ext) => Some(call_stl(ctx, ext, args.to_vec())),
+        None => None,
+    }
+}
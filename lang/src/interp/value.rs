@@ -0,0 +1,133 @@
+//! Dynamic values manipulated by the interpreter
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use atom::Atom;
+use compiler::types::TypeId;
+
+/// Runtime value of an expression, the dynamic counterpart of a TypeId
+/// Aggregates are shared on copy, like the pointers of the compiled code
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Void,
+    f64(f64),
+    bool(bool),
+    i64(i64),
+
+    Atom(Atom),
+    Entity(Atom),
+    String(String),
+    vec3([f64; 3]),
+    qangle([f64; 3]),
+
+    Array(Rc<RefCell<Vec<Value>>>),
+    /// Elements and their static type, which stays known when the list is empty
+    Vec(Rc<RefCell<Vec<Value>>>, TypeId),
+
+    /// Items sorted by key
+    Object(Rc<RefCell<Vec<(Atom, Value)>>>),
+    /// Entries in insertion order, and the static types of their keys and values
+    Dict(Rc<RefCell<Vec<(Value, Value)>>>, TypeId, TypeId),
+}
+
+macro_rules! accessor {
+    ($name:ident, $variant:ident, $ty:ty) => {
+        pub fn $name(&self) -> $ty {
+            match *self {
+                Value::$variant(ref val) => val.clone(),
+                ref val => panic!(concat!("expected a ", stringify!($variant), ", found {:?}"), val),
+            }
+        }
+    };
+}
+
+impl Value {
+    // Scripts are type checked before being run, so a mismatch is a bug
+    accessor!(as_bool, bool, bool);
+    accessor!(as_i64, i64, i64);
+    accessor!(as_atom, Atom, Atom);
+    accessor!(as_entity, Entity, Atom);
+    accessor!(as_string, String, String);
+
+    /// Get the elements of a growable list
+    pub fn as_vec(&self) -> Rc<RefCell<Vec<Value>>> {
+        match *self {
+            Value::Vec(ref val, _) => val.clone(),
+            ref val => panic!("expected a Vec, found {:?}", val),
+        }
+    }
+
+    /// Get the type of the elements of a growable list
+    pub fn element_type(&self) -> TypeId {
+        match *self {
+            Value::Vec(_, ref ty) => ty.clone(),
+            ref val => panic!("expected a Vec, found {:?}", val),
+        }
+    }
+
+    /// Get a float, widening integers like the type checker allows
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Value::f64(val) => val,
+            Value::i64(val) => val as f64,
+            ref val => panic!("expected a f64, found {:?}", val),
+        }
+    }
+
+    /// Get the components of a vector or an angle
+    pub fn as_vector(&self) -> [f64; 3] {
+        match *self {
+            Value::vec3(val) | Value::qangle(val) => val,
+            ref val => panic!("expected a vector, found {:?}", val),
+        }
+    }
+
+    /// Get the elements of an array or a vector
+    pub fn as_list(&self) -> Rc<RefCell<Vec<Value>>> {
+        match *self {
+            Value::Array(ref val) | Value::Vec(ref val, _) => val.clone(),
+            ref val => panic!("expected a list, found {:?}", val),
+        }
+    }
+
+    /// Get the type of a value that can be stored in a growable list
+    pub fn vec_element(&self) -> Option<TypeId> {
+        match *self {
+            Value::f64(_) => Some(TypeId::f64),
+            Value::bool(_) => Some(TypeId::bool),
+            Value::i64(_) => Some(TypeId::i64),
+            Value::Atom(_) => Some(TypeId::Atom),
+            Value::Entity(_) => Some(TypeId::Entity),
+            Value::String(_) => Some(TypeId::String),
+            _ => None,
+        }
+    }
+
+    /// Convert a value stored with a static type,
+    /// widening integers like the type checker allows
+    pub fn widen_to(self, ty: &TypeId) -> Value {
+        match (self, ty) {
+            (Value::i64(val), &TypeId::f64) => Value::f64(val as f64),
+            (value, _) => value,
+        }
+    }
+
+    pub fn array(values: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RefCell::new(values)))
+    }
+
+    pub fn vec(values: Vec<Value>, ty: TypeId) -> Value {
+        Value::Vec(Rc::new(RefCell::new(values)), ty)
+    }
+
+    pub fn dict(entries: Vec<(Value, Value)>, key: TypeId, value: TypeId) -> Value {
+        Value::Dict(Rc::new(RefCell::new(entries)), key, value)
+    }
+
+    pub fn object(mut items: Vec<(Atom, Value)>) -> Value {
+        items.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+        Value::Object(Rc::new(RefCell::new(items)))
+    }
+}
@@ -207,6 +207,10 @@ macro_rules! write_col {
         print_statement($fmt, $val)?;
         write_col!( $fmt $( $rest )* )
     }};
+    ( $fmt:expr, Pattern( $val:expr ) $( $rest:tt )* ) => {{
+        print_pattern($fmt, $val)?;
+        write_col!( $fmt $( $rest )* )
+    }};
     ( $fmt:expr, $col:ident( $val:expr ) $( $rest:tt )* ) => {{
         print_colored($fmt, $val, ColorType::$col)?;
         write_col!( $fmt $( $rest )* )
@@ -231,16 +235,31 @@ macro_rules! writeln_col {
 fn print_literal(fmt: &mut Terminal<Output=io::Stdout>, lit: &Literal) -> TermResult<()> {
     match *lit {
         Literal::Number(ref val) => write_col!(fmt, Number(val)),
+        Literal::Int(ref val) => write_col!(fmt, Number(val)),
+        Literal::Bool(ref val) => write_col!(fmt, Keyword(val)),
         Literal::String(ref val) => {
             write_col!(fmt, String("\""))?;
             for part in val {
                 match *part {
-                    StringPart::Expression(ref e) => {
+                    StringPart::Expression(ref e, ref format) => {
                         write_col!(fmt, String("${"))?;
                         print_expression(fmt, e)?;
+                        if let Some(ref format) = *format {
+                            write_col!(fmt, String(format!(":{}", format)))?;
+                        }
                         write_col!(fmt, String("}"))?;
                     },
-                    StringPart::String(ref s) => write_col!(fmt, String(s))?,
+                    StringPart::String(ref s) => {
+                        let s = {
+                            s.replace('\\', "\\\\")
+                                .replace('"', "\\\"")
+                                .replace('\n', "\\n")
+                                .replace('\t', "\\t")
+                                .replace("${", "\\${")
+                        };
+
+                        write_col!(fmt, String(s))?
+                    },
                 }
             }
             write_col!(fmt, String("\""))
@@ -249,21 +268,54 @@ fn print_literal(fmt: &mut Terminal<Output=io::Stdout>, lit: &Literal) -> TermRe
 }
 
 fn print_path(fmt: &mut Terminal<Output=io::Stdout>, path: &Path, is_func: bool) -> TermResult<()> {
-    match *path {
-        Path::Deref(ref obj, ref prop) => {
+    match path.kind {
+        PathKind::Deref(ref obj, ref prop) => {
             print_path(fmt, obj, false)?;
-            if let Path::Instance(_) = **obj {} else {
+            if let PathKind::Instance(_) = obj.kind {} else {
                 write!(fmt, ".")?;
             }
             print_colored(fmt, prop, if is_func { ColorType::Function } else { ColorType::Name })
         },
-        Path::Instance(ref pat) => write_col!(fmt, Path(pat, false), ":"),
-        Path::Binding(ref name) => {
+        PathKind::Instance(ref pat) => write_col!(fmt, Path(pat, false), ":"),
+        PathKind::Binding(ref name) => {
             print_colored(fmt, name, if is_func { ColorType::Function } else { ColorType::Name })
         },
     }
 }
 
+fn print_pattern(fmt: &mut Terminal<Output=io::Stdout>, pattern: &Pattern) -> TermResult<()> {
+    match *pattern {
+        Pattern::Name(ref name) => write_col!(fmt, Name(name)),
+        Pattern::Object(ref fields) => {
+            write!(fmt, "{{")?;
+            for (i, &(ref field, ref name)) in fields.iter().enumerate() {
+                if i > 0 {
+                    write!(fmt, ", ")?;
+                }
+
+                write_col!(fmt, Name(field))?;
+                if name != field {
+                    write_col!(fmt, ": ", Name(name))?;
+                }
+            }
+            write!(fmt, "}}")?;
+            Ok(())
+        },
+        Pattern::Array(ref names) => {
+            write!(fmt, "[")?;
+            for (i, name) in names.iter().enumerate() {
+                if i > 0 {
+                    write!(fmt, ", ")?;
+                }
+
+                write_col!(fmt, Name(name))?;
+            }
+            write!(fmt, "]")?;
+            Ok(())
+        },
+    }
+}
+
 fn print_call(fmt: &mut Terminal<Output=io::Stdout>, call: &Call) -> TermResult<()> {
     write_col!(fmt, Path(&call.path, true), "(")?;
     for (i, arg) in call.args.iter().enumerate() {
@@ -277,13 +329,47 @@ fn print_call(fmt: &mut Terminal<Output=io::Stdout>, call: &Call) -> TermResult<
 }
 
 fn print_expression(fmt: &mut Terminal<Output=io::Stdout>, exp: &Expression) -> TermResult<()> {
-    use hct::ast::Expression::*;
-    match *exp {
+    use hct::ast::ExpressionKind::*;
+    match exp.kind {
         Call(ref call) => print_call(fmt, call),
         Reference(ref path) => print_path(fmt, path, false),
+        Index { ref expr, ref index } => {
+            write_col!(fmt, Expression(expr), "[", Expression(index), "]")
+        },
+        Field { ref expr, ref name } => {
+            write_col!(fmt, Expression(expr), ".", Name(name))
+        },
+        Range { ref start, ref end, inclusive } => {
+            let op = if inclusive { "..=" } else { ".." };
+            write_col!(fmt, Expression(start), op, Expression(end))
+        },
+        Slice { ref expr, ref start, ref end } => {
+            write_col!(fmt, Expression(expr), "[")?;
+            if let Some(ref start) = *start {
+                write_col!(fmt, Expression(start))?;
+            }
+            write!(fmt, "..")?;
+            if let Some(ref end) = *end {
+                write_col!(fmt, Expression(end))?;
+            }
+            Ok(write!(fmt, "]")?)
+        },
+        Conditional { ref condition, ref consequent, ref alternate } => {
+            write_col!(
+                fmt,
+                Keyword("if"), " ", Expression(condition), " { ", Expression(consequent), " } ",
+                Keyword("else"), " { ", Expression(alternate), " }"
+            )
+        },
+        Unary { ref op, ref expr } => {
+            write_col!(fmt, op, Expression(expr))
+        },
         Binary { ref lhs, ref op, ref rhs } => {
             write_col!(fmt, "(", Expression(lhs), " ", op, " ", Expression(rhs), ")")
         },
+        Cast { ref expr, ref ty } => {
+            write_col!(fmt, "(", Expression(expr), " ", Keyword("as"), " ", ty, ")")
+        },
         Literal(ref lit) => print_literal(fmt, lit),
 
         Array(ref items) => {
@@ -310,8 +396,17 @@ fn print_expression(fmt: &mut Terminal<Output=io::Stdout>, exp: &Expression) ->
 }
 
 fn print_statement(fmt: &mut Terminal<Output=io::Stdout>, stmt: &Statement) -> TermResult<()> {
-    use hct::ast::Statement::*;
-    match *stmt {
+    use hct::ast::StatementKind::*;
+    match stmt.kind {
+        Import { ref path, ref alias } => {
+            write_col!(fmt, Keyword("import"), " ", String(format!("{:?}", path)))?;
+            if let Some(ref alias) = *alias {
+                write_col!(fmt, " ", Keyword("as"), " ", Name(alias))?;
+            }
+
+            Ok(())
+        },
+
         Auto { ref body } => {
             {
                 let mut fmt = PadAdapterTerm::new(fmt);
@@ -364,10 +459,14 @@ fn print_statement(fmt: &mut Terminal<Output=io::Stdout>, stmt: &Statement) -> T
             }
             Ok(write!(fmt, "}}")?)
         },
-        Iterator { ref var, ref array, ref body } => {
+        Iterator { ref var, ref value, ref array, ref body } => {
             {
                 let mut fmt = PadAdapterTerm::new(fmt);
-                writeln_col!(&mut fmt, Keyword("for"), " ", Name(var), " ", Keyword("in"), " ", Expression(array), " {")?;
+                write_col!(&mut fmt, Keyword("for"), " ", Pattern(var))?;
+                if let Some(ref value) = *value {
+                    write_col!(&mut fmt, ", ", Pattern(value))?;
+                }
+                writeln_col!(&mut fmt, " ", Keyword("in"), " ", Expression(array), " {")?;
                 for stmt in body {
                     writeln_col!(&mut fmt, Statement(stmt))?;
                 }
@@ -393,15 +492,101 @@ fn print_statement(fmt: &mut Terminal<Output=io::Stdout>, stmt: &Statement) -> T
 
             Ok(write!(fmt, "}}")?)
         },
+        BranchLet { ref name, ref value, ref consequent, ref alternate } => {
+            {
+                let mut fmt = PadAdapterTerm::new(fmt);
+                writeln_col!(
+                    &mut fmt,
+                    Keyword("if"), " ", Keyword("let"), " ", Name(name), " = ", Expression(value), " {"
+                )?;
+                for stmt in consequent {
+                    writeln_col!(&mut fmt, Statement(stmt))?;
+                }
+            }
+
+            if let Some(ref alternate) = *alternate {
+                let mut fmt = PadAdapterTerm::new(fmt);
+                writeln_col!(&mut fmt, "} ", Keyword("else"), " {")?;
+                for stmt in alternate {
+                    writeln_col!(&mut fmt, Statement(stmt))?;
+                }
+            }
 
-        Binding { ref name, ref value } => {
-            write_col!(fmt, Keyword("let"), " ", Name(name), " = ", Expression(value))
+            Ok(write!(fmt, "}}")?)
         },
-        Assignment { ref prop, ref value } => {
-            write_col!(fmt, Path(prop, false), " = ", Expression(value))
+        Match { ref value, ref arms } => {
+            {
+                let mut fmt = PadAdapterTerm::new(fmt);
+                writeln_col!(&mut fmt, Keyword("match"), " ", Expression(value), " {")?;
+                for arm in arms {
+                    {
+                        let mut fmt = PadAdapterTerm::new(&mut fmt);
+                        match arm.values {
+                            Some(ref values) => {
+                                for (i, value) in values.iter().enumerate() {
+                                    if i > 0 {
+                                        write!(fmt, " | ")?;
+                                    }
+                                    write_col!(&mut fmt, Expression(value))?;
+                                }
+                            },
+                            None => write!(fmt, "_")?,
+                        }
+
+                        writeln!(fmt, " => {{")?;
+                        for stmt in &arm.body {
+                            writeln_col!(&mut fmt, Statement(stmt))?;
+                        }
+                    }
+                    writeln!(fmt, "}}")?;
+                }
+            }
+
+            Ok(write!(fmt, "}}")?)
+        },
+
+        Binding { ref pattern, ref ty, ref value } => {
+            write_col!(fmt, Keyword("let"), " ", Pattern(pattern))?;
+            if let Some(ref ty) = *ty {
+                write!(fmt, ": {}", ty)?;
+            }
+
+            write_col!(fmt, " = ", Expression(value))
+        },
+        Assignment { ref target, ref op, ref value } => {
+            write_col!(fmt, Expression(target), " ")?;
+            if let Some(ref op) = *op {
+                write!(fmt, "{}", op)?;
+            }
+
+            write_col!(fmt, "= ", Expression(value))
         },
 
         Call(ref call) => print_call(fmt, call),
+
+        Function(ref func) => {
+            {
+                let mut fmt = PadAdapterTerm::new(fmt);
+                write_col!(&mut fmt, Keyword("fn"), " ", Function(&func.name), "(")?;
+                for (i, arg) in func.args.iter().enumerate() {
+                    print_colored(&mut fmt, arg, ColorType::Name)?;
+                    if i < func.args.len() - 1 {
+                        write!(fmt, ", ")?;
+                    }
+                }
+                writeln!(fmt, ") {{")?;
+                for stmt in &func.body {
+                    writeln_col!(&mut fmt, Statement(stmt))?;
+                }
+            }
+            Ok(write!(fmt, "}}")?)
+        },
+        Return(Some(ref value)) => write_col!(fmt, Keyword("return"), " ", Expression(value)),
+        Return(None) => write_col!(fmt, Keyword("return")),
+        Break => write_col!(fmt, Keyword("break")),
+        Continue => write_col!(fmt, Keyword("continue")),
+
+        Error(ref message) => write_col!(fmt, Keyword("<error>"), " ", String(message)),
     }
 }
 
@@ -1,11 +1,10 @@
 #![feature(box_syntax, box_patterns, splice, specialization)]
-#![cfg_attr(feature="clippy", feature(plugin))]
-#![cfg_attr(feature="clippy", plugin(clippy))]
 // The atom macro generates huge u64 literals
-#![cfg_attr(feature="clippy", allow(unreadable_literal))]
+#![cfg_attr(feature="cargo-clippy", allow(unreadable_literal))]
 
 extern crate rayon;
 extern crate rand;
+#[cfg(feature = "jit")]
 extern crate llvm_sys;
 extern crate libc;
 #[macro_use]
@@ -32,10 +31,12 @@ pub mod atom {
 
 #[macro_use]
 pub mod logging;
+pub mod error;
 pub mod hct;
 pub mod vmf;
 pub mod compiler;
 pub mod runtime;
+pub mod interp;
 
 use std::io::{Write, Error, ErrorKind};
 use std::fs::{File, create_dir_all};
@@ -49,6 +50,21 @@ use clap::{App, Arg};
 use log::LogLevel::Debug;
 use simplelog::{SimpleLogger, TermLogger, LogLevelFilter};
 use logging::*;
+use hct::diagnostic::Level;
+use error::*;
+
+/// Execution engine used to run the scripts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    Interp,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+#[cfg(feature = "jit")]
+static BACKENDS: &'static [&'static str] = &["jit", "interp"];
+#[cfg(not(feature = "jit"))]
+static BACKENDS: &'static [&'static str] = &["interp"];
 
 /// Find an instance file relative to a base file by walking up the fs tree
 /// Slightly differs from the algorithm used in VBSP, but should work for most cases
@@ -70,73 +86,114 @@ fn find_instance<P: Copy + AsRef<Path>>(mut base: PathBuf, target: P) -> Option<
 /// Runs the build for a map file, recursively spawning threads for all instances
 /// Returns the newly created file if one or more script was applied to it,
 /// or the original unmodified file otherwise
-fn build(input: &PathBuf) -> Result<PathBuf, Error> {
+fn build(input: &PathBuf, backend: Backend, loader: &hct::loader::Loader) -> HatchetResult<PathBuf> {
     let vmf::MapFile {
         nodes, entities,
         scripts, instances
-    } = vmf::parse_file(input);
+    } = vmf::parse_file(input)?;
 
     if scripts.is_empty() {
         return Ok(input.clone());
     }
 
     let vmf_dir = input.parent().ok_or_else(
-        || Error::new(ErrorKind::InvalidInput, "Not a directory")
+        || HatchetError::io(input, Error::new(ErrorKind::InvalidInput, "Not a directory"))
     )?;
 
     // Progressively fold each script on the map AST
-    let entities = Arc::new(Mutex::new(
-        scripts.into_iter()
-            .map(|ent| {
-                let ast = hct::parse_file(vmf_dir.join(&ent.script));
-                (ent, ast)
-            })
-            .fold(entities, |map, (ent, ast)| {
-                compiler::apply(ent, ast, map)
+    let mut entities = entities;
+    for ent in scripts {
+        let ast = loader.script(vmf_dir.join(&ent.script))?;
+
+        let (diagnostics, warnings): (Vec<_>, Vec<_>) = {
+            compiler::check::check(&ast, &entities)
+                .into_iter()
+                .partition(|diag| diag.level == Level::Error)
+        };
+
+        for diag in warnings {
+            warn!("{}", diag.render(&ast.file));
+        }
+
+        if !diagnostics.is_empty() {
+            return Err(HatchetError::Type {
+                file: ast.file.clone(),
+                diagnostics,
+            });
+        }
+
+        entities = match backend {
+            Backend::Interp => interp::apply(ent, ast, entities)?,
+            #[cfg(feature = "jit")]
+            Backend::Jit => compiler::apply(ent, ast, entities)?,
+        };
+    }
+
+    let entities = Arc::new(Mutex::new(entities));
+
+    // Instance sub-process
+    let instances = instances.into_par_iter()
+        .map(|instance| {
+            let target = instance.file.clone().into_path();
+            let (base, inst_path) = find_instance(input.clone(), &target).ok_or_else(
+                || HatchetError::vmf(input, format!("instance file {} not found", target))
+            )?;
+
+            let result = build(&inst_path, backend, loader)?;
+            let file = if result != inst_path {
+                let path = result.strip_prefix(&base).map_err(
+                    |_| HatchetError::vmf(&result, "compiled instance is outside of the map directory")
+                )?;
+
+                vmf::InstFile::Compiled(path.display().to_string())
+            } else {
+                instance.file
+            };
+
+            Ok(match instance.entity {
+                // If the entity was anonymous, write it to the output
+                vmf::EntRef::Anon(mut ent) => {
+                    if let vmf::InstFile::Compiled(new_path) = file {
+                        ent.properties.insert(hct_atom!("file"), new_path);
+                    }
+
+                    Some(ent.into_value().to_string())
+                },
+                // If the entity was named, just change the file path as needed
+                vmf::EntRef::Named(ref targetname) => {
+                    let mut entities = entities.lock().unwrap();
+                    let ent = entities.get_mut(targetname).ok_or_else(
+                        || HatchetError::vmf(input.clone(), format!("instance {} was removed by a script", targetname))
+                    )?;
+                    if let vmf::InstFile::Compiled(new_path) = file {
+                        ent.properties.insert(hct_atom!("file"), new_path);
+                    }
+
+                    None
+                }
             })
-    ));
+        })
+        .collect::<Vec<HatchetResult<Option<String>>>>();
+
+    // All the instances are built before reporting their errors together
+    let (instances, mut errors): (Vec<_>, Vec<_>) = instances.into_iter().partition(|res| res.is_ok());
+    if errors.len() == 1 {
+        return Err(errors.remove(0).unwrap_err());
+    }
+    if !errors.is_empty() {
+        return Err(HatchetError::Instances {
+            path: input.clone(),
+            errors: errors.into_iter().map(Result::unwrap_err).collect(),
+        });
+    }
+
+    let instances: Vec<_> = instances.into_iter().map(Result::unwrap).collect();
 
     let data = {
         // Unmodified nodes
         nodes.into_par_iter()
             .map(|block| block.to_string())
-            .chain(
-                // Instance sub-process
-                instances.into_par_iter()
-                    .filter_map(|instance| {
-                        let target = instance.file.clone().unwrap();
-                        let (base, input) = find_instance(input.clone(), &target).unwrap();
-                        let result = build(&input).unwrap();
-                        let file = if result != input {
-                            vmf::InstFile::Compiled(
-                                result.strip_prefix(&base).unwrap().display().to_string()
-                            )
-                        } else {
-                            instance.file
-                        };
-
-                        match instance.entity {
-                            // If the entity was anonymous, write it to the output
-                            vmf::EntRef::Anon(mut ent) => {
-                                if let vmf::InstFile::Compiled(new_path) = file {
-                                    ent.properties.insert(hct_atom!("file"), new_path);
-                                }
-
-                                Some(ent.into_value().to_string())
-                            },
-                            // If the entity was named, just change the file path as needed
-                            vmf::EntRef::Named(ref targetname) => {
-                                let mut entities = entities.lock().unwrap();
-                                let ent = entities.get_mut(targetname).unwrap();
-                                if let vmf::InstFile::Compiled(new_path) = file {
-                                    ent.properties.insert(hct_atom!("file"), new_path);
-                                }
-
-                                None
-                            }
-                        }
-                    })
-            )
+            .chain(instances.into_par_iter().filter_map(|inst| inst))
             .reduce(
                 String::new,
                 |a, b| a + &b,
@@ -155,16 +212,19 @@ fn build(input: &PathBuf) -> Result<PathBuf, Error> {
     };
 
     let hct_dir = vmf_dir.join(".hct");
-    create_dir_all(&hct_dir)?;
+    create_dir_all(&hct_dir).map_err(|err| HatchetError::io(&hct_dir, err))?;
 
     let out_path = hct_dir.join(input.file_name().ok_or_else(
-        || Error::new(ErrorKind::InvalidInput, "Not a file")
+        || HatchetError::io(input, Error::new(ErrorKind::InvalidInput, "Not a file"))
     )?);
 
     // First, write all the unmodified nodes
-    let mut out = File::create(out_path.clone())?;
-    write!(&mut out, "{}", data)?;
-    write!(&mut out, "{}", entities)?;
+    File::create(out_path.clone())
+        .and_then(|mut out| {
+            write!(&mut out, "{}", data)?;
+            write!(&mut out, "{}", entities)
+        })
+        .map_err(|err| HatchetError::io(&out_path, err))?;
 
     Ok(out_path)
 }
@@ -187,6 +247,23 @@ fn main() {
                     .multiple(true)
                     .help("Show debug informations")
             )
+            .arg(
+                Arg::with_name("backend")
+                    .long("backend")
+                    .takes_value(true)
+                    .possible_values(BACKENDS)
+                    .default_value(BACKENDS[0])
+                    .help("Engine used to run the scripts")
+            )
+            .arg(
+                Arg::with_name("lib")
+                    .short("L")
+                    .long("lib")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Add a directory to the search paths of the script imports")
+            )
             .arg(
                 Arg::with_name("trace")
                     .short("t")
@@ -212,6 +289,18 @@ fn main() {
         }
     }
 
+    let backend = match args.value_of("backend") {
+        #[cfg(feature = "jit")]
+        Some("jit") => Backend::Jit,
+        _ => Backend::Interp,
+    };
+
+    let loader = hct::loader::Loader::new(
+        args.values_of("lib")
+            .map_or_else(Vec::new, |paths| paths.map(PathBuf::from).collect())
+    );
+
+    let mut failed = false;
     for argument in args.values_of("INPUT").unwrap() {
         let vmf_path = Path::new(&argument).with_extension("vmf");
         let start = if log_enabled!(Debug) {
@@ -221,7 +310,15 @@ fn main() {
             None
         };
 
-        build(&vmf_path).unwrap();
+        if let Err(err) = build(&vmf_path, backend, &loader) {
+            for detail in err.details() {
+                error!("{}", detail);
+            }
+
+            error!("{}", err);
+            failed = true;
+            continue;
+        }
 
         if let Some(start) = start {
             let duration = start.elapsed();
@@ -234,4 +331,8 @@ fn main() {
             info!("Finished transforming file {}", path_fmt(vmf_path));
         }
     }
+
+    if failed {
+        ::std::process::exit(1);
+    }
 }
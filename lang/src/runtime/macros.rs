@@ -29,6 +29,13 @@ macro_rules! decl_type {
         *const String
     };
 
+    ($gen:tt, vec3) => {
+        *const [f64; 3]
+    };
+    ($gen:tt, qangle) => {
+        decl_type!($gen, vec3)
+    };
+
     ($gen:tt, (mut Vec<$ty:tt>)) => {
         *mut Vec<decl_type!($gen, $ty)>
     };
@@ -67,6 +74,16 @@ macro_rules! check_ptr {
     };
 }
 
+/// Report a runtime error from an external function and return early
+/// Unwinding through the JIT-compiled code is undefined behaviour, so the
+/// error is stored in the context and checked by the caller instead
+macro_rules! runtime_error {
+    ($ctx:expr, $( $arg:tt )* ) => {{
+        $ctx.error = Some(format!( $( $arg )* ));
+        return RuntimeDefault::runtime_default();
+    }};
+}
+
 macro_rules! find_context {
     ($name:ident Context $( $rest:tt )* ) => {
         $name
@@ -90,11 +107,17 @@ macro_rules! convert_arg {
     };
     ($gen:tt, $name:ident, (ref Entity), $ctx:expr) => {{
         let $name = convert_arg!($gen, $name, Atom, $ctx);
-        $ctx.entities.get($name).expect(&format!("entity \"{}\" not found", $name))
+        match $ctx.entities.get($name) {
+            Some(ent) => ent,
+            None => runtime_error!($ctx, "entity \"{}\" not found", $name),
+        }
     }};
     ($gen:tt, $name:ident, (mut Entity), $ctx:expr) => {{
         let $name = convert_arg!($gen, $name, Atom, $ctx);
-        $ctx.entities.get_mut($name).expect(&format!("entity \"{}\" not found", $name))
+        match $ctx.entities.get_mut($name) {
+            Some(ent) => ent,
+            None => runtime_error!($ctx, "entity \"{}\" not found", $name),
+        }
     }};
 
     ($gen:tt, $name:ident, (Vec<T>), $ctx:expr) => {
@@ -120,6 +143,12 @@ macro_rules! convert_arg {
     ($gen:tt, $name:ident, String, $ctx:expr) => {
         check_ptr!($name, as_ref, String)
     };
+    ($gen:tt, $name:ident, vec3, $ctx:expr) => {
+        check_ptr!($name, as_ref, vec3)
+    };
+    ($gen:tt, $name:ident, qangle, $ctx:expr) => {
+        convert_arg!($gen, $name, vec3, $ctx)
+    };
     ($gen:tt, $name:ident, $ty:tt, $ctx:expr) => {
         $name
     };
@@ -143,10 +172,21 @@ macro_rules! convert_res {
         $ctx.arenas.strings.alloc($name) as decl_type!($gen, String)
     };
 
-    ($gen:tt, $name:block, $ctx:expr, (Vec<Entity>)) => {
-        $ctx.arenas.ent_vec.alloc($name) as decl_type!($gen, (Vec<Entity>))
+    ($gen:tt, $name:block, $ctx:expr, vec3) => {
+        $ctx.arenas.vectors.alloc($name) as decl_type!($gen, vec3)
+    };
+    ($gen:tt, $name:block, $ctx:expr, qangle) => {
+        convert_res!($gen, $name, $ctx, vec3)
     };
 
+    ($gen:tt, $name:block, $ctx:expr, (Vec<T>)) => {
+        convert_res!($gen, $name, $ctx, (Vec<$gen>))
+    };
+    ($gen:tt, $name:block, $ctx:expr, (Vec<$ty:tt>)) => {{
+        let res: Vec<decl_type!($gen, $ty)> = $name;
+        $ctx.arenas.alloc_vec(res) as decl_type!($gen, (Vec<$ty>))
+    }};
+
     ($gen:tt, $name:block, $ctx:expr, (ref T)) => {
         $name
     };
@@ -254,6 +294,16 @@ macro_rules! for_each_type {
         $submac!{ Entity; $( $args )* }
         $submac!{ String; $( $args )* }
     };
+
+    ( (T: Number); $submac:ident ! { $( $args:tt )* } ) => {
+        $submac!{ f64; $( $args )* }
+        $submac!{ i64; $( $args )* }
+    };
+
+    ( (T: Vector); $submac:ident ! { $( $args:tt )* } ) => {
+        $submac!{ vec3; $( $args )* }
+        $submac!{ qangle; $( $args )* }
+    };
 }
 
 macro_rules! build_externals {
@@ -286,17 +336,69 @@ macro_rules! build_externals {
     ($ctx:tt; ) => {};
 }
 
+macro_rules! insert_signature {
+    ( $gen:tt; $m_name:ident; $name:ident; $ret:tt; $( $args:tt ),* ) => {
+        if *$m_name == hct_atom_function!($gen, $name) {
+            return Some((
+                vec![
+                    $( type_id!($gen, $args) ),*
+                ],
+                type_id!($gen, $ret),
+            ));
+        }
+    };
+}
+
+macro_rules! build_signatures {
+    ($m_name:ident; intrinsic!( $name:ident = $id:tt ( $( $args:tt ),* ) -> $ret:tt ); $( $tail:tt )* ) => {
+        insert_signature!{ (); $m_name; $name; $ret; $( $args ),* }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+    ($m_name:ident; intrinsic!( $name:ident = $id:tt ( $( $args:tt ),* ) ); $( $tail:tt )* ) => {
+        insert_signature!{ (); $m_name; $name; Void; $( $args ),* }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+
+    ($m_name:ident;  $( #[ $attr:ident ] )* fn $name:ident ( $( $args:ident : $type:tt ),* ) -> $ret:tt $body:block $( $tail:tt )* ) => {
+        insert_signature!{ (); $m_name; $name; $ret; $( $type ),* }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+    ($m_name:ident;  $( #[ $attr:ident ] )* fn $name:ident ( $( $args:ident : $type:tt ),* ) $body:block $( $tail:tt )* ) => {
+        insert_signature!{ (); $m_name; $name; Void; $( $type ),* }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+    ($m_name:ident;  $( #[ $attr:ident ] )* fn $name:ident < $gen:tt > ( $( $args:ident : $type:tt ),* ) -> $ret:tt $body:block $( $tail:tt )* ) => {
+        for_each_type! { $gen; insert_signature!{ $m_name; $name; $ret; $( $type ),* } }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+    ($m_name:ident;  $( #[ $attr:ident ] )* fn $name:ident < $gen:tt > ( $( $args:ident : $type:tt ),* ) $body:block $( $tail:tt )* ) => {
+        for_each_type! { $gen; insert_signature!{ $m_name; $name; Void; $( $type ),* } }
+        build_signatures!{ $m_name; $( $tail )* }
+    };
+
+    ($m_name:ident; ) => {};
+}
+
+/// Build the raw function pointer of an external function
+macro_rules! raw_fn {
+    ($gen:tt; $ret:tt; $body:block; $( $args:ident : $type:tt ),* ) => {{
+        let func: fn( $( decl_type!($gen, $type) ),* ) -> decl_type!($gen, $ret) = {
+            |$( $args : decl_type!($gen, $type) ),*| -> decl_type!($gen, $ret) {
+                convert_args!( $gen, find_context!( $( $args $type )* ); $( $args $type )* );
+                convert_res!($gen, $body, find_context!( $( $args $type )* ), $ret)
+            }
+        };
+
+        func
+    }};
+}
+
 macro_rules! add_symbol {
     ($gen:tt; $self:ident; $name:ident; $ret:tt; $body:block; $( $args:ident : $type:tt ),* ) => {
         if $self.functions.borrow().contains_key(&hct_atom_function!($gen, $name)) {
             trace!(concat!("Register function ", stringify!($name)));
 
-            let func: fn( $( decl_type!($gen, $type) ),* ) -> decl_type!($gen, $ret) = {
-                |$( $args : decl_type!($gen, $type) ),*| -> decl_type!($gen, $ret) {
-                    convert_args!( $gen, find_context!( $( $args $type )* ); $( $args $type )* );
-                    convert_res!($gen, $body, find_context!( $( $args $type )* ), $ret)
-                }
-            };
+            let func = raw_fn!($gen; $ret; $body; $( $args : $type ),* );
 
             unsafe {
                 ::llvm_sys::support::LLVMAddSymbol(
@@ -336,24 +438,197 @@ macro_rules! register_symbols {
     ($self:ident; ) => {};
 }
 
+/// Convert an interpreter value to the raw type of an argument
+/// The values are copied to the arenas of the context when needed
+macro_rules! to_raw {
+    ($gen:tt, $val:expr, $ctx:ident, T) => {
+        to_raw!($gen, $val, $ctx, $gen)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, (ref $ty:tt)) => {
+        to_raw!($gen, $val, $ctx, $ty)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, (mut $ty:tt)) => {
+        to_raw!($gen, $val, $ctx, $ty)
+    };
+
+    ($gen:tt, $val:expr, $ctx:ident, Atom) => {
+        unsafe { &*$ctx }.arenas.atoms.alloc($val.as_atom().clone()) as decl_type!($gen, Atom)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, Entity) => {
+        unsafe { &*$ctx }.arenas.atoms.alloc($val.as_entity().clone()) as decl_type!($gen, Entity)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, String) => {
+        unsafe { &*$ctx }.arenas.strings.alloc($val.as_string().clone()) as decl_type!($gen, String)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, vec3) => {
+        unsafe { &*$ctx }.arenas.vectors.alloc($val.as_vector()) as decl_type!($gen, vec3)
+    };
+    ($gen:tt, $val:expr, $ctx:ident, qangle) => {
+        to_raw!($gen, $val, $ctx, vec3)
+    };
+
+    ($gen:tt, $val:expr, $ctx:ident, (Vec<T>)) => {
+        to_raw!($gen, $val, $ctx, (Vec<$gen>))
+    };
+    ($gen:tt, $val:expr, $ctx:ident, (Vec<$ty:tt>)) => {
+        &$val.as_vec().borrow().iter()
+            .map(|val| to_raw!($gen, val, $ctx, $ty))
+            .collect::<Vec<_>>() as decl_type!($gen, (Vec<$ty>))
+    };
+    // The interpreter implements the mutating list operations itself,
+    // so the changes made to this copy are never observed
+    ($gen:tt, $val:expr, $ctx:ident, (mut Vec<T>)) => {
+        to_raw!($gen, $val, $ctx, (mut Vec<$gen>))
+    };
+    ($gen:tt, $val:expr, $ctx:ident, (mut Vec<$ty:tt>)) => {
+        &mut $val.as_vec().borrow().iter()
+            .map(|val| to_raw!($gen, val, $ctx, $ty))
+            .collect::<Vec<_>>() as decl_type!($gen, (mut Vec<$ty>))
+    };
+
+    ($gen:tt, $val:expr, $ctx:ident, f64) => {
+        $val.as_f64()
+    };
+    ($gen:tt, $val:expr, $ctx:ident, bool) => {
+        $val.as_bool()
+    };
+    ($gen:tt, $val:expr, $ctx:ident, i64) => {
+        $val.as_i64()
+    };
+}
+
+/// Get the next raw argument of an external function from a list of values
+macro_rules! raw_arg {
+    ($gen:tt, $args:ident, $ctx:ident, Context) => {
+        $ctx
+    };
+    ($gen:tt, $args:ident, $ctx:ident, $ty:tt) => {
+        to_raw!($gen, $args.next().expect("missing argument"), $ctx, $ty)
+    };
+}
+
+/// Convert the raw result of an external function to an interpreter value
+macro_rules! from_raw {
+    ($gen:tt, $raw:expr, Void) => {{
+        let () = $raw;
+        ::interp::value::Value::Void
+    }};
+
+    ($gen:tt, $raw:expr, T) => {
+        from_raw!($gen, $raw, $gen)
+    };
+    ($gen:tt, $raw:expr, (ref $ty:tt)) => {
+        from_raw!($gen, $raw, $ty)
+    };
+
+    ($gen:tt, $raw:expr, Atom) => {
+        ::interp::value::Value::Atom(unsafe { (*$raw).clone() })
+    };
+    ($gen:tt, $raw:expr, Entity) => {
+        ::interp::value::Value::Entity(unsafe { (*$raw).clone() })
+    };
+    ($gen:tt, $raw:expr, String) => {
+        ::interp::value::Value::String(unsafe { (*$raw).clone() })
+    };
+    ($gen:tt, $raw:expr, vec3) => {
+        ::interp::value::Value::vec3(unsafe { *$raw })
+    };
+    ($gen:tt, $raw:expr, qangle) => {
+        ::interp::value::Value::qangle(unsafe { *$raw })
+    };
+
+    ($gen:tt, $raw:expr, (Vec<$ty:tt>)) => {
+        ::interp::value::Value::vec(
+            unsafe { &*$raw }.iter()
+                .map(|val| from_raw!($gen, *val, $ty))
+                .collect(),
+            type_id!($gen, $ty),
+        )
+    };
+
+    ($gen:tt, $raw:expr, $ty:tt) => {
+        ::interp::value::Value::$ty($raw)
+    };
+}
+
+macro_rules! insert_call {
+    ( $gen:tt; ( $ctx:ident, $m_name:ident, $m_args:ident ); $name:ident; $ret:tt; $body:block; $( $args:ident : $type:tt ),* ) => {
+        if *$m_name == hct_atom_function!($gen, $name) {
+            let func = raw_fn!($gen; $ret; $body; $( $args : $type ),* );
+
+            let mut $m_args = $m_args.into_iter();
+            let res = func( $( raw_arg!($gen, $m_args, $ctx, $type) ),* );
+
+            if let Some(message) = unsafe { &mut *$ctx }.error.take() {
+                return Some(Err(message));
+            }
+
+            return Some(Ok(from_raw!($gen, res, $ret)));
+        }
+    };
+}
+
+macro_rules! insert_intrinsic {
+    ( ( $ctx:ident, $m_name:ident, $m_args:ident ); $name:ident; $ret:tt; $( $args:tt ),* ) => {
+        if *$m_name == hct_atom_function!((), $name) {
+            let mut $m_args = $m_args.into_iter();
+            let res = ::interp::intrinsics::$name( $( raw_arg!((), $m_args, $ctx, $args) ),* );
+            return Some(Ok(from_raw!((), res, $ret)));
+        }
+    };
+}
+
+macro_rules! build_calls {
+    ($ctx:tt; intrinsic!( $name:ident = $id:tt ( $( $args:tt ),* ) -> $ret:tt ); $( $tail:tt )* ) => {
+        insert_intrinsic!{ $ctx; $name; $ret; $( $args ),* }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+    ($ctx:tt; intrinsic!( $name:ident = $id:tt ( $( $args:tt ),* ) ); $( $tail:tt )* ) => {
+        insert_intrinsic!{ $ctx; $name; Void; $( $args ),* }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+
+    ($ctx:tt;  $( #[ $attr:ident ] )* fn $name:ident ( $( $args:ident : $type:tt ),* ) -> $ret:tt $body:block $( $tail:tt )* ) => {
+        insert_call!{ (); $ctx; $name; $ret; $body; $( $args : $type ),* }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+    ($ctx:tt;  $( #[ $attr:ident ] )* fn $name:ident ( $( $args:ident : $type:tt ),* ) $body:block $( $tail:tt )* ) => {
+        insert_call!{ (); $ctx; $name; Void; $body; $( $args : $type ),* }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+    ($ctx:tt;  $( #[ $attr:ident ] )* fn $name:ident < $gen:tt > ( $( $args:ident : $type:tt ),* ) -> $ret:tt $body:block $( $tail:tt )* ) => {
+        for_each_type! { $gen; insert_call!{ $ctx; $name; $ret; $body; $( $args : $type ),* } }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+    ($ctx:tt;  $( #[ $attr:ident ] )* fn $name:ident < $gen:tt > ( $( $args:ident : $type:tt ),* ) $body:block $( $tail:tt )* ) => {
+        for_each_type! { $gen; insert_call!{ $ctx; $name; Void; $body; $( $args : $type ),* } }
+        build_calls!{ $ctx; $( $tail )* }
+    };
+
+    ($ctx:tt; ) => {};
+}
+
 /// Declare STL functions through the Externals structs
 #[macro_export]
 macro_rules! declare_externals {
     ( $( $body:tt )* ) => {
         #[derive(Default, Debug, PartialEq)]
         pub struct Externals {
+            #[cfg(feature = "jit")]
             pub functions: RefCell<HashMap<Atom, Function>>,
         }
 
         impl Externals {
-            #[cfg_attr(feature="clippy", allow(new_without_default_derive))]
+            #[cfg_attr(feature="cargo-clippy", allow(new_without_default_derive))]
             pub fn new() -> Externals {
                 Externals {
+                    #[cfg(feature = "jit")]
                     functions: RefCell::new(HashMap::new()),
                 }
             }
 
             /// Get an external function by name, adding it to the context if needed
+            #[cfg(feature = "jit")]
             pub fn get_function(&self, builder: &Builder, name: Atom) -> Function {
                 self.functions.borrow_mut()
                     .entry(name.clone())
@@ -364,10 +639,25 @@ macro_rules! declare_externals {
                     .clone()
             }
 
+            /// Get the argument and return types of an external function, if it exists
+            pub fn signature(name: &Atom) -> Option<(Vec<TypeId>, TypeId)> {
+                build_signatures!{ name; $( $body )* }
+                None
+            }
+
             /// Add the corresponding symbols to the runtime for all the registered functions
+            #[cfg(feature = "jit")]
             pub fn register_symbols(&self) {
                 register_symbols!(self; $( $body )* );
             }
+
+            /// Call an external function from the interpreter,
+            /// returning None if it does not exist
+            pub fn call(context: &mut Context, name: &Atom, args: Vec<::interp::value::Value>) -> Option<Result<::interp::value::Value, String>> {
+                let context: *mut Context = context;
+                build_calls!{ (context, name, args); $( $body )* }
+                None
+            }
         }
     };
 }
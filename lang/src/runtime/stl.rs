@@ -1,12 +1,16 @@
 use std::collections::HashMap;
+#[cfg(feature = "jit")]
 use std::ffi::CString;
+#[cfg(feature = "jit")]
 use std::cell::RefCell;
 
 use rand::Rng;
+use rand::distributions::{IndependentSample, Normal};
 use rayon::prelude::*;
 
 use atom::Atom;
 use vmf::ir::{Connection, Entity};
+#[cfg(feature = "jit")]
 use compiler::builder::Builder;
 use compiler::types::TypeId;
 use super::types::*;
@@ -21,11 +25,127 @@ declare_externals! {
     intrinsic!(ceil = "llvm.ceil.f64" (f64) -> f64);
     intrinsic!(round = "llvm.round.f64" (f64) -> f64);
     intrinsic!(fmuladd = "llvm.fmuladd.f64" (f64, f64, f64) -> f64);
+    intrinsic!(abs = "llvm.fabs.f64" (f64) -> f64);
+    intrinsic!(log = "llvm.log.f64" (f64) -> f64);
+    intrinsic!(min = "llvm.minnum.f64" (f64, f64) -> f64);
+    intrinsic!(max = "llvm.maxnum.f64" (f64, f64) -> f64);
+
+    #[readonly]
+    fn tan(value: f64) -> f64 {
+        value.tan()
+    }
+
+    #[readonly]
+    fn asin(value: f64) -> f64 {
+        value.asin()
+    }
+
+    #[readonly]
+    fn acos(value: f64) -> f64 {
+        value.acos()
+    }
+
+    #[readonly]
+    fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+
+    #[readonly]
+    fn clamp(value: f64, low: f64, high: f64) -> f64 {
+        value.max(low).min(high)
+    }
+
+    #[readonly]
+    fn lerp(from: f64, to: f64, t: f64) -> f64 {
+        from + (to - from) * t
+    }
+
+    #[readonly]
+    fn sign(value: f64) -> f64 {
+        sign(value)
+    }
+
+    #[readonly]
+    fn deg(value: f64) -> f64 {
+        value.to_degrees()
+    }
+
+    #[readonly]
+    fn rad(value: f64) -> f64 {
+        value.to_radians()
+    }
+
+    #[readonly]
+    fn int_abs(value: i64) -> i64 {
+        value.wrapping_abs()
+    }
+
+    #[readonly]
+    fn int_min(lhs: i64, rhs: i64) -> i64 {
+        lhs.min(rhs)
+    }
+
+    #[readonly]
+    fn int_max(lhs: i64, rhs: i64) -> i64 {
+        lhs.max(rhs)
+    }
+
+    #[readonly]
+    fn int_clamp(value: i64, low: i64, high: i64) -> i64 {
+        value.max(low).min(high)
+    }
+
+    #[readonly]
+    fn int_sign(value: i64) -> i64 {
+        value.signum()
+    }
+
+    fn int_mod(context: Context, lhs: i64, rhs: i64) -> i64 {
+        if rhs == 0 {
+            runtime_error!(context, "division by zero: mod({}, 0)", lhs);
+        }
+
+        modulo(lhs, rhs)
+    }
 
     fn rand(context: Context, low: f64, high: f64) -> f64 {
+        if low >= high {
+            runtime_error!(context, "rand: empty range {} .. {}", low, high);
+        }
+
+        context.rng.gen_range(low, high)
+    }
+
+    fn rand_int(context: Context, low: i64, high: i64) -> i64 {
+        if low >= high {
+            runtime_error!(context, "rand_int: empty range {} .. {}", low, high);
+        }
+
         context.rng.gen_range(low, high)
     }
 
+    fn chance(context: Context, probability: f64) -> bool {
+        context.rng.next_f64() < probability
+    }
+
+    fn gaussian(context: Context, mean: f64, deviation: f64) -> f64 {
+        if deviation.is_nan() || deviation < 0.0 {
+            runtime_error!(context, "gaussian: invalid standard deviation {}", deviation);
+        }
+
+        Normal::new(mean, deviation).ind_sample(&mut context.rng)
+    }
+
+    #[readonly]
+    fn noise2(context: Context, x: f64, y: f64) -> f64 {
+        perlin_noise(&context.noise, x, y, 0.0)
+    }
+
+    #[readonly]
+    fn noise3(context: Context, x: f64, y: f64, z: f64) -> f64 {
+        perlin_noise(&context.noise, x, y, z)
+    }
+
     fn create(context: Context, name: String, class: String) -> Entity {
         let name = Atom::from(name.clone());
         context.entities.insert(name.clone(), Entity {
@@ -39,9 +159,9 @@ declare_externals! {
 
     fn clone(context: Context, name: Entity) -> Entity {
         let mut ent = {
-            let ent = {
-                context.entities.get_mut(name)
-                    .expect(&format!("entity \"{}\" not found", name))
+            let ent = match context.entities.get_mut(name) {
+                Some(ent) => ent,
+                None => runtime_error!(context, "clone: entity \"{}\" not found", name),
             };
 
             ent.clones += 1;
@@ -56,8 +176,9 @@ declare_externals! {
     }
 
     fn remove(context: Context, name: Entity) {
-        context.entities.remove(name)
-            .expect(&format!("remove: entity \"{}\" not found", name));
+        if context.entities.remove(name).is_none() {
+            runtime_error!(context, "remove: entity \"{}\" not found", name);
+        }
 
         context.entities.par_iter_mut()
             .for_each(|(_, other)| {
@@ -73,9 +194,29 @@ declare_externals! {
             });
     }
 
+    #[readonly]
+    fn has_error(context: Context) -> bool {
+        context.error.is_some()
+    }
+
     #[readonly]
     fn find(context: Context, name: String) -> Entity {
-        Atom::from(name as &str)
+        // Missing entities are found as a null handle, with an empty name
+        if is_known_entity(name, &context.entities) {
+            Atom::from(name as &str)
+        } else {
+            Atom::from("")
+        }
+    }
+
+    #[readonly]
+    fn exists(context: Context, name: String) -> bool {
+        is_known_entity(name, &context.entities)
+    }
+
+    #[readonly]
+    fn entity_exists(context: Context, entity: Entity) -> bool {
+        is_known_entity(entity, &context.entities)
     }
 
     #[readonly]
@@ -99,9 +240,132 @@ declare_externals! {
         array.len() as _
     }
 
+    fn vec_get<T>(context: Context, array: (Vec<T>), index: i64) -> (ref T) {
+        match list_index(index, array.len()) {
+            Some(index) => array[index],
+            None => runtime_error!(context, "index {} is out of bounds for an array of length {}", index, array.len()),
+        }
+    }
+
+    fn vec_slice<T>(context: Context, array: (Vec<T>), start: i64, end: i64) -> (Vec<T>) {
+        match list_range(start, end, array.len()) {
+            Some((start, end)) => array[start..end].to_vec(),
+            None => runtime_error!(context, "range {}..{} is out of bounds for an array of length {}", start, end, array.len()),
+        }
+    }
+
+    fn vec_new<T>(context: Context, capacity: i64) -> (Vec<T>) {
+        Vec::with_capacity(capacity as usize)
+    }
+
+    fn vec_push<T>(array: (mut Vec<T>), value: T) {
+        array.push(value);
+    }
+
+    fn vec_pop<T>(context: Context, array: (mut Vec<T>)) -> (ref T) {
+        match array.pop() {
+            Some(value) => value,
+            None => runtime_error!(context, "cannot pop from an empty list"),
+        }
+    }
+
+    fn vec_concat<T>(context: Context, lhs: (Vec<T>), rhs: (Vec<T>)) -> (Vec<T>) {
+        lhs.iter().chain(rhs.iter()).cloned().collect()
+    }
+
+    #[readonly]
+    fn vec_contains<T>(array: (Vec<T>), value: T) -> bool {
+        let value = value.into_raw();
+        array.iter().any(|elem| elem.value_eq(&value))
+    }
+
+    fn vec_sort<T>(array: (mut Vec<T>)) {
+        array.sort_by(|a, b| a.value_cmp(b));
+    }
+
+    fn vec_choose<T>(context: Context, array: (Vec<T>)) -> (ref T) {
+        if array.is_empty() {
+            runtime_error!(context, "cannot choose from an empty list");
+        }
+
+        array[context.rng.gen_range(0, array.len())]
+    }
+
+    fn vec_shuffle<T>(context: Context, array: (mut Vec<T>)) {
+        context.rng.shuffle(array);
+    }
+
+    fn weighted_index<(T: Number)>(context: Context, weights: (Vec<T>), count: i64) -> i64 {
+        let weights: Vec<f64> = weights.iter().map(|weight| *weight as f64).collect();
+        let sample = context.rng.next_f64();
+        match weighted_choice(&weights, count as usize, sample) {
+            Ok(index) => index as i64,
+            Err(message) => runtime_error!(context, "{}", message),
+        }
+    }
+
     #[readonly]
-    fn vec_get<T>(array: (Vec<T>), index: i64) -> (ref T) {
-        array[index as usize]
+    fn vec_find<T>(array: (Vec<T>), value: T) -> i64 {
+        let value = value.into_raw();
+        match array.iter().position(|elem| elem.value_eq(&value)) {
+            Some(index) => index as i64,
+            None => -1,
+        }
+    }
+
+    #[readonly]
+    fn vec_get_or<T>(array: (Vec<T>), index: i64, default: T) -> (ref T) {
+        match array.get(index as usize) {
+            Some(value) if index >= 0 => *value,
+            _ => default.into_raw(),
+        }
+    }
+
+    fn vec_set<T>(context: Context, array: (mut Vec<T>), index: i64, value: T) {
+        match list_index(index, array.len()) {
+            Some(index) => array[index] = value.into_raw(),
+            None => runtime_error!(context, "index {} is out of bounds for an array of length {}", index, array.len()),
+        }
+    }
+
+    fn vec_remove<T>(context: Context, array: (mut Vec<T>), index: i64) {
+        match list_index(index, array.len()) {
+            Some(index) => {
+                array.remove(index);
+            },
+            None => runtime_error!(context, "index {} is out of bounds for an array of length {}", index, array.len()),
+        }
+    }
+
+    fn array_index(context: Context, index: i64, len: i64) -> i64 {
+        match list_index(index, len as usize) {
+            Some(index) => index as i64,
+            None => runtime_error!(context, "index {} is out of bounds for an array of length {}", index, len),
+        }
+    }
+
+    fn range_step(context: Context, step: f64) -> f64 {
+        if step == 0.0 {
+            runtime_error!(context, "range step cannot be zero");
+        }
+
+        step
+    }
+
+    fn int_div(context: Context, lhs: i64, rhs: i64) -> i64 {
+        if rhs == 0 {
+            runtime_error!(context, "division by zero: {} / 0", lhs);
+        }
+
+        lhs.wrapping_div(rhs)
+    }
+
+    fn int_rem(context: Context, lhs: i64, rhs: i64) -> i64 {
+        if rhs == 0 {
+            runtime_error!(context, "division by zero: {} % 0", lhs);
+        }
+
+        lhs.wrapping_rem(rhs)
     }
 
     #[readonly]
@@ -115,8 +379,205 @@ declare_externals! {
     }
 
     #[readonly]
-    fn parse(val: String) -> f64 {
-        val.parse().expect("not a number")
+    fn int_to_string(context: Context, val: i64) -> String {
+        val.to_string()
+    }
+
+    #[readonly]
+    fn entity_name(context: Context, entity: Entity) -> String {
+        entity.to_string()
+    }
+
+    #[readonly]
+    fn format_number(context: Context, val: f64, zero: bool, width: i64, precision: i64) -> String {
+        // Negative widths and precisions are left unspecified
+        let width = if width < 0 { 0 } else { width as usize };
+        match (zero, precision) {
+            (true, precision) if precision >= 0 => format!("{:0width$.precision$}", val, width = width, precision = precision as usize),
+            (false, precision) if precision >= 0 => format!("{:width$.precision$}", val, width = width, precision = precision as usize),
+            (true, _) => format!("{:0width$}", val, width = width),
+            (false, _) => format!("{:width$}", val, width = width),
+        }
+    }
+
+    fn parse(context: Context, val: String) -> f64 {
+        match val.parse::<f64>() {
+            Ok(val) => val,
+            Err(_) => runtime_error!(context, "parse: \"{}\" is not a number", val),
+        }
+    }
+
+    fn parse_int(context: Context, val: String) -> i64 {
+        match parse_integer(val) {
+            Some(val) => val,
+            None => runtime_error!(context, "parse_int: \"{}\" is not a number", val),
+        }
+    }
+
+    fn parse_bool(context: Context, val: String) -> bool {
+        match val.as_str() {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => runtime_error!(context, "parse_bool: \"{}\" is not a boolean", val),
+        }
+    }
+
+    #[readonly]
+    fn str_len(value: String) -> i64 {
+        value.chars().count() as i64
+    }
+
+    #[readonly]
+    fn str_contains(value: String, pattern: String) -> bool {
+        value.contains(pattern.as_str())
+    }
+
+    #[readonly]
+    fn str_find(value: String, pattern: String) -> i64 {
+        string_find(value, pattern)
+    }
+
+    #[readonly]
+    fn str_starts_with(value: String, pattern: String) -> bool {
+        value.starts_with(pattern.as_str())
+    }
+
+    #[readonly]
+    fn str_ends_with(value: String, pattern: String) -> bool {
+        value.ends_with(pattern.as_str())
+    }
+
+    #[readonly]
+    fn str_matches(value: String, pattern: String) -> bool {
+        glob_match(pattern, value)
+    }
+
+    #[readonly]
+    fn str_split(context: Context, value: String, separator: String) -> (Vec<String>) {
+        string_split(value, separator)
+            .into_iter()
+            .map(|part| context.arenas.strings.alloc(part) as *const _)
+            .collect()
+    }
+
+    #[readonly]
+    fn str_join(context: Context, parts: (Vec<String>), separator: String) -> String {
+        let parts: Vec<&str> = parts.iter().map(|part| unsafe { (**part).as_str() }).collect();
+        parts.join(separator.as_str())
+    }
+
+    #[readonly]
+    fn str_replace(context: Context, value: String, pattern: String, replacement: String) -> String {
+        value.replace(pattern.as_str(), replacement)
+    }
+
+    fn str_substr(context: Context, value: String, start: i64, length: i64) -> String {
+        match substring(value, start, length) {
+            Some(res) => res,
+            None => runtime_error!(context, "substring {}+{} is out of bounds for a string of length {}", start, length, value.chars().count()),
+        }
+    }
+
+    #[readonly]
+    fn str_trim(context: Context, value: String) -> String {
+        value.trim().to_string()
+    }
+
+    #[readonly]
+    fn str_upper(context: Context, value: String) -> String {
+        value.to_uppercase()
+    }
+
+    #[readonly]
+    fn str_lower(context: Context, value: String) -> String {
+        value.to_lowercase()
+    }
+
+    #[readonly]
+    fn vec3(context: Context, x: f64, y: f64, z: f64) -> vec3 {
+        [x, y, z]
+    }
+
+    #[readonly]
+    fn qangle(context: Context, pitch: f64, yaw: f64, roll: f64) -> qangle {
+        [pitch, yaw, roll]
+    }
+
+    #[readonly]
+    fn vector_add<(T: Vector)>(context: Context, lhs: T, rhs: T) -> T {
+        [lhs[0] + rhs[0], lhs[1] + rhs[1], lhs[2] + rhs[2]]
+    }
+
+    #[readonly]
+    fn vector_sub<(T: Vector)>(context: Context, lhs: T, rhs: T) -> T {
+        [lhs[0] - rhs[0], lhs[1] - rhs[1], lhs[2] - rhs[2]]
+    }
+
+    #[readonly]
+    fn vector_scale<(T: Vector)>(context: Context, value: T, factor: f64) -> T {
+        [value[0] * factor, value[1] * factor, value[2] * factor]
+    }
+
+    #[readonly]
+    fn vector_to_string<(T: Vector)>(context: Context, value: T) -> String {
+        format!("{} {} {}", value[0], value[1], value[2])
+    }
+
+    fn parse_vector<(T: Vector)>(context: Context, value: String) -> T {
+        match parse_components(value) {
+            Some(res) => res,
+            None => runtime_error!(context, "\"{}\" is not a vector of three numbers", value),
+        }
+    }
+
+    #[readonly]
+    fn dot(lhs: vec3, rhs: vec3) -> f64 {
+        dot_product(lhs, rhs)
+    }
+
+    #[readonly]
+    fn cross(context: Context, lhs: vec3, rhs: vec3) -> vec3 {
+        [
+            lhs[1] * rhs[2] - lhs[2] * rhs[1],
+            lhs[2] * rhs[0] - lhs[0] * rhs[2],
+            lhs[0] * rhs[1] - lhs[1] * rhs[0],
+        ]
+    }
+
+    #[readonly]
+    fn normalize(context: Context, value: vec3) -> vec3 {
+        // The zero vector has no direction and is left unchanged
+        let len = dot_product(value, value).sqrt();
+        if len == 0.0 {
+            *value
+        } else {
+            [value[0] / len, value[1] / len, value[2] / len]
+        }
+    }
+
+    #[readonly]
+    fn vector_length(value: vec3) -> f64 {
+        dot_product(value, value).sqrt()
+    }
+
+    #[readonly]
+    fn distance(lhs: vec3, rhs: vec3) -> f64 {
+        let diff = [lhs[0] - rhs[0], lhs[1] - rhs[1], lhs[2] - rhs[2]];
+        dot_product(&diff, &diff).sqrt()
+    }
+
+    #[readonly]
+    fn vector_lerp(context: Context, from: vec3, to: vec3, t: f64) -> vec3 {
+        [
+            from[0] + (to[0] - from[0]) * t,
+            from[1] + (to[1] - from[1]) * t,
+            from[2] + (to[2] - from[2]) * t,
+        ]
+    }
+
+    #[readonly]
+    fn rotate(context: Context, value: vec3, angles: qangle) -> vec3 {
+        rotate_vector(value, angles)
     }
 
     #[readonly]
@@ -129,11 +590,21 @@ declare_externals! {
     }
 
     fn get_property(context: Context, entity: (ref Entity), key: Atom) -> (ref String) {
-        entity.properties.get(key).expect("key not found")
+        match entity.properties.get(key) {
+            Some(value) => value,
+            None => runtime_error!(context, "property \"{}\" not found", key),
+        }
+    }
+
+    fn has(context: Context, entity: (ref Entity), key: String) -> bool {
+        entity.properties.contains_key(&Atom::from(key.as_str()))
     }
 
     fn get_sub_property(context: Context, value: String, index: i64) -> String {
-        value.split_whitespace().nth(index as usize).unwrap().into()
+        match value.split_whitespace().nth(index as usize) {
+            Some(value) => value.into(),
+            None => runtime_error!(context, "sub-property {} not found in \"{}\"", index, value),
+        }
     }
 
     fn set_property(context: Context, entity: (mut Entity), key: Atom, value: String) {
@@ -141,14 +612,19 @@ declare_externals! {
     }
 
     fn set_sub_property(context: Context, entity: (mut Entity), key: Atom, index: i64, value: String) {
-        let mut current: Vec<String> = {
-            entity.properties.get(key)
-                .expect(&format!("property \"{}\" not found", key))
-                .split_whitespace()
-                .map(|s| s.into())
-                .collect()
+        let mut current: Vec<String> = match entity.properties.get(key) {
+            Some(current) => {
+                current.split_whitespace()
+                    .map(|s| s.into())
+                    .collect()
+            },
+            None => runtime_error!(context, "property \"{}\" not found", key),
         };
 
+        if index < 0 || index as usize >= current.len() {
+            runtime_error!(context, "sub-property {} not found in \"{}\"", index, current.join(" "));
+        }
+
         current[index as usize] = value.to_string();
 
         let joined = current.join(" ");
@@ -171,3 +647,423 @@ declare_externals! {
         });
     }
 }
+
+/// Resolve an index into a list of the given length, negative indices
+/// counting from the end, returning None if it is out of bounds
+pub fn list_index(index: i64, len: usize) -> Option<usize> {
+    let len = len as i64;
+    let index = if index < 0 { index + len } else { index };
+    if index >= 0 && index < len {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+/// Resolve the bounds of a slice of a list of the given length,
+/// returning None if the range is out of bounds or reversed
+pub fn list_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { start + len } else { start };
+    let end = if end < 0 { end + len } else { end };
+    if start >= 0 && start <= end && end <= len {
+        Some((start as usize, end as usize))
+    } else {
+        None
+    }
+}
+
+/// Get the name of the external implementing a string function for
+/// the type of its first argument, or None if it is not one
+pub fn string_function(name: &Atom, arity: usize, first: &TypeId) -> Option<Atom> {
+    if let TypeId::Vec { ref ty } = *first {
+        return match *name {
+            hct_atom!("join") if **ty == TypeId::String => Some(hct_atom!("str_join")),
+            _ => None,
+        };
+    }
+
+    if *first != TypeId::String {
+        return None;
+    }
+
+    match *name {
+        hct_atom!("len") => Some(hct_atom!("str_len")),
+        hct_atom!("contains") => Some(hct_atom!("str_contains")),
+        // Finding an entity by name only takes a single string
+        hct_atom!("find") if arity != 1 => Some(hct_atom!("str_find")),
+        hct_atom!("starts_with") => Some(hct_atom!("str_starts_with")),
+        hct_atom!("ends_with") => Some(hct_atom!("str_ends_with")),
+        hct_atom!("matches") => Some(hct_atom!("str_matches")),
+        hct_atom!("split") => Some(hct_atom!("str_split")),
+        hct_atom!("replace") => Some(hct_atom!("str_replace")),
+        hct_atom!("substr") => Some(hct_atom!("str_substr")),
+        hct_atom!("trim") => Some(hct_atom!("str_trim")),
+        hct_atom!("upper") => Some(hct_atom!("str_upper")),
+        hct_atom!("lower") => Some(hct_atom!("str_lower")),
+        _ => None,
+    }
+}
+
+/// Get the name of the external implementing a math function for
+/// the types of its arguments, or None if it is not one
+pub fn math_function<'a, I>(name: &Atom, args: I) -> Option<Atom>
+    where I: IntoIterator<Item=&'a TypeId> {
+    let args: Vec<_> = args.into_iter().collect();
+    let is_int = !args.is_empty() && args.iter().all(|ty| **ty == TypeId::i64);
+
+    match *name {
+        hct_atom!("lerp") if args.first() == Some(&&TypeId::vec3) => Some(hct_atom!("vector_lerp")),
+        hct_atom!("mod") => Some(hct_atom!("int_mod")),
+        hct_atom!("noise") if args.len() == 2 => Some(hct_atom!("noise2")),
+        hct_atom!("noise") => Some(hct_atom!("noise3")),
+
+        // Integers keep their type through the functions that can preserve it
+        hct_atom!("abs") if is_int => Some(hct_atom!("int_abs")),
+        hct_atom!("min") if is_int => Some(hct_atom!("int_min")),
+        hct_atom!("max") if is_int => Some(hct_atom!("int_max")),
+        hct_atom!("clamp") if is_int => Some(hct_atom!("int_clamp")),
+        hct_atom!("sign") if is_int => Some(hct_atom!("int_sign")),
+
+        hct_atom!("exp") | hct_atom!("sqrt") | hct_atom!("pow") | hct_atom!("sin") |
+        hct_atom!("cos") | hct_atom!("tan") | hct_atom!("asin") | hct_atom!("acos") |
+        hct_atom!("atan2") | hct_atom!("floor") | hct_atom!("ceil") | hct_atom!("round") |
+        hct_atom!("abs") | hct_atom!("log") | hct_atom!("min") | hct_atom!("max") |
+        hct_atom!("clamp") | hct_atom!("lerp") | hct_atom!("sign") | hct_atom!("deg") |
+        hct_atom!("rad") => Some(name.clone()),
+
+        _ => None,
+    }
+}
+
+/// Pick the index of an element of a list with a probability proportional to
+/// its weight, from a sample taken uniformly between 0 included and 1 excluded
+pub fn weighted_choice(weights: &[f64], count: usize, sample: f64) -> Result<usize, String> {
+    if weights.len() != count {
+        return Err(format!("choose_weighted: {} weights for a list of length {}", weights.len(), count));
+    }
+    if weights.iter().any(|weight| *weight < 0.0 || !weight.is_finite()) {
+        return Err(String::from("choose_weighted: the weights must be positive numbers"));
+    }
+
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return Err(String::from("choose_weighted: the sum of the weights must not be zero"));
+    }
+
+    let target = sample * total;
+    let mut sum = 0.0;
+    for (index, weight) in weights.iter().enumerate() {
+        sum += *weight;
+        if target < sum {
+            return Ok(index);
+        }
+    }
+
+    // Rounding errors can leave the target past the last sum
+    Ok(weights.iter().rposition(|weight| *weight > 0.0).unwrap_or(0))
+}
+
+/// Sample the improved Perlin noise at a point, using a permutation table
+/// of 512 entries, and get a value roughly between -1 and 1
+pub fn perlin_noise(perm: &[usize], x: f64, y: f64, z: f64) -> f64 {
+    fn fade(t: f64) -> f64 {
+        t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+    }
+
+    fn lerp(t: f64, a: f64, b: f64) -> f64 {
+        a + t * (b - a)
+    }
+
+    fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 {
+            y
+        } else if h == 12 || h == 14 {
+            x
+        } else {
+            z
+        };
+
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+
+    let (fx, fy, fz) = (x.floor(), y.floor(), z.floor());
+    let (xi, yi, zi) = (
+        (fx as i64 & 255) as usize,
+        (fy as i64 & 255) as usize,
+        (fz as i64 & 255) as usize,
+    );
+    let (x, y, z) = (x - fx, y - fy, z - fz);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = perm[xi] + yi;
+    let aa = perm[a] + zi;
+    let ab = perm[a + 1] + zi;
+    let b = perm[xi + 1] + yi;
+    let ba = perm[b] + zi;
+    let bb = perm[b + 1] + zi;
+
+    lerp(w,
+        lerp(v,
+            lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1.0, y, z)),
+            lerp(u, grad(perm[ab], x, y - 1.0, z), grad(perm[bb], x - 1.0, y - 1.0, z)),
+        ),
+        lerp(v,
+            lerp(u, grad(perm[aa + 1], x, y, z - 1.0), grad(perm[ba + 1], x - 1.0, y, z - 1.0)),
+            lerp(u, grad(perm[ab + 1], x, y - 1.0, z - 1.0), grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0)),
+        ),
+    )
+}
+
+/// Check if a name refers to an entity of the map, the names resolved
+/// by the engine (`!player`) or left to an instance (`@name`) being
+/// assumed to exist
+pub fn is_known_entity(name: &str, entities: &HashMap<Atom, Entity>) -> bool {
+    name.starts_with('!') || name.starts_with('@') || entities.contains_key(&Atom::from(name))
+}
+
+/// Parse a string as an integer, truncating the numbers written with a fraction
+pub fn parse_integer(value: &str) -> Option<i64> {
+    if let Ok(res) = value.parse::<i64>() {
+        return Some(res);
+    }
+
+    match value.parse::<f64>() {
+        Ok(res) if res.is_finite() => Some(res.trunc() as i64),
+        _ => None,
+    }
+}
+
+/// Get the sign of a number as -1, 0 or 1
+pub fn sign(value: f64) -> f64 {
+    if value > 0.0 {
+        1.0
+    } else if value < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+/// Compute the remainder of an integer division, always
+/// positive unlike the remainder of the % operator
+pub fn modulo(lhs: i64, rhs: i64) -> i64 {
+    let rem = lhs.wrapping_rem(rhs);
+    if rem < 0 {
+        rem.wrapping_add(rhs.wrapping_abs())
+    } else {
+        rem
+    }
+}
+
+/// Find the index of the first character of a pattern in a string,
+/// returning -1 if it is not found
+pub fn string_find(value: &str, pattern: &str) -> i64 {
+    match value.find(pattern) {
+        Some(index) => value[..index].chars().count() as i64,
+        None => -1,
+    }
+}
+
+/// Split a string on a separator, or on whitespace if the separator is empty
+pub fn string_split(value: &str, separator: &str) -> Vec<String> {
+    if separator.is_empty() {
+        value.split_whitespace().map(String::from).collect()
+    } else {
+        value.split(separator).map(String::from).collect()
+    }
+}
+
+/// Get the characters of a string from a start index, negative indices
+/// counting from the end, returning None if they are out of bounds
+pub fn substring(value: &str, start: i64, length: i64) -> Option<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let start = if start < 0 { start + chars.len() as i64 } else { start };
+    if start < 0 || length < 0 {
+        return None;
+    }
+
+    list_range(start, start + length, chars.len())
+        .map(|(start, end)| chars[start..end].iter().cloned().collect())
+}
+
+/// Match a string against a pattern where * matches any sequence
+/// of characters and ? matches a single character
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut p, mut v) = (0, 0);
+    // Position of the last star in the pattern and of the value when it was reached
+    let mut backtrack = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) && pattern[p] != '*' {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the star consume one more character
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            v = matched + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|chr| *chr == '*')
+}
+
+/// Parse the three components of a vector property
+pub fn parse_components(value: &str) -> Option<[f64; 3]> {
+    let mut parts = value.split_whitespace();
+    let mut res = [0.0; 3];
+    for component in &mut res {
+        *component = match parts.next().map(|part| part.parse::<f64>()) {
+            Some(Ok(val)) => val,
+            _ => return None,
+        };
+    }
+
+    if parts.next().is_none() {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+/// Sum of the products of the components of two vectors
+pub fn dot_product(lhs: &[f64; 3], rhs: &[f64; 3]) -> f64 {
+    lhs[0] * rhs[0] + lhs[1] * rhs[1] + lhs[2] * rhs[2]
+}
+
+/// Rotate a vector by pitch, yaw and roll angles in degrees,
+/// following the axes of the Source engine
+pub fn rotate_vector(value: &[f64; 3], angles: &[f64; 3]) -> [f64; 3] {
+    let (sp, cp) = angles[0].to_radians().sin_cos();
+    let (sy, cy) = angles[1].to_radians().sin_cos();
+    let (sr, cr) = angles[2].to_radians().sin_cos();
+
+    // Rows of the rotation matrix
+    let forward = [cp * cy, sr * sp * cy - cr * sy, cr * sp * cy + sr * sy];
+    let left = [cp * sy, sr * sp * sy + cr * cy, cr * sp * sy - sr * cy];
+    let up = [-sp, sr * cp, cr * cp];
+
+    [
+        dot_product(value, &forward),
+        dot_product(value, &left),
+        dot_product(value, &up),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_characters() {
+        assert_eq!(string_find("héllo", "l"), 2);
+        assert_eq!(string_find("abc", "d"), -1);
+        assert_eq!(string_find("abc", ""), 0);
+    }
+
+    #[test]
+    fn splits_strings() {
+        assert_eq!(string_split("button_3_panel", "_"), vec!["button", "3", "panel"]);
+        assert_eq!(string_split("a,,b", ","), vec!["a", "", "b"]);
+        assert_eq!(string_split("  a \tb\n", ""), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn takes_substrings() {
+        assert_eq!(substring("héllo", 1, 3), Some(String::from("éll")));
+        assert_eq!(substring("hello", -3, 2), Some(String::from("ll")));
+        assert_eq!(substring("hello", 3, 3), None);
+        assert_eq!(substring("hello", -6, 1), None);
+        assert_eq!(substring("hello", 0, -1), None);
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("door_*", "door_12"));
+        assert!(glob_match("*_?", "door_1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("door_?", "door_12"));
+        assert!(!glob_match("a*c", "abcd"));
+    }
+
+    #[test]
+    fn computes_positive_remainders() {
+        assert_eq!(modulo(7, 3), 1);
+        assert_eq!(modulo(-7, 3), 2);
+        assert_eq!(modulo(7, -3), 1);
+        assert_eq!(modulo(-7, -3), 2);
+        assert_eq!(modulo(i64::min_value(), -1), 0);
+    }
+
+    #[test]
+    fn keeps_integers_in_math_functions() {
+        let function = |name: &str, args: &[TypeId]| math_function(&Atom::from(name), args);
+
+        assert_eq!(function("min", &[TypeId::i64, TypeId::i64]), Some(Atom::from("int_min")));
+        assert_eq!(function("min", &[TypeId::i64, TypeId::f64]), Some(Atom::from("min")));
+        assert_eq!(function("clamp", &[TypeId::i64, TypeId::i64, TypeId::i64]), Some(Atom::from("int_clamp")));
+        assert_eq!(function("sqrt", &[TypeId::i64]), Some(Atom::from("sqrt")));
+        assert_eq!(function("mod", &[TypeId::f64, TypeId::i64]), Some(Atom::from("int_mod")));
+        assert_eq!(function("lerp", &[TypeId::vec3, TypeId::vec3, TypeId::f64]), Some(Atom::from("vector_lerp")));
+        assert_eq!(function("noise", &[TypeId::f64, TypeId::f64]), Some(Atom::from("noise2")));
+        assert_eq!(function("split", &[TypeId::String]), None);
+    }
+
+    #[test]
+    fn chooses_by_weight() {
+        assert_eq!(weighted_choice(&[1.0, 3.0], 2, 0.2), Ok(0));
+        assert_eq!(weighted_choice(&[1.0, 3.0], 2, 0.3), Ok(1));
+        assert_eq!(weighted_choice(&[0.0, 2.0, 0.0], 3, 0.0), Ok(1));
+        assert_eq!(
+            weighted_choice(&[1.0], 2, 0.5),
+            Err(String::from("choose_weighted: 1 weights for a list of length 2")),
+        );
+        assert_eq!(
+            weighted_choice(&[1.0, -1.0], 2, 0.5),
+            Err(String::from("choose_weighted: the weights must be positive numbers")),
+        );
+        assert_eq!(
+            weighted_choice(&[0.0, 0.0], 2, 0.5),
+            Err(String::from("choose_weighted: the sum of the weights must not be zero")),
+        );
+    }
+
+    #[test]
+    fn samples_noise() {
+        let perm: Vec<usize> = (0..256).rev().chain((0..256).rev()).collect();
+
+        // The noise is zero on the points of the lattice
+        assert_eq!(perlin_noise(&perm, 1.0, 2.0, 3.0), 0.0);
+        assert_eq!(perlin_noise(&perm, -4.0, 0.0, 0.0), 0.0);
+
+        for i in 0..100 {
+            let x = f64::from(i) * 0.37;
+            let value = perlin_noise(&perm, x, x * 0.5 - 3.0, 0.25);
+            assert!(value >= -1.5 && value <= 1.5, "noise({}) = {}", x, value);
+            assert_eq!(value, perlin_noise(&perm, x, x * 0.5 - 3.0, 0.25));
+        }
+    }
+
+    #[test]
+    fn knows_the_entities_of_the_map() {
+        let mut entities = HashMap::new();
+        entities.insert(Atom::from("door"), Entity::default());
+
+        assert!(is_known_entity("door", &entities));
+        assert!(is_known_entity("!player", &entities));
+        assert!(is_known_entity("@button", &entities));
+        assert!(!is_known_entity("doors", &entities));
+        assert!(!is_known_entity("", &entities));
+    }
+}
@@ -1,20 +1,30 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt::*;
+use std::ptr;
+#[cfg(feature = "jit")]
 use libc::c_void;
 
+#[cfg(feature = "jit")]
 use llvm_sys::LLVMModule;
+#[cfg(feature = "jit")]
 use llvm_sys::execution_engine::*;
+#[cfg(feature = "jit")]
 use llvm_sys::prelude::*;
+#[cfg(feature = "jit")]
 use llvm_sys::core::*;
 use rand::{Rng, SeedableRng, StdRng};
 use typed_arena::Arena;
 
+#[cfg(feature = "jit")]
 use compiler::builder::*;
+#[cfg(feature = "jit")]
 use compiler::types::{TypeId, Global};
 use vmf::ir::{Script, Entity};
 use atom::Atom;
 
 /// Pointer and type metadata for a function
+#[cfg(feature = "jit")]
 #[derive(Clone, Debug, PartialEq)]
 pub struct Function {
     pub ptr: Value,
@@ -25,11 +35,108 @@ pub struct Function {
 pub struct Arenas {
     pub atoms: Arena<Atom>,
     pub strings: Arena<String>,
-    pub ent_vec: Arena<Vec<*const Atom>>
+    /// Components of the vectors and the angles
+    pub vectors: Arena<[f64; 3]>,
+    pub f64_vec: Arena<Vec<f64>>,
+    pub bool_vec: Arena<Vec<bool>>,
+    pub i64_vec: Arena<Vec<i64>>,
+    pub ent_vec: Arena<Vec<*const Atom>>,
+    pub str_vec: Arena<Vec<*const String>>,
 }
 
+/// Raw element type of the vectors allocated in the arenas
+/// Pointers are compared by the value they point to
+pub trait VecElement: Sized {
+    fn vec_arena(arenas: &Arenas) -> &Arena<Vec<Self>>;
+    fn value_eq(&self, other: &Self) -> bool;
+    fn value_cmp(&self, other: &Self) -> Ordering;
+}
+
+macro_rules! vec_element {
+    (*const $ty:ty, $field:ident) => {
+        impl VecElement for *const $ty {
+            fn vec_arena(arenas: &Arenas) -> &Arena<Vec<*const $ty>> {
+                &arenas.$field
+            }
+            fn value_eq(&self, other: &Self) -> bool {
+                unsafe { **self == **other }
+            }
+            fn value_cmp(&self, other: &Self) -> Ordering {
+                unsafe { (**self).cmp(&**other) }
+            }
+        }
+    };
+    ($ty:ty, $field:ident) => {
+        impl VecElement for $ty {
+            fn vec_arena(arenas: &Arenas) -> &Arena<Vec<$ty>> {
+                &arenas.$field
+            }
+            fn value_eq(&self, other: &Self) -> bool {
+                self == other
+            }
+            fn value_cmp(&self, other: &Self) -> Ordering {
+                self.partial_cmp(other).unwrap_or(Ordering::Equal)
+            }
+        }
+    };
+}
+
+vec_element!(f64, f64_vec);
+vec_element!(bool, bool_vec);
+vec_element!(i64, i64_vec);
+vec_element!(*const Atom, ent_vec);
+vec_element!(*const String, str_vec);
+
+/// Convert a generic argument of an external function back to its raw type
+pub trait IntoRaw {
+    type Raw;
+    fn into_raw(self) -> Self::Raw;
+}
+
+impl<'a, T> IntoRaw for &'a T {
+    type Raw = *const T;
+    fn into_raw(self) -> *const T {
+        self
+    }
+}
+
+macro_rules! into_raw {
+    ($ty:ty) => {
+        impl IntoRaw for $ty {
+            type Raw = $ty;
+            fn into_raw(self) -> $ty {
+                self
+            }
+        }
+    };
+}
+
+into_raw!(f64);
+into_raw!(bool);
+into_raw!(i64);
+
 impl Arenas {
-    #[cfg_attr(feature="clippy", allow(not_unsafe_ptr_arg_deref))]
+    #[cfg_attr(feature="cargo-clippy", allow(new_without_default))]
+    pub fn new() -> Arenas {
+        Arenas {
+            atoms: Arena::new(),
+            strings: Arena::new(),
+            vectors: Arena::new(),
+            f64_vec: Arena::new(),
+            bool_vec: Arena::new(),
+            i64_vec: Arena::new(),
+            ent_vec: Arena::new(),
+            str_vec: Arena::new(),
+        }
+    }
+
+    /// Move a vector to the arena of its element type
+    pub fn alloc_vec<T: VecElement>(&self, vec: Vec<T>) -> &mut Vec<T> {
+        T::vec_arena(self).alloc(vec)
+    }
+
+    #[cfg(feature = "jit")]
+    #[cfg_attr(feature="cargo-clippy", allow(not_unsafe_ptr_arg_deref))]
     pub fn with_globals(module: *mut LLVMModule, engine: LLVMExecutionEngineRef, globals: HashMap<Global, LLVMValueRef>) -> Arenas {
         let atoms = Arena::new();
         let strings = Arena::new();
@@ -59,7 +166,12 @@ impl Arenas {
 
         Arenas {
             atoms, strings,
+            vectors: Arena::new(),
+            f64_vec: Arena::new(),
+            bool_vec: Arena::new(),
+            i64_vec: Arena::new(),
             ent_vec: Arena::new(),
+            str_vec: Arena::new(),
         }
     }
 }
@@ -69,13 +181,25 @@ pub struct Context {
     pub entities: HashMap<Atom, Entity>,
     pub arenas: Arenas,
     pub rng: Box<Rng>,
+    /// Permutation table of the gradient noise, derived from the seed
+    pub noise: Vec<usize>,
+    /// Error raised by an external function, checked by the generated code
+    /// after each call to abort the script
+    pub error: Option<String>,
 }
 
 impl Context {
     pub fn new(ent: Script, arenas: Arenas, entities: HashMap<Atom, Entity>) -> Context {
+        // The noise has its own generator, so that sampling
+        // it does not change the sequence of random numbers
+        let mut noise: Vec<usize> = (0..256).collect();
+        StdRng::from_seed(&[ent.seed, 1]).shuffle(&mut noise);
+        let noise = noise.iter().chain(noise.iter()).cloned().collect();
+
         Context {
-            arenas, entities,
+            arenas, entities, noise,
             rng: box StdRng::from_seed(&[ent.seed]),
+            error: None,
         }
     }
 }
@@ -84,6 +208,32 @@ impl Debug for Context {
     fn fmt(&self, fmt: &mut Formatter) -> Result {
         fmt.debug_struct("Context")
             .field("entities", &self.entities)
+            .field("error", &self.error)
             .finish()
     }
 }
+
+/// Placeholder value returned by an external function after a runtime error
+/// The generated code never reads it, as it leaves the script right away
+pub trait RuntimeDefault {
+    fn runtime_default() -> Self;
+}
+
+impl RuntimeDefault for () {
+    fn runtime_default() {}
+}
+impl RuntimeDefault for f64 {
+    fn runtime_default() -> f64 { 0.0 }
+}
+impl RuntimeDefault for bool {
+    fn runtime_default() -> bool { false }
+}
+impl RuntimeDefault for i64 {
+    fn runtime_default() -> i64 { 0 }
+}
+impl<T> RuntimeDefault for *const T {
+    fn runtime_default() -> *const T { ptr::null() }
+}
+impl<T> RuntimeDefault for *mut T {
+    fn runtime_default() -> *mut T { ptr::null_mut() }
+}
@@ -23,14 +23,17 @@ pub struct Connection {
 }
 
 impl Connection {
-    fn from_value(prop: &Property<Atom>) -> Connection {
+    fn from_value(prop: &Property<Atom>) -> Result<Connection, String> {
         let mut evt: Vec<_> = prop.value.split('\x1b').collect();
         if evt.len() == 1 {
             evt = prop.value.split(',').collect();
         }
 
-        assert_eq!(evt.len(), 5, "invalid event: {:?}", evt);
-        Connection {
+        if evt.len() != 5 {
+            return Err(format!("invalid event: {:?}", evt));
+        }
+
+        Ok(Connection {
             event: prop.key.clone(),
             entity: evt[0].into(),
             method: evt[1].into(),
@@ -43,9 +46,9 @@ impl Connection {
                     "".into()
                 }
             },
-            delay: evt[3].parse().expect("invalid number"),
+            delay: evt[3].parse().map_err(|_| format!("invalid delay in event: {:?}", evt))?,
             once: evt[4] == "1"
-        }
+        })
     }
     pub fn into_value(self) -> Property<Atom> {
         Property {
@@ -81,7 +84,7 @@ impl PartialEq for Entity {
 }
 
 impl Entity {
-    fn from_value(block: &Block<Atom>) -> Option<Entity> {
+    fn from_value(block: &Block<Atom>) -> Result<Option<Entity>, String> {
         if block.name == hct_atom!("entity") {
             let mut ent = Entity::default();
             for prop in &block.props {
@@ -101,7 +104,7 @@ impl Entity {
                 if block.name == hct_atom!("connections") {
                     for prop in &block.props {
                         ent.connections.push(
-                            Connection::from_value(prop)
+                            Connection::from_value(prop)?
                         );
                     }
                 } else {
@@ -109,9 +112,9 @@ impl Entity {
                 }
             }
 
-            Some(ent)
+            Ok(Some(ent))
         } else {
-            None
+            Ok(None)
         }
     }
     pub fn into_value(self) -> Block<Atom> {
@@ -167,7 +170,8 @@ pub enum InstFile {
 }
 
 impl InstFile {
-    pub fn unwrap(self) -> String {
+    /// Get the path of the instance file, compiled or not
+    pub fn into_path(self) -> String {
         match self {
             InstFile::Original(val) |
             InstFile::Compiled(val) => val,
@@ -228,25 +232,29 @@ pub struct MapFile {
 }
 
 impl MapFile {
-    pub fn from_ast(tree: Vec<Block<Atom>>) -> MapFile {
+    pub fn from_ast(tree: Vec<Block<Atom>>) -> Result<MapFile, String> {
         tree.into_par_iter()
             .fold(
-                MapFile::default,
-                |mut infos, block| {
-                    match Entity::from_value(&block) {
+                || Ok(MapFile::default()),
+                |infos: Result<MapFile, String>, block| {
+                    let mut infos = infos?;
+                    match Entity::from_value(&block)? {
                         // Script entities are removed from the AST
                         Some(Entity { ref classname, ref properties, .. }) if *classname == hct_atom!("logic_hatchet") => {
                             infos.scripts.push(Script {
                                 script: properties.get(&hct_atom!("script"))
-                                    .expect("missing script in logic_hatchet").clone(),
-                                seed: properties.get(&hct_atom!("seed"))
-                                    .map(|seed| {
-                                        seed.parse::<usize>().expect("seed is not a valid integer")
-                                    })
-                                    .unwrap_or_else(|| {
+                                    .ok_or_else(|| String::from("missing script in logic_hatchet"))?
+                                    .clone(),
+                                seed: match properties.get(&hct_atom!("seed")) {
+                                    Some(seed) => {
+                                        seed.parse::<usize>()
+                                            .map_err(|_| format!("seed {:?} is not a valid integer", seed))?
+                                    },
+                                    None => {
                                         warn!("logic_hatchet with no seed, using 0");
                                         0
-                                    }),
+                                    },
+                                },
                             });
                         },
 
@@ -256,7 +264,8 @@ impl MapFile {
                             infos.instances.insert(Instance {
                                 file: InstFile::Original(
                                     ent.properties.get(&hct_atom!("file"))
-                                        .expect("missing file property in func_instance").clone()
+                                        .ok_or_else(|| String::from("missing file property in func_instance"))?
+                                        .clone()
                                 ),
                                 entity: if let Some(ref targetname) = ent.targetname {
                                     infos.entities.insert(targetname.clone(), ent.clone());
@@ -279,12 +288,12 @@ impl MapFile {
                         },
                     }
 
-                    infos
+                    Ok(infos)
                 }
             )
             .reduce(
-                MapFile::default,
-                MapFile::merge
+                || Ok(MapFile::default()),
+                |a, b| Ok(MapFile::merge(a?, b?))
             )
     }
     fn merge(a: MapFile, mut b: MapFile) -> MapFile {
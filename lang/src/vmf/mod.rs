@@ -5,22 +5,26 @@ use std::path::Path;
 use std::fs::File;
 use vmfparser::*;
 
+use error::*;
+
 pub use vmfparser::ast::*;
 pub use vmfparser::parser::*;
 pub use self::ir::*;
 
 // Parse a VMF file, returning an IR representation
-pub fn parse_file<P>(path: P) -> MapFile where P: AsRef<Path> {
-    let mut f = File::open(path).expect("could not open file");
+pub fn parse_file<P>(path: P) -> HatchetResult<MapFile> where P: AsRef<Path> {
+    let path = path.as_ref();
     let mut s = String::new();
-    f.read_to_string(&mut s).expect("could not read file");
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|err| HatchetError::io(path, err))?;
 
     let parse_start = timer_start!();
-    let ast = parse(&s).unwrap();
+    let ast = parse(&s).map_err(|err| HatchetError::vmf(path, format!("{:?}", err)))?;
 
     let trans_start = timer_chain!(parse_start, time, "Parsing time: {}", time);
-    let res = MapFile::from_ast(ast);
+    let res = MapFile::from_ast(ast).map_err(|err| HatchetError::vmf(path, err))?;
 
     timer_end!(trans_start, time, "Loading time: {}", time);
-    res
+    Ok(res)
 }